@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ngvm::Code;
+
+fuzz_target!(|data: &[u8]| {
+    let code = Code::from_slice(data);
+    let decoded = code.decode();
+    assert!(decoded.size <= data.len());
+    assert_eq!(decoded.is_full, decoded.size == data.len());
+    let _ = code.compile();
+    let _ = code.control_flow();
+});
@@ -0,0 +1,17 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ngvm::Code;
+
+use common::limited_vm;
+
+#[path = "../../tests/common/mod.rs"]
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let code = Code::from_slice(data);
+    let _ = code.interpret(&mut limited_vm());
+    if let Some(threaded) = code.compile() {
+        let _ = threaded.interpret(&mut limited_vm());
+    }
+});
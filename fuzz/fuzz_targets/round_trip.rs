@@ -0,0 +1,29 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ngvm::decoder::model::DecodedOpcode;
+use ngvm::Code;
+
+/// Canonical encoding of the decoded opcodes
+fn encode(opcodes: &[DecodedOpcode]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for op in opcodes {
+        bytes.extend_from_slice(&op.op_bytes());
+        bytes.extend_from_slice(&op.refs().bytes().expect("decoded refs encode"));
+    }
+    bytes
+}
+
+fuzz_target!(|data: &[u8]| {
+    let decoded = Code::from_slice(data).decode();
+    let bytes = encode(&decoded.opcodes);
+
+    let again = Code::from_slice(&bytes).decode();
+    assert!(again.is_full);
+    assert_eq!(again.opcodes.len(), decoded.opcodes.len());
+    for (a, b) in decoded.opcodes.iter().zip(&again.opcodes) {
+        assert_eq!(a.op_code(), b.op_code());
+        assert_eq!(a.name(), b.name());
+    }
+    assert_eq!(encode(&again.opcodes), bytes);
+});
@@ -0,0 +1,363 @@
+//! Control-flow graph of the bytecode
+//!
+//! The code is split into basic blocks, straight runs of opcodes that are only entered at
+//! their first opcode and only left after their last one. Blocks start at the start of the
+//! code, at jump targets, after jumps and at scope boundaries, so a block never spans
+//! a `StartScope` or an `EndScope` in its middle.
+//!
+//! Along with the blocks, the graph has the dominators of every block reachable from the
+//! start of the code and the natural loops, and renders to Graphviz DOT.
+
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+use crate::code::refs::CodeRef;
+use crate::code::Code;
+use crate::decoder::model::DecodedOpcode;
+use crate::model;
+use crate::opcodes::Opcode;
+use crate::vm::ExtensionTable;
+
+/// Index of a block in the graph, the entry block is `BlockId(0)`
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Hash)]
+pub struct BlockId(pub usize);
+
+pub struct BasicBlock {
+    /// Offset of the first opcode in the code
+    pub start: usize,
+    /// Offset after the last opcode
+    pub end: usize,
+    pub opcodes: Vec<DecodedOpcode>,
+    /// Blocks control goes to after this one, the fallthrough first
+    pub successors: Vec<BlockId>,
+    pub predecessors: Vec<BlockId>,
+    /// If control can leave the code after this block, by a jump to its end or falling off it
+    pub exits: bool,
+}
+
+/// A loop whose body is only entered through its header
+pub struct NaturalLoop {
+    pub header: BlockId,
+    /// Blocks of the body, the header included, in the order of the code
+    pub blocks: Vec<BlockId>,
+}
+
+pub struct ControlFlowGraph {
+    blocks: Vec<BasicBlock>,
+    /// Immediate dominators, the entry block dominates itself, `None` for unreachable blocks
+    dominators: Vec<Option<BlockId>>,
+    loops: Vec<NaturalLoop>,
+}
+
+/// Offsets a jump of `op` at `location` goes to, `None` if one is before the code
+fn jump_targets(location: usize, op: &DecodedOpcode) -> Option<Vec<usize>> {
+    op.refs()
+        .iter()
+        .filter_map(|r| match r.code_ref {
+            CodeRef::Jump(offset) => Some(usize::try_from(location as isize + offset).ok()),
+            _ => None,
+        })
+        .collect()
+}
+
+impl Code<'_> {
+    /// Build the control-flow graph of the code
+    ///
+    /// Returns `None` if the code can't be decoded fully, or a jump does not land
+    /// at the start of an opcode or the end of the code.
+    pub fn control_flow(&self) -> Option<ControlFlowGraph> {
+        self.control_flow_with(&ExtensionTable::default())
+    }
+
+    /// Build the control-flow graph, along with the extension opcodes of `extensions`
+    pub fn control_flow_with(&self, extensions: &ExtensionTable) -> Option<ControlFlowGraph> {
+        let decoded = self.decode_with(extensions);
+        if !decoded.is_full {
+            return None;
+        }
+        let count = decoded.opcodes.len();
+        let mut offsets = Vec::with_capacity(count + 1);
+        let mut size = 0;
+        for op in &decoded.opcodes {
+            offsets.push(size);
+            size += op.consumed;
+        }
+        offsets.push(size);
+        let index_of = |offset: usize| offsets.binary_search(&offset).ok();
+
+        let mut targets = Vec::with_capacity(count);
+        let mut leaders = vec![false; count + 1];
+        leaders[0] = true;
+        for (index, op) in decoded.opcodes.iter().enumerate() {
+            let jumps = jump_targets(offsets[index], op)?
+                .into_iter()
+                .map(index_of)
+                .collect::<Option<Vec<_>>>()?;
+            for &target in &jumps {
+                leaders[target] = true;
+            }
+            match op.op_code() {
+                Opcode::StartScope => leaders[index] = true,
+                Opcode::EndScope => leaders[index + 1] = true,
+                _ if !jumps.is_empty() => leaders[index + 1] = true,
+                _ => {}
+            }
+            targets.push(jumps);
+        }
+
+        let mut block_of = Vec::with_capacity(count);
+        let mut lasts = Vec::new();
+        let mut blocks: Vec<BasicBlock> = Vec::new();
+        for (index, op) in decoded.opcodes.into_iter().enumerate() {
+            if leaders[index] {
+                blocks.push(BasicBlock {
+                    start: offsets[index],
+                    end: offsets[index],
+                    opcodes: Vec::new(),
+                    successors: Vec::new(),
+                    predecessors: Vec::new(),
+                    exits: false,
+                });
+                lasts.push(index);
+            }
+            let id = blocks.len() - 1;
+            block_of.push(BlockId(id));
+            blocks[id].end = offsets[index + 1];
+            blocks[id].opcodes.push(op);
+            lasts[id] = index;
+        }
+
+        for (id, &last) in lasts.iter().enumerate() {
+            let falls_through = blocks[id].opcodes.last()?.op_code() != Opcode::J;
+            let next = if falls_through { Some(last + 1) } else { None };
+            for target in next.into_iter().chain(targets[last].iter().copied()) {
+                if target == count {
+                    blocks[id].exits = true;
+                } else if !blocks[id].successors.contains(&block_of[target]) {
+                    blocks[id].successors.push(block_of[target]);
+                    blocks[block_of[target].0].predecessors.push(BlockId(id));
+                }
+            }
+        }
+
+        let dominators = dominators(&blocks);
+        let mut graph = ControlFlowGraph {
+            blocks,
+            dominators,
+            loops: Vec::new(),
+        };
+        graph.loops = graph.natural_loops();
+        Some(graph)
+    }
+}
+
+/// Immediate dominators, by Cooper, Harvey and Kennedy's iterative algorithm
+fn dominators(blocks: &[BasicBlock]) -> Vec<Option<BlockId>> {
+    let mut dominators = vec![None; blocks.len()];
+    if blocks.is_empty() {
+        return dominators;
+    }
+
+    // reverse postorder of the reachable blocks
+    let mut order = Vec::with_capacity(blocks.len());
+    let mut visited = vec![false; blocks.len()];
+    let mut stack = vec![(0, 0)];
+    visited[0] = true;
+    while let Some((block, next)) = stack.pop() {
+        match blocks[block].successors.get(next) {
+            Some(&BlockId(successor)) => {
+                stack.push((block, next + 1));
+                if !visited[successor] {
+                    visited[successor] = true;
+                    stack.push((successor, 0));
+                }
+            }
+            None => order.push(block),
+        }
+    }
+    order.reverse();
+    let mut position = vec![usize::MAX; blocks.len()];
+    for (i, &block) in order.iter().enumerate() {
+        position[block] = i;
+    }
+
+    let intersect = |dominators: &[Option<BlockId>], mut a: usize, mut b: usize| {
+        while a != b {
+            while position[a] > position[b] {
+                a = dominators[a].map_or(0, |d| d.0);
+            }
+            while position[b] > position[a] {
+                b = dominators[b].map_or(0, |d| d.0);
+            }
+        }
+        a
+    };
+    dominators[0] = Some(BlockId(0));
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in &order[1..] {
+            let mut processed = blocks[block]
+                .predecessors
+                .iter()
+                .map(|p| p.0)
+                .filter(|&p| dominators[p].is_some());
+            let first = match processed.next() {
+                Some(first) => first,
+                None => continue,
+            };
+            let dominator = processed.fold(first, |d, p| intersect(&dominators, p, d));
+            if dominators[block] != Some(BlockId(dominator)) {
+                dominators[block] = Some(BlockId(dominator));
+                changed = true;
+            }
+        }
+    }
+    dominators
+}
+
+impl ControlFlowGraph {
+    /// Build the graph of the model opcodes, labels are resolved by encoding them
+    pub fn from_model(ops: &[model::Opcode]) -> Option<Self> {
+        Code::from_model(ops)?.control_flow()
+    }
+
+    /// Blocks, in the order of the code
+    pub fn blocks(&self) -> &[BasicBlock] {
+        &self.blocks
+    }
+
+    pub fn block(&self, id: BlockId) -> Option<&BasicBlock> {
+        self.blocks.get(id.0)
+    }
+
+    /// Block that holds the opcode at `offset`
+    pub fn block_at(&self, offset: usize) -> Option<BlockId> {
+        let index = self
+            .blocks
+            .binary_search_by_key(&offset, |b| b.start)
+            .unwrap_or_else(|next| next.wrapping_sub(1));
+        self.blocks
+            .get(index)
+            .filter(|b| offset < b.end)
+            .map(|_| BlockId(index))
+    }
+
+    /// If the block can be reached from the start of the code
+    pub fn is_reachable(&self, id: BlockId) -> bool {
+        matches!(self.dominators.get(id.0), Some(Some(_)))
+    }
+
+    /// Closest block every path from the entry to `id` goes through
+    ///
+    /// `None` for the entry block and unreachable blocks.
+    pub fn immediate_dominator(&self, id: BlockId) -> Option<BlockId> {
+        self.dominators
+            .get(id.0)
+            .copied()
+            .flatten()
+            .filter(|&d| d != id)
+    }
+
+    /// If every path from the entry to `b` goes through `a`, a block dominates itself
+    pub fn dominates(&self, a: BlockId, b: BlockId) -> bool {
+        if !self.is_reachable(a) || !self.is_reachable(b) {
+            return false;
+        }
+        let mut current = b;
+        loop {
+            if current == a {
+                return true;
+            }
+            match self.immediate_dominator(current) {
+                Some(d) => current = d,
+                None => return false,
+            }
+        }
+    }
+
+    /// Natural loops, ordered by their headers, loops sharing a header are merged
+    pub fn loops(&self) -> &[NaturalLoop] {
+        &self.loops
+    }
+
+    /// If the edge `from -> to` closes a loop
+    pub fn is_back_edge(&self, from: BlockId, to: BlockId) -> bool {
+        matches!(self.block(from), Some(b) if b.successors.contains(&to))
+            && self.dominates(to, from)
+    }
+
+    fn natural_loops(&self) -> Vec<NaturalLoop> {
+        let mut bodies: Vec<(BlockId, Vec<bool>)> = Vec::new();
+        for (id, block) in self.blocks.iter().enumerate() {
+            for &header in &block.successors {
+                if !self.is_back_edge(BlockId(id), header) {
+                    continue;
+                }
+                let index = match bodies.iter().position(|(h, _)| *h == header) {
+                    Some(index) => index,
+                    None => {
+                        bodies.push((header, vec![false; self.blocks.len()]));
+                        bodies.len() - 1
+                    }
+                };
+                let body = &mut bodies[index].1;
+                body[header.0] = true;
+                let mut work = vec![BlockId(id)];
+                while let Some(b) = work.pop() {
+                    if body[b.0] {
+                        continue;
+                    }
+                    body[b.0] = true;
+                    let predecessors = self.blocks[b.0].predecessors.iter();
+                    work.extend(predecessors.filter(|&&p| self.is_reachable(p)));
+                }
+            }
+        }
+        bodies.sort_by_key(|(header, _)| *header);
+        bodies
+            .into_iter()
+            .map(|(header, body)| NaturalLoop {
+                header,
+                blocks: (0..body.len()).filter(|&b| body[b]).map(BlockId).collect(),
+            })
+            .collect()
+    }
+
+    /// Render the graph to Graphviz DOT, each block showing its disassembly
+    ///
+    /// Back edges are dashed, the edges that leave the code go to the `exit` node.
+    pub fn to_dot(&self) -> String {
+        let mut dot =
+            String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for (id, block) in self.blocks.iter().enumerate() {
+            let mut label = format!("b{}\\l", id);
+            let mut offset = block.start;
+            for op in &block.opcodes {
+                let line = format!("{} {} {}", offset, op.name(), op.refs());
+                label.push_str(&line.trim_end().replace('\\', "\\\\").replace('"', "\\\""));
+                label.push_str("\\l");
+                offset += op.consumed;
+            }
+            let _ = writeln!(dot, "    b{} [label=\"{}\"];", id, label);
+        }
+        for (id, block) in self.blocks.iter().enumerate() {
+            for &successor in &block.successors {
+                let style = if self.is_back_edge(BlockId(id), successor) {
+                    " [style=dashed]"
+                } else {
+                    ""
+                };
+                let _ = writeln!(dot, "    b{} -> b{}{};", id, successor.0, style);
+            }
+        }
+        if self.blocks.iter().any(|b| b.exits) {
+            dot.push_str("    exit [shape=oval];\n");
+            for (id, _) in self.blocks.iter().enumerate().filter(|(_, b)| b.exits) {
+                let _ = writeln!(dot, "    b{} -> exit;", id);
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
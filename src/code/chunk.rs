@@ -1,5 +1,8 @@
+use arrayvec::ArrayVec;
+
+use crate::code::refs::{Ref, IMMEDIATE_SIZE, OFFSET_SIZE};
 use crate::code::RefSource;
-use crate::opcodes::{Opcode, OpcodeKind};
+use crate::opcodes::{Opcode, OpcodeKind, OperandKind};
 
 use super::Code;
 
@@ -10,6 +13,8 @@ use super::Code;
 pub struct Chunk<'a> {
     pub(super) bytes: &'a [u8],
     pub(super) offset: usize,
+    /// Refs of the opcode at `offset`, each with the position in the opcode after it
+    refs: ArrayVec<[(Ref, usize); 6]>,
 }
 
 impl<'a> Chunk<'a> {
@@ -18,19 +23,51 @@ impl<'a> Chunk<'a> {
         &self.bytes[self.offset..]
     }
 
-    pub fn from_code(code: &'a Code) -> Chunk<'a> {
-        Self {
+    pub fn from_code(code: &'a Code<'_>) -> Chunk<'a> {
+        let mut chunk = Self {
             bytes: &code.0,
             offset: 0,
-        }
+            refs: ArrayVec::new(),
+        };
+        chunk.set_offset(0);
+        chunk
     }
 
     pub(crate) fn advance(&mut self, by: usize) {
-        self.offset += by;
+        self.set_offset(self.offset + by);
     }
 
     pub(crate) fn set_offset(&mut self, new_offset: usize) {
         self.offset = new_offset;
+        self.refs = self.decode_refs();
+    }
+
+    /// Decode the refs of the opcode at the offset in one pass, up to the first one that
+    /// can't be read
+    ///
+    /// The operands are laid out as [`Opcode::operands`] lists them, extension opcodes read
+    /// theirs on their own.
+    fn decode_refs(&self) -> ArrayVec<[(Ref, usize); 6]> {
+        let mut refs = ArrayVec::new();
+        let op = match self.full_opcode() {
+            Some(op) if op.is_supported() => op,
+            _ => return refs,
+        };
+        let mut position = op.size();
+        for operand in op.operands() {
+            position += match operand.kind {
+                OperandKind::Size | OperandKind::Jump => OFFSET_SIZE,
+                OperandKind::Immediate => IMMEDIATE_SIZE,
+                _ => match self.read_encoded_ref(position) {
+                    Some((r, size)) => {
+                        refs.push((r, position + size));
+                        size
+                    }
+                    None => break,
+                },
+            };
+        }
+        refs
     }
 
     #[inline]
@@ -63,8 +100,8 @@ impl<'a> Chunk<'a> {
     }
 }
 
-impl<'a> From<&'a Code> for Chunk<'a> {
-    fn from(code: &'a Code) -> Self {
+impl<'a> From<&'a Code<'_>> for Chunk<'a> {
+    fn from(code: &'a Code<'_>) -> Self {
         Self::from_code(code)
     }
 }
@@ -78,4 +115,8 @@ impl<'a> RefSource for Chunk<'a> {
             None
         }
     }
+
+    fn decoded_refs(&self) -> &[(Ref, usize)] {
+        &self.refs
+    }
 }
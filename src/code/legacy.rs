@@ -0,0 +1,115 @@
+//! Migration of bytecode written in the old encoding
+//!
+//! The old encoding wrote every operand as a little endian 64 bit `usize`,
+//! refs, absolute jump offsets and array sizes alike.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use crate::code::refs::{encode_jump, encode_offset, encode_ref, OFFSET_SIZE};
+use crate::code::Code;
+use crate::opcodes::{Opcode, OperandKind};
+
+/// Size of an operand in the old encoding
+const LEGACY_REF_SIZE: usize = 8;
+
+/// Operands an opcode has, in the order they appear in the bytecode
+enum Operands {
+    Refs(usize),
+    /// Jump offset followed by refs
+    Jump(usize),
+    /// Array size followed by refs
+    Size(usize),
+}
+
+/// Operands of `op` as the old encoding wrote them
+///
+/// Immediates had no old encoding, nor had reserved opcodes
+fn operands(op: Opcode) -> Option<Operands> {
+    if !op.is_supported() {
+        return None;
+    }
+    let kinds: Vec<_> = op.operands().iter().map(|o| o.kind).collect();
+    let n_refs = |kinds: &[OperandKind]| {
+        let is_ref = |kind: &OperandKind| {
+            matches!(
+                kind,
+                OperandKind::Stack | OperandKind::Pool | OperandKind::Value | OperandKind::BiOp
+            )
+        };
+        kinds.iter().all(is_ref).then(|| kinds.len())
+    };
+    let operands = match kinds.split_first() {
+        Some((OperandKind::Jump, refs)) => Operands::Jump(n_refs(refs)?),
+        Some((OperandKind::Size, refs)) => Operands::Size(n_refs(refs)?),
+        _ => Operands::Refs(n_refs(&kinds)?),
+    };
+    Some(operands)
+}
+
+fn read_legacy(bytes: &[u8], offset: usize) -> Option<usize> {
+    let bytes = bytes.get(offset..offset + LEGACY_REF_SIZE)?;
+    let value = u64::from_le_bytes(bytes.try_into().ok()?);
+    value.try_into().ok()
+}
+
+/// Convert bytecode in the old encoding to the current one
+///
+/// Absolute jump offsets are turned into relative ones to the new positions of their targets.
+/// Returns `None` if the bytecode is malformed or contains opcodes without a known encoding.
+pub fn migrate(bytes: &[u8]) -> Option<Code<'static>> {
+    struct Op {
+        code: Opcode,
+        offset: Option<usize>,
+        is_jump: bool,
+        refs: Vec<usize>,
+    }
+
+    let mut ops = Vec::new();
+    let mut new_offsets = HashMap::new();
+    let mut old_offset = 0;
+    let mut new_offset = 0;
+    while old_offset < bytes.len() {
+        let code = Opcode::single(bytes[old_offset])?;
+        let (n_refs, offset, is_jump) = match operands(code)? {
+            Operands::Refs(n) => (n, None, false),
+            Operands::Jump(n) => (n, Some(read_legacy(bytes, old_offset + 1)?), true),
+            Operands::Size(n) => (n, Some(read_legacy(bytes, old_offset + 1)?), false),
+        };
+        let refs_start = old_offset + 1 + offset.map_or(0, |_| LEGACY_REF_SIZE);
+        let refs = (0..n_refs)
+            .map(|i| read_legacy(bytes, refs_start + i * LEGACY_REF_SIZE))
+            .collect::<Option<Vec<_>>>()?;
+
+        new_offsets.insert(old_offset, new_offset);
+        new_offset += code.size()
+            + offset.map_or(0, |_| OFFSET_SIZE)
+            + refs.iter().map(|r| encode_ref(*r).len()).sum::<usize>();
+        old_offset = refs_start + n_refs * LEGACY_REF_SIZE;
+        ops.push(Op {
+            code,
+            offset,
+            is_jump,
+            refs,
+        });
+    }
+    new_offsets.insert(old_offset, new_offset);
+
+    let mut res = Vec::with_capacity(new_offset);
+    for op in ops {
+        let position = res.len();
+        res.extend_from_slice(&op.code.bytes());
+        match op.offset {
+            Some(target) if op.is_jump => {
+                let target = *new_offsets.get(&target)?;
+                res.extend_from_slice(&encode_jump(target as isize - position as isize)?);
+            }
+            Some(size) => res.extend_from_slice(&encode_offset(size)?),
+            None => {}
+        }
+        for r in op.refs {
+            res.extend_from_slice(&encode_ref(r));
+        }
+    }
+    Some(Code::from_vec(res))
+}
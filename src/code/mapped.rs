@@ -0,0 +1,59 @@
+//! Bytecode interpreted directly from a memory mapped file
+//!
+//! Only raw bytecode is mapped, the constant pool of the code comes from the vm, see
+//! [`Vm::load_module`].
+
+use std::fs::File;
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::code::Code;
+use crate::error::{LoadError, VmContextError};
+use crate::vm::ExtensionTable;
+use crate::Vm;
+
+/// Bytecode file mapped into memory, the bytes are never copied
+pub struct MappedCode {
+    map: Mmap,
+}
+
+impl MappedCode {
+    /// Map the bytecode file at `path` and check once that it decodes fully
+    ///
+    /// # Safety
+    ///
+    /// The file must not be changed or truncated while it is mapped, by this process or any
+    /// other. The code is only decoded here, the vm trusts the mapped bytes from then on.
+    pub unsafe fn open(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        Self::open_with(path, &ExtensionTable::default())
+    }
+
+    /// Map the bytecode file at `path`, it may contain the extension opcodes of `extensions`
+    ///
+    /// # Safety
+    ///
+    /// The same as [`MappedCode::open`].
+    pub unsafe fn open_with(
+        path: impl AsRef<Path>,
+        extensions: &ExtensionTable,
+    ) -> Result<Self, LoadError> {
+        let file = File::open(path)?;
+        // SAFETY: the caller keeps the file unchanged while it is mapped
+        let map = Mmap::map(&file)?;
+        let decoded = Code::from_slice(&map).decode_with(extensions);
+        if !decoded.is_full {
+            return Err(LoadError::InvalidBytecode(decoded.size));
+        }
+        Ok(Self { map })
+    }
+
+    /// The code over the mapped bytes
+    pub fn code(&self) -> Code<'_> {
+        Code::from_slice(&self.map)
+    }
+
+    pub fn interpret(&self, vm: &mut Vm) -> Result<(), VmContextError> {
+        self.code().interpret(vm)
+    }
+}
@@ -1,36 +1,63 @@
+use std::borrow::Cow;
 use std::convert::{TryFrom, TryInto};
-use std::mem::size_of;
 use std::option::NoneError;
 
 pub use chunk::Chunk;
-use refs::{PoolRef, Ref, StackRef, ThreeStackRefs, TwoStackRefs};
+use refs::{
+    decode_jump, decode_offset, decode_ref, Immediate, PoolRef, Ref, RefBytes, StackRef,
+    ThreeStackRefs, TwoStackRefs, IMMEDIATE_SIZE, MAX_REF_SIZE, OFFSET_SIZE,
+};
 
-use crate::decoder::{DecodedOpcode, HANDLERS as D_HANDLERS};
-use crate::error::VmContextError;
-use crate::interpreter::HANDLERS as I_HANDLERS;
+use crate::decoder::{self, DecodedOpcode};
+use crate::error::{VmContextError, VmError};
+use crate::interpreter::chunk_handlers;
 use crate::model;
 use crate::model::ToBytesCtx;
+use crate::vm::ExtensionTable;
 use crate::Vm;
 
+pub mod cfg;
 mod chunk;
+pub mod legacy;
+pub mod mapped;
 pub mod refs;
+pub mod threaded;
+pub mod verifier;
 
 /// Byte-code of this machine
-/// A wrapper around the raw bytes
+/// A wrapper around the raw bytes, either owned or borrowed
 #[derive(Debug)]
-pub struct Code(Vec<u8>);
+pub struct Code<'a>(Cow<'a, [u8]>);
 
-impl Code {
-    pub fn from_slice(slice: &[u8]) -> Self {
-        Code(slice.to_owned())
+impl<'a> Code<'a> {
+    /// Code over borrowed bytes, nothing is copied
+    pub fn from_slice(slice: &'a [u8]) -> Self {
+        Code(Cow::Borrowed(slice))
     }
 
-    pub fn from_vec(vec: Vec<u8>) -> Self {
-        Code(vec)
+    pub fn from_vec(vec: Vec<u8>) -> Code<'static> {
+        Code(Cow::Owned(vec))
+    }
+
+    /// Copy borrowed bytes, so the code outlives them
+    pub fn into_owned(self) -> Code<'static> {
+        Code(Cow::Owned(self.0.into_owned()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Link `other` after this code
+    ///
+    /// Jumps are relative, so the jumps of both parts stay valid.
+    /// Borrowed code is copied first.
+    pub fn append(&mut self, other: &Code) {
+        self.0.to_mut().extend_from_slice(&other.0);
     }
 }
 
-impl TryFrom<Vec<model::Opcode>> for Code {
+impl TryFrom<Vec<model::Opcode>> for Code<'static> {
     type Error = NoneError;
 
     fn try_from(opcodes: Vec<model::Opcode>) -> Result<Self, Self::Error> {
@@ -38,14 +65,14 @@ impl TryFrom<Vec<model::Opcode>> for Code {
     }
 }
 
-impl Code {
-    pub fn from_model(ops: &[model::Opcode]) -> Option<Code> {
+impl Code<'static> {
+    pub fn from_model(ops: &[model::Opcode]) -> Option<Self> {
         Self::from_model_with_ctx(ops, ToBytesCtx::new())
     }
 
-    pub fn from_model_with_ctx(ops: &[model::Opcode], ctx: ToBytesCtx) -> Option<Code> {
+    pub fn from_model_with_ctx(ops: &[model::Opcode], ctx: ToBytesCtx) -> Option<Self> {
         let converted = ctx.convert(ops)?;
-        Some(Self(converted))
+        Some(Self::from_vec(converted))
     }
 }
 
@@ -55,13 +82,17 @@ pub struct DecodeResult {
     pub is_full: bool,
 }
 
-impl Code {
+impl Code<'_> {
     pub fn interpret(&self, vm: &mut Vm) -> Result<(), VmContextError> {
         let mut chunk = Chunk::from_code(self);
+        let handlers = chunk_handlers();
         while vm.ip < chunk.bytes.len() {
-            let byte = chunk.read_byte(0).unwrap();
-            let op_fn = I_HANDLERS[byte as usize];
-            let consumed = op_fn(&chunk, vm);
+            let consumed = match chunk.read_byte(0) {
+                Some(byte) => vm
+                    .take_step()
+                    .and_then(|_| handlers[byte as usize](&chunk, vm)),
+                None => Err(VmError::InvalidBytecode),
+            };
             match consumed {
                 Err(e) => {
                     return Err(VmContextError {
@@ -82,13 +113,15 @@ impl Code {
     }
 
     pub fn decode(&self) -> DecodeResult {
+        self.decode_with(&ExtensionTable::default())
+    }
+
+    /// Decode the code, along with the extension opcodes of `extensions`
+    pub fn decode_with(&self, extensions: &ExtensionTable) -> DecodeResult {
         let mut chunk = Chunk::from_code(self);
         let mut opcodes = Vec::new();
         while chunk.offset < self.0.len() {
-            let byte = chunk.read_byte(0).unwrap();
-            let op_fn = D_HANDLERS[byte as usize];
-            let res_opt = op_fn(&chunk);
-            match res_opt {
+            match decoder::decode(&chunk, extensions) {
                 None => {
                     return DecodeResult {
                         opcodes,
@@ -112,34 +145,77 @@ impl Code {
 
 pub trait RefSource {
     fn read_from_offset(&self, offset: usize, size: usize) -> Option<&[u8]>;
+    /// Reads a LEB128 encoded `Ref` starting at `offset`
+    ///
+    /// Returns the ref and the amount of bytes it took
+    #[inline]
+    fn read_encoded_ref(&self, offset: usize) -> Option<(Ref, usize)> {
+        let mut bytes = RefBytes::new();
+        loop {
+            let byte = self.read_from_offset(offset + bytes.len(), 1)?[0];
+            bytes.push(byte);
+            if byte & 0x80 == 0 || bytes.len() == MAX_REF_SIZE {
+                return decode_ref(&bytes);
+            }
+        }
+    }
+
+    /// Refs of the opcode, decoded once, each with the position in the opcode after it
+    fn decoded_refs(&self) -> &[(Ref, usize)];
+
+    /// Position in the opcode after its first `n_refs` refs, which start at `start`
+    #[inline]
+    fn refs_end(&self, start: usize, n_refs: usize) -> Option<usize> {
+        match n_refs {
+            0 => Some(start),
+            n => self.decoded_refs().get(n - 1).map(|&(_, end)| end),
+        }
+    }
+
     /// Reads a `Ref` from bytecode
     ///
     /// `index` is the index of the ref in the bytecode
     #[inline]
     fn read_ref(&self, index: usize) -> Option<Ref> {
-        let bytes = self.read_from_offset(1 + index * size_of::<Ref>(), size_of::<Ref>())?;
-        const S: usize = size_of::<Ref>();
-        let bytes: [u8; S] = bytes.try_into().ok()?;
-        Some(Ref::from_le_bytes(bytes))
+        self.decoded_refs().get(index).map(|&(r, _)| r)
     }
 
+    /// Reads a `Ref` that follows an offset in bytecode
     #[inline]
     fn read_ref_with_offset(&self, index: usize) -> Option<Ref> {
-        let bytes = self.read_from_offset(
-            1 + size_of::<usize>() + index * size_of::<Ref>(),
-            size_of::<Ref>(),
-        )?;
-        const S: usize = size_of::<Ref>();
-        let bytes: [u8; S] = bytes.try_into().ok()?;
-        Some(Ref::from_le_bytes(bytes))
+        self.read_ref(index)
     }
 
     #[inline]
     fn read_offset(&self) -> Option<usize> {
-        let bytes = self.read_from_offset(1, size_of::<usize>())?;
-        const S: usize = size_of::<usize>();
-        let bytes: [u8; S] = bytes.try_into().ok()?;
-        Some(usize::from_le_bytes(bytes))
+        let bytes = self.read_from_offset(1, OFFSET_SIZE)?;
+        Some(decode_offset(bytes.try_into().ok()?))
+    }
+
+    /// Reads a jump offset, relative to the start of the opcode
+    #[inline]
+    fn read_jump(&self) -> Option<isize> {
+        let bytes = self.read_from_offset(1, OFFSET_SIZE)?;
+        Some(decode_jump(bytes.try_into().ok()?))
+    }
+
+    /// Reads the immediate that follows the first `n_refs` refs of the opcode
+    #[inline]
+    fn read_immediate(&self, n_refs: usize) -> Option<Immediate> {
+        let offset = 1 + self.refs_size(n_refs)?;
+        Immediate::from_bytes(self.read_from_offset(offset, IMMEDIATE_SIZE)?)
+    }
+
+    /// Return the amount of bytes the first `n_refs` refs of the opcode take
+    #[inline]
+    fn refs_size(&self, n_refs: usize) -> Option<usize> {
+        self.refs_end(1, n_refs).map(|end| end - 1)
+    }
+
+    /// Return the amount of bytes an offset and the `n_refs` refs after it take
+    #[inline]
+    fn refs_size_with_offset(&self, n_refs: usize) -> Option<usize> {
+        self.refs_end(1 + OFFSET_SIZE, n_refs).map(|end| end - 1)
     }
 
     fn read_two(&self) -> Option<TwoStackRefs> {
@@ -172,25 +248,24 @@ impl DecodeResult {
         let w = self.size.to_string().len();
         for op in &self.opcodes {
             if print_bytes {
-                let capacity = op.op_code.size() + op.refs.count() * size_of::<Ref>();
-                let mut bytes = Vec::with_capacity(capacity);
-                bytes.extend_from_slice(&op.op_code.bytes());
-                bytes.extend_from_slice(&op.refs.bytes());
+                let mut bytes = Vec::with_capacity(op.consumed);
+                bytes.extend_from_slice(&op.op_bytes());
+                bytes.extend_from_slice(&op.refs.bytes().unwrap_or_default());
                 let bytes = bytes
                     .into_iter()
                     .map(|v| format!("{:02x}", v))
                     .collect::<Vec<_>>()
                     .join("");
                 println!(
-                    "{:<w$} 0x{:<64} {:?} {}",
+                    "{:<w$} 0x{:<64} {} {}",
                     offset,
                     bytes,
-                    op.op_code,
+                    op.name(),
                     op.refs,
                     w = w
                 );
             } else {
-                println!("{:<w$} {:?} {}", offset, op.op_code, op.refs, w = w);
+                println!("{:<w$} {} {}", offset, op.name(), op.refs, w = w);
             }
             offset += op.consumed;
         }
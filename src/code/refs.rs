@@ -1,6 +1,12 @@
+use std::convert::{TryFrom, TryInto};
 use std::mem::size_of;
 
+use num_traits::FromPrimitive;
 use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+
+use crate::stack::data::IntoStackData;
+use crate::types::{HasPrimitiveType, PrimitiveType};
 
 pub type Ref = usize;
 
@@ -26,11 +32,16 @@ impl From<Ref> for PoolRef {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Deserialize, Serialize)]
 pub enum CodeRef {
     Stack(StackRef),
     Pool(PoolRef),
     Offset(usize),
+    /// Jump offset, relative to the start of the jump opcode
+    Jump(isize),
+    /// Plain number operand, encoded the same way as a ref
+    Value(Ref),
+    Immediate(Immediate),
 }
 
 impl CodeRef {
@@ -39,14 +50,32 @@ impl CodeRef {
             CodeRef::Stack(r) => Some(r.0),
             CodeRef::Pool(r) => Some(r.0),
             CodeRef::Offset(_) => None,
+            CodeRef::Jump(_) => None,
+            CodeRef::Value(_) => None,
+            CodeRef::Immediate(_) => None,
+        }
+    }
+
+    /// Encoded form of the ref, `None` if an offset does not fit into [`OFFSET_SIZE`] bytes
+    pub fn to_bytes(&self) -> Option<RefBytes> {
+        match self {
+            CodeRef::Stack(r) => Some(encode_ref(r.0)),
+            CodeRef::Pool(r) => Some(encode_ref(r.0)),
+            CodeRef::Value(r) => Some(encode_ref(*r)),
+            CodeRef::Offset(r) => encode_offset(*r).map(|b| RefBytes::from_slice(&b)),
+            CodeRef::Jump(r) => encode_jump(*r).map(|b| RefBytes::from_slice(&b)),
+            CodeRef::Immediate(i) => Some(RefBytes::from_slice(&i.to_bytes())),
         }
     }
 
-    pub fn to_bytes(&self) -> [u8; size_of::<Ref>()] {
+    /// Amount of bytes the ref takes in the bytecode
+    pub fn size(&self) -> usize {
         match self {
-            CodeRef::Stack(r) => r.0.to_le_bytes(),
-            CodeRef::Pool(r) => r.0.to_le_bytes(),
-            CodeRef::Offset(r) => r.to_le_bytes(),
+            CodeRef::Offset(_) | CodeRef::Jump(_) => OFFSET_SIZE,
+            CodeRef::Stack(r) => encode_ref(r.0).len(),
+            CodeRef::Pool(r) => encode_ref(r.0).len(),
+            CodeRef::Value(r) => encode_ref(*r).len(),
+            CodeRef::Immediate(_) => IMMEDIATE_SIZE,
         }
     }
 }
@@ -63,6 +92,68 @@ impl From<PoolRef> for CodeRef {
     }
 }
 
+impl From<Immediate> for CodeRef {
+    fn from(obj: Immediate) -> Self {
+        CodeRef::Immediate(obj)
+    }
+}
+
+/// A single stack value stored in the bytecode along with its type
+///
+/// Encoded after the refs of its opcode, as the tag of its [`PrimitiveType`]
+/// followed by the little endian bytes of the value
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Deserialize, Serialize)]
+pub struct Immediate {
+    tag: u8,
+    value: [u8; 8],
+}
+
+impl Immediate {
+    pub fn new<T: IntoStackData + HasPrimitiveType>(value: T) -> Self {
+        Self {
+            tag: T::get_type() as u8,
+            value: value.into_stack_data(),
+        }
+    }
+
+    /// Immediate of type `t` holding `value` as is, `None` if `t` is not a single value type
+    pub fn from_raw(t: PrimitiveType, value: [u8; 8]) -> Option<Self> {
+        if t.is_single() {
+            Some(Self {
+                tag: t as u8,
+                value,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Declared type of the value, `None` if it is not a single value type
+    pub fn value_type(self) -> Option<PrimitiveType> {
+        PrimitiveType::from_u8(self.tag).filter(|t| t.is_single())
+    }
+
+    pub fn value(self) -> [u8; 8] {
+        self.value
+    }
+
+    pub fn to_bytes(self) -> [u8; IMMEDIATE_SIZE] {
+        let mut bytes = [self.tag; IMMEDIATE_SIZE];
+        bytes[1..].copy_from_slice(&self.value);
+        bytes
+    }
+
+    /// Decode an immediate from the start of `bytes`, `None` if its type is not valid
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..IMMEDIATE_SIZE)?;
+        let imm = Self {
+            tag: bytes[0],
+            value: bytes[1..].try_into().ok()?,
+        };
+        imm.value_type().map(|_| imm)
+    }
+}
+
 /// 3 reference opcode
 #[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
 pub struct ThreeStackRefs {
@@ -102,11 +193,67 @@ pub const fn s(r: usize) -> StackRef {
 pub const fn p(r: usize) -> PoolRef {
     PoolRef(r)
 }
-/// Return the amount of bytes `n_refs` takes in the bytecode
-pub const fn refs_size(n_refs: usize) -> usize {
-    n_refs * size_of::<StackRef>()
+/// Maximum amount of bytes a LEB128 encoded ref takes
+pub const MAX_REF_SIZE: usize = 10;
+
+/// Amount of bytes a jump offset or an array size takes
+///
+/// Array sizes are a little endian `u32`, jump offsets a little endian `i32`
+pub const OFFSET_SIZE: usize = size_of::<u32>();
+
+/// Amount of bytes an [`Immediate`] takes
+pub const IMMEDIATE_SIZE: usize = 1 + size_of::<u64>();
+
+pub type RefBytes = SmallVec<[u8; MAX_REF_SIZE]>;
+
+/// Encode a ref as unsigned LEB128
+///
+/// Small refs, which are the most common ones, take a single byte
+pub fn encode_ref(r: Ref) -> RefBytes {
+    let mut value = r as u64;
+    let mut res = RefBytes::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            res.push(byte);
+            return res;
+        }
+        res.push(byte | 0x80);
+    }
+}
+
+/// Decode a LEB128 ref from the start of `bytes`
+///
+/// Returns the ref and the amount of bytes it took, `None` if the encoding is truncated,
+/// too long or the value does not fit into the `Ref` of this host
+pub fn decode_ref(bytes: &[u8]) -> Option<(Ref, usize)> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().take(MAX_REF_SIZE).enumerate() {
+        let part = (byte & 0x7f) as u64;
+        if i == MAX_REF_SIZE - 1 && part > 1 {
+            return None;
+        }
+        value |= part << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((Ref::try_from(value).ok()?, i + 1));
+        }
+    }
+    None
+}
+
+pub fn encode_offset(offset: usize) -> Option<[u8; OFFSET_SIZE]> {
+    u32::try_from(offset).ok().map(u32::to_le_bytes)
+}
+
+pub fn decode_offset(bytes: [u8; OFFSET_SIZE]) -> usize {
+    u32::from_le_bytes(bytes) as usize
+}
+
+pub fn encode_jump(offset: isize) -> Option<[u8; OFFSET_SIZE]> {
+    i32::try_from(offset).ok().map(i32::to_le_bytes)
 }
 
-pub const fn refs_size_with_offset(n_refs: usize) -> usize {
-    refs_size(n_refs) + size_of::<usize>()
+pub fn decode_jump(bytes: [u8; OFFSET_SIZE]) -> isize {
+    i32::from_le_bytes(bytes) as isize
 }
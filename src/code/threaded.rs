@@ -0,0 +1,232 @@
+//! Pre-decoded form of the bytecode
+//!
+//! The code is decoded once into a list of instructions, each holding its handler and
+//! already parsed operands. Jumps are resolved to relative instruction indices, so while
+//! interpreting, `Vm::ip` is the index of the current instruction.
+
+use std::convert::TryFrom;
+
+use arrayvec::ArrayVec;
+
+use crate::code::refs::{CodeRef, Immediate, PoolRef, Ref, StackRef};
+use crate::code::Code;
+use crate::error::{VmContextError, VmError};
+use crate::interpreter::{IntHandler, THREADED_HANDLERS};
+use crate::opcodes::{Opcode, Operand, OperandKind};
+use crate::vm::extensions::ExtensionOperands;
+use crate::vm::{ExtensionTable, OperandSource, VmRefSource};
+use crate::Vm;
+
+/// Already parsed operands of an instruction
+#[derive(Debug, Clone)]
+pub struct Operands {
+    pub(crate) refs: ArrayVec<[Ref; 6]>,
+    /// Array size
+    offset: usize,
+    /// Jump, relative to the index of the instruction
+    pub(crate) jump: isize,
+    immediate: Option<Immediate>,
+    /// Offset of the instruction in the bytecode
+    pub(crate) location: usize,
+    op_bytes: ArrayVec<[u8; 2]>,
+    /// Indices into the stack data of the result and the operands, set by the verifier
+    pub(crate) slots: [usize; 3],
+}
+
+pub(crate) struct Instruction {
+    pub(crate) handler: IntHandler<Operands>,
+    pub(crate) operands: Operands,
+    pub(crate) opcode: Opcode,
+}
+
+/// Code decoded into instructions that are interpreted without parsing the bytecode again
+pub struct ThreadedCode {
+    pub(crate) instructions: Vec<Instruction>,
+    size: usize,
+}
+
+impl Code<'_> {
+    /// Decode the code into instructions once
+    ///
+    /// Returns `None` if the code can't be decoded fully, or a jump does not land
+    /// at the start of an instruction or the end of the code.
+    pub fn compile(&self) -> Option<ThreadedCode> {
+        self.compile_with(&ExtensionTable::default())
+    }
+
+    /// Decode the code into instructions once, along with the extension opcodes of `extensions`
+    pub fn compile_with(&self, extensions: &ExtensionTable) -> Option<ThreadedCode> {
+        let decoded = self.decode_with(extensions);
+        if !decoded.is_full {
+            return None;
+        }
+        let mut offsets = Vec::with_capacity(decoded.opcodes.len());
+        let mut size = 0;
+        for op in &decoded.opcodes {
+            offsets.push(size);
+            size += op.consumed;
+        }
+        let index_of = |offset: usize| -> Option<usize> {
+            if offset == size {
+                Some(offsets.len())
+            } else {
+                offsets.binary_search(&offset).ok()
+            }
+        };
+
+        let mut instructions = Vec::with_capacity(decoded.opcodes.len());
+        for (index, op) in decoded.opcodes.iter().enumerate() {
+            let location = offsets[index];
+            let mut operands = Operands {
+                refs: ArrayVec::new(),
+                offset: 0,
+                jump: 0,
+                immediate: None,
+                location,
+                op_bytes: op.op_bytes(),
+                slots: [0; 3],
+            };
+            for r in op.refs.as_vec() {
+                match r.code_ref {
+                    CodeRef::Stack(r) => operands.refs.push(r.0),
+                    CodeRef::Pool(r) => operands.refs.push(r.0),
+                    CodeRef::Value(v) => operands.refs.push(v),
+                    CodeRef::Offset(o) => operands.offset = o,
+                    CodeRef::Immediate(i) => operands.immediate = Some(i),
+                    CodeRef::Jump(j) => {
+                        let target = usize::try_from(location as isize + j).ok()?;
+                        operands.jump = index_of(target)? as isize - index as isize;
+                    }
+                }
+            }
+            instructions.push(Instruction {
+                handler: THREADED_HANDLERS[operands.op_bytes[0] as usize],
+                operands,
+                opcode: op.op_code,
+            });
+        }
+        Some(ThreadedCode { instructions, size })
+    }
+}
+
+impl ThreadedCode {
+    /// Interpret the instructions, with the same results as [`Code::interpret`]
+    pub fn interpret(&self, vm: &mut Vm) -> Result<(), VmContextError> {
+        vm.ip = self.index_of(vm.ip).ok_or(VmContextError {
+            error: VmError::InvalidBytecode,
+            location: Some(vm.ip),
+            opcode: None,
+        })?;
+        while let Some(instruction) = self.instructions.get(vm.ip) {
+            let result = vm
+                .take_step()
+                .and_then(|_| (instruction.handler)(&instruction.operands, vm));
+            match result {
+                Err(error) => {
+                    vm.ip = instruction.operands.location;
+                    return Err(VmContextError {
+                        error,
+                        location: Some(instruction.operands.location),
+                        opcode: Some(instruction.opcode),
+                    });
+                }
+                // a jump was taken
+                Ok(0) => {}
+                Ok(_) => vm.ip += 1,
+            }
+        }
+        vm.ip = self.size;
+        Ok(())
+    }
+
+    fn index_of(&self, offset: usize) -> Option<usize> {
+        if offset >= self.size {
+            Some(self.instructions.len())
+        } else {
+            self.instructions
+                .binary_search_by_key(&offset, |i| i.operands.location)
+                .ok()
+        }
+    }
+}
+
+impl VmRefSource for Operands {
+    type VmError = VmError;
+
+    fn read_from_offset_vm(&self, _: usize, _: usize) -> Result<&[u8], Self::VmError> {
+        Err(VmError::InvalidBytecode)
+    }
+
+    #[inline]
+    fn read_ref_vm(&self, index: usize) -> Result<Ref, Self::VmError> {
+        self.refs
+            .get(index)
+            .copied()
+            .ok_or(VmError::InvalidBytecode)
+    }
+
+    #[inline]
+    fn read_ref_with_offset_vm(&self, index: usize) -> Result<Ref, Self::VmError> {
+        self.read_ref_vm(index)
+    }
+
+    #[inline]
+    fn read_offset_vm(&self) -> Result<usize, Self::VmError> {
+        Ok(self.offset)
+    }
+
+    #[inline]
+    fn read_jump_vm(&self) -> Result<isize, Self::VmError> {
+        Ok(self.jump)
+    }
+
+    #[inline]
+    fn read_immediate_vm(&self, _: usize) -> Result<Immediate, Self::VmError> {
+        self.immediate.ok_or(VmError::InvalidBytecode)
+    }
+
+    /// Refs, the size and the immediate are taken in the order the operands declare them
+    fn read_extension_operands_vm(
+        &self,
+        operands: &[Operand],
+    ) -> Result<(ExtensionOperands, usize), Self::VmError> {
+        let mut refs = self.refs.iter();
+        let mut res = ExtensionOperands::new();
+        for operand in operands {
+            let mut next_ref = || refs.next().copied().ok_or(VmError::InvalidBytecode);
+            let code_ref = match operand.kind {
+                OperandKind::Stack => CodeRef::Stack(StackRef(next_ref()?)),
+                OperandKind::Pool => CodeRef::Pool(PoolRef(next_ref()?)),
+                OperandKind::Value | OperandKind::BiOp => CodeRef::Value(next_ref()?),
+                OperandKind::Size => CodeRef::Offset(self.offset),
+                OperandKind::Immediate => {
+                    CodeRef::Immediate(self.immediate.ok_or(VmError::InvalidBytecode)?)
+                }
+                OperandKind::Jump => return Err(VmError::InvalidBytecode),
+            };
+            res.push(code_ref);
+        }
+        Ok((res, 0))
+    }
+
+    /// Instructions are advanced one by one, the size of the operands does not matter
+    #[inline]
+    fn refs_size_vm(&self, _: usize) -> Result<usize, Self::VmError> {
+        Ok(0)
+    }
+
+    #[inline]
+    fn refs_size_with_offset_vm(&self, _: usize) -> Result<usize, Self::VmError> {
+        Ok(0)
+    }
+}
+
+impl OperandSource for Operands {
+    fn offset(&self) -> usize {
+        self.location
+    }
+
+    fn read_byte(&self, index: usize) -> Option<u8> {
+        self.op_bytes.get(index).copied()
+    }
+}
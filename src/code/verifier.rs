@@ -0,0 +1,263 @@
+//! Whole-program type and stack verification of pre-decoded code
+//!
+//! The verifier follows every path of the code with the types of the stack values instead
+//! of their data. Paths that meet at an instruction must agree on the stack layout, so the
+//! type and the data slot of every stack ref is known ahead of time at each instruction.
+//! Values may span several cells, like strings, so each slot is at the offset of its data.
+//! Binary operations get handlers that skip the type checks and read the data at those slots.
+//!
+//! Only a subset of the opcodes is supported, any other opcode makes the code unverifiable.
+
+use crate::code::refs::{PoolRef, StackRef};
+use crate::code::threaded::{Instruction, ThreadedCode};
+use crate::error::{VmContextError, VmError};
+use crate::interpreter::unchecked_handler;
+use crate::opcodes::Opcode;
+use crate::types::checker::{
+    tags, HasTypeCheckerCtx, Taggable, ThreeTypesChecker, TypeChecker, TypeCheckerCtx,
+};
+use crate::types::{PrimitiveType, VmType};
+use crate::{ConstantPool, Vm};
+
+/// Pre-decoded code whose types were checked ahead of time
+///
+/// Only these opcodes are verified, any other one makes the code
+/// [`Unverifiable`](VmError::Unverifiable):
+/// - the loads `U64Ld0`, `I64Ld0`, `LdTrue`, `LdFalse`, `LdTyped0`, `LdType` and `LdConst`
+/// - `StartScope` and `EndScope`
+/// - the jumps `J` and `JC`
+/// - the arithmetic of the unsigned, signed and float families, like `UAdd`, `ISub` or `FMul`
+/// - the comparisons `Eq`, `Ne`, `Lt`, `Le`, `Gt` and `Ge` of numbers
+pub struct VerifiedCode {
+    code: ThreadedCode,
+    /// Types read from the constant pool, the pool of the vm must agree with them
+    pool_types: Vec<(PoolRef, PrimitiveType)>,
+}
+
+/// A value on the stack
+#[derive(Clone, PartialEq)]
+struct Slot {
+    value_type: VmType,
+    mutable: bool,
+    /// Index of the first cell of the data of the value
+    offset: usize,
+}
+
+/// Stack layout before an instruction
+#[derive(Clone, Default, PartialEq)]
+struct State {
+    slots: Vec<Slot>,
+    /// Number of slots when each of the open scopes was started
+    scopes: Vec<usize>,
+}
+
+impl State {
+    fn push(&mut self, t: PrimitiveType, mutable: bool) {
+        let offset = match self.slots.last() {
+            Some(last) => last.offset + last.value_type.size(),
+            None => 0,
+        };
+        self.slots.push(Slot {
+            value_type: t.into(),
+            mutable,
+            offset,
+        });
+    }
+
+    fn slot(&self, index: usize) -> Result<&Slot, VmError> {
+        self.slots.get(index).ok_or(VmError::BadVmState)
+    }
+}
+
+enum Family {
+    Unsigned,
+    Signed,
+    Float,
+    Cmp,
+}
+
+fn family(op: Opcode) -> Option<Family> {
+    use Opcode::*;
+    match op {
+        UAdd | USub | UMul | UDiv | URem => Some(Family::Unsigned),
+        IAdd | ISub | IMul | IDiv | IRem => Some(Family::Signed),
+        FAdd | FSub | FMul | FDiv | FRem => Some(Family::Float),
+        Ge | Gt | Le | Lt | Eq | Ne => Some(Family::Cmp),
+        _ => None,
+    }
+}
+
+struct Verifier<'a> {
+    pool: &'a ConstantPool,
+    pool_types: Vec<(PoolRef, PrimitiveType)>,
+}
+
+impl<'a> Verifier<'a> {
+    /// The type at `index`, `zeroed` if it's loaded by `LdTyped0`, which accepts strings
+    fn pool_type(&mut self, index: PoolRef, zeroed: bool) -> Result<PrimitiveType, VmError> {
+        let t = self
+            .pool
+            .get_type(index)
+            .filter(|t| t.is_single() || (zeroed && VmType::from(*t).is_zeroable()))
+            .ok_or(VmError::ConstantPoolError)?;
+        self.pool_types.push((index, t));
+        Ok(t)
+    }
+
+    /// Apply the instruction to the state, returns the relative indices of the next instructions
+    fn step(
+        &mut self,
+        instruction: &mut Instruction,
+        state: &mut State,
+    ) -> Result<Vec<isize>, VmError> {
+        let refs = &instruction.operands.refs;
+        let ref_at = |i: usize| refs.get(i).copied().ok_or(VmError::InvalidBytecode);
+        match instruction.opcode {
+            Opcode::U64Ld0 => state.push(PrimitiveType::U64, true),
+            Opcode::I64Ld0 => state.push(PrimitiveType::I64, true),
+            Opcode::LdTrue | Opcode::LdFalse => state.push(PrimitiveType::Bool, true),
+            Opcode::LdTyped0 => {
+                let t = self.pool_type(PoolRef(ref_at(0)?), true)?;
+                state.push(t, true);
+            }
+            Opcode::LdType => {
+                let t = self.pool_type(PoolRef(ref_at(0)?), false)?;
+                state.push(t, true);
+            }
+            Opcode::LdConst => {
+                let t = self.pool_type(PoolRef(ref_at(0)?), false)?;
+                state.push(t, false);
+            }
+            Opcode::StartScope => state.scopes.push(state.slots.len()),
+            Opcode::EndScope => {
+                let len = state.scopes.pop().ok_or(VmError::BadVmState)?;
+                state.slots.truncate(len);
+            }
+            Opcode::J => return Ok(vec![instruction.operands.jump]),
+            Opcode::JC => {
+                let cond = state.slot(ref_at(0)?)?;
+                TypeChecker {
+                    tag: tags::COND.into(),
+                    vm_type: Some(&cond.value_type),
+                    ctx: &mut TypeCheckerCtx::new(),
+                }
+                .primitive()
+                .bool()
+                .and()
+                .get_vm()?;
+                return Ok(vec![1, instruction.operands.jump]);
+            }
+            op => {
+                let family = family(op).ok_or(VmError::Unverifiable(op))?;
+                let [result, op1, op2] = [ref_at(0)?, ref_at(1)?, ref_at(2)?];
+                let metas = [state.slot(result)?, state.slot(op1)?, state.slot(op2)?];
+                let mut ctx = TypeCheckerCtx::new();
+                let checker = ThreeTypesChecker {
+                    result: &metas[0].value_type,
+                    op1: &metas[1].value_type,
+                    op2: &metas[2].value_type,
+                    ctx: &mut ctx,
+                };
+                let t = match family {
+                    Family::Cmp => {
+                        checker
+                            .all_primitives()
+                            .result()
+                            .bool()
+                            .and()
+                            .operands()
+                            .same()
+                            .get_vm()?
+                            .op
+                    }
+                    _ => checker.all_primitives().all_same().get_vm()?,
+                };
+                let supported = match family {
+                    Family::Unsigned => t.is_unsigned(),
+                    Family::Signed => t.is_signed(),
+                    Family::Float => t.is_float(),
+                    Family::Cmp => t.is_number(),
+                };
+                if !supported {
+                    return Err(VmError::InvalidTypeForOperation(VmType::from(t).no_tag()));
+                }
+                if !metas[0].mutable {
+                    return Err(VmError::WriteToImmutable(StackRef(result)));
+                }
+                instruction.operands.slots = [metas[0].offset, metas[1].offset, metas[2].offset];
+                instruction.handler = unchecked_handler(op, t).ok_or(VmError::Unverifiable(op))?;
+            }
+        }
+        Ok(vec![1])
+    }
+}
+
+impl ThreadedCode {
+    /// Check the types and the stack layout on every path of the code
+    ///
+    /// Verified code starts with an empty stack, so every value has a known type and data
+    /// slot at each instruction. Errors that a dynamic check would report on some path are
+    /// reported here, located at the offending instruction.
+    pub fn verify(mut self, pool: &ConstantPool) -> Result<VerifiedCode, VmContextError> {
+        let count = self.instructions.len();
+        let mut states: Vec<Option<State>> = vec![None; count + 1];
+        states[0] = Some(State::default());
+        let mut work = vec![0];
+        let mut verifier = Verifier {
+            pool,
+            pool_types: Vec::new(),
+        };
+
+        while let Some(index) = work.pop() {
+            let instruction = match self.instructions.get_mut(index) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            let (location, opcode) = (instruction.operands.location, instruction.opcode);
+            let context = |error| VmContextError {
+                error,
+                location: Some(location),
+                opcode: Some(opcode),
+            };
+            let mut state = states[index].clone().ok_or(VmError::BadVmState)?;
+            let next = verifier.step(instruction, &mut state).map_err(context)?;
+            for offset in next {
+                let target = index as isize + offset;
+                if target < 0 || target as usize > count {
+                    return Err(context(VmError::InvalidBytecode));
+                }
+                match &states[target as usize] {
+                    Some(existing) if *existing == state => {}
+                    Some(_) => return Err(context(VmError::StackMismatch)),
+                    None => {
+                        states[target as usize] = Some(state.clone());
+                        work.push(target as usize);
+                    }
+                }
+            }
+        }
+
+        Ok(VerifiedCode {
+            code: self,
+            pool_types: verifier.pool_types,
+        })
+    }
+}
+
+impl VerifiedCode {
+    /// Interpret the code with the checks proven ahead of time skipped
+    ///
+    /// The code must start on an empty stack of the vm and with a constant pool agreeing
+    /// with the one it was verified against, otherwise nothing is run.
+    pub fn interpret(&self, vm: &mut Vm) -> Result<(), VmContextError> {
+        let pool = vm.current_const_pool()?;
+        let same_pool = self
+            .pool_types
+            .iter()
+            .all(|(index, t)| pool.get_type(*index) == Some(*t));
+        if !same_pool || !vm.stack_metadata.is_empty() || vm.last_stack_frame != 0 || vm.ip != 0 {
+            return Err(VmError::BadVmState.into());
+        }
+        self.code.interpret(vm)
+    }
+}
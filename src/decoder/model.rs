@@ -1,10 +1,12 @@
 use std::borrow::Cow;
 use std::fmt::{self, Display, Formatter};
 
+use arrayvec::ArrayVec;
 use smallvec::SmallVec;
 
-use crate::code::refs::{refs_size, CodeRef};
+use crate::code::refs::CodeRef;
 use crate::opcodes::Opcode;
+use crate::vm::ExtensionTable;
 
 /// The result of the decoding the input stream
 pub struct DecodedOpcode {
@@ -14,6 +16,8 @@ pub struct DecodedOpcode {
     pub(crate) op_code: Opcode,
     /// refs that the opcode contains
     pub(crate) refs: DecoderRefs,
+    /// Number and name of an extension opcode, `op_code` is then [`Opcode::HWide`]
+    pub(crate) extension: Option<(u8, &'static str)>,
 }
 
 pub struct DecoderRef {
@@ -52,9 +56,15 @@ impl DecoderRef {
 impl Display for DecoderRef {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let (symbol, value) = match self.code_ref {
-            CodeRef::Stack(r) => ("@", r.0),
-            CodeRef::Pool(r) => ("$", r.0),
-            CodeRef::Offset(r) => ("*", r),
+            CodeRef::Stack(r) => ("@", r.0.to_string()),
+            CodeRef::Pool(r) => ("$", r.0.to_string()),
+            CodeRef::Offset(r) => ("*", r.to_string()),
+            CodeRef::Jump(r) => ("*", format!("{:+}", r)),
+            CodeRef::Value(r) => ("#", r.to_string()),
+            CodeRef::Immediate(i) => {
+                let bits = u64::from_le_bytes(i.value());
+                ("#", format!("{:?}({:#x})", i.value_type(), bits))
+            }
         };
         f.write_str(symbol)?;
         if self.tag.is_empty() {
@@ -71,9 +81,26 @@ pub enum DecoderRefs {
     Two(DecoderRef, DecoderRef),
     Three(DecoderRef, DecoderRef, DecoderRef),
     Four(DecoderRef, DecoderRef, DecoderRef, DecoderRef),
+    /// More refs than the other variants hold, used by fused opcodes
+    Many(Vec<DecoderRef>),
 }
 
 impl DecoderRefs {
+    /// Refs in the variant that holds as many as `refs` has
+    pub fn from_vec(refs: Vec<DecoderRef>) -> Self {
+        if refs.len() > 4 {
+            return DecoderRefs::Many(refs);
+        }
+        let mut refs = refs.into_iter();
+        match (refs.next(), refs.next(), refs.next(), refs.next()) {
+            (None, ..) => DecoderRefs::Zero,
+            (Some(r1), None, ..) => DecoderRefs::One(r1),
+            (Some(r1), Some(r2), None, _) => DecoderRefs::Two(r1, r2),
+            (Some(r1), Some(r2), Some(r3), None) => DecoderRefs::Three(r1, r2, r3),
+            (Some(r1), Some(r2), Some(r3), Some(r4)) => DecoderRefs::Four(r1, r2, r3, r4),
+        }
+    }
+
     pub fn count(&self) -> usize {
         match self {
             DecoderRefs::Zero => 0,
@@ -81,35 +108,40 @@ impl DecoderRefs {
             DecoderRefs::Two(_, _) => 2,
             DecoderRefs::Three(_, _, _) => 3,
             DecoderRefs::Four(_, _, _, _) => 4,
+            DecoderRefs::Many(refs) => refs.len(),
         }
     }
 
-    pub fn bytes(&self) -> SmallVec<[u8; 32]> {
-        // TODO: possible optimization with the allocated size
+    pub fn iter(&self) -> impl Iterator<Item = &DecoderRef> {
+        self.as_vec().into_iter()
+    }
+
+    pub(crate) fn as_vec(&self) -> SmallVec<[&DecoderRef; 4]> {
         let mut res = SmallVec::new();
         match self {
             DecoderRefs::Zero => {}
-            DecoderRefs::One(r) => {
-                res.extend_from_slice(&r.code_ref.to_bytes());
-            }
-            DecoderRefs::Two(r1, r2) => {
-                res.extend_from_slice(&r1.code_ref.to_bytes());
-                res.extend_from_slice(&r2.code_ref.to_bytes());
-            }
-            DecoderRefs::Three(r1, r2, r3) => {
-                res.extend_from_slice(&r1.code_ref.to_bytes());
-                res.extend_from_slice(&r2.code_ref.to_bytes());
-                res.extend_from_slice(&r3.code_ref.to_bytes());
-            }
-            DecoderRefs::Four(r1, r2, r3, r4) => {
-                res.extend_from_slice(&r1.code_ref.to_bytes());
-                res.extend_from_slice(&r2.code_ref.to_bytes());
-                res.extend_from_slice(&r3.code_ref.to_bytes());
-                res.extend_from_slice(&r4.code_ref.to_bytes());
-            }
+            DecoderRefs::One(r) => res.push(r),
+            DecoderRefs::Two(r1, r2) => res.extend_from_slice(&[r1, r2]),
+            DecoderRefs::Three(r1, r2, r3) => res.extend_from_slice(&[r1, r2, r3]),
+            DecoderRefs::Four(r1, r2, r3, r4) => res.extend_from_slice(&[r1, r2, r3, r4]),
+            DecoderRefs::Many(refs) => res.extend(refs),
         }
         res
     }
+
+    /// Encoded refs, `None` if an offset does not fit into the bytecode
+    pub fn bytes(&self) -> Option<SmallVec<[u8; 32]>> {
+        let mut res = SmallVec::new();
+        for r in self.as_vec() {
+            res.extend_from_slice(&r.code_ref.to_bytes()?);
+        }
+        Some(res)
+    }
+
+    /// Amount of bytes the refs take in the bytecode
+    pub fn size(&self) -> usize {
+        self.as_vec().iter().map(|r| r.code_ref.size()).sum()
+    }
 }
 
 impl Display for DecoderRefs {
@@ -120,28 +152,41 @@ impl Display for DecoderRefs {
             DecoderRefs::Two(r1, r2) => write!(f, "{} {}", r1, r2),
             DecoderRefs::Three(r1, r2, r3) => write!(f, "{} {} {}", r1, r2, r3),
             DecoderRefs::Four(r1, r2, r3, r4) => write!(f, "{} {} {} {}", r1, r2, r3, r4),
+            DecoderRefs::Many(refs) => {
+                let refs: Vec<_> = refs.iter().map(ToString::to_string).collect();
+                f.write_str(&refs.join(" "))
+            }
         }
     }
 }
 
 impl DecodedOpcode {
-    pub(crate) fn new(op_code: Opcode, refs: DecoderRefs) -> Self {
-        Self {
-            consumed: op_code.size() + refs_size(refs.count()),
-            op_code,
-            refs,
-        }
+    pub fn op_code(&self) -> Opcode {
+        self.op_code
     }
 
-    pub(crate) fn zero(op_code: Opcode) -> Self {
-        Self {
-            consumed: op_code.size(),
-            op_code,
-            refs: DecoderRefs::Zero,
+    pub fn refs(&self) -> &DecoderRefs {
+        &self.refs
+    }
+
+    /// Number of the extension opcode, if it is one
+    pub fn extension(&self) -> Option<u8> {
+        self.extension.map(|(code, _)| code)
+    }
+
+    /// Bytes the opcode starts with, before its refs
+    pub fn op_bytes(&self) -> ArrayVec<[u8; 2]> {
+        match self.extension {
+            Some((code, _)) => ArrayVec::from(ExtensionTable::opcode_bytes(code)),
+            None => self.op_code.bytes(),
         }
     }
 
-    pub(crate) fn one(op_code: Opcode, rf: DecoderRef) -> Self {
-        Self::new(op_code, DecoderRefs::One(rf))
+    /// Name of the opcode, as the disassembler shows it
+    pub fn name(&self) -> Cow<'static, str> {
+        match self.extension {
+            Some((_, name)) => name.into(),
+            None => format!("{:?}", self.op_code).into(),
+        }
     }
 }
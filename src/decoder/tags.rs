@@ -8,7 +8,20 @@ pub const OP: &str = "op";
 
 pub const OFFSET: &str = "*";
 pub const CONDITION: &str = "cond";
+pub const DEPTH: &str = "depth";
+pub const MUT_REF: &str = "&mut";
+pub const CELL_REF: &str = "&cell";
+pub const RC: &str = "rc";
+pub const WEAK: &str = "weak";
+pub const ANY: &str = "any";
 
 pub const S_ARR_REF: &str = "&s_arr";
 pub const S_ARR_MUT: &str = "&mut s_arr";
 pub const IDX: &str = "index";
+pub const SIZE: &str = "size";
+
+pub const OPCODE: &str = "opcode";
+pub const COUNTER: &str = "counter";
+pub const STEP: &str = "step";
+pub const LIMIT: &str = "limit";
+pub const IMMEDIATE: &str = "immediate";
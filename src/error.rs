@@ -1,17 +1,20 @@
 use thiserror::Error;
 
-use crate::code::refs::StackRef;
+use crate::code::refs::{PoolRef, StackRef};
 use crate::opcodes::Opcode;
 use crate::types::checker::{TaggedType, TypeError};
-use crate::types::RefKind;
+use crate::types::{PrimitiveType, RefKind, RefLocation, VmType};
 use crate::vm::lock::LockError;
 use crate::vm::ValueLocation;
+use crate::ConstantKind;
 
 /// Represents an error that originated inside the vm internal logic
 #[derive(Error, Debug)]
 pub enum VmError {
     #[error("bad bytecode")]
     InvalidBytecode,
+    #[error("The stack grows past {} values", crate::vm::MAX_STACK_LEN)]
+    StackOverflow,
     #[error("bad vm state, cannot continue")]
     BadVmState,
     #[error("The operation is not supported for type {0:?}")]
@@ -35,8 +38,113 @@ pub enum VmError {
 
     #[error("{0} (@{1:?})")]
     LockError(LockError, ValueLocation),
+    #[error("The index {0} is past the end of an array of length {1}")]
+    IndexOutOfBounds(usize, usize),
+    #[error("Arrays behind a reference of location {0:?} can't be indexed")]
+    UnsupportedArrayLocation(RefLocation),
     #[error("Use of moved value @{}", (.0).0)]
     UseOfMovedValue(StackRef),
+    #[error("Attempt to write to the immutable value @{}", (.0).0)]
+    WriteToImmutable(StackRef),
+    #[error("Attempt to move or drop the dereferenced value @{}", (.0).0)]
+    MoveOfDeref(StackRef),
+    #[error("Attempt to upgrade a weak reference @{} to an already released value", (.0).0)]
+    UpgradeOfReleasedWeak(StackRef),
+    #[error("The opcode {0:?} is not supported in verified code")]
+    Unverifiable(Opcode),
+    #[error("Paths of the code meet with different stack layouts")]
+    StackMismatch,
+    #[error("A module named {0:?} is already loaded")]
+    ModuleAlreadyLoaded(String),
+    #[error("An extension opcode {0} is already registered")]
+    ExtensionAlreadyRegistered(u8),
+    #[error("The operands of the extension opcode {0} are not supported")]
+    InvalidExtension(u8),
+    #[error("No extension opcode {0} is registered")]
+    UnknownExtension(u8),
+    #[error("The code ran past its step limit")]
+    OutOfSteps,
+    #[error("Statics are loaded before any other value of the stack")]
+    MisplacedStatic,
+    #[error("The function {0:?} does not match the constant pool of its module: {1}")]
+    InvalidPool(String, PoolError),
+}
+
+/// Represents an error while loading code from a file
+#[derive(Error, Debug)]
+pub enum LoadError {
+    #[error("Failed to read the code: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("The code does not decode past the opcode at {0}")]
+    InvalidBytecode(usize),
+}
+
+/// Represents an error while lifting model code into SSA form or lowering it back
+///
+/// Opcodes are counted with the scopes spelled out, see [`Function::lift`].
+///
+/// [`Function::lift`]: crate::model::ssa::Function::lift
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum SsaError {
+    #[error("The opcode at {0} has no SSA form")]
+    Unsupported(usize),
+    #[error("The opcode at {0} refers to a slot above the stack")]
+    InvalidStackRef(usize),
+    #[error("The type of the value the opcode at {0} pushes is not known")]
+    UnknownType(usize),
+    #[error("The opcode at {0} ends a scope that was not started")]
+    UnbalancedScope(usize),
+    #[error("The label {0} is not defined exactly once")]
+    BadLabel(usize),
+    #[error("Paths of the code meet at the opcode at {0} with different stack layouts")]
+    StackMismatch(usize),
+    #[error("The value v{0} is not on the stack where block {1} uses it")]
+    NotOnStack(usize, usize),
+    #[error("The stack at the end of block {0} does not match the start of block {1}")]
+    EdgeMismatch(usize, usize),
+}
+
+/// Represents the constants of a pool that don't match their uses by some code
+#[derive(Error, Debug, Eq, PartialEq)]
+#[error("The constant pool does not match the code: {0:?}")]
+pub struct PoolError(pub Vec<PoolProblem>);
+
+/// A use of the constant pool by the opcode at the offset that the pool can't satisfy
+#[derive(Debug, Eq, PartialEq)]
+pub enum PoolProblem {
+    /// The ref is past the end of the pool
+    OutOfRange(usize, PoolRef),
+    /// The constant is not of the kind the opcode reads
+    WrongKind(usize, PoolRef, ConstantKind),
+    /// The value is not a valid value of the type loaded along with it
+    InvalidValue(usize, PoolRef, PrimitiveType),
+    /// The array has an element that is not of its element type
+    InvalidArray(usize, PoolRef),
+    /// The code does not decode past the offset
+    InvalidBytecode(usize),
+}
+
+/// Represents a misuse of [`CodeBuilder`], found at the opcode of the index
+///
+/// [`CodeBuilder`]: crate::model::builder::CodeBuilder
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum BuildError {
+    #[error("The opcode at {0} uses a slot that is no longer on the stack")]
+    StaleSlot(usize),
+    #[error("The opcode at {0} uses a value moved out of its slot")]
+    Moved(usize),
+    #[error("The opcode at {0} expects {1:?} but got {2:?}")]
+    TypeMismatch(usize, VmType, VmType),
+    #[error("The opcode at {0} is not supported for type {1:?}")]
+    InvalidType(usize, VmType),
+    #[error("The stack at {0} does not match the one at label {1}")]
+    StackMismatch(usize, usize),
+    #[error("The label {0} is placed twice")]
+    DuplicateLabel(usize),
+    #[error("The label {0} is jumped to but never placed")]
+    UnplacedLabel(usize),
+    #[error("The built code can't be encoded")]
+    Encoding,
 }
 
 #[derive(Debug)]
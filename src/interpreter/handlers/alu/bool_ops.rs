@@ -1,16 +1,14 @@
-use crate::code::refs::refs_size;
-use crate::code::Chunk;
 use crate::error::VmError;
 use crate::stack::data::{FromPrimitive, FromSingle, IntoStackData, StackData};
 use crate::types::checker::{HasTypeCheckerCtx, TypeCheckerCtx};
 use crate::types::PrimitiveType;
-use crate::vm::VmRefSource;
+use crate::vm::OperandSource;
 use crate::Vm;
 
 use super::AluExtensions;
 
 fn handle_bi_op(
-    chunk: &Chunk,
+    chunk: &impl OperandSource,
     vm: &mut Vm,
     processor: impl FnOnce(StackData, StackData) -> StackData,
 ) -> Result<usize, VmError> {
@@ -33,11 +31,11 @@ fn handle_bi_op(
     let op1 = *vm.single_stack_data(rf.op1)?;
     let op2 = *vm.single_stack_data(rf.op2)?;
     *vm.single_stack_data_mut(rf.result)? = processor(op1, op2);
-    Ok(1 + refs_size(3))
+    Ok(1 + chunk.refs_size_vm(3)?)
 }
 
 fn handle_u_op(
-    chunk: &Chunk,
+    chunk: &impl OperandSource,
     vm: &mut Vm,
     processor: impl Fn(StackData) -> StackData,
 ) -> Result<usize, VmError> {
@@ -59,7 +57,7 @@ fn handle_u_op(
 
     let op = vm.single_stack_data(rf.op)?;
     *vm.single_stack_data_mut(rf.result)? = processor(*op);
-    Ok(1 + refs_size(3))
+    Ok(1 + chunk.refs_size_vm(3)?)
 }
 
 fn be(op: StackData) -> bool {
@@ -75,29 +73,44 @@ fn bi_as_bool(op1: StackData, op2: StackData, h: impl Fn(bool, bool) -> bool) ->
 }
 
 fn handle_b_bi_op(
-    chunk: &Chunk,
+    chunk: &impl OperandSource,
     vm: &mut Vm,
     h: impl Fn(bool, bool) -> bool,
 ) -> Result<usize, VmError> {
     handle_bi_op(chunk, vm, |op1, op2| bi_as_bool(op1, op2, h))
 }
 
-pub(in crate::interpreter) fn handle_b_and(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_b_and(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     handle_b_bi_op(chunk, vm, |op1, op2| op1 && op2)
 }
 
-pub(in crate::interpreter) fn handle_b_or(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_b_or(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     handle_b_bi_op(chunk, vm, |op1, op2| op1 || op2)
 }
 
-pub(in crate::interpreter) fn handle_b_xor(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_b_xor(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     handle_b_bi_op(chunk, vm, |op1, op2| op1 ^ op2)
 }
 
-pub(in crate::interpreter) fn handle_b_be(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_b_be(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     handle_u_op(chunk, vm, |data| be(data).into_stack_data())
 }
 
-pub(in crate::interpreter) fn handle_b_not(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_b_not(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     handle_u_op(chunk, vm, |data| not(data).into_stack_data())
 }
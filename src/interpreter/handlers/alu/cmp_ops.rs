@@ -1,17 +1,16 @@
 use std::cmp::Ordering;
 
-use crate::code::refs::{refs_size, ThreeStackRefs};
-use crate::code::Chunk;
+use crate::code::refs::ThreeStackRefs;
 use crate::error::VmError;
 use crate::interpreter::handlers::alu::AluExtensions;
 use crate::stack::data::{FromSingle, IntoStackData, StackData};
 use crate::types::checker::{HasTypeCheckerCtx, Taggable, TypeCheckerCtx};
 use crate::types::{PrimitiveType, VmType};
-use crate::vm::VmRefSource;
+use crate::vm::OperandSource;
 use crate::Vm;
 
 fn handle_cmp_op(
-    chunk: &Chunk,
+    chunk: &impl OperandSource,
     vm: &mut Vm,
     to_bool: impl Fn(Ordering) -> bool,
 ) -> Result<usize, VmError> {
@@ -44,30 +43,30 @@ fn handle_cmp_op(
             VmType::from(types.op).no_tag(),
         )),
     }?;
-    Ok(1 + refs_size(3))
+    Ok(1 + chunk.refs_size_vm(3)?)
 }
 
-fn eq(o: Ordering) -> bool {
+pub(super) fn eq(o: Ordering) -> bool {
     matches!(o, Ordering::Equal)
 }
 
-fn ne(o: Ordering) -> bool {
+pub(super) fn ne(o: Ordering) -> bool {
     !eq(o)
 }
 
-fn le(o: Ordering) -> bool {
+pub(super) fn le(o: Ordering) -> bool {
     matches!(o, Ordering::Less | Ordering::Equal)
 }
 
-fn ge(o: Ordering) -> bool {
+pub(super) fn ge(o: Ordering) -> bool {
     matches!(o, Ordering::Greater | Ordering::Equal)
 }
 
-fn lt(o: Ordering) -> bool {
+pub(super) fn lt(o: Ordering) -> bool {
     matches!(o, Ordering::Less)
 }
 
-fn gt(o: Ordering) -> bool {
+pub(super) fn gt(o: Ordering) -> bool {
     matches!(o, Ordering::Greater)
 }
 
@@ -86,26 +85,44 @@ where
     Ok(())
 }
 
-pub(in crate::interpreter) fn handle_eq(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_eq(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     handle_cmp_op(chunk, vm, eq)
 }
 
-pub(in crate::interpreter) fn handle_ne(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_ne(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     handle_cmp_op(chunk, vm, ne)
 }
 
-pub(in crate::interpreter) fn handle_lt(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_lt(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     handle_cmp_op(chunk, vm, lt)
 }
 
-pub(in crate::interpreter) fn handle_le(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_le(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     handle_cmp_op(chunk, vm, le)
 }
 
-pub(in crate::interpreter) fn handle_gt(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_gt(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     handle_cmp_op(chunk, vm, gt)
 }
 
-pub(in crate::interpreter) fn handle_ge(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_ge(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     handle_cmp_op(chunk, vm, ge)
 }
@@ -1,5 +1,3 @@
-use crate::code::refs::refs_size;
-use crate::code::{Chunk, RefSource};
 use crate::error::VmError;
 use crate::interpreter::handlers::alu::process_u_op;
 use crate::operations::markers::*;
@@ -7,11 +5,14 @@ use crate::operations::{BiOp, BiOpMarker, UOp, UOpMarker};
 use crate::stack::data::IntoStackData;
 use crate::types::checker::{HasTypeCheckerCtx, Taggable, TypeCheckerCtx};
 use crate::types::{HasPrimitiveType, PrimitiveType, VmType};
-use crate::vm::{Vm, VmRefSource};
+use crate::vm::{OperandSource, Vm};
 
 use super::{process_bi_op, AluExtensions};
 
-fn handle_bi_float_op<M: BiOpMarker>(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError>
+fn handle_bi_float_op<M: BiOpMarker, S: OperandSource>(
+    chunk: &S,
+    vm: &mut Vm,
+) -> Result<usize, VmError>
 where
     f64: BiOp<M>,
     f32: BiOp<M>,
@@ -33,17 +34,17 @@ where
         PrimitiveType::F32 => process_bi_op::<M, f32>(vm, rf)?,
         _ => return Err(VmError::InvalidTypeForOperation(VmType::from(t).no_tag())),
     }
-    Ok(1 + refs_size(3))
+    Ok(1 + chunk.refs_size_vm(3)?)
 }
 
-fn handle_u_float_op<M: UOpMarker>(chunk: &Chunk, vm: &mut Vm) -> Result<(), VmError>
+fn handle_u_float_op<M: UOpMarker, S: OperandSource>(chunk: &S, vm: &mut Vm) -> Result<(), VmError>
 where
     f64: UOp<M>,
     f32: UOp<M>,
     <f64 as UOp<M>>::Output: HasPrimitiveType + IntoStackData,
     <f32 as UOp<M>>::Output: HasPrimitiveType + IntoStackData,
 {
-    let rf = &chunk.read_two().ok_or(VmError::InvalidBytecode)?;
+    let rf = &chunk.read_two_vm()?;
 
     let meta = vm.two_stack_metadata(rf)?;
     let t = meta
@@ -63,8 +64,8 @@ where
 macro_rules! handle_f_ops {
    ($($fn_name: ident => $method_marker: ty),*) => {
         $(
-        pub(in crate::interpreter) fn $fn_name(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError>{
-            handle_bi_float_op::<$method_marker>(chunk, vm)
+        pub(in crate::interpreter) fn $fn_name(chunk: &impl OperandSource, vm: &mut Vm) -> Result<usize, VmError>{
+            handle_bi_float_op::<$method_marker, _>(chunk, vm)
         })*
 
     };
@@ -78,7 +79,10 @@ handle_f_ops! {
     handle_f_rem => Rem
 }
 
-pub(in crate::interpreter) fn handle_f_neg(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
-    handle_u_float_op::<Neg>(chunk, vm)?;
-    Ok(1 + refs_size(2))
+pub(in crate::interpreter) fn handle_f_neg(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    handle_u_float_op::<Neg, _>(chunk, vm)?;
+    Ok(1 + chunk.refs_size_vm(2)?)
 }
@@ -1,18 +1,20 @@
 use std::ops::Try;
 use std::option::NoneError;
 
-use crate::code::{refs::refs_size, Chunk};
 use crate::error::VmError;
 use crate::interpreter::handlers::alu::AluExtensions;
 use crate::operations::markers::*;
 use crate::operations::{BiOp, BiOpMarker, UOp, UOpMarker};
 use crate::types::checker::{HasTypeCheckerCtx, Taggable, TypeCheckerCtx};
 use crate::types::{PrimitiveType, VmType};
-use crate::vm::{Vm, VmRefSource};
+use crate::vm::{OperandSource, Vm};
 
 use super::{process_fallible_bi_op, process_fallible_u_op};
 
-fn handle_bi_signed_op<M: BiOpMarker>(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError>
+fn handle_bi_signed_op<M: BiOpMarker, S: OperandSource>(
+    chunk: &S,
+    vm: &mut Vm,
+) -> Result<usize, VmError>
 where
     i64: BiOp<M>,
     i32: BiOp<M>,
@@ -39,10 +41,13 @@ where
         PrimitiveType::I8 => process_fallible_bi_op::<M, i8, i8>(vm, rf),
         _ => Err(VmError::InvalidTypeForOperation(VmType::from(t).no_tag())),
     }?;
-    Ok(1 + refs_size(3))
+    Ok(1 + chunk.refs_size_vm(3)?)
 }
 
-fn handle_u_signed_op<M: UOpMarker>(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError>
+fn handle_u_signed_op<M: UOpMarker, S: OperandSource>(
+    chunk: &S,
+    vm: &mut Vm,
+) -> Result<usize, VmError>
 where
     i64: UOp<M>,
     i32: UOp<M>,
@@ -69,14 +74,14 @@ where
         PrimitiveType::I8 => process_fallible_u_op::<M, i8>(vm, rf),
         _ => Err(VmError::InvalidTypeForOperation(VmType::from(t).no_tag())),
     }?;
-    Ok(1 + refs_size(2))
+    Ok(1 + chunk.refs_size_vm(2)?)
 }
 
 macro_rules! handle_i_ops {
    ($($fn_name: ident => $marker: ty),*) => {
         $(
-        pub(in crate::interpreter) fn $fn_name(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
-            handle_bi_signed_op::<$marker>(chunk, vm)
+        pub(in crate::interpreter) fn $fn_name(chunk: &impl OperandSource, vm: &mut Vm) -> Result<usize, VmError> {
+            handle_bi_signed_op::<$marker, _>(chunk, vm)
         })*
 
     };
@@ -90,6 +95,9 @@ handle_i_ops! {
     handle_i_rem => CheckedRem
 }
 
-pub(in crate::interpreter) fn handle_i_neg(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
-    handle_u_signed_op::<CheckedNeg>(chunk, vm)
+pub(in crate::interpreter) fn handle_i_neg(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    handle_u_signed_op::<CheckedNeg, _>(chunk, vm)
 }
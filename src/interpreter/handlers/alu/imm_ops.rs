@@ -0,0 +1,217 @@
+//! Binary operations whose second operand is an immediate stored in the bytecode
+//!
+//! The declared type of the immediate is checked in place of the type of a second stack
+//! value, so the operations accept the same types as their stack counterparts.
+
+use std::cmp::Ordering;
+use std::ops::Try;
+use std::option::NoneError;
+
+use crate::code::refs::{Immediate, TwoStackRefs, IMMEDIATE_SIZE};
+use crate::error::VmError;
+use crate::operations::markers::*;
+use crate::operations::{BiOp, BiOpMarker};
+use crate::stack::data::{FromSingle, IntoStackData, StackData};
+use crate::types::checker::{HasTypeCheckerCtx, Taggable, TypeCheckerCtx};
+use crate::types::{HasPrimitiveType, PrimitiveType, VmType};
+use crate::vm::{OperandSource, Vm};
+
+use super::cmp_ops::{eq, ge, gt, le, lt, ne};
+use super::ThreeStackMetadata;
+
+fn read_imm_operands(chunk: &impl OperandSource) -> Result<(TwoStackRefs, Immediate), VmError> {
+    let rf = chunk.read_two_vm()?;
+    let imm = chunk.read_immediate_vm(2)?;
+    Ok((rf, imm))
+}
+
+fn imm_type(imm: Immediate) -> Result<VmType, VmError> {
+    imm.value_type()
+        .map(VmType::from)
+        .ok_or(VmError::InvalidBytecode)
+}
+
+fn process_fallible_imm_op<M: BiOpMarker, T>(
+    vm: &mut Vm,
+    refs: &TwoStackRefs,
+    imm: StackData,
+) -> Result<(), VmError>
+where
+    T: FromSingle<StackData> + BiOp<M> + IntoStackData,
+    <T as BiOp<M>>::Output: Try<Ok = T, Error = NoneError>,
+{
+    let op1 = T::from_single(*vm.single_stack_data(refs.op)?);
+    let r = op1
+        .invoke(T::from_single(imm))
+        .into_result()
+        .map_err(|_| VmError::BiOpError)?;
+    *vm.single_stack_data_mut(refs.result)? = r.into_stack_data();
+    Ok(())
+}
+
+fn process_imm_op<M: BiOpMarker, T>(
+    vm: &mut Vm,
+    refs: &TwoStackRefs,
+    imm: StackData,
+) -> Result<(), VmError>
+where
+    T: FromSingle<StackData> + BiOp<M>,
+    <T as BiOp<M>>::Output: IntoStackData + HasPrimitiveType,
+{
+    let op1 = T::from_single(*vm.single_stack_data(refs.op)?);
+    let r = op1.invoke(T::from_single(imm));
+    *vm.single_stack_data_mut(refs.result)? = r.into_stack_data();
+    Ok(())
+}
+
+/// Integers use the checked operation `C`, floats the plain operation `F`
+fn handle_imm_arith_op<C: BiOpMarker, F: BiOpMarker>(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError>
+where
+    u64: BiOp<C>,
+    u32: BiOp<C>,
+    u16: BiOp<C>,
+    u8: BiOp<C>,
+    i64: BiOp<C>,
+    i32: BiOp<C>,
+    i16: BiOp<C>,
+    i8: BiOp<C>,
+    f64: BiOp<F>,
+    f32: BiOp<F>,
+    <u64 as BiOp<C>>::Output: Try<Ok = u64, Error = NoneError>,
+    <u32 as BiOp<C>>::Output: Try<Ok = u32, Error = NoneError>,
+    <u16 as BiOp<C>>::Output: Try<Ok = u16, Error = NoneError>,
+    <u8 as BiOp<C>>::Output: Try<Ok = u8, Error = NoneError>,
+    <i64 as BiOp<C>>::Output: Try<Ok = i64, Error = NoneError>,
+    <i32 as BiOp<C>>::Output: Try<Ok = i32, Error = NoneError>,
+    <i16 as BiOp<C>>::Output: Try<Ok = i16, Error = NoneError>,
+    <i8 as BiOp<C>>::Output: Try<Ok = i8, Error = NoneError>,
+    <f64 as BiOp<F>>::Output: HasPrimitiveType + IntoStackData,
+    <f32 as BiOp<F>>::Output: HasPrimitiveType + IntoStackData,
+{
+    let (rf, imm) = read_imm_operands(chunk)?;
+    let imm_type = imm_type(imm)?;
+
+    let meta = ThreeStackMetadata {
+        result: vm.value_type(rf.result)?,
+        op1: vm.value_type(rf.op)?,
+        op2: &imm_type,
+    };
+    let mut type_checker_ctx = TypeCheckerCtx::new();
+    let t = meta
+        .check(&mut type_checker_ctx)
+        .all_primitives()
+        .all_same()
+        .get_vm()?;
+    let v = imm.value();
+    match t {
+        PrimitiveType::U64 => process_fallible_imm_op::<C, u64>(vm, &rf, v),
+        PrimitiveType::U32 => process_fallible_imm_op::<C, u32>(vm, &rf, v),
+        PrimitiveType::U16 => process_fallible_imm_op::<C, u16>(vm, &rf, v),
+        PrimitiveType::U8 => process_fallible_imm_op::<C, u8>(vm, &rf, v),
+        PrimitiveType::I64 => process_fallible_imm_op::<C, i64>(vm, &rf, v),
+        PrimitiveType::I32 => process_fallible_imm_op::<C, i32>(vm, &rf, v),
+        PrimitiveType::I16 => process_fallible_imm_op::<C, i16>(vm, &rf, v),
+        PrimitiveType::I8 => process_fallible_imm_op::<C, i8>(vm, &rf, v),
+        PrimitiveType::F64 => process_imm_op::<F, f64>(vm, &rf, v),
+        PrimitiveType::F32 => process_imm_op::<F, f32>(vm, &rf, v),
+        _ => Err(VmError::InvalidTypeForOperation(VmType::from(t).no_tag())),
+    }?;
+    Ok(1 + chunk.refs_size_vm(2)? + IMMEDIATE_SIZE)
+}
+
+pub(in crate::interpreter) fn handle_add_imm(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    handle_imm_arith_op::<CheckedAdd, Add>(chunk, vm)
+}
+
+pub(in crate::interpreter) fn handle_sub_imm(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    handle_imm_arith_op::<CheckedSub, Sub>(chunk, vm)
+}
+
+fn process_imm_cmp_op<T, F: Fn(Ordering) -> bool>(
+    vm: &mut Vm,
+    refs: &TwoStackRefs,
+    imm: StackData,
+    to_bool: F,
+) -> Result<(), VmError>
+where
+    T: FromSingle<StackData> + PartialOrd,
+{
+    let op1 = T::from_single(*vm.single_stack_data(refs.op)?);
+    let op2 = T::from_single(imm);
+    let r = op1.partial_cmp(&op2).ok_or(VmError::BiOpError)?;
+    *vm.single_stack_data_mut(refs.result)? = to_bool(r).into_stack_data();
+    Ok(())
+}
+
+fn handle_imm_cmp_op(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+    to_bool: impl Fn(Ordering) -> bool,
+) -> Result<usize, VmError> {
+    let (rf, imm) = read_imm_operands(chunk)?;
+    let imm_type = imm_type(imm)?;
+
+    let meta = ThreeStackMetadata {
+        result: vm.value_type(rf.result)?,
+        op1: vm.value_type(rf.op)?,
+        op2: &imm_type,
+    };
+    let mut type_checker = TypeCheckerCtx::new();
+    let types = meta
+        .check(&mut type_checker)
+        .all_primitives()
+        .result()
+        .bool()
+        .and()
+        .operands()
+        .same()
+        .get_vm()?;
+
+    let v = imm.value();
+    match types.op {
+        PrimitiveType::U64 => process_imm_cmp_op::<u64, _>(vm, &rf, v, to_bool),
+        PrimitiveType::U32 => process_imm_cmp_op::<u32, _>(vm, &rf, v, to_bool),
+        PrimitiveType::U16 => process_imm_cmp_op::<u16, _>(vm, &rf, v, to_bool),
+        PrimitiveType::U8 => process_imm_cmp_op::<u8, _>(vm, &rf, v, to_bool),
+        PrimitiveType::I64 => process_imm_cmp_op::<i64, _>(vm, &rf, v, to_bool),
+        PrimitiveType::I32 => process_imm_cmp_op::<i32, _>(vm, &rf, v, to_bool),
+        PrimitiveType::I16 => process_imm_cmp_op::<i16, _>(vm, &rf, v, to_bool),
+        PrimitiveType::I8 => process_imm_cmp_op::<i8, _>(vm, &rf, v, to_bool),
+        PrimitiveType::F64 => process_imm_cmp_op::<f64, _>(vm, &rf, v, to_bool),
+        PrimitiveType::F32 => process_imm_cmp_op::<f32, _>(vm, &rf, v, to_bool),
+        _ => Err(VmError::InvalidTypeForOperation(
+            VmType::from(types.op).no_tag(),
+        )),
+    }?;
+    Ok(1 + chunk.refs_size_vm(2)? + IMMEDIATE_SIZE)
+}
+
+macro_rules! handle_imm_cmp_ops {
+    ($($fn_name: ident => $to_bool: ident),* $(,)?) => {
+        $(
+        pub(in crate::interpreter) fn $fn_name(
+            chunk: &impl OperandSource,
+            vm: &mut Vm,
+        ) -> Result<usize, VmError> {
+            handle_imm_cmp_op(chunk, vm, $to_bool)
+        })*
+    };
+}
+
+handle_imm_cmp_ops! {
+    handle_ge_imm => ge,
+    handle_gt_imm => gt,
+    handle_le_imm => le,
+    handle_lt_imm => lt,
+    handle_eq_imm => eq,
+    handle_ne_imm => ne,
+}
@@ -1,15 +1,14 @@
-use crate::code::{refs::refs_size, Chunk};
 use crate::error::VmError;
 use crate::operations::markers::{And, Not, Or, Xor};
 use crate::operations::{BiOp, BiOpMarker};
 use crate::types::checker::{HasTypeCheckerCtx, Taggable, TypeCheckerCtx};
 use crate::types::{PrimitiveType, VmType};
-use crate::vm::VmRefSource;
+use crate::vm::OperandSource;
 use crate::Vm;
 
 use super::{process_bi_op, process_u_op, AluExtensions};
 
-fn handle_l_op<M: BiOpMarker>(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError>
+fn handle_l_op<M: BiOpMarker, S: OperandSource>(chunk: &S, vm: &mut Vm) -> Result<usize, VmError>
 where
     u64: BiOp<M, Output = u64>,
     u32: BiOp<M, Output = u32>,
@@ -43,14 +42,14 @@ where
         PrimitiveType::Bool => process_bi_op::<M, bool>(vm, rf),
         _ => Err(VmError::InvalidTypeForOperation(VmType::from(t).no_tag())),
     }?;
-    Ok(1 + refs_size(3))
+    Ok(1 + chunk.refs_size_vm(3)?)
 }
 
 macro_rules! handle_l_ops {
     ($($fn_name: ident => $marker: ty),* $(,)?) => {
         $(
-        pub(in crate::interpreter) fn $fn_name(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
-            handle_l_op::<$marker>(chunk, vm)
+        pub(in crate::interpreter) fn $fn_name(chunk: &impl OperandSource, vm: &mut Vm) -> Result<usize, VmError> {
+            handle_l_op::<$marker, _>(chunk, vm)
         }
         )*
     };
@@ -62,7 +61,10 @@ handle_l_ops! {
     handle_l_xor => Xor,
 }
 
-pub(in crate::interpreter) fn handle_l_not(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_l_not(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     let rf = &chunk.read_two_vm()?;
 
     let meta = vm.two_stack_metadata(rf)?;
@@ -94,5 +96,5 @@ pub(in crate::interpreter) fn handle_l_not(chunk: &Chunk, vm: &mut Vm) -> Result
         _ => return Err(VmError::InvalidTypeForOperation(VmType::from(t).no_tag())),
     }?;
 
-    Ok(1 + refs_size(3))
+    Ok(1 + chunk.refs_size_vm(3)?)
 }
@@ -3,20 +3,21 @@ use std::option::NoneError;
 
 use crate::code::refs::{ThreeStackRefs, TwoStackRefs};
 use crate::error::VmError;
-use crate::meta::StackMeta;
 use crate::operations::{BiOp, BiOpMarker, UOp, UOpMarker};
 use crate::stack::data::{FromSingle, IntoStackData, StackData};
 use crate::types::checker::{ThreeTypesChecker, TwoTypesChecker, TypeCheckerCtx};
-use crate::types::HasPrimitiveType;
+use crate::types::{HasPrimitiveType, VmType};
 use crate::vm::Vm;
 
 pub mod bool_ops;
 pub mod cmp_ops;
 pub mod f_ops;
 pub mod i_ops;
+pub mod imm_ops;
 pub mod logic_ops;
 pub mod shifts;
 pub mod u_ops;
+pub mod unchecked;
 
 fn process_fallible_bi_op<M: BiOpMarker, T, O>(
     vm: &mut Vm,
@@ -81,22 +82,22 @@ where
 }
 
 struct ThreeStackMetadata<'a> {
-    result: &'a StackMeta,
-    op1: &'a StackMeta,
-    op2: &'a StackMeta,
+    result: &'a VmType,
+    op1: &'a VmType,
+    op2: &'a VmType,
 }
 
 struct TwoStackMetadata<'a> {
-    result: &'a StackMeta,
-    op: &'a StackMeta,
+    result: &'a VmType,
+    op: &'a VmType,
 }
 
 impl<'a> ThreeStackMetadata<'a> {
     fn check<'c>(&'a self, ctx: &'c mut TypeCheckerCtx) -> ThreeTypesChecker<'a, 'c> {
         ThreeTypesChecker {
-            result: &self.result.value_type,
-            op1: &self.op1.value_type,
-            op2: &self.op2.value_type,
+            result: self.result,
+            op1: self.op1,
+            op2: self.op2,
             ctx,
         }
     }
@@ -105,8 +106,8 @@ impl<'a> ThreeStackMetadata<'a> {
 impl<'a> TwoStackMetadata<'a> {
     fn check<'c>(&'a self, ctx: &'c mut TypeCheckerCtx) -> TwoTypesChecker<'a, 'c> {
         TwoTypesChecker {
-            result: &self.result.value_type,
-            op: &self.op.value_type,
+            result: self.result,
+            op: self.op,
             ctx,
         }
     }
@@ -120,15 +121,15 @@ trait AluExtensions {
 
 impl AluExtensions for Vm {
     fn three_stack_metadata(&self, refs: &ThreeStackRefs) -> Result<ThreeStackMetadata, VmError> {
-        let result = self.stack_metadata(refs.result)?;
-        let op1 = self.stack_metadata(refs.op1)?;
-        let op2 = self.stack_metadata(refs.op2)?;
+        let result = self.value_type(refs.result)?;
+        let op1 = self.value_type(refs.op1)?;
+        let op2 = self.value_type(refs.op2)?;
         Ok(ThreeStackMetadata { result, op1, op2 })
     }
 
     fn two_stack_metadata(&self, refs: &TwoStackRefs) -> Result<TwoStackMetadata, VmError> {
-        let result = self.stack_metadata(refs.result)?;
-        let op = self.stack_metadata(refs.op)?;
+        let result = self.value_type(refs.result)?;
+        let op = self.value_type(refs.op)?;
         Ok(TwoStackMetadata { result, op })
     }
 }
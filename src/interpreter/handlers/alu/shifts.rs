@@ -1,16 +1,18 @@
 use std::ops::Try;
 use std::option::NoneError;
 
-use crate::code::{refs::refs_size, Chunk};
 use crate::error::VmError;
 use crate::interpreter::handlers::alu::{process_fallible_bi_op, AluExtensions};
 use crate::operations::markers::*;
 use crate::operations::{BiOp, BiOpMarker};
 use crate::types::checker::{HasTypeCheckerCtx, Taggable, TypeCheckerCtx};
 use crate::types::{PrimitiveType, VmType};
-use crate::vm::{Vm, VmRefSource};
+use crate::vm::{OperandSource, Vm};
 
-fn handle_shift_op<M: BiOpMarker>(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError>
+fn handle_shift_op<M: BiOpMarker, S: OperandSource>(
+    chunk: &S,
+    vm: &mut Vm,
+) -> Result<usize, VmError>
 where
     u64: BiOp<M, u32>,
     u32: BiOp<M, u32>,
@@ -58,14 +60,14 @@ where
             VmType::from(types.op1).no_tag(),
         )),
     }?;
-    Ok(1 + refs_size(3))
+    Ok(1 + chunk.refs_size_vm(3)?)
 }
 
 macro_rules! handle_shifts {
     ($($fn_name: ident => $marker: ty),*) => {
         $(
-        pub(in crate::interpreter) fn $fn_name(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
-            handle_shift_op::<$marker>(chunk, vm)
+        pub(in crate::interpreter) fn $fn_name(chunk: &impl OperandSource, vm: &mut Vm) -> Result<usize, VmError> {
+            handle_shift_op::<$marker, _>(chunk, vm)
         }
         )*
     };
@@ -0,0 +1,202 @@
+//! Handlers for verified code
+//!
+//! The verifier has already proven the types of the operands and that the result is
+//! mutable, so these handlers skip the type checks and access the stack data directly
+//! at the slots precomputed in [`Operands`]. The slots are still bounds checked, a vm whose
+//! stack does not match the verified layout reports a bad state instead of panicking.
+
+use std::cmp::Ordering;
+use std::ops::Try;
+use std::option::NoneError;
+
+use crate::code::threaded::Operands;
+use crate::error::VmError;
+use crate::interpreter::IntHandler;
+use crate::opcodes::Opcode;
+use crate::operations::markers::*;
+use crate::operations::{BiOp, BiOpMarker};
+use crate::stack::data::{FromSingle, IntoStackData, StackData};
+use crate::types::PrimitiveType;
+use crate::Vm;
+
+use super::cmp_ops;
+
+/// The data at `slot`, the verifier placed it on the stack so a miss is a bad vm state
+#[inline]
+fn read(vm: &Vm, slot: usize) -> Result<StackData, VmError> {
+    vm.stack.get(slot).copied().ok_or(VmError::BadVmState)
+}
+
+#[inline]
+fn write(vm: &mut Vm, slot: usize, value: StackData) -> Result<(), VmError> {
+    *vm.stack.get_mut(slot).ok_or(VmError::BadVmState)? = value;
+    Ok(())
+}
+
+fn unchecked_fallible_bi_op<M: BiOpMarker, T>(ops: &Operands, vm: &mut Vm) -> Result<usize, VmError>
+where
+    T: FromSingle<StackData> + BiOp<M> + IntoStackData,
+    <T as BiOp<M>>::Output: Try<Ok = T, Error = NoneError>,
+{
+    let [result, op1, op2] = ops.slots;
+    let op1 = T::from_single(read(vm, op1)?);
+    let op2 = T::from_single(read(vm, op2)?);
+    let r = op1
+        .invoke(op2)
+        .into_result()
+        .map_err(|_| VmError::BiOpError)?;
+    write(vm, result, r.into_stack_data())?;
+    Ok(1)
+}
+
+fn unchecked_bi_op<M: BiOpMarker, T>(ops: &Operands, vm: &mut Vm) -> Result<usize, VmError>
+where
+    T: FromSingle<StackData> + BiOp<M>,
+    <T as BiOp<M>>::Output: IntoStackData,
+{
+    let [result, op1, op2] = ops.slots;
+    let op1 = T::from_single(read(vm, op1)?);
+    let op2 = T::from_single(read(vm, op2)?);
+    write(vm, result, op1.invoke(op2).into_stack_data())?;
+    Ok(1)
+}
+
+trait Comparison {
+    fn holds(o: Ordering) -> bool;
+}
+
+macro_rules! comparisons {
+    ($($t: ident => $f: ident),* $(,)?) => {
+        $(
+        struct $t;
+
+        impl Comparison for $t {
+            #[inline]
+            fn holds(o: Ordering) -> bool {
+                cmp_ops::$f(o)
+            }
+        }
+        )*
+    };
+}
+
+comparisons! {
+    IsEq => eq,
+    IsNe => ne,
+    IsLt => lt,
+    IsLe => le,
+    IsGt => gt,
+    IsGe => ge,
+}
+
+fn unchecked_cmp_op<C: Comparison, T>(ops: &Operands, vm: &mut Vm) -> Result<usize, VmError>
+where
+    T: FromSingle<StackData> + PartialOrd,
+{
+    let [result, op1, op2] = ops.slots;
+    let op1 = T::from_single(read(vm, op1)?);
+    let op2 = T::from_single(read(vm, op2)?);
+    let r = op1.partial_cmp(&op2).ok_or(VmError::BiOpError)?;
+    write(vm, result, C::holds(r).into_stack_data())?;
+    Ok(1)
+}
+
+fn unsigned<M: BiOpMarker>(t: PrimitiveType) -> Option<IntHandler<Operands>>
+where
+    u64: BiOp<M>,
+    u32: BiOp<M>,
+    u16: BiOp<M>,
+    u8: BiOp<M>,
+    <u64 as BiOp<M>>::Output: Try<Ok = u64, Error = NoneError>,
+    <u32 as BiOp<M>>::Output: Try<Ok = u32, Error = NoneError>,
+    <u16 as BiOp<M>>::Output: Try<Ok = u16, Error = NoneError>,
+    <u8 as BiOp<M>>::Output: Try<Ok = u8, Error = NoneError>,
+{
+    match t {
+        PrimitiveType::U64 => Some(unchecked_fallible_bi_op::<M, u64>),
+        PrimitiveType::U32 => Some(unchecked_fallible_bi_op::<M, u32>),
+        PrimitiveType::U16 => Some(unchecked_fallible_bi_op::<M, u16>),
+        PrimitiveType::U8 => Some(unchecked_fallible_bi_op::<M, u8>),
+        _ => None,
+    }
+}
+
+fn signed<M: BiOpMarker>(t: PrimitiveType) -> Option<IntHandler<Operands>>
+where
+    i64: BiOp<M>,
+    i32: BiOp<M>,
+    i16: BiOp<M>,
+    i8: BiOp<M>,
+    <i64 as BiOp<M>>::Output: Try<Ok = i64, Error = NoneError>,
+    <i32 as BiOp<M>>::Output: Try<Ok = i32, Error = NoneError>,
+    <i16 as BiOp<M>>::Output: Try<Ok = i16, Error = NoneError>,
+    <i8 as BiOp<M>>::Output: Try<Ok = i8, Error = NoneError>,
+{
+    match t {
+        PrimitiveType::I64 => Some(unchecked_fallible_bi_op::<M, i64>),
+        PrimitiveType::I32 => Some(unchecked_fallible_bi_op::<M, i32>),
+        PrimitiveType::I16 => Some(unchecked_fallible_bi_op::<M, i16>),
+        PrimitiveType::I8 => Some(unchecked_fallible_bi_op::<M, i8>),
+        _ => None,
+    }
+}
+
+fn float<M: BiOpMarker>(t: PrimitiveType) -> Option<IntHandler<Operands>>
+where
+    f64: BiOp<M>,
+    f32: BiOp<M>,
+    <f64 as BiOp<M>>::Output: IntoStackData,
+    <f32 as BiOp<M>>::Output: IntoStackData,
+{
+    match t {
+        PrimitiveType::F64 => Some(unchecked_bi_op::<M, f64>),
+        PrimitiveType::F32 => Some(unchecked_bi_op::<M, f32>),
+        _ => None,
+    }
+}
+
+fn compare<C: Comparison>(t: PrimitiveType) -> Option<IntHandler<Operands>> {
+    match t {
+        PrimitiveType::U64 => Some(unchecked_cmp_op::<C, u64>),
+        PrimitiveType::U32 => Some(unchecked_cmp_op::<C, u32>),
+        PrimitiveType::U16 => Some(unchecked_cmp_op::<C, u16>),
+        PrimitiveType::U8 => Some(unchecked_cmp_op::<C, u8>),
+        PrimitiveType::I64 => Some(unchecked_cmp_op::<C, i64>),
+        PrimitiveType::I32 => Some(unchecked_cmp_op::<C, i32>),
+        PrimitiveType::I16 => Some(unchecked_cmp_op::<C, i16>),
+        PrimitiveType::I8 => Some(unchecked_cmp_op::<C, i8>),
+        PrimitiveType::F64 => Some(unchecked_cmp_op::<C, f64>),
+        PrimitiveType::F32 => Some(unchecked_cmp_op::<C, f32>),
+        _ => None,
+    }
+}
+
+/// The unchecked handler of a binary operation on operands of type `t`
+///
+/// Returns `None` if the operation has no fast path for the type.
+pub(crate) fn unchecked_handler(op: Opcode, t: PrimitiveType) -> Option<IntHandler<Operands>> {
+    match op {
+        Opcode::UAdd => unsigned::<CheckedAdd>(t),
+        Opcode::USub => unsigned::<CheckedSub>(t),
+        Opcode::UMul => unsigned::<CheckedMul>(t),
+        Opcode::UDiv => unsigned::<CheckedDiv>(t),
+        Opcode::URem => unsigned::<CheckedRem>(t),
+        Opcode::IAdd => signed::<CheckedAdd>(t),
+        Opcode::ISub => signed::<CheckedSub>(t),
+        Opcode::IMul => signed::<CheckedMul>(t),
+        Opcode::IDiv => signed::<CheckedDiv>(t),
+        Opcode::IRem => signed::<CheckedRem>(t),
+        Opcode::FAdd => float::<Add>(t),
+        Opcode::FSub => float::<Sub>(t),
+        Opcode::FMul => float::<Mul>(t),
+        Opcode::FDiv => float::<Div>(t),
+        Opcode::FRem => float::<Rem>(t),
+        Opcode::Eq => compare::<IsEq>(t),
+        Opcode::Ne => compare::<IsNe>(t),
+        Opcode::Lt => compare::<IsLt>(t),
+        Opcode::Le => compare::<IsLe>(t),
+        Opcode::Gt => compare::<IsGt>(t),
+        Opcode::Ge => compare::<IsGe>(t),
+        _ => None,
+    }
+}
@@ -0,0 +1,41 @@
+use crate::error::VmError;
+use crate::types::{PrimitiveType, VmType};
+use crate::vm::OperandSource;
+use crate::Vm;
+
+pub(in crate::interpreter) fn handle_any_wrap(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let op = chunk.read_ref_stack_vm(0)?;
+    vm.push_any(op)?;
+    Ok(1 + chunk.refs_size_vm(1)?)
+}
+
+pub(in crate::interpreter) fn handle_any_is(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let op = chunk.read_ref_stack_vm(0)?;
+    let t = read_type(chunk, vm)?;
+    let is = vm.any_type(op)? == &t;
+    vm.push_single_typed(is, PrimitiveType::Bool);
+    Ok(1 + chunk.refs_size_vm(2)?)
+}
+
+pub(in crate::interpreter) fn handle_any_downcast(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let op = chunk.read_ref_stack_vm(0)?;
+    let t = read_type(chunk, vm)?;
+    vm.push_downcast(op, &t)?;
+    Ok(1 + chunk.refs_size_vm(2)?)
+}
+
+fn read_type(chunk: &impl OperandSource, vm: &Vm) -> Result<VmType, VmError> {
+    let type_ref = chunk.read_ref_pool_vm(1)?;
+    vm.current_const_pool()?
+        .get_vm_type(type_ref)
+        .ok_or(VmError::ConstantPoolError)
+}
@@ -1,34 +1,32 @@
 //! TODO: refactor
 
-use crate::code::Chunk;
-use crate::code::refs::{refs_size, refs_size_with_offset};
 use crate::error::VmError;
 use crate::meta::{Meta, TransientMeta};
 use crate::stack::data::IntoPrimitive;
-use crate::types::{HasVmType, RefKind, RefLocation, RefType, VmType};
+use crate::types::{RefKind, RefLocation, TypeId};
 use crate::types::checker::{combine_checks, HasTypeCheckerCtx};
-use crate::vm::{ValueLocation, VmRefSource};
+use crate::vm::{OperandSource, ValueLocation};
 use crate::Vm;
 use crate::vm::lock::{ValueLock, ValueLockData};
 use crate::vm::refs::LocatedRef;
 
 pub(in crate::interpreter) fn handle_s_arr_create_0(
-    chunk: &Chunk,
+    chunk: &impl OperandSource,
     vm: &mut Vm,
 ) -> Result<usize, VmError> {
-    let pool = vm.current_const_pool();
+    let pool = vm.current_const_pool()?;
     let size = chunk.read_offset_vm()?;
     let type_of = chunk.read_ref_pool_with_offset_vm(0)?;
-    let type_of = pool.get_type(type_of).ok_or(VmError::ConstantPoolError)?;
-    if !type_of.is_single() {
+    let type_of = pool.get_vm_type(type_of).ok_or(VmError::ConstantPoolError)?;
+    if !type_of.is_zeroable() {
         return Err(VmError::ConstantPoolError);
     }
-    vm.push_array_0(size, type_of);
-    Ok(1 + refs_size_with_offset(1))
+    vm.push_array_0(size, type_of)?;
+    Ok(1 + chunk.refs_size_with_offset_vm(1)?)
 }
 
 pub(in crate::interpreter) fn handle_s_arr_get(
-    chunk: &Chunk,
+    chunk: &impl OperandSource,
     vm: &mut Vm,
 ) -> Result<usize, VmError> {
     let cycle = vm.current_cycle();
@@ -41,33 +39,33 @@ pub(in crate::interpreter) fn handle_s_arr_get(
     }
     let index_meta = vm.stack_metadata(index_ref)?;
     let arr_type = arr_ref_meta
-        .check("s_arr_ref")
+        .check(&vm.types, "s_arr_ref")
         .any_ref()
         .to()
         .s_arr()
         .and()
         .get();
-    let index_type = index_meta.check("index").primitive().unsigned().get();
+    let index_type = index_meta
+        .check(&vm.types, "index")
+        .primitive()
+        .unsigned()
+        .get();
     let (ref_type, _) = combine_checks(arr_type, index_type).map_err(VmError::TypeError)?;
 
     let ref_data = vm.single_stack_data(arr_ref)?;
     // get the array location
-    let arr_loc_ref = ref_type.locate(ref_data);
+    let arr_loc_ref = ref_type.locate(ref_data)?;
     // type of an element of an array
-    let (arr_location, ptr) = get_arr_data(vm, arr_loc_ref)?;
-    let ptr = ptr.clone();
+    let (arr_location, ptr, len) = get_arr_data(vm, arr_loc_ref)?;
+    let index_value: usize = vm.single_stack_data(index_ref)?.into_primitive();
+    let value_location = element_location(vm, arr_location, ptr, index_value, len)?;
 
     vm.stack_metadata_mut(arr_ref)?
         .lock
         .add_mut_lock_partial(cycle)
         .map_err(|e| VmError::LockError(e, ValueLocation::Stack(arr_ref.0)))?;
-    let index_value: usize = vm.single_stack_data(index_ref)?.into_primitive();
-    let value_location = match arr_location {
-        ValueLocation::Stack(si) => ValueLocation::Stack(si + index_value * ptr.size()),
-        ValueLocation::Heap(_) => unimplemented!(),
-    };
     let meta = TransientMeta {
-        value_type: ptr.clone(),
+        type_id: ptr,
         root_object: arr_loc_ref,
         lock: ValueLock::Ref(ValueLockData {
             lock_cycle: vm.current_cycle(),
@@ -77,17 +75,15 @@ pub(in crate::interpreter) fn handle_s_arr_get(
     };
 
     vm.transient_refs.insert(value_location, meta);
-    let ref_type = RefType {
-        kind: RefKind::Ref,
-        points_to: RefLocation::TransientOnStack,
-        pointer: ptr,
-    };
+    let ref_type = vm
+        .types
+        .reference(ptr, RefKind::Ref, RefLocation::TransientOnStack);
     vm.push_single_typed(value_location, ref_type);
-    Ok(1 + refs_size(2))
+    Ok(1 + chunk.refs_size_vm(2)?)
 }
 
 pub(in crate::interpreter) fn handle_s_arr_mut(
-    chunk: &Chunk,
+    chunk: &impl OperandSource,
     vm: &mut Vm,
 ) -> Result<usize, VmError> {
     let cycle = vm.current_cycle();
@@ -100,31 +96,30 @@ pub(in crate::interpreter) fn handle_s_arr_mut(
     }
     let index_meta = vm.stack_metadata(index_ref)?;
     let arr_type = arr_ref_meta
-        .check("s_arr_ref")
+        .check(&vm.types, "s_arr_ref")
         .mut_ref()
         .to()
         .s_arr()
         .and()
         .get();
-    let index_type = index_meta.check("index").primitive().unsigned().get();
+    let index_type = index_meta
+        .check(&vm.types, "index")
+        .primitive()
+        .unsigned()
+        .get();
     let (ref_type, _) = combine_checks(arr_type, index_type).map_err(VmError::TypeError)?;
 
     let ref_data = vm.single_stack_data(arr_ref)?;
     // get the array location
-    let arr_loc_ref = ref_type.locate(ref_data);
+    let arr_loc_ref = ref_type.locate(ref_data)?;
     // type of an element of an array
-    let (arr_location, ptr) = get_arr_data(vm, arr_loc_ref)?;
-    let ptr = ptr.clone();
+    let (arr_location, ptr, len) = get_arr_data(vm, arr_loc_ref)?;
+    let index_value: usize = vm.single_stack_data(index_ref)?.into_primitive();
+    let value_location = element_location(vm, arr_location, ptr, index_value, len)?;
     vm.stack_metadata_mut(arr_ref)?
         .lock
         .add_mut_lock_partial(cycle)
         .map_err(|e| VmError::LockError(e, ValueLocation::Stack(arr_ref.0)))?;
-
-    let index_value: usize = vm.single_stack_data(index_ref)?.into_primitive();
-    let value_location = match arr_location {
-        ValueLocation::Stack(si) => ValueLocation::Stack(si + index_value * ptr.size()),
-        ValueLocation::Heap(_) => unimplemented!(),
-    };
     if let Some(t_meta) = vm.transient_refs.get_mut(&value_location) {
         t_meta
             .lock
@@ -132,7 +127,7 @@ pub(in crate::interpreter) fn handle_s_arr_mut(
             .map_err(|e| VmError::LockError(e, arr_location))?;
     } else {
         let meta = TransientMeta {
-            value_type: ptr.clone(),
+            type_id: ptr,
             root_object: arr_loc_ref,
             lock: ValueLock::Mut(ValueLockData {
                 lock_cycle: vm.current_cycle(),
@@ -143,28 +138,56 @@ pub(in crate::interpreter) fn handle_s_arr_mut(
         vm.transient_refs.insert(value_location, meta);
     }
 
-    let ref_type = RefType {
-        kind: RefKind::Mut,
-        points_to: RefLocation::TransientOnStack,
-        pointer: ptr,
-    };
+    let ref_type = vm
+        .types
+        .reference(ptr, RefKind::Mut, RefLocation::TransientOnStack);
     vm.push_single_typed(value_location, ref_type);
-    Ok(1 + refs_size(2))
+    Ok(1 + chunk.refs_size_vm(2)?)
+}
+
+/// Location of the element at `index` of the array of elements of type `ptr` at `arr_location`
+fn element_location(
+    vm: &Vm,
+    arr_location: ValueLocation,
+    ptr: TypeId,
+    index: usize,
+    len: usize,
+) -> Result<ValueLocation, VmError> {
+    if index >= len {
+        return Err(VmError::IndexOutOfBounds(index, len));
+    }
+    match arr_location {
+        ValueLocation::Stack(si) => index
+            .checked_mul(vm.types.size(ptr))
+            .and_then(|offset| si.checked_add(offset))
+            .map(ValueLocation::Stack)
+            .ok_or(VmError::BadVmState),
+        ValueLocation::Heap(_) => Err(VmError::BadVmState),
+    }
 }
 
-fn get_arr_data(vm: &Vm, located_ref: LocatedRef) -> Result<(ValueLocation, &VmType), VmError> {
+/// Location of the array, the type of its elements and its length
+fn get_arr_data(
+    vm: &Vm,
+    located_ref: LocatedRef,
+) -> Result<(ValueLocation, TypeId, usize), VmError> {
     let (loc, arr_type) = match located_ref {
         LocatedRef::Stack(sr) => {
             let stack_meta = vm.stack_metadata(sr)?;
-            (ValueLocation::from(stack_meta.index), stack_meta.vm_type())
+            (ValueLocation::from(stack_meta.index), stack_meta.type_id)
         }
         LocatedRef::Transient(loc) => {
             let meta = vm.transient_refs.get(&loc).ok_or(VmError::BadVmState)?;
-            (loc, meta.vm_type())
+            (loc, meta.type_id)
         }
+        LocatedRef::Cell(_) => {
+            return Err(VmError::UnsupportedArrayLocation(RefLocation::CellOnStack))
+        }
+        LocatedRef::Rc(_) => return Err(VmError::UnsupportedArrayLocation(RefLocation::Rc)),
     };
-    arr_type
-        .s_arr()
-        .map(|arr| (loc, &arr.pointer))
+    let len = vm.types[arr_type].s_arr().ok_or(VmError::BadVmState)?.len;
+    vm.types
+        .inner(arr_type)
+        .map(|ptr| (loc, ptr, len))
         .ok_or(VmError::BadVmState)
 }
@@ -0,0 +1,37 @@
+use crate::error::VmError;
+use crate::types::RefKind;
+use crate::vm::OperandSource;
+use crate::Vm;
+
+pub(in crate::interpreter) fn handle_cell_new(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let op = chunk.read_ref_stack_vm(0)?;
+    vm.push_cell(op)?;
+    Ok(1 + chunk.refs_size_vm(1)?)
+}
+
+fn handle_cell_borrow_lock(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+    kind: RefKind,
+) -> Result<usize, VmError> {
+    let rf = chunk.read_ref_stack_vm(0)?;
+    vm.push_cell_borrow(rf, kind)?;
+    Ok(1 + chunk.refs_size_vm(1)?)
+}
+
+pub(in crate::interpreter) fn handle_cell_borrow(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    handle_cell_borrow_lock(chunk, vm, RefKind::Ref)
+}
+
+pub(in crate::interpreter) fn handle_cell_borrow_mut(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    handle_cell_borrow_lock(chunk, vm, RefKind::Mut)
+}
@@ -0,0 +1,225 @@
+//! Handlers of fused opcodes
+//!
+//! A fused opcode runs the handlers of the opcodes it replaces one after another, each
+//! reading its operands through a [`Picked`] view of the operands of the fused opcode.
+//! Only the dispatch between them is saved, so the results and the errors are the same
+//! as the ones of the unfused sequence.
+
+use crate::code::refs::{Immediate, Ref};
+use crate::error::VmError;
+use crate::opcodes::{Opcode, Operand};
+use crate::stack::data::IntoPrimitive;
+use crate::vm::extensions::ExtensionOperands;
+use crate::vm::{OperandSource, VmRefSource};
+use crate::Vm;
+
+use super::alu::{bool_ops::*, cmp_ops::*, f_ops::*, i_ops::*, logic_ops::*, shifts::*, u_ops::*};
+use super::jumps::jump_target;
+use super::load::handle_ld_type;
+use super::memory::{handle_end_scope, handle_start_scope};
+
+/// Operands of one of the opcodes a fused opcode replaces
+struct Picked<'a, S> {
+    fused: &'a S,
+    /// Indices of the operands in the fused opcode
+    indices: &'a [usize],
+    /// The operands follow the jump offset of the fused opcode
+    after_jump: bool,
+}
+
+impl<'a, S: OperandSource> Picked<'a, S> {
+    fn new(fused: &'a S, indices: &'a [usize]) -> Self {
+        Self {
+            fused,
+            indices,
+            after_jump: false,
+        }
+    }
+
+    fn after_jump(fused: &'a S, indices: &'a [usize]) -> Self {
+        Self {
+            fused,
+            indices,
+            after_jump: true,
+        }
+    }
+}
+
+impl<S: OperandSource> VmRefSource for Picked<'_, S> {
+    type VmError = VmError;
+
+    fn read_from_offset_vm(&self, _: usize, _: usize) -> Result<&[u8], Self::VmError> {
+        Err(VmError::InvalidBytecode)
+    }
+
+    fn read_ref_vm(&self, index: usize) -> Result<Ref, Self::VmError> {
+        let index = *self.indices.get(index).ok_or(VmError::InvalidBytecode)?;
+        if self.after_jump {
+            self.fused.read_ref_with_offset_vm(index)
+        } else {
+            self.fused.read_ref_vm(index)
+        }
+    }
+
+    fn read_ref_with_offset_vm(&self, _: usize) -> Result<Ref, Self::VmError> {
+        Err(VmError::InvalidBytecode)
+    }
+
+    fn read_offset_vm(&self) -> Result<usize, Self::VmError> {
+        Err(VmError::InvalidBytecode)
+    }
+
+    fn read_jump_vm(&self) -> Result<isize, Self::VmError> {
+        Err(VmError::InvalidBytecode)
+    }
+
+    fn read_immediate_vm(&self, _: usize) -> Result<Immediate, Self::VmError> {
+        Err(VmError::InvalidBytecode)
+    }
+
+    fn read_extension_operands_vm(
+        &self,
+        _: &[Operand],
+    ) -> Result<(ExtensionOperands, usize), Self::VmError> {
+        Err(VmError::InvalidBytecode)
+    }
+
+    /// The fused opcode advances past all of its operands at once
+    fn refs_size_vm(&self, _: usize) -> Result<usize, Self::VmError> {
+        Ok(0)
+    }
+
+    fn refs_size_with_offset_vm(&self, _: usize) -> Result<usize, Self::VmError> {
+        Ok(0)
+    }
+}
+
+impl<S: OperandSource> OperandSource for Picked<'_, S> {
+    fn offset(&self) -> usize {
+        self.fused.offset()
+    }
+
+    fn read_byte(&self, _: usize) -> Option<u8> {
+        None
+    }
+}
+
+/// Jump if the result of the comparison of a compare-and-branch opcode holds
+fn jump_if_result(chunk: &impl OperandSource, vm: &mut Vm) -> Result<usize, VmError> {
+    let offset = chunk.read_jump_vm()?;
+    let result = chunk.read_ref_stack_with_offset_vm(0)?;
+    if vm.single_stack_data(result)?.into_primitive() {
+        vm.ip = jump_target(vm.ip, offset)?;
+        Ok(0)
+    } else {
+        Ok(1 + chunk.refs_size_with_offset_vm(3)?)
+    }
+}
+
+macro_rules! handle_cmp_jumps {
+    ($($fn_name: ident => $cmp: ident),* $(,)?) => {
+        $(
+        pub(in crate::interpreter) fn $fn_name(
+            chunk: &impl OperandSource,
+            vm: &mut Vm,
+        ) -> Result<usize, VmError> {
+            $cmp(&Picked::after_jump(chunk, &[0, 1, 2]), vm)?;
+            jump_if_result(chunk, vm)
+        })*
+    };
+}
+
+handle_cmp_jumps! {
+    handle_j_ge => handle_ge,
+    handle_j_gt => handle_gt,
+    handle_j_le => handle_le,
+    handle_j_lt => handle_lt,
+    handle_j_eq => handle_eq,
+    handle_j_ne => handle_ne,
+}
+
+macro_rules! handle_u_add_cmps {
+    ($($fn_name: ident => $cmp: ident),* $(,)?) => {
+        $(
+        pub(in crate::interpreter) fn $fn_name(
+            chunk: &impl OperandSource,
+            vm: &mut Vm,
+        ) -> Result<usize, VmError> {
+            handle_u_add(&Picked::new(chunk, &[0, 0, 1]), vm)?;
+            $cmp(&Picked::new(chunk, &[2, 0, 3]), vm)?;
+            Ok(1 + chunk.refs_size_vm(4)?)
+        })*
+    };
+}
+
+handle_u_add_cmps! {
+    handle_u_add_ge => handle_ge,
+    handle_u_add_gt => handle_gt,
+    handle_u_add_le => handle_le,
+    handle_u_add_lt => handle_lt,
+    handle_u_add_eq => handle_eq,
+    handle_u_add_ne => handle_ne,
+}
+
+fn handle_bi_op(op: Opcode, operands: &impl OperandSource, vm: &mut Vm) -> Result<usize, VmError> {
+    match op {
+        Opcode::UAdd => handle_u_add(operands, vm),
+        Opcode::USub => handle_u_sub(operands, vm),
+        Opcode::UMul => handle_u_mul(operands, vm),
+        Opcode::UDiv => handle_u_div(operands, vm),
+        Opcode::URem => handle_u_rem(operands, vm),
+        Opcode::IAdd => handle_i_add(operands, vm),
+        Opcode::ISub => handle_i_sub(operands, vm),
+        Opcode::IMul => handle_i_mul(operands, vm),
+        Opcode::IDiv => handle_i_div(operands, vm),
+        Opcode::IRem => handle_i_rem(operands, vm),
+        Opcode::FAdd => handle_f_add(operands, vm),
+        Opcode::FSub => handle_f_sub(operands, vm),
+        Opcode::FMul => handle_f_mul(operands, vm),
+        Opcode::FDiv => handle_f_div(operands, vm),
+        Opcode::FRem => handle_f_rem(operands, vm),
+        Opcode::BAnd => handle_b_and(operands, vm),
+        Opcode::BOr => handle_b_or(operands, vm),
+        Opcode::BXor => handle_b_xor(operands, vm),
+        Opcode::LAnd => handle_l_and(operands, vm),
+        Opcode::LOr => handle_l_or(operands, vm),
+        Opcode::LXor => handle_l_xor(operands, vm),
+        Opcode::Shl => handle_shl(operands, vm),
+        Opcode::Shr => handle_shr(operands, vm),
+        Opcode::RotL => handle_rotl(operands, vm),
+        Opcode::RotR => handle_rotr(operands, vm),
+        Opcode::Ge => handle_ge(operands, vm),
+        Opcode::Gt => handle_gt(operands, vm),
+        Opcode::Le => handle_le(operands, vm),
+        Opcode::Lt => handle_lt(operands, vm),
+        Opcode::Eq => handle_eq(operands, vm),
+        Opcode::Ne => handle_ne(operands, vm),
+        _ => Err(VmError::InvalidBytecode),
+    }
+}
+
+/// Load a value from the constant pool, then run the binary operation of a load-and-op opcode
+fn ld_op(chunk: &impl OperandSource, vm: &mut Vm) -> Result<(), VmError> {
+    let op = Opcode::bi_op_from(chunk.read_ref_vm(0)?).ok_or(VmError::InvalidBytecode)?;
+    handle_ld_type(&Picked::new(chunk, &[1, 2]), vm)?;
+    handle_bi_op(op, &Picked::new(chunk, &[3, 4, 5]), vm)?;
+    Ok(())
+}
+
+pub(in crate::interpreter) fn handle_ld_op(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    ld_op(chunk, vm)?;
+    Ok(1 + chunk.refs_size_vm(6)?)
+}
+
+pub(in crate::interpreter) fn handle_scoped_ld_op(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    handle_start_scope(chunk, vm)?;
+    ld_op(chunk, vm)?;
+    handle_end_scope(chunk, vm)?;
+    Ok(1 + chunk.refs_size_vm(6)?)
+}
@@ -1,34 +1,46 @@
-use std::mem::size_of;
+use std::convert::TryFrom;
 
-use crate::code::{refs::refs_size, Chunk};
 use crate::error::VmError;
 use crate::meta::Meta;
 use crate::stack::data::IntoPrimitive;
 use crate::types::checker::{tags, HasTypeCheckerCtx, TypeCheckerCtx};
-use crate::vm::VmRefSource;
+use crate::vm::OperandSource;
 use crate::Vm;
 
-pub(in crate::interpreter) fn handle_j(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
-    let offset = chunk.read_offset_vm()?;
-    vm.ip = offset;
+pub(super) fn jump_target(ip: usize, offset: isize) -> Result<usize, VmError> {
+    (ip as isize)
+        .checked_add(offset)
+        .and_then(|target| usize::try_from(target).ok())
+        .ok_or(VmError::InvalidBytecode)
+}
+
+pub(in crate::interpreter) fn handle_j(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let offset = chunk.read_jump_vm()?;
+    vm.ip = jump_target(vm.ip, offset)?;
     Ok(0)
 }
 
-pub(in crate::interpreter) fn handle_jc(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
-    let offset = chunk.read_offset_vm()?;
+pub(in crate::interpreter) fn handle_jc(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let offset = chunk.read_jump_vm()?;
     let cond = chunk.read_ref_stack_with_offset_vm(0)?;
     let meta = vm.stack_metadata(cond)?;
     let mut t_ctx = TypeCheckerCtx::new();
     let _ = meta
-        .check_with(tags::COND, &mut t_ctx)
+        .check_with(&vm.types, tags::COND, &mut t_ctx)
         .primitive()
         .bool()
         .and()
         .get_vm()?;
     if vm.single_stack_data(cond)?.into_primitive() {
-        vm.ip = offset;
+        vm.ip = jump_target(vm.ip, offset)?;
         Ok(0)
     } else {
-        Ok(1 + size_of::<usize>() + refs_size(1))
+        Ok(1 + chunk.refs_size_with_offset_vm(1)?)
     }
 }
@@ -1,64 +1,124 @@
-use crate::code::{refs::refs_size, Chunk};
+use crate::code::refs::{StackRef, IMMEDIATE_SIZE};
 use crate::error::VmError;
 use crate::stack::data::StackData;
 use crate::types::PrimitiveType;
-use crate::vm::{Vm, VmRefSource};
+use crate::vm::{OperandSource, Vm};
 
-pub(in crate::interpreter) fn handle_u64_ld0(_: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_u64_ld0(
+    _: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     vm.push_primitive_zeroed(PrimitiveType::U64);
     Ok(1)
 }
 
-pub(in crate::interpreter) fn handle_i64_ld0(_: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_i64_ld0(
+    _: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     vm.push_primitive_zeroed(PrimitiveType::I64);
     Ok(1)
 }
 
-pub(in crate::interpreter) fn handle_ld_unit(_: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_ld_unit(
+    _: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     vm.push_single_typed(StackData::default(), PrimitiveType::Unit);
     Ok(1)
 }
 
 pub(in crate::interpreter) fn handle_ld_typed0(
-    chunk: &Chunk,
+    chunk: &impl OperandSource,
     vm: &mut Vm,
 ) -> Result<usize, VmError> {
-    let pool = vm.current_const_pool();
+    let pool = vm.current_const_pool()?;
     let type_ref = chunk.read_ref_pool_vm(0)?;
-    let t = pool.get_type(type_ref).ok_or(VmError::ConstantPoolError)?;
-    vm.push_single_typed(StackData::default(), t);
-    Ok(1 + refs_size(1))
+    let t = pool.get_vm_type(type_ref).ok_or(VmError::ConstantPoolError)?;
+    if !t.is_zeroable() {
+        return Err(VmError::ConstantPoolError);
+    }
+    vm.push_zeroed(t)?;
+    Ok(1 + chunk.refs_size_vm(1)?)
 }
 
-pub(in crate::interpreter) fn handle_ld_type(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
-    let pool = vm.current_const_pool();
+pub(in crate::interpreter) fn handle_ld_type(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let pool = vm.current_const_pool()?;
     let type_ref = chunk.read_ref_pool_vm(0)?;
     let value_ref = chunk.read_ref_pool_vm(1)?;
     let t = pool.get_type(type_ref).ok_or(VmError::ConstantPoolError)?;
+    if !t.is_single() {
+        return Err(VmError::ConstantPoolError);
+    }
     let v = pool
         .get_single(value_ref)
         .ok_or(VmError::ConstantPoolError)?;
     vm.push_single_typed(v, t);
 
-    Ok(1 + refs_size(2))
+    Ok(1 + chunk.refs_size_vm(2)?)
 }
 
-pub(in crate::interpreter) fn handle_ld_true(_: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_ld_const(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    handle_ld_type(chunk, vm)?;
+    let index = vm.stack_metadata.len() - 1 - vm.last_stack_frame;
+    vm.stack_metadata_mut(StackRef(index))?.mutable = false;
+    Ok(1 + chunk.refs_size_vm(2)?)
+}
+
+pub(in crate::interpreter) fn handle_ld_imm(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let imm = chunk.read_immediate_vm(0)?;
+    let t = imm.value_type().ok_or(VmError::InvalidBytecode)?;
+    vm.push_single_typed(imm.value(), t);
+    Ok(1 + IMMEDIATE_SIZE)
+}
+
+pub(in crate::interpreter) fn handle_ld_true(
+    _: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     vm.push_single_typed(true, PrimitiveType::Bool);
     Ok(1)
 }
 
-pub(in crate::interpreter) fn handle_ld_false(_: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_ld_false(
+    _: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     vm.push_single_typed(false, PrimitiveType::Bool);
     Ok(1)
 }
 
-pub(in crate::interpreter) fn handle_ld_ss(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
-    let pool = vm.current_const_pool();
+pub(in crate::interpreter) fn handle_ld_static(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let rf = chunk.read_ref_pool_vm(0)?;
+    let (t, data) = vm
+        .current_const_pool()?
+        .get_static(rf)
+        .ok_or(VmError::ConstantPoolError)?;
+    vm.push_static(t, data)?;
+    Ok(1 + chunk.refs_size_vm(1)?)
+}
+
+pub(in crate::interpreter) fn handle_ld_ss(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let pool = vm.current_const_pool()?;
     let rf = chunk.read_ref_pool_vm(0)?;
     let str = pool.get_s_str(rf).ok_or(VmError::ConstantPoolError)?;
     let ptr: usize = str.as_ptr() as usize;
     let len = str.len();
     vm.push_s_str(ptr, len);
-    Ok(1 + refs_size(1))
+    Ok(1 + chunk.refs_size_vm(1)?)
 }
@@ -1,21 +1,23 @@
-use smallvec::{SmallVec, ToSmallVec};
-
-use crate::code::{refs::refs_size, Chunk};
+use crate::code::refs::StackRef;
 use crate::error::VmError;
-use crate::stack::data::StackData;
-use crate::stack::get_stack_range;
 use crate::types::RefKind;
 use crate::vm::lock::DerefLock;
 use crate::vm::refs::LocatedRef;
-use crate::vm::{ValueLocation, VmRefSource};
+use crate::vm::{OperandSource, ValueLocation};
 use crate::Vm;
 
-pub(in crate::interpreter) fn handle_start_scope(_: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_start_scope(
+    _: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     vm.push_scope()?;
     Ok(1)
 }
 
-pub(in crate::interpreter) fn handle_end_scope(_: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_end_scope(
+    _: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     let current_cycle = vm.current_cycle();
 
     while let Some(meta) = vm.stack_metadata.last() {
@@ -28,7 +30,11 @@ pub(in crate::interpreter) fn handle_end_scope(_: &Chunk, vm: &mut Vm) -> Result
     Ok(1)
 }
 
-fn handle_take_lock(chunk: &Chunk, vm: &mut Vm, kind: RefKind) -> Result<usize, VmError> {
+fn handle_take_lock(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+    kind: RefKind,
+) -> Result<usize, VmError> {
     let rf = chunk.read_ref_stack_vm(0)?;
     let meta = vm.stack_metadata(rf)?;
     if meta.deref != DerefLock::None {
@@ -37,66 +43,98 @@ fn handle_take_lock(chunk: &Chunk, vm: &mut Vm, kind: RefKind) -> Result<usize,
         Err(VmError::SameCycleRef(kind, rf))
     } else {
         vm.push_stack_ref(rf, kind)?;
-        Ok(1 + refs_size(1))
+        Ok(1 + chunk.refs_size_vm(1)?)
     }
 }
 pub(in crate::interpreter) fn handle_take_ref(
-    chunk: &Chunk,
+    chunk: &impl OperandSource,
     vm: &mut Vm,
 ) -> Result<usize, VmError> {
     handle_take_lock(chunk, vm, RefKind::Ref)
 }
 
 pub(in crate::interpreter) fn handle_take_mut(
-    chunk: &Chunk,
+    chunk: &impl OperandSource,
     vm: &mut Vm,
 ) -> Result<usize, VmError> {
     handle_take_lock(chunk, vm, RefKind::Mut)
 }
 
-pub(in crate::interpreter) fn handle_start_deref(
-    chunk: &Chunk,
+fn handle_reborrow_lock(
+    chunk: &impl OperandSource,
     vm: &mut Vm,
+    kind: RefKind,
 ) -> Result<usize, VmError> {
     let rf = chunk.read_ref_stack_vm(0)?;
+    let meta = vm.stack_metadata(rf)?;
+    if vm.cycle <= meta.cycle {
+        Err(VmError::SameCycleRef(kind, rf))
+    } else {
+        vm.push_reborrow(rf, kind)?;
+        Ok(1 + chunk.refs_size_vm(1)?)
+    }
+}
+
+pub(in crate::interpreter) fn handle_reborrow(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    handle_reborrow_lock(chunk, vm, RefKind::Ref)
+}
+
+pub(in crate::interpreter) fn handle_reborrow_mut(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    handle_reborrow_lock(chunk, vm, RefKind::Mut)
+}
+
+fn start_deref(vm: &mut Vm, rf: StackRef, depth: usize) -> Result<(), VmError> {
     let cycle = vm.current_cycle();
-    let (located_ref, r) = vm.locate_ref(rf)?;
-    let r_kind = r.kind;
-    let meta = vm.stack_metadata_mut(rf)?;
+    let (located_ref, kind, t) = vm.locate_ref_chain(rf, depth)?;
 
-    if matches!(r_kind, RefKind::Mut) {
+    if matches!(kind, RefKind::Mut) {
+        if let LocatedRef::Stack(index) = located_ref {
+            if !vm.stack_metadata(index)?.mutable {
+                return Err(VmError::WriteToImmutable(index));
+            }
+        }
+        let meta = vm.stack_metadata_mut(rf)?;
         meta.lock
             .add_lock(cycle, RefKind::Mut)
             .map_err(|e| VmError::LockError(e, ValueLocation::Stack(rf.0)))?;
     }
-    match located_ref {
-        LocatedRef::Stack(index) => {
-            let t = vm.stack_metadata(index)?.value_type.clone();
-            let v: SmallVec<[StackData; 2]> = vm.stack_data(index)?.to_smallvec();
-            vm.push_deref(v, t, r_kind, rf);
-        }
-        LocatedRef::Transient(index) => {
-            let meta = vm.transient_refs.get(&index).ok_or(VmError::BadVmState)?;
-            let t = meta.value_type.clone();
-            match index {
-                ValueLocation::Stack(index) => {
-                    let range = get_stack_range(index, &t);
-                    let v: SmallVec<[StackData; 2]> = vm
-                        .stack
-                        .get(range)
-                        .ok_or(VmError::BadVmState)?
-                        .to_smallvec();
-                    vm.push_deref(v, t, r_kind, rf);
-                }
-                ValueLocation::Heap(_) => unimplemented!(),
-            }
-        }
-    };
+    let v = vm.located_data(located_ref, t)?;
+    vm.push_deref(v, t, kind, rf, depth);
+    Ok(())
+}
+
+pub(in crate::interpreter) fn handle_start_deref(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let rf = chunk.read_ref_stack_vm(0)?;
+    start_deref(vm, rf, 1)?;
+    Ok(1 + chunk.refs_size_vm(1)?)
+}
 
-    Ok(1 + refs_size(1))
+pub(in crate::interpreter) fn handle_start_deref_n(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let rf = chunk.read_ref_stack_vm(0)?;
+    let depth = chunk.read_ref_vm(1)?;
+    if depth == 0 {
+        return Err(VmError::InvalidBytecode);
+    }
+    start_deref(vm, rf, depth)?;
+    Ok(1 + chunk.refs_size_vm(2)?)
 }
 
-pub(in crate::interpreter) fn handle_end_deref(_: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_end_deref(
+    _: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     vm.pop_deref()?;
     Ok(1)
 }
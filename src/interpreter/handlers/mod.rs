@@ -1,41 +1,48 @@
-use crate::code::refs::refs_size;
-use crate::code::Chunk;
 use crate::error::VmError;
-use crate::vm::{Vm, VmRefSource};
+use crate::vm::{OperandSource, Vm};
 
 use super::stack_tracer::StackTracer;
 
 pub(in crate::interpreter) mod alu;
+pub(in crate::interpreter) mod any;
 pub(in crate::interpreter) mod array;
+pub(in crate::interpreter) mod cell;
+pub(in crate::interpreter) mod fused;
 pub(in crate::interpreter) mod jumps;
 pub(in crate::interpreter) mod load;
 pub(in crate::interpreter) mod memory;
+pub(in crate::interpreter) mod rc;
 pub(in crate::interpreter) mod stack;
 
 /// For debug only
-pub(super) fn handle_trace_stack_value(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(super) fn handle_trace_stack_value(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     let stack_ref = chunk.read_ref_stack_vm(0)?;
-    let meta = vm.stack_metadata(stack_ref)?;
+    let value_type = vm.value_type(stack_ref)?;
     let data = vm.stack_data(stack_ref)?;
-    eprintln!("Trace @{}: {:#?}", stack_ref.0, StackTracer(data, meta));
-    Ok(1 + refs_size(1))
+    eprintln!(
+        "Trace @{}: {:#?}",
+        stack_ref.0,
+        StackTracer(data, value_type)
+    );
+    Ok(1 + chunk.refs_size_vm(1)?)
 }
 
-pub(super) fn handle_wide(chunk: &Chunk, _: &mut Vm) -> Result<usize, VmError> {
-    let mut new_chunk = chunk.clone();
-    new_chunk.advance(1);
-
-    unimplemented!(
-        "Wide opcodes are not supported yet (@{}) {}",
-        chunk.offset(),
-        new_chunk.read_byte(0).ok_or(VmError::InvalidBytecode)?
-    )
+/// Interpret an extension opcode, registered in the vm under the byte after `HWide`
+pub(super) fn handle_wide(chunk: &impl OperandSource, vm: &mut Vm) -> Result<usize, VmError> {
+    let code = chunk.read_byte(1).ok_or(VmError::InvalidBytecode)?;
+    let extension = vm
+        .extensions
+        .get(code)
+        .ok_or(VmError::UnknownExtension(code))?;
+    let (operands, size) = chunk.read_extension_operands_vm(extension.operands)?;
+    (extension.handler)(&operands, vm)?;
+    Ok(2 + size)
 }
 
-pub(crate) fn noop(chunk: &Chunk, _vm: &mut Vm) -> Result<usize, VmError> {
-    panic!(
-        "a bad opcode detected (@{}){}",
-        chunk.offset(),
-        chunk.read_byte(0).ok_or(VmError::InvalidBytecode)?
-    );
+/// Handler of the bytes that are not an opcode
+pub(crate) fn noop(_chunk: &impl OperandSource, _vm: &mut Vm) -> Result<usize, VmError> {
+    Err(VmError::InvalidBytecode)
 }
@@ -0,0 +1,54 @@
+use crate::error::VmError;
+use crate::types::{PrimitiveType, RefKind};
+use crate::vm::OperandSource;
+use crate::Vm;
+
+pub(in crate::interpreter) fn handle_rc_new(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let op = chunk.read_ref_stack_vm(0)?;
+    vm.push_rc(op)?;
+    Ok(1 + chunk.refs_size_vm(1)?)
+}
+
+pub(in crate::interpreter) fn handle_rc_deref(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let op = chunk.read_ref_stack_vm(0)?;
+    let meta = vm.stack_metadata(op)?;
+    if vm.cycle <= meta.cycle {
+        return Err(VmError::SameCycleRef(RefKind::Ref, op));
+    }
+    vm.push_rc_deref(op)?;
+    Ok(1 + chunk.refs_size_vm(1)?)
+}
+
+pub(in crate::interpreter) fn handle_rc_downgrade(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let op = chunk.read_ref_stack_vm(0)?;
+    vm.push_weak(op)?;
+    Ok(1 + chunk.refs_size_vm(1)?)
+}
+
+pub(in crate::interpreter) fn handle_weak_upgrade(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let op = chunk.read_ref_stack_vm(0)?;
+    vm.push_upgraded(op)?;
+    Ok(1 + chunk.refs_size_vm(1)?)
+}
+
+pub(in crate::interpreter) fn handle_weak_is_alive(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let op = chunk.read_ref_stack_vm(0)?;
+    let alive = vm.weak_strong_count(op)? > 0;
+    vm.push_single_typed(alive, PrimitiveType::Bool);
+    Ok(1 + chunk.refs_size_vm(1)?)
+}
@@ -1,42 +1,47 @@
 use smallvec::SmallVec;
 
 use crate::{vm, Vm};
-use crate::code::Chunk;
-use crate::code::refs::{refs_size, StackRef};
+use crate::code::refs::StackRef;
 use crate::error::VmError;
 use crate::stack::data::StackData;
-use crate::types::{RefKind, RefType, VmType};
-use crate::types::checker::{Taggable, TypeError};
-use crate::vm::VmRefSource;
+use crate::types::{PointedType, RefKind, RefLocation, RefType, VmType};
+use crate::meta::Meta;
+use crate::types::checker::{tags, HasTypeCheckerCtx, Taggable, TypeError};
+use crate::vm::{OperandSource, ValueLocation};
 
-pub(in crate::interpreter) fn handle_mv(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_mv(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     let result = chunk.read_ref_stack_vm(0)?;
     let op = chunk.read_ref_stack_vm(1)?;
+    vm.check_owned(op)?;
     let result_meta = vm.stack_metadata(result)?;
     let op_meta = vm.stack_metadata(op)?;
 
     let from = result_meta.index.0;
-    let until = from + result_meta.value_type.size();
-    if result_meta.value_type != op_meta.value_type {
-        let result_type = result_meta.value_type.clone().tag("r");
-        let op_type = op_meta.value_type.clone().tag("o");
+    let until = from + vm.types.size(result_meta.type_id);
+    if result_meta.type_id != op_meta.type_id {
+        let result_type = vm.types[result_meta.type_id].tag("r");
+        let op_type = vm.types[op_meta.type_id].tag("o");
         let e = TypeError::TwoNotEqual(result_type, op_type);
         return Err(VmError::TypeError(vec![e]));
     }
-    if let (Some(result_r), Some(op_r)) = (
-        result_meta.value_type.ref_type(),
-        op_meta.value_type.ref_type(),
-    ) {
-        check_ref_move_rules(vm, result, result_r)?;
-        check_ref_move_rules(vm, op, op_r)?;
+    if let Some(r) = vm.types[op_meta.type_id].ref_type() {
+        check_ref_move_rules(vm, result, r)?;
+        check_ref_move_rules(vm, op, r)?;
     }
+    let is_copy = vm.types.is_copy(op_meta.type_id);
 
+    if !result_meta.mutable {
+        return Err(VmError::WriteToImmutable(result));
+    }
     vm.free_by_index(result)?;
     let op_meta = vm.stack_metadata_mut(op)?;
     if op_meta.was_moved {
         return Err(VmError::UseOfMovedValue(op));
     }
-    if !op_meta.value_type.is_copy() {
+    if !is_copy {
         op_meta.was_moved = true;
     }
 
@@ -47,25 +52,29 @@ pub(in crate::interpreter) fn handle_mv(chunk: &Chunk, vm: &mut Vm) -> Result<us
         .collect::<SmallVec<[StackData; 2]>>();
 
     vm.stack.splice(from..until, value);
-    vm.stack_metadata_mut(op)?.was_moved = false;
-    Ok(1 + refs_size(2))
+    vm.stack_metadata_mut(result)?.was_moved = false;
+    Ok(1 + chunk.refs_size_vm(2)?)
 }
 
-pub(in crate::interpreter) fn handle_mp(chunk: &Chunk, vm: &mut Vm) -> Result<usize, VmError> {
+pub(in crate::interpreter) fn handle_mp(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
     let op = chunk.read_ref_stack_vm(0)?;
-    let op_meta = vm.stack_metadata(op)?;
+    vm.check_owned(op)?;
+    let t = vm.stack_metadata(op)?.type_id;
 
-    if let Some(r) = op_meta.value_type.ref_type() {
+    if let Some(r) = vm.types[t].ref_type() {
         check_ref_move_rules(vm, op, r)?;
     }
+    let is_copy = vm.types.is_copy(t);
     let op_meta = vm.stack_metadata_mut(op)?;
     if op_meta.was_moved {
         return Err(VmError::UseOfMovedValue(op));
     }
-    if !op_meta.value_type.is_copy() {
+    if !is_copy {
         op_meta.was_moved = true;
     }
-    let t = op_meta.value_type.clone();
     let value = vm
         .stack_data(op)?
         .iter()
@@ -73,15 +82,133 @@ pub(in crate::interpreter) fn handle_mp(chunk: &Chunk, vm: &mut Vm) -> Result<us
         .collect::<SmallVec<[StackData; 2]>>();
 
     vm.push_typed(value, t);
-    vm.stack_metadata_mut(op)?.was_moved = false;
-    Ok(1 + refs_size(2))
+    Ok(1 + chunk.refs_size_vm(1)?)
+}
+
+pub(in crate::interpreter) fn handle_drop(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let op = chunk.read_ref_stack_vm(0)?;
+    vm.drop_by_index(op)?;
+    Ok(1 + chunk.refs_size_vm(1)?)
+}
+
+pub(in crate::interpreter) fn handle_clone(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let op = chunk.read_ref_stack_vm(0)?;
+    let op_meta = vm.stack_metadata(op)?;
+    if op_meta.was_moved {
+        return Err(VmError::UseOfMovedValue(op));
+    }
+    let t = op_meta.type_id;
+    match vm.types[t].pointed() {
+        Some(PointedType::Ref(r)) if r.kind == RefKind::Ref => {
+            vm.push_reborrow(op, RefKind::Ref)?
+        }
+        Some(PointedType::Rc(_)) | Some(PointedType::Weak(_)) => vm.push_rc_clone(op)?,
+        _ if vm.types[t].is_clone() => {
+            let value = vm
+                .stack_data(op)?
+                .iter()
+                .copied()
+                .collect::<SmallVec<[StackData; 2]>>();
+            vm.push_typed(value, t);
+        }
+        _ => {
+            let op_type = vm.types[t].tag(tags::OP);
+            return Err(VmError::InvalidTypeForOperation(op_type));
+        }
+    }
+    Ok(1 + chunk.refs_size_vm(1)?)
+}
+
+pub(in crate::interpreter) fn handle_freeze(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let op = chunk.read_ref_stack_vm(0)?;
+    vm.freeze(op)?;
+    Ok(1 + chunk.refs_size_vm(1)?)
+}
+
+pub(in crate::interpreter) fn handle_swap(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let a = chunk.read_ref_stack_vm(0)?;
+    let b = chunk.read_ref_stack_vm(1)?;
+    vm.swap_by_index(a, b)?;
+    Ok(1 + chunk.refs_size_vm(2)?)
+}
+
+pub(in crate::interpreter) fn handle_replace(
+    chunk: &impl OperandSource,
+    vm: &mut Vm,
+) -> Result<usize, VmError> {
+    let dest = chunk.read_ref_stack_vm(0)?;
+    let value = chunk.read_ref_stack_vm(1)?;
+    let dest_meta = vm.stack_metadata(dest)?;
+    let value_meta = vm.stack_metadata(value)?;
+
+    let _ = dest_meta
+        .check(&vm.types, tags::RESULT)
+        .mut_ref()
+        .get_vm()?;
+    let pointer = vm
+        .types
+        .inner(dest_meta.type_id)
+        .ok_or(VmError::BadVmState)?;
+    if pointer != value_meta.type_id {
+        let dest_type = vm.types[pointer].tag(tags::RESULT);
+        let value_type = vm.types[value_meta.type_id].tag(tags::OP);
+        let e = TypeError::TwoNotEqual(dest_type, value_type);
+        return Err(VmError::TypeError(vec![e]));
+    }
+    if vm.types[pointer].ref_type().is_some() {
+        let value_type = vm.types[pointer].tag(tags::OP);
+        return Err(VmError::InvalidTypeForOperation(value_type));
+    }
+    dest_meta
+        .lock
+        .clone()
+        .add_mut_lock(vm.current_cycle())
+        .map_err(|e| VmError::LockError(e, ValueLocation::Stack(dest.0)))?;
+    vm.check_movable(value)?;
+
+    let (located_ref, _) = vm.locate_ref(dest)?;
+    let from = vm.data_index(located_ref)?;
+    let until = from + vm.types.size(pointer);
+    let old = vm
+        .stack
+        .get(from..until)
+        .ok_or(VmError::BadVmState)?
+        .iter()
+        .copied()
+        .collect::<SmallVec<[StackData; 2]>>();
+    let new = vm
+        .stack_data(value)?
+        .iter()
+        .copied()
+        .collect::<SmallVec<[StackData; 2]>>();
+    vm.stack[from..until].copy_from_slice(&new);
+    vm.stack_data_mut(value)?.copy_from_slice(&old);
+    Ok(1 + chunk.refs_size_vm(2)?)
 }
 
 fn check_ref_move_rules(vm: &Vm, op: StackRef, r: &RefType) -> vm::Result<()> {
     let cycle = vm.current_cycle();
+    if r.points_to == RefLocation::CellOnStack {
+        let op_type = VmType::from(r.clone()).tag("o");
+        let msg = "Cannot move a cell borrow. Borrow the cell again instead";
+        let e = TypeError::Condition(op_type, msg.into());
+        return Err(VmError::TypeError(vec![e]));
+    }
     if matches!(r.kind, RefKind::Ref) {
         let ref_value = vm.single_stack_data(op)?;
-        let located_ref = r.locate(ref_value);
+        let located_ref = r.locate(ref_value)?;
         let value_meta = vm.meta_view(located_ref)?;
         if let Some(c) = value_meta.lock().lock_cycle() {
             if c <= cycle {
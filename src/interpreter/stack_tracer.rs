@@ -3,29 +3,28 @@ use std::ops::Deref;
 use std::slice::from_raw_parts;
 use std::str::from_utf8_unchecked;
 
-use crate::meta::StackMeta;
 use crate::stack::data::{FromSingle, IntoPrimitive, StackData};
 use crate::types::{PointedType, PrimitiveType, VmType};
 
 /// Traces the stack value contained in a slice of stack data
-pub struct StackTracer<'a>(pub &'a [StackData], pub &'a StackMeta);
+pub struct StackTracer<'a>(pub &'a [StackData], pub &'a VmType);
 
 impl<'a> Debug for StackTracer<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         // TODO: DST tracing
         let stack = self.0;
-        let data_0 = stack.get(0);
-        let value_type = &self.1.value_type;
+        let data_0 = stack.first().copied().unwrap_or_default();
+        let value_type = self.1;
         let alt = f.alternate();
         let mut s = f.debug_struct("stack_value");
         if alt {
             match value_type {
                 VmType::Primitive(p) => {
                     s.field("type", p);
-                    let data_0 = *data_0.unwrap();
                     match p {
-                        PrimitiveType::StackFrame => todo!(),
-                        PrimitiveType::ReturnAddr => todo!(),
+                        PrimitiveType::StackFrame | PrimitiveType::ReturnAddr => {
+                            s.field("data", &usize::from_single(data_0))
+                        }
                         PrimitiveType::Unit => s.field("data", &"(unit)"),
                         PrimitiveType::Never => s.field("data", &"(never!)"),
                         PrimitiveType::U64 => s.field("data", &u64::from_single(data_0)),
@@ -43,7 +42,11 @@ impl<'a> Debug for StackTracer<'a> {
                         PrimitiveType::SStr => {
                             let ptr = usize::from_single(data_0) as *const u8;
                             let len: usize = self.0.get(1).ok_or(fmt::Error)?.into_primitive();
-                            let str = unsafe { from_utf8_unchecked(from_raw_parts(ptr, len)) };
+                            // a zeroed string has no pointer
+                            let str = match len {
+                                0 => "",
+                                _ => unsafe { from_utf8_unchecked(from_raw_parts(ptr, len)) },
+                            };
                             s.field("data", &str)
                         }
                     };
@@ -51,7 +54,7 @@ impl<'a> Debug for StackTracer<'a> {
                 VmType::PointedType(p) => {
                     match p.deref() {
                         PointedType::SArr(a) => {
-                            if let Some(p) = a.pointer.primitive() {
+                            if let Some(p) = a.pointer.primitive().filter(|p| p.is_single()) {
                                 let vec = self
                                     .0
                                     .iter()
@@ -67,15 +70,34 @@ impl<'a> Debug for StackTracer<'a> {
                         }
                         PointedType::Ref(r) => {
                             // TODO: add cycle to the type of ref
-                            s.field("data", &usize::from_single(*data_0.unwrap()));
+                            s.field("data", &usize::from_single(data_0));
                             s.field("location", &r.points_to);
                             s.field("type", &format!("{}", r));
                         }
                         PointedType::Boxed(t) => {
-                            let ptr = usize::from_single(*data_0.unwrap()) as *const ();
+                            let ptr = usize::from_single(data_0) as *const ();
                             s.field("data", &ptr);
                             s.field("type", &format!("Box<{:?}>", t));
                         }
+                        PointedType::Cell(t) => {
+                            s.field("borrow", &usize::from_single(data_0));
+                            s.field("type", &format!("Cell<{:?}>", t));
+                        }
+                        PointedType::Rc(t) => {
+                            let ptr = usize::from_single(data_0) as *const ();
+                            s.field("data", &ptr);
+                            s.field("type", &format!("Rc<{:?}>", t));
+                        }
+                        PointedType::Weak(t) => {
+                            let ptr = usize::from_single(data_0) as *const ();
+                            s.field("data", &ptr);
+                            s.field("type", &format!("Weak<{:?}>", t));
+                        }
+                        PointedType::Any => {
+                            let ptr = usize::from_single(data_0) as *const ();
+                            s.field("data", &ptr);
+                            s.field("type", &"Any");
+                        }
                     }
                 }
             }
@@ -108,9 +130,8 @@ fn display_of_primitive(p: PrimitiveType, data: StackData) -> Box<dyn Debug> {
         PrimitiveType::F64 => Box::new(f64::from_single(data)),
         PrimitiveType::Bool => Box::new(bool::from_single(data)),
         PrimitiveType::Char => Box::new(char::from_single(data)),
-        PrimitiveType::SStr => todo!(),
-        PrimitiveType::StackFrame => unimplemented!(),
-        PrimitiveType::ReturnAddr => unimplemented!(),
+        PrimitiveType::SStr => Box::new("<SStr>"),
+        PrimitiveType::StackFrame | PrimitiveType::ReturnAddr => Box::new(usize::from_single(data)),
         PrimitiveType::Unit => Box::new("(unit)"),
     }
 }
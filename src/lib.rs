@@ -5,7 +5,7 @@
 use std::collections::HashMap;
 
 pub use code::Code;
-pub use pool::{Constant, ConstantPool};
+pub use pool::{Constant, ConstantKind, ConstantPool, ConstantPoolBuilder};
 pub use vm::Vm;
 use crate::types::VmType;
 
@@ -42,7 +42,7 @@ impl Module {
 
 pub struct Function {
     pub signature: Signature,
-    pub bytecode: Code,
+    pub bytecode: Code<'static>,
 }
 
 #[derive(PartialEq, Hash)]
@@ -50,3 +50,9 @@ pub struct Signature {
     params: Vec<VmType>,
     return_type: VmType,
 }
+
+impl Signature {
+    pub fn new(params: Vec<VmType>, return_type: VmType) -> Self {
+        Self { params, return_type }
+    }
+}
@@ -1,5 +1,5 @@
-use crate::types::{HasVmType, VmType};
 use crate::types::checker::{Tag, TypeChecker, TypeCheckerCtx};
+use crate::types::{TypeId, TypeTable};
 use crate::vm::lock::{DerefLock, ValueLock};
 use crate::vm::refs::LocatedRef;
 use crate::vm::StackDataRef;
@@ -16,62 +16,71 @@ impl VmMetaView<'_> {
             VmMetaView::Transient(t) => t.lock(),
         }
     }
-}
 
-impl HasVmType for VmMetaView<'_> {
-    fn vm_type(&self) -> &VmType {
+    pub fn type_id(&self) -> TypeId {
         match *self {
-            VmMetaView::Stack(s) => s.vm_type(),
-            VmMetaView::Transient(t) => t.vm_type(),
+            VmMetaView::Stack(s) => s.type_id(),
+            VmMetaView::Transient(t) => t.type_id(),
         }
     }
 }
 
 #[derive(Debug)]
 pub struct StackMeta {
-    pub value_type: VmType,
+    /// Type of the value, interned in the type table of the vm
+    pub type_id: TypeId,
     pub index: StackDataRef,
     pub cycle: usize,
     pub lock: ValueLock,
     pub was_moved: bool,
     pub deref: DerefLock,
+    /// Whether the value can be written to after it was pushed
+    pub mutable: bool,
     // TODO: other meta fields
 }
 
 impl StackMeta {
-    pub fn new(value_type: impl Into<VmType>, index: StackDataRef, cycle: usize) -> Self {
+    pub fn new(type_id: TypeId, index: StackDataRef, cycle: usize) -> Self {
         StackMeta {
-            value_type: value_type.into(),
+            type_id,
             index,
             cycle,
             lock: Default::default(),
             was_moved: false,
             deref: Default::default(),
+            mutable: true,
         }
     }
 }
 
-pub trait Meta: HasVmType {
+pub trait Meta {
     fn lock(&self) -> &ValueLock;
 
     fn lock_mut(&mut self) -> &mut ValueLock;
 
+    fn type_id(&self) -> TypeId;
+
     fn check_with<'a, 'c>(
-        &'a self,
+        &self,
+        types: &'a TypeTable,
         tag: impl Into<Tag>,
         ctx: &'c mut TypeCheckerCtx,
     ) -> TypeChecker<'a, &'c mut TypeCheckerCtx> {
         TypeChecker {
             tag: tag.into(),
-            vm_type: Some(self.vm_type()),
+            vm_type: Some(&types[self.type_id()]),
             ctx,
         }
     }
 
-    fn check(&self, tag: impl Into<Tag>) -> TypeChecker<TypeCheckerCtx> {
+    fn check<'a>(
+        &self,
+        types: &'a TypeTable,
+        tag: impl Into<Tag>,
+    ) -> TypeChecker<'a, TypeCheckerCtx> {
         TypeChecker {
             tag: tag.into(),
-            vm_type: Some(self.vm_type()),
+            vm_type: Some(&types[self.type_id()]),
             ctx: TypeCheckerCtx::new(),
         }
     }
@@ -85,16 +94,15 @@ impl Meta for StackMeta {
     fn lock_mut(&mut self) -> &mut ValueLock {
         &mut self.lock
     }
-}
-impl HasVmType for StackMeta {
-    fn vm_type(&self) -> &VmType {
-        &self.value_type
+
+    fn type_id(&self) -> TypeId {
+        self.type_id
     }
 }
 
 #[derive(Debug)]
 pub struct TransientMeta {
-    pub value_type: VmType,
+    pub type_id: TypeId,
     pub root_object: LocatedRef,
     pub lock: ValueLock,
     pub was_moved: bool,
@@ -108,10 +116,8 @@ impl Meta for TransientMeta {
     fn lock_mut(&mut self) -> &mut ValueLock {
         &mut self.lock
     }
-}
 
-impl HasVmType for TransientMeta {
-    fn vm_type(&self) -> &VmType {
-        &self.value_type
+    fn type_id(&self) -> TypeId {
+        self.type_id
     }
 }
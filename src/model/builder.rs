@@ -0,0 +1,555 @@
+//! Building model code without numbering stack refs and pool entries by hand
+//!
+//! [`CodeBuilder`] hands out [`Slot`]s for the values it pushes and turns them into stack
+//! refs, it interns the constants the code loads into the pool it builds along with the code.
+//! Scopes, derefs and forward labels are closed by guards when they go out of scope.
+//!
+//! The types of the values are followed while building, misuses are collected and
+//! returned by [`CodeBuilder::build`] along with the index of the opcode they were found at.
+
+use std::ops::{Deref, DerefMut};
+
+use super::{Comparison, Opcode};
+use crate::code::refs::{StackRef, ThreeStackRefs};
+use crate::error::BuildError;
+use crate::types::{PointedType, PrimitiveType, RefKind, RefLocation, VmType};
+use crate::{Code, Constant, ConstantPool, ConstantPoolBuilder};
+
+/// A value pushed by the builder, valid while it stays on the stack
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Slot {
+    index: usize,
+    id: usize,
+}
+
+/// A position in the code jumps can go to
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Label(usize);
+
+/// Arithmetic operations, the opcode is picked by the type of the operands
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl Arith {
+    fn opcode(self, t: PrimitiveType, refs: ThreeStackRefs) -> Option<Opcode> {
+        use Opcode::*;
+        let ops: [fn(ThreeStackRefs) -> Opcode; 5] = if t.is_unsigned() {
+            [UAdd, USub, UMul, UDiv, URem]
+        } else if t.is_signed() {
+            [IAdd, ISub, IMul, IDiv, IRem]
+        } else if t.is_float() {
+            [FAdd, FSub, FMul, FDiv, FRem]
+        } else {
+            return None;
+        };
+        Some(ops[self as usize](refs))
+    }
+}
+
+fn comparison(cmp: Comparison, refs: ThreeStackRefs) -> Opcode {
+    match cmp {
+        Comparison::Ge => Opcode::Ge(refs),
+        Comparison::Gt => Opcode::Gt(refs),
+        Comparison::Le => Opcode::Le(refs),
+        Comparison::Lt => Opcode::Lt(refs),
+        Comparison::Eq => Opcode::Eq(refs),
+        Comparison::Ne => Opcode::Ne(refs),
+    }
+}
+
+#[derive(Clone)]
+struct Entry {
+    value_type: VmType,
+    id: usize,
+    /// If the value was moved out of its slot
+    moved: bool,
+}
+
+/// Stack layout at some point of the code
+#[derive(Clone, Default)]
+struct Layout {
+    stack: Vec<Entry>,
+    /// Number of values on the stack when each of the open scopes was started
+    scopes: Vec<usize>,
+}
+
+impl Layout {
+    fn matches(&self, other: &Layout) -> bool {
+        self.scopes == other.scopes
+            && self.stack.len() == other.stack.len()
+            && self
+                .stack
+                .iter()
+                .zip(&other.stack)
+                .all(|(a, b)| a.value_type == b.value_type)
+    }
+}
+
+#[derive(Default)]
+struct LabelState {
+    placed: bool,
+    /// Layout every jump to the label and its placement must agree on
+    layout: Option<Layout>,
+}
+
+/// Builder of model code and of the constant pool it loads from
+#[derive(Default)]
+pub struct CodeBuilder {
+    ops: Vec<Opcode>,
+    layout: Layout,
+    /// If the current position can be reached, it can't after an unconditional jump
+    unreachable: bool,
+    next_id: usize,
+    labels: Vec<LabelState>,
+    pool: ConstantPoolBuilder,
+    errors: Vec<BuildError>,
+}
+
+impl CodeBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Type of the value in `slot`, `None` if it's no longer on the stack
+    pub fn type_of(&self, slot: Slot) -> Option<&VmType> {
+        let entry = self.layout.stack.get(slot.index)?;
+        Some(&entry.value_type).filter(|_| entry.id == slot.id)
+    }
+
+    fn report(&mut self, e: BuildError) {
+        self.errors.push(e);
+    }
+
+    fn emit(&mut self, op: Opcode) {
+        self.ops.push(op);
+    }
+
+    fn push(&mut self, value_type: VmType) -> Slot {
+        let slot = Slot {
+            index: self.layout.stack.len(),
+            id: self.next_id,
+        };
+        self.next_id += 1;
+        self.layout.stack.push(Entry {
+            value_type,
+            id: slot.id,
+            moved: false,
+        });
+        slot
+    }
+
+    /// Type of the value in `slot` for the opcode about to be emitted, errors are reported
+    fn operand(&mut self, slot: Slot) -> Option<VmType> {
+        let at = self.ops.len();
+        match self.layout.stack.get(slot.index) {
+            Some(entry) if entry.id == slot.id && entry.moved => {
+                self.report(BuildError::Moved(at));
+                None
+            }
+            Some(entry) if entry.id == slot.id => Some(entry.value_type.clone()),
+            _ => {
+                self.report(BuildError::StaleSlot(at));
+                None
+            }
+        }
+    }
+
+    /// Report an error unless `actual` is the `expected` type, unknown types are not checked
+    fn expect(&mut self, expected: Option<&VmType>, actual: Option<&VmType>) {
+        if let (Some(expected), Some(actual)) = (expected, actual) {
+            if expected != actual {
+                let e = BuildError::TypeMismatch(self.ops.len(), expected.clone(), actual.clone());
+                self.report(e);
+            }
+        }
+    }
+
+    /// Load `value` of type `t`, both are added to the pool
+    pub fn constant(&mut self, t: PrimitiveType, value: impl Into<Constant>) -> Slot {
+        let type_location = self.pool.primitive_type(t);
+        let value_location = self.pool.value(value);
+        self.emit(Opcode::LDType {
+            type_location,
+            value_location,
+        });
+        self.push(t.into())
+    }
+
+    pub fn u64(&mut self, value: u64) -> Slot {
+        self.constant(PrimitiveType::U64, value)
+    }
+
+    pub fn i64(&mut self, value: i64) -> Slot {
+        self.constant(PrimitiveType::I64, value)
+    }
+
+    pub fn f64(&mut self, value: f64) -> Slot {
+        self.constant(PrimitiveType::F64, value)
+    }
+
+    pub fn bool(&mut self, value: bool) -> Slot {
+        self.emit(if value {
+            Opcode::LdTrue
+        } else {
+            Opcode::LdFalse
+        });
+        self.push(PrimitiveType::Bool.into())
+    }
+
+    /// Load the empty value of `t`, see [`VmType::is_zeroable`]
+    pub fn zero(&mut self, t: impl Into<VmType>) -> Slot {
+        let t = t.into();
+        if !t.is_zeroable() {
+            self.report(BuildError::InvalidType(self.ops.len(), t.clone()));
+        }
+        let type_location = self.pool.vm_type(t.clone());
+        self.emit(Opcode::LdTyped0 { type_location });
+        self.push(t)
+    }
+
+    /// Create an array of `len` empty values of `element`
+    pub fn s_arr(&mut self, element: impl Into<VmType>, len: usize) -> Slot {
+        let element = element.into();
+        if !element.is_zeroable() {
+            self.report(BuildError::InvalidType(self.ops.len(), element.clone()));
+        }
+        let location = self.pool.vm_type(element.clone());
+        self.emit(Opcode::SArrCreate0(len, location));
+        self.push(PointedType::s_arr(element, len).into())
+    }
+
+    /// Load a static string
+    pub fn str(&mut self, value: &str) -> Slot {
+        let location = self.pool.string(value);
+        self.emit(Opcode::LdSS(location));
+        self.push(PrimitiveType::SStr.into())
+    }
+
+    /// Load an array of `values` as a static, statics come before any other value
+    pub fn static_array<T: Into<Constant>>(
+        &mut self,
+        element: impl Into<VmType>,
+        values: impl IntoIterator<Item = T>,
+    ) -> Slot {
+        let element = element.into();
+        let array = Constant::array(element.clone(), values);
+        let t = match array.array_type() {
+            Some(t) => t,
+            None => {
+                self.report(BuildError::InvalidType(self.ops.len(), element.clone()));
+                PointedType::s_arr(element, 0).into()
+            }
+        };
+        let location = self.pool.intern(array);
+        self.emit(Opcode::LdStatic(location));
+        self.push(t)
+    }
+
+    fn three(&mut self, result: Slot, op1: Slot, op2: Slot) -> [Option<VmType>; 3] {
+        [self.operand(result), self.operand(op1), self.operand(op2)]
+    }
+
+    fn three_refs(result: Slot, op1: Slot, op2: Slot) -> ThreeStackRefs {
+        ThreeStackRefs {
+            result: StackRef(result.index),
+            op1: StackRef(op1.index),
+            op2: StackRef(op2.index),
+        }
+    }
+
+    /// `result = op1 <op> op2`, all three have the same number type
+    pub fn arith(&mut self, op: Arith, result: Slot, op1: Slot, op2: Slot) -> &mut Self {
+        let [result_type, op1_type, op2_type] = self.three(result, op1, op2);
+        self.expect(op1_type.as_ref(), op2_type.as_ref());
+        self.expect(op1_type.as_ref(), result_type.as_ref());
+        let refs = Self::three_refs(result, op1, op2);
+        let opcode = op1_type.as_ref().and_then(VmType::primitive);
+        let opcode = opcode.and_then(|p| op.opcode(p, refs.clone()));
+        if let (None, Some(t)) = (&opcode, op1_type) {
+            self.report(BuildError::InvalidType(self.ops.len(), t));
+        }
+        // the code is not built with errors, the opcode only keeps the indices of the others
+        let opcode = opcode.unwrap_or(Opcode::UAdd(refs));
+        self.emit(opcode);
+        self
+    }
+
+    pub fn add(&mut self, result: Slot, op1: Slot, op2: Slot) -> &mut Self {
+        self.arith(Arith::Add, result, op1, op2)
+    }
+
+    pub fn sub(&mut self, result: Slot, op1: Slot, op2: Slot) -> &mut Self {
+        self.arith(Arith::Sub, result, op1, op2)
+    }
+
+    pub fn mul(&mut self, result: Slot, op1: Slot, op2: Slot) -> &mut Self {
+        self.arith(Arith::Mul, result, op1, op2)
+    }
+
+    /// `result = op1 <cmp> op2`, the operands are numbers of the same type and `result` a bool
+    pub fn compare(&mut self, cmp: Comparison, result: Slot, op1: Slot, op2: Slot) -> &mut Self {
+        let [result_type, op1_type, op2_type] = self.three(result, op1, op2);
+        self.expect(op1_type.as_ref(), op2_type.as_ref());
+        self.expect(Some(&PrimitiveType::Bool.into()), result_type.as_ref());
+        if let Some(t) = op1_type {
+            if !matches!(t.primitive(), Some(p) if p.is_number()) {
+                self.report(BuildError::InvalidType(self.ops.len(), t));
+            }
+        }
+        self.emit(comparison(cmp, Self::three_refs(result, op1, op2)));
+        self
+    }
+
+    /// Move the value of `src` into `dest`, both have the same type
+    pub fn mv(&mut self, dest: Slot, src: Slot) -> &mut Self {
+        let dest_type = self.operand(dest);
+        let src_type = self.operand(src);
+        self.expect(dest_type.as_ref(), src_type.as_ref());
+        self.emit(Opcode::Mv(StackRef(dest.index), StackRef(src.index)));
+        self
+    }
+
+    /// Exchange the values of two slots of the same type
+    pub fn swap(&mut self, a: Slot, b: Slot) -> &mut Self {
+        let a_type = self.operand(a);
+        let b_type = self.operand(b);
+        self.expect(a_type.as_ref(), b_type.as_ref());
+        self.emit(Opcode::Swap(StackRef(a.index), StackRef(b.index)));
+        self
+    }
+
+    pub fn trace(&mut self, slot: Slot) -> &mut Self {
+        self.operand(slot);
+        self.emit(Opcode::TraceStackValue(StackRef(slot.index)));
+        self
+    }
+
+    fn take(&mut self, slot: Slot, kind: RefKind) -> Slot {
+        let pointer = self.operand(slot);
+        let r = StackRef(slot.index);
+        self.emit(match kind {
+            RefKind::Ref => Opcode::TakeRef(r),
+            RefKind::Mut => Opcode::TakeMut(r),
+        });
+        let pointer = pointer.unwrap_or_else(|| PrimitiveType::Never.into());
+        self.push(PointedType::reference(pointer, kind, RefLocation::Stack).into())
+    }
+
+    /// Take a shared reference to `slot`
+    pub fn take_ref(&mut self, slot: Slot) -> Slot {
+        self.take(slot, RefKind::Ref)
+    }
+
+    /// Take a mutable reference to `slot`
+    pub fn take_mut(&mut self, slot: Slot) -> Slot {
+        self.take(slot, RefKind::Mut)
+    }
+
+    /// Start a scope, the values pushed in it are dropped with the guard
+    pub fn scope(&mut self) -> ScopeGuard<'_> {
+        self.emit(Opcode::StartScope);
+        self.layout.scopes.push(self.layout.stack.len());
+        ScopeGuard { builder: self }
+    }
+
+    fn end_scope(&mut self) {
+        self.emit(Opcode::EndScope);
+        if let Some(height) = self.layout.scopes.pop() {
+            self.layout.stack.truncate(height);
+        }
+    }
+
+    /// Push a view of the value `reference` points to, it's written back when the guard drops
+    pub fn start_deref(&mut self, reference: Slot) -> DerefGuard<'_> {
+        let t = self.operand(reference);
+        let pointer = match t.as_ref().map(|t| (t, t.ref_type())) {
+            Some((_, Some(r))) => r.pointer.clone(),
+            Some((t, None)) => {
+                self.report(BuildError::InvalidType(self.ops.len(), t.clone()));
+                PrimitiveType::Never.into()
+            }
+            None => PrimitiveType::Never.into(),
+        };
+        self.emit(Opcode::StartDeref(StackRef(reference.index)));
+        let value = self.push(pointer);
+        DerefGuard {
+            builder: self,
+            value,
+        }
+    }
+
+    fn end_deref(&mut self, view: Slot) {
+        self.emit(Opcode::EndDeref);
+        if let Some(entry) = self.layout.stack.get_mut(view.index) {
+            entry.moved = true;
+        }
+    }
+
+    /// A new label, to be placed with [`place`](Self::place)
+    pub fn label(&mut self) -> Label {
+        self.labels.push(LabelState::default());
+        Label(self.labels.len() - 1)
+    }
+
+    /// A new label placed at the current position, for jumps back to it
+    pub fn here(&mut self) -> Label {
+        let label = self.label();
+        self.place(label);
+        label
+    }
+
+    /// A new label placed where the guard drops, for jumps forward to it
+    pub fn forward(&mut self) -> LabelGuard<'_> {
+        let label = self.label();
+        LabelGuard {
+            builder: self,
+            label,
+        }
+    }
+
+    /// Agree on the layout of the stack at `label` with the current one
+    fn join(&mut self, label: Label) {
+        let at = self.ops.len();
+        let state = &mut self.labels[label.0];
+        match &state.layout {
+            None => state.layout = Some(self.layout.clone()),
+            Some(layout) if !layout.matches(&self.layout) => {
+                self.report(BuildError::StackMismatch(at, label.0))
+            }
+            Some(_) => {}
+        }
+    }
+
+    pub fn place(&mut self, label: Label) -> &mut Self {
+        if self.labels[label.0].placed {
+            self.report(BuildError::DuplicateLabel(label.0));
+            return self;
+        }
+        if self.unreachable {
+            if let Some(layout) = &self.labels[label.0].layout {
+                self.layout = layout.clone();
+            }
+            self.unreachable = false;
+        }
+        self.join(label);
+        self.labels[label.0].placed = true;
+        self.emit(Opcode::Label(label.0));
+        self
+    }
+
+    pub fn jump(&mut self, label: Label) -> &mut Self {
+        self.join(label);
+        self.emit(Opcode::J { label: label.0 });
+        self.unreachable = true;
+        self
+    }
+
+    /// Jump to `label` if the bool `cond` is true
+    pub fn jump_if(&mut self, cond: Slot, label: Label) -> &mut Self {
+        let cond_type = self.operand(cond);
+        self.expect(Some(&PrimitiveType::Bool.into()), cond_type.as_ref());
+        self.join(label);
+        self.emit(Opcode::JC {
+            label: label.0,
+            cond: StackRef(cond.index),
+        });
+        self
+    }
+
+    /// The model code and its pool, or every misuse found while building
+    pub fn into_model(mut self) -> Result<(Vec<Opcode>, ConstantPool), Vec<BuildError>> {
+        for (label, state) in self.labels.iter().enumerate() {
+            if !state.placed && state.layout.is_some() {
+                self.errors.push(BuildError::UnplacedLabel(label));
+            }
+        }
+        if self.errors.is_empty() {
+            Ok((self.ops, self.pool.build()))
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    /// The code and its pool, or every misuse found while building
+    pub fn build(self) -> Result<(Code<'static>, ConstantPool), Vec<BuildError>> {
+        let (ops, pool) = self.into_model()?;
+        let code = Code::from_model(&ops).ok_or_else(|| vec![BuildError::Encoding])?;
+        Ok((code, pool))
+    }
+}
+
+/// An open scope, ended when dropped
+pub struct ScopeGuard<'a> {
+    builder: &'a mut CodeBuilder,
+}
+
+impl Drop for ScopeGuard<'_> {
+    fn drop(&mut self) {
+        self.builder.end_scope();
+    }
+}
+
+/// A label placed when dropped
+pub struct LabelGuard<'a> {
+    builder: &'a mut CodeBuilder,
+    label: Label,
+}
+
+impl LabelGuard<'_> {
+    pub fn label(&self) -> Label {
+        self.label
+    }
+}
+
+impl Drop for LabelGuard<'_> {
+    fn drop(&mut self) {
+        self.builder.place(self.label);
+    }
+}
+
+/// A value viewed through a reference, written back when dropped
+pub struct DerefGuard<'a> {
+    builder: &'a mut CodeBuilder,
+    value: Slot,
+}
+
+impl DerefGuard<'_> {
+    /// Slot of the view, it's moved once the guard drops
+    pub fn value(&self) -> Slot {
+        self.value
+    }
+}
+
+impl Drop for DerefGuard<'_> {
+    fn drop(&mut self) {
+        self.builder.end_deref(self.value);
+    }
+}
+
+macro_rules! impl_builder_deref {
+    ( $($guard: ident),* ) => {
+        $(
+            impl Deref for $guard<'_> {
+                type Target = CodeBuilder;
+
+                fn deref(&self) -> &CodeBuilder {
+                    self.builder
+                }
+            }
+
+            impl DerefMut for $guard<'_> {
+                fn deref_mut(&mut self) -> &mut CodeBuilder {
+                    self.builder
+                }
+            }
+        )*
+    };
+}
+
+impl_builder_deref!(ScopeGuard, LabelGuard, DerefGuard);
@@ -1,17 +1,21 @@
 //! This module contains types that represent VM as high level object model.
 
 use std::collections::HashMap;
-use std::convert::TryInto;
 use std::iter::FromIterator;
-use std::mem::size_of;
 
 use serde::{Deserialize, Serialize};
-use smallvec::SmallVec;
+use smallvec::{smallvec, SmallVec};
 
 use Opcode::*;
 
 use crate::code::refs::*;
 use crate::opcodes::Opcode as Nc;
+use crate::vm::ExtensionTable;
+
+pub mod builder;
+pub mod optimizer;
+pub mod peephole;
+pub mod ssa;
 
 /// Vm opcode represented as Rust enum (size constraints be dammed)
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -22,7 +26,9 @@ pub enum Opcode {
     /// Load 0 i64
     Ld0I64,
 
-    /// Load primitive types **empty** value from the constant pool
+    /// Load the **empty** value of a type from the constant pool, see [`VmType::is_zeroable`]
+    ///
+    /// [`VmType::is_zeroable`]: crate::types::VmType::is_zeroable
     LdTyped0 {
         type_location: PoolRef,
     },
@@ -32,6 +38,11 @@ pub enum Opcode {
         type_location: PoolRef,
         value_location: PoolRef,
     },
+    /// Load an immutable value of type from constant pool
+    LdConst {
+        type_location: PoolRef,
+        value_location: PoolRef,
+    },
     /// LD Unit
     LdUnit,
     // LD True
@@ -40,6 +51,12 @@ pub enum Opcode {
 
     /// Load static string from constant pool
     LdSS(PoolRef),
+    /// Load an array of the constant pool as a static, see [`Vm::push_static`]
+    ///
+    /// [`Vm::push_static`]: crate::Vm::push_static
+    LdStatic(PoolRef),
+    /// Load a value stored in the bytecode
+    LdImm(Immediate),
     UAdd(ThreeStackRefs),
     USub(ThreeStackRefs),
     UMul(ThreeStackRefs),
@@ -68,7 +85,7 @@ pub enum Opcode {
 
     LAnd(ThreeStackRefs),
     LOr(ThreeStackRefs),
-    LNot(ThreeStackRefs),
+    LNot(TwoStackRefs),
     LXor(ThreeStackRefs),
 
     Shl(ThreeStackRefs),
@@ -81,6 +98,15 @@ pub enum Opcode {
     Lt(ThreeStackRefs),
     Eq(ThreeStackRefs),
     Ne(ThreeStackRefs),
+    /// Add an immediate of the type of `op`, checked for integers
+    AddImm(TwoStackRefs, Immediate),
+    SubImm(TwoStackRefs, Immediate),
+    /// Compare `refs.op` with an immediate of its type into `refs.result`
+    CmpImm {
+        cmp: Comparison,
+        refs: TwoStackRefs,
+        imm: Immediate,
+    },
     J {
         label: usize,
     },
@@ -88,11 +114,12 @@ pub enum Opcode {
         label: usize,
         cond: StackRef,
     },
+    /// Jump by `offset` bytes, relative to the start of the jump
     JOffset {
-        offset: usize,
+        offset: isize,
     },
     JCOffset {
-        offset: usize,
+        offset: isize,
         cond: StackRef,
     },
     Label(usize),
@@ -102,9 +129,31 @@ pub enum Opcode {
     TakeRef(StackRef),
     TakeMut(StackRef),
     StartDeref(StackRef),
+    /// Dereference `depth` levels of references at once
+    StartDerefN {
+        rf: StackRef,
+        depth: usize,
+    },
     EndDeref,
+    /// Reborrow a reference as `&`
+    Reborrow(StackRef),
+    /// Reborrow a `&mut` reference as `&mut`
+    ReborrowMut(StackRef),
     Mv(StackRef, StackRef),
     Mp(StackRef),
+    /// End the life of a value before its scope ends
+    Drop(StackRef),
+    /// Push a copy of a value, a `&` reference is reborrowed
+    Clone(StackRef),
+    Swap(StackRef, StackRef),
+    /// Write `value` through a `&mut` reference, `value` receives the old value
+    Replace {
+        dest: StackRef,
+        value: StackRef,
+    },
+    /// Make a value immutable for the rest of its life
+    Freeze(StackRef),
+    /// Create an array of the length of empty values of the pool type
     SArrCreate0(usize, PoolRef),
     SArrGet {
         arr_ref: StackRef,
@@ -114,7 +163,304 @@ pub enum Opcode {
         arr_mut: StackRef,
         index: StackRef,
     },
+    /// Move a value into a new cell
+    CellNew(StackRef),
+    /// Borrow the inside of a cell through a reference to it, the cell must be on the stack
+    CellBorrow(StackRef),
+    CellBorrowMut(StackRef),
+    /// Move a value into a new reference counted heap block
+    RcNew(StackRef),
+    /// Take a `&` reference to the value owned by an `Rc`
+    RcDeref(StackRef),
+    RcDowngrade(StackRef),
+    WeakUpgrade(StackRef),
+    /// Push `true` if the value a `Weak` points to is not released yet
+    WeakIsAlive(StackRef),
+    /// Move a value into an `Any` that remembers its type
+    AnyWrap(StackRef),
+    /// Push `true` if the value inside `any` is of type `type_location`
+    AnyIs {
+        any: StackRef,
+        type_location: PoolRef,
+    },
+    /// Move the value out of `any`, fails if it is not of type `type_location`
+    AnyDowncast {
+        any: StackRef,
+        type_location: PoolRef,
+    },
     TraceStackValue(StackRef),
+    /// Compare `refs.op1` and `refs.op2` into `refs.result`, then jump to `label` if it holds
+    JCmp {
+        cmp: Comparison,
+        label: usize,
+        refs: ThreeStackRefs,
+    },
+    /// Add `step` to `counter`, then compare `counter` and `limit` into `result`
+    UAddCmp {
+        cmp: Comparison,
+        counter: StackRef,
+        step: StackRef,
+        result: StackRef,
+        limit: StackRef,
+    },
+    /// Load a value from the constant pool, then run `op`, a binary operation
+    LdOp {
+        type_location: PoolRef,
+        value_location: PoolRef,
+        op: Box<Opcode>,
+    },
+    /// [`Opcode::LdOp`] in a scope of its own, the loaded value is dropped after `op`
+    ScopedLdOp {
+        type_location: PoolRef,
+        value_location: PoolRef,
+        op: Box<Opcode>,
+    },
+    /// Extension opcode `code`, its operands in the order it declares them
+    Extension {
+        code: u8,
+        operands: Vec<CodeRef>,
+    },
+}
+
+/// Comparisons that can be fused with the opcode before or after them
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Ge,
+    Gt,
+    Le,
+    Lt,
+    Eq,
+    Ne,
+}
+
+impl Comparison {
+    /// The comparison done by `op`, along with its operands
+    pub fn of(op: &Opcode) -> Option<(Self, &ThreeStackRefs)> {
+        match op {
+            Ge(v) => Some((Comparison::Ge, v)),
+            Gt(v) => Some((Comparison::Gt, v)),
+            Le(v) => Some((Comparison::Le, v)),
+            Lt(v) => Some((Comparison::Lt, v)),
+            Eq(v) => Some((Comparison::Eq, v)),
+            Ne(v) => Some((Comparison::Ne, v)),
+            _ => None,
+        }
+    }
+
+    fn jump_code(self) -> Nc {
+        match self {
+            Comparison::Ge => Nc::JGe,
+            Comparison::Gt => Nc::JGt,
+            Comparison::Le => Nc::JLe,
+            Comparison::Lt => Nc::JLt,
+            Comparison::Eq => Nc::JEq,
+            Comparison::Ne => Nc::JNe,
+        }
+    }
+
+    fn imm_code(self) -> Nc {
+        match self {
+            Comparison::Ge => Nc::GeImm,
+            Comparison::Gt => Nc::GtImm,
+            Comparison::Le => Nc::LeImm,
+            Comparison::Lt => Nc::LtImm,
+            Comparison::Eq => Nc::EqImm,
+            Comparison::Ne => Nc::NeImm,
+        }
+    }
+
+    fn u_add_code(self) -> Nc {
+        match self {
+            Comparison::Ge => Nc::UAddGe,
+            Comparison::Gt => Nc::UAddGt,
+            Comparison::Le => Nc::UAddLe,
+            Comparison::Lt => Nc::UAddLt,
+            Comparison::Eq => Nc::UAddEq,
+            Comparison::Ne => Nc::UAddNe,
+        }
+    }
+}
+
+/// An operand of a model opcode, jumps are still to labels
+enum Operand<S> {
+    Stack(S),
+    Label(usize),
+    Other(CodeRef),
+}
+
+impl<S> Operand<S> {
+    fn stack(self) -> Option<S> {
+        match self {
+            Operand::Stack(r) => Some(r),
+            _ => None,
+        }
+    }
+}
+
+type ModelOperands<S> = SmallVec<[Operand<S>; 6]>;
+
+/// The opcode `$op` is encoded as and its operands
+///
+/// Written once for [`Opcode::operands`] and [`Opcode::operands_mut`], which only differ
+/// in how they borrow `$op`. `$bi_op` is the code of `$op` if it is a binary operation,
+/// `$recurse` the method giving the operands of the operation of a fused load.
+macro_rules! model_operands {
+    ($op: expr, $bi_op: expr, $recurse: ident $(, $m: tt)?) => {{
+        use Operand::{Label as L, Other as O, Stack as S};
+        let operands: (Nc, ModelOperands<_>) = match $op {
+            Ld0U64 => (Nc::U64Ld0, smallvec![]),
+            Ld0I64 => (Nc::I64Ld0, smallvec![]),
+            LdTyped0 { type_location } => (Nc::LdTyped0, smallvec![O((*type_location).into())]),
+            LDType {
+                type_location,
+                value_location,
+            } => (
+                Nc::LdType,
+                smallvec![O((*type_location).into()), O((*value_location).into())],
+            ),
+            LdConst {
+                type_location,
+                value_location,
+            } => (
+                Nc::LdConst,
+                smallvec![O((*type_location).into()), O((*value_location).into())],
+            ),
+            LdSS(p) => (Nc::LdSS, smallvec![O((*p).into())]),
+            LdStatic(p) => (Nc::LdStatic, smallvec![O((*p).into())]),
+            LdUnit => (Nc::LdUnit, smallvec![]),
+            LdImm(imm) => (Nc::LdImm, smallvec![O((*imm).into())]),
+            LdTrue => (Nc::LdTrue, smallvec![]),
+            LdFalse => (Nc::LdFalse, smallvec![]),
+            UAdd(v) | USub(v) | UMul(v) | UDiv(v) | URem(v) | IAdd(v) | ISub(v) | IMul(v)
+            | IDiv(v) | IRem(v) | FAdd(v) | FSub(v) | FMul(v) | FDiv(v) | FRem(v) | BAnd(v)
+            | BOr(v) | BXor(v) | LAnd(v) | LOr(v) | LXor(v) | Shl(v) | Shr(v) | RotL(v)
+            | RotR(v) | Ge(v) | Gt(v) | Le(v) | Lt(v) | Eq(v) | Ne(v) => {
+                ($bi_op?, model_operands!(@three [$($m)?] v))
+            }
+            INeg(v) => (Nc::INeg, model_operands!(@two [$($m)?] v)),
+            FNeg(v) => (Nc::FNeg, model_operands!(@two [$($m)?] v)),
+            BNot(v) => (Nc::BNot, model_operands!(@two [$($m)?] v)),
+            BBe(v) => (Nc::BBe, model_operands!(@two [$($m)?] v)),
+            LNot(v) => (Nc::LNot, model_operands!(@two [$($m)?] v)),
+            AddImm(v, imm) => (Nc::AddImm, model_operands!(@imm [$($m)?] v, imm)),
+            SubImm(v, imm) => (Nc::SubImm, model_operands!(@imm [$($m)?] v, imm)),
+            CmpImm { cmp, refs, imm } => {
+                (cmp.imm_code(), model_operands!(@imm [$($m)?] refs, imm))
+            }
+            J { label } => (Nc::J, smallvec![L(*label)]),
+            JC { label, cond } => (Nc::JC, smallvec![L(*label), S(cond)]),
+            JOffset { offset } => (Nc::J, smallvec![O(CodeRef::Jump(*offset))]),
+            JCOffset { offset, cond } => (Nc::JC, smallvec![O(CodeRef::Jump(*offset)), S(cond)]),
+            Label(_) | Scope(_) | Extension { .. } => return None,
+            StartScope => (Nc::StartScope, smallvec![]),
+            EndScope => (Nc::EndScope, smallvec![]),
+            TakeRef(r) => (Nc::TakeRef, smallvec![S(r)]),
+            TakeMut(r) => (Nc::TakeMut, smallvec![S(r)]),
+            TraceStackValue(v) => (Nc::TraceStackValue, smallvec![S(v)]),
+            StartDeref(r) => (Nc::StartDeref, smallvec![S(r)]),
+            StartDerefN { rf, depth } => {
+                (Nc::StartDerefN, smallvec![S(rf), O(CodeRef::Value(*depth))])
+            }
+            EndDeref => (Nc::EndDeref, smallvec![]),
+            Reborrow(r) => (Nc::Reborrow, smallvec![S(r)]),
+            ReborrowMut(r) => (Nc::ReborrowMut, smallvec![S(r)]),
+            Mv(r, o) => (Nc::Mv, smallvec![S(r), S(o)]),
+            Mp(o) => (Nc::Mp, smallvec![S(o)]),
+            Drop(o) => (Nc::Drop, smallvec![S(o)]),
+            Clone(o) => (Nc::Clone, smallvec![S(o)]),
+            Swap(a, b) => (Nc::Swap, smallvec![S(a), S(b)]),
+            Replace { dest, value } => (Nc::Replace, smallvec![S(dest), S(value)]),
+            Freeze(o) => (Nc::Freeze, smallvec![S(o)]),
+            SArrCreate0(len, r) => (
+                Nc::SArrCreate0,
+                smallvec![O(CodeRef::Offset(*len)), O((*r).into())],
+            ),
+            SArrGet { arr_ref, index } => (Nc::SArrRef, smallvec![S(arr_ref), S(index)]),
+            SArrMut { arr_mut, index } => (Nc::SArrMut, smallvec![S(arr_mut), S(index)]),
+            CellNew(o) => (Nc::CellNew, smallvec![S(o)]),
+            CellBorrow(r) => (Nc::CellBorrow, smallvec![S(r)]),
+            CellBorrowMut(r) => (Nc::CellBorrowMut, smallvec![S(r)]),
+            RcNew(o) => (Nc::RcNew, smallvec![S(o)]),
+            RcDeref(o) => (Nc::RcDeref, smallvec![S(o)]),
+            RcDowngrade(o) => (Nc::RcDowngrade, smallvec![S(o)]),
+            WeakUpgrade(o) => (Nc::WeakUpgrade, smallvec![S(o)]),
+            WeakIsAlive(o) => (Nc::WeakIsAlive, smallvec![S(o)]),
+            AnyWrap(o) => (Nc::AnyWrap, smallvec![S(o)]),
+            AnyIs { any, type_location } => {
+                (Nc::AnyIs, smallvec![S(any), O((*type_location).into())])
+            }
+            AnyDowncast { any, type_location } => {
+                (Nc::AnyDowncast, smallvec![S(any), O((*type_location).into())])
+            }
+            JCmp { cmp, label, refs } => {
+                let mut operands = model_operands!(@three [$($m)?] refs);
+                operands.insert(0, L(*label));
+                (cmp.jump_code(), operands)
+            }
+            UAddCmp {
+                cmp,
+                counter,
+                step,
+                result,
+                limit,
+            } => (
+                cmp.u_add_code(),
+                smallvec![S(counter), S(step), S(result), S(limit)],
+            ),
+            LdOp {
+                type_location,
+                value_location,
+                op,
+            } => {
+                let (code, refs) = op.$recurse()?;
+                if !code.is_bi_op() {
+                    return None;
+                }
+                let mut operands: ModelOperands<_> = smallvec![
+                    O(CodeRef::Value(code as u16 as usize)),
+                    O((*type_location).into()),
+                    O((*value_location).into())
+                ];
+                operands.extend(refs);
+                (Nc::LdOp, operands)
+            }
+            ScopedLdOp {
+                type_location,
+                value_location,
+                op,
+            } => {
+                let (code, refs) = op.$recurse()?;
+                if !code.is_bi_op() {
+                    return None;
+                }
+                let mut operands: ModelOperands<_> = smallvec![
+                    O(CodeRef::Value(code as u16 as usize)),
+                    O((*type_location).into()),
+                    O((*value_location).into())
+                ];
+                operands.extend(refs);
+                (Nc::ScopedLdOp, operands)
+            }
+        };
+        Some(operands)
+    }};
+    (@two [$($m: tt)?] $v: ident) => {
+        smallvec![Operand::Stack(&$($m)? $v.result), Operand::Stack(&$($m)? $v.op)]
+    };
+    (@three [$($m: tt)?] $v: ident) => {
+        smallvec![
+            Operand::Stack(&$($m)? $v.result),
+            Operand::Stack(&$($m)? $v.op1),
+            Operand::Stack(&$($m)? $v.op2)
+        ]
+    };
+    (@imm [$($m: tt)?] $v: ident, $imm: ident) => {
+        smallvec![
+            Operand::Stack(&$($m)? $v.result),
+            Operand::Stack(&$($m)? $v.op),
+            Operand::Other((*$imm).into())
+        ]
+    };
 }
 
 type OpcodeBytes = SmallVec<[u8; 32]>;
@@ -122,8 +468,11 @@ type OpcodeBytes = SmallVec<[u8; 32]>;
 #[derive(Default)]
 pub struct ToBytesCtx {
     label_table: HashMap<usize, usize>,
-    jump_patch_table: Vec<usize>,
+    /// Positions of jumps to labels that were not defined yet, with their labels
+    jump_patch_table: Vec<(usize, usize)>,
     bytes: Vec<u8>,
+    /// Bytes of the enclosing scopes that are not in `bytes` yet
+    pending: usize,
 }
 
 impl ToBytesCtx {
@@ -132,6 +481,7 @@ impl ToBytesCtx {
             label_table: Default::default(),
             jump_patch_table: Default::default(),
             bytes: Vec::new(),
+            pending: 0,
         }
     }
 
@@ -141,6 +491,7 @@ impl ToBytesCtx {
             label_table: Default::default(),
             jump_patch_table: Default::default(),
             bytes: Vec::with_capacity(capacity + ops.len()),
+            pending: 0,
         }
     }
 
@@ -149,251 +500,211 @@ impl ToBytesCtx {
             let extend = op.to_bytes(&mut self)?;
             self.bytes.extend(extend);
         }
-        for to_patch in self.jump_patch_table {
-            const S: usize = size_of::<usize>();
-            let from = 1 + to_patch;
-            let until = from + S;
-            let value: [u8; S] = self.bytes[from..until].try_into().ok()?;
-            let key = usize::from_le_bytes(value);
-            let value = self.label_table.get(&key)?.to_le_bytes();
-            let bytes = value.iter().copied();
-
-            self.bytes.splice(from..until, bytes);
+        for (position, label) in self.jump_patch_table {
+            let target = *self.label_table.get(&label)?;
+            let value = encode_jump(target as isize - position as isize)?;
+            let from = 1 + position;
+
+            self.bytes[from..from + OFFSET_SIZE].copy_from_slice(&value);
         }
         Some(self.bytes)
     }
+
+    /// Position of the next opcode in the final bytecode
+    fn position(&self) -> usize {
+        self.bytes.len() + self.pending
+    }
+
+    /// Relative offset to `label` for a jump at the current position
+    ///
+    /// Jumps to labels that are not defined yet get patched at the end of the conversion
+    fn jump_offset(&mut self, label: usize) -> isize {
+        let position = self.position();
+        match self.label_table.get(&label) {
+            Some(&target) => target as isize - position as isize,
+            None => {
+                self.jump_patch_table.push((position, label));
+                0
+            }
+        }
+    }
 }
 
 impl Opcode {
     pub fn to_bytes(&self, ctx: &mut ToBytesCtx) -> Option<OpcodeBytes> {
         let b = match self {
-            Ld0U64 => single(Nc::U64Ld0),
-            Ld0I64 => single(Nc::I64Ld0),
-            LdTyped0 { type_location } => with_one_ref(Nc::LdTyped0, type_location.0),
-            LDType {
-                type_location,
-                value_location,
-            } => with_refs(Nc::LdType, &[type_location.0, value_location.0]),
-            LdSS(p) => with_one_ref(Nc::LdSS, p.0),
-            LdUnit => single(Nc::LdUnit),
-            UAdd(v) => with_three_stack_refs(Nc::UAdd, v),
-            USub(v) => with_three_stack_refs(Nc::USub, v),
-            UMul(v) => with_three_stack_refs(Nc::UMul, v),
-            UDiv(v) => with_three_stack_refs(Nc::UDiv, v),
-            URem(v) => with_three_stack_refs(Nc::URem, v),
-            IAdd(v) => with_three_stack_refs(Nc::IAdd, v),
-            ISub(v) => with_three_stack_refs(Nc::ISub, v),
-            IMul(v) => with_three_stack_refs(Nc::IMul, v),
-            IDiv(v) => with_three_stack_refs(Nc::IDiv, v),
-            IRem(v) => with_three_stack_refs(Nc::IRem, v),
-            INeg(v) => with_two_stack_refs(Nc::INeg, v),
-            FAdd(v) => with_three_stack_refs(Nc::FAdd, v),
-            FSub(v) => with_three_stack_refs(Nc::FSub, v),
-            FMul(v) => with_three_stack_refs(Nc::FMul, v),
-            FDiv(v) => with_three_stack_refs(Nc::FDiv, v),
-            FRem(v) => with_three_stack_refs(Nc::FRem, v),
-            FNeg(v) => with_two_stack_refs(Nc::FNeg, v),
-
-            LdTrue => single(Nc::LdTrue),
-            LdFalse => single(Nc::LdFalse),
-            BAnd(v) => with_three_stack_refs(Nc::BAnd, v),
-            BOr(v) => with_three_stack_refs(Nc::BOr, v),
-            BNot(v) => with_two_stack_refs(Nc::BNot, v),
-            BBe(v) => with_two_stack_refs(Nc::BBe, v),
-            BXor(v) => with_three_stack_refs(Nc::BXor, v),
-            LAnd(v) => with_three_stack_refs(Nc::LAnd, v),
-            LOr(v) => with_three_stack_refs(Nc::LOr, v),
-            LNot(v) => with_three_stack_refs(Nc::LNot, v),
-            LXor(v) => with_three_stack_refs(Nc::LXor, v),
-            Shl(v) => with_three_stack_refs(Nc::Shl, v),
-            Shr(v) => with_three_stack_refs(Nc::Shr, v),
-            RotL(v) => with_three_stack_refs(Nc::RotL, v),
-            RotR(v) => with_three_stack_refs(Nc::RotR, v),
-
-            Ge(v) => with_three_stack_refs(Nc::Ge, v),
-            Gt(v) => with_three_stack_refs(Nc::Gt, v),
-            Le(v) => with_three_stack_refs(Nc::Le, v),
-            Lt(v) => with_three_stack_refs(Nc::Lt, v),
-            Eq(v) => with_three_stack_refs(Nc::Eq, v),
-            Ne(v) => with_three_stack_refs(Nc::Ne, v),
-            J { label } => {
-                let offset = ctx.label_table.get(label);
-                if let Some(offset) = offset {
-                    with_offset(Nc::J, *offset)
-                } else {
-                    let len = ctx.bytes.len();
-                    ctx.jump_patch_table.push(len);
-                    with_offset(Nc::J, *label)
-                }
-            }
-            JC { label, cond } => {
-                let offset = ctx.label_table.get(label);
-                if let Some(offset) = offset {
-                    with_offset_and_ref(Nc::JC, *offset, cond.0)
-                } else {
-                    let len = ctx.bytes.len();
-                    ctx.jump_patch_table.push(len);
-                    with_offset_and_ref(Nc::JC, *label, cond.0)
-                }
-            }
-            JOffset { offset } => with_offset(Nc::J, *offset),
-            JCOffset { offset, cond } => with_offset_and_ref(Nc::JC, *offset, cond.0),
             Label(l) => {
-                let len = ctx.bytes.len();
-                ctx.label_table.insert(*l, len);
+                let position = ctx.position();
+                ctx.label_table.insert(*l, position);
                 OpcodeBytes::new()
             }
-            StartScope => single(Nc::StartScope),
-            EndScope => single(Nc::EndScope),
-            TakeRef(r) => with_one_ref(Nc::TakeRef, r.0),
-            TakeMut(r) => with_one_ref(Nc::TakeMut, r.0),
-            TraceStackValue(v) => with_one_ref(Nc::TraceStackValue, v.0),
             Scope(opcodes) => {
                 let mut result = SmallVec::new();
-                result.extend_from_slice(&single(Nc::StartScope));
+                result.extend_from_slice(&Nc::StartScope.bytes());
 
                 for op in opcodes {
-                    result.extend_from_slice(&op.to_bytes(ctx)?);
+                    ctx.pending += result.len();
+                    let bytes = op.to_bytes(ctx);
+                    ctx.pending -= result.len();
+                    result.extend_from_slice(&bytes?);
                 }
-                result.extend_from_slice(&single(Nc::EndScope));
+                result.extend_from_slice(&Nc::EndScope.bytes());
                 result
             }
-            StartDeref(r) => with_one_ref(Nc::StartDeref, r.0),
-            EndDeref => single(Nc::EndDeref),
-            Mv(r, o) => with_two_stack_refs(Nc::Mv, &TwoStackRefs { result: *r, op: *o }),
-            Mp(o) => with_one_ref(Nc::Mp, o.0),
-            SArrCreate0(len, r) => with_offset_and_ref(Nc::SArrCreate0, *len, r.0),
-            SArrGet { arr_ref, index } => with_two_refs(Nc::SArrRef, arr_ref.0, index.0),
-            SArrMut { arr_mut, index } => with_two_refs(Nc::SArrMut, arr_mut.0, index.0),
+            Extension { code, operands } => {
+                let mut result = OpcodeBytes::from_slice(&ExtensionTable::opcode_bytes(*code));
+                for operand in operands {
+                    result.extend_from_slice(&operand.to_bytes()?);
+                }
+                result
+            }
+            op => {
+                let (code, operands) = op.encoding(&mut |label| ctx.jump_offset(label))?;
+                encode(code, &operands)?
+            }
         };
         Some(b)
     }
 
-    pub fn size_in_bytes(&self) -> usize {
-        match self {
-            Ld0U64 => 1,
-            Ld0I64 => 1,
-            LdTyped0 { .. } => 1 + refs_size(2),
-            LDType { .. } => 1 + refs_size(2),
-            LdUnit => 1,
-            LdTrue => 1,
-            LdFalse => 1,
-            LdSS(_) => 1 + refs_size(1),
-            UAdd(_) => 1 + refs_size(3),
-            USub(_) => 1 + refs_size(1),
-            UMul(_) => 1 + refs_size(1),
-            UDiv(_) => 1 + refs_size(1),
-            URem(_) => 1 + refs_size(1),
-            IAdd(_) => 1 + refs_size(3),
-            ISub(_) => 1 + refs_size(3),
-            IMul(_) => 1 + refs_size(3),
-            IDiv(_) => 1 + refs_size(3),
-            IRem(_) => 1 + refs_size(3),
-            INeg(_) => 1 + refs_size(2),
-            FAdd(_) => 1 + refs_size(3),
-            FSub(_) => 1 + refs_size(3),
-            FMul(_) => 1 + refs_size(3),
-            FDiv(_) => 1 + refs_size(3),
-            FRem(_) => 1 + refs_size(3),
-            FNeg(_) => 1 + refs_size(2),
-            BAnd(_) => 1 + refs_size(3),
-            BOr(_) => 1 + refs_size(3),
-            BNot(_) => 1 + refs_size(2),
-            BBe(_) => 1 + refs_size(2),
-            BXor(_) => 1 + refs_size(3),
-            LAnd(_) => 1 + refs_size(3),
-            LOr(_) => 1 + refs_size(3),
-            LNot(_) => 1 + refs_size(2),
-            LXor(_) => 1 + refs_size(3),
-            Shl(_) => 1 + refs_size(3),
-            Shr(_) => 1 + refs_size(3),
-            RotL(_) => 1 + refs_size(3),
-            RotR(_) => 1 + refs_size(3),
-            Ge(_) => 1 + refs_size(3),
-            Gt(_) => 1 + refs_size(3),
-            Le(_) => 1 + refs_size(3),
-            Lt(_) => 1 + refs_size(3),
-            Eq(_) => 1 + refs_size(3),
-            Ne(_) => 1 + refs_size(3),
-            J { .. } => 1 + refs_size(1),
-            JC { .. } => 1 + refs_size(2),
-            JOffset { .. } => 1 + refs_size(1),
-            JCOffset { .. } => 1 + refs_size(1),
-            Label(_) => 0,
-            StartScope => 1,
-            EndScope => 1,
-            Scope(ops) => 2 + ops.iter().map(|o| o.size_in_bytes()).sum::<usize>(),
-            TakeRef(_) => 1 + refs_size(1),
-            TakeMut(_) => 1 + refs_size(1),
-            StartDeref(_) => 1 + refs_size(1),
-            EndDeref => 1,
-            Mv(_, _) => 1 + refs_size(2),
-            Mp(_) => 1 + refs_size(1),
-            SArrCreate0(_, _) => 1 + refs_size(2),
-            TraceStackValue(_) => 1 + refs_size(1),
-            SArrGet { .. } => 1 + refs_size(2),
-            SArrMut { .. } => 1 + refs_size(2),
-        }
+    /// The opcode this one is encoded as, along with its operands
+    ///
+    /// `jump` gives the offset of a jump to a label.
+    /// Labels, scopes and extension opcodes are not encoded as a single built-in opcode,
+    /// they have none.
+    fn encoding(&self, jump: &mut dyn FnMut(usize) -> isize) -> Option<(Nc, Operands)> {
+        let (code, operands) = self.operands()?;
+        let operands = operands
+            .into_iter()
+            .map(|operand| match operand {
+                Operand::Stack(r) => (*r).into(),
+                Operand::Label(label) => CodeRef::Jump(jump(label)),
+                Operand::Other(r) => r,
+            })
+            .collect();
+        Some((code, operands))
     }
-}
-
-#[inline]
-fn single(code: Nc) -> OpcodeBytes {
-    OpcodeBytes::from_iter(code.bytes())
-}
 
-fn with_refs(code: Nc, refs: &[usize]) -> OpcodeBytes {
-    let mut res = OpcodeBytes::new();
-    res.extend_from_slice(&code.bytes());
-    for &reference in refs {
-        res.extend_from_slice(&reference.to_le_bytes());
+    /// [`encoding`](Opcode::encoding) with the jumps left to their labels
+    fn operands(&self) -> Option<(Nc, ModelOperands<&StackRef>)> {
+        let bi_op = self.bi_op().map(|(code, _)| code);
+        model_operands!(self, bi_op, operands)
     }
-    res
-}
 
-fn with_one_ref(code: Nc, r: Ref) -> OpcodeBytes {
-    let mut res = OpcodeBytes::new();
-    res.extend_from_slice(&code.bytes());
-    res.extend_from_slice(&r.to_le_bytes());
-    res
-}
+    /// [`operands`](Opcode::operands), with the stack refs to be rewritten in place
+    fn operands_mut(&mut self) -> Option<(Nc, ModelOperands<&mut StackRef>)> {
+        let bi_op = self.bi_op().map(|(code, _)| code);
+        model_operands!(self, bi_op, operands_mut, mut)
+    }
 
-fn with_two_refs(code: Nc, r1: Ref, r2: Ref) -> OpcodeBytes {
-    let mut res = OpcodeBytes::new();
-    res.extend_from_slice(&code.bytes());
-    res.extend_from_slice(&r1.to_le_bytes());
-    res.extend_from_slice(&r2.to_le_bytes());
-    res
-}
+    /// The binary operation done by the opcode, along with its operands
+    pub fn bi_op(&self) -> Option<(Nc, &ThreeStackRefs)> {
+        let op = match self {
+            UAdd(v) => (Nc::UAdd, v),
+            USub(v) => (Nc::USub, v),
+            UMul(v) => (Nc::UMul, v),
+            UDiv(v) => (Nc::UDiv, v),
+            URem(v) => (Nc::URem, v),
+            IAdd(v) => (Nc::IAdd, v),
+            ISub(v) => (Nc::ISub, v),
+            IMul(v) => (Nc::IMul, v),
+            IDiv(v) => (Nc::IDiv, v),
+            IRem(v) => (Nc::IRem, v),
+            FAdd(v) => (Nc::FAdd, v),
+            FSub(v) => (Nc::FSub, v),
+            FMul(v) => (Nc::FMul, v),
+            FDiv(v) => (Nc::FDiv, v),
+            FRem(v) => (Nc::FRem, v),
+            BAnd(v) => (Nc::BAnd, v),
+            BOr(v) => (Nc::BOr, v),
+            BXor(v) => (Nc::BXor, v),
+            LAnd(v) => (Nc::LAnd, v),
+            LOr(v) => (Nc::LOr, v),
+            LXor(v) => (Nc::LXor, v),
+            Shl(v) => (Nc::Shl, v),
+            Shr(v) => (Nc::Shr, v),
+            RotL(v) => (Nc::RotL, v),
+            RotR(v) => (Nc::RotR, v),
+            Ge(v) => (Nc::Ge, v),
+            Gt(v) => (Nc::Gt, v),
+            Le(v) => (Nc::Le, v),
+            Lt(v) => (Nc::Lt, v),
+            Eq(v) => (Nc::Eq, v),
+            Ne(v) => (Nc::Ne, v),
+            _ => return None,
+        };
+        Some(op)
+    }
 
-fn with_two_stack_refs(code: Nc, refs: &TwoStackRefs) -> OpcodeBytes {
-    let mut res = OpcodeBytes::new();
-    res.extend_from_slice(&code.bytes());
-    res.extend_from_slice(&refs.result.0.to_le_bytes());
-    res.extend_from_slice(&refs.op.0.to_le_bytes());
-    res
-}
+    /// Stack refs the opcode takes, those of the opcodes in a scope are not included
+    pub(crate) fn stack_refs(&self) -> SmallVec<[StackRef; 4]> {
+        match self {
+            Extension { operands, .. } => operands
+                .iter()
+                .filter_map(|r| match r {
+                    CodeRef::Stack(r) => Some(*r),
+                    _ => None,
+                })
+                .collect(),
+            op => op.operands().map_or_else(SmallVec::new, |(_, operands)| {
+                operands
+                    .into_iter()
+                    .filter_map(Operand::stack)
+                    .copied()
+                    .collect()
+            }),
+        }
+    }
 
-fn with_three_stack_refs(code: Nc, refs: &ThreeStackRefs) -> OpcodeBytes {
-    let mut res = OpcodeBytes::new();
-    res.extend(code.bytes());
-    res.extend_from_slice(&refs.result.0.to_le_bytes());
-    res.extend_from_slice(&refs.op1.0.to_le_bytes());
-    res.extend_from_slice(&refs.op2.0.to_le_bytes());
-    res
-}
+    /// [`stack_refs`](Opcode::stack_refs), to be rewritten in place
+    pub(crate) fn stack_refs_mut(&mut self) -> SmallVec<[&mut StackRef; 4]> {
+        match self {
+            Extension { operands, .. } => operands
+                .iter_mut()
+                .filter_map(|r| match r {
+                    CodeRef::Stack(r) => Some(r),
+                    _ => None,
+                })
+                .collect(),
+            op => op
+                .operands_mut()
+                .map_or_else(SmallVec::new, |(_, operands)| {
+                    operands.into_iter().filter_map(Operand::stack).collect()
+                }),
+        }
+    }
 
-fn with_offset(code: Nc, offset: usize) -> OpcodeBytes {
-    let mut res = OpcodeBytes::new();
-    res.extend(code.bytes());
-    res.extend_from_slice(&offset.to_le_bytes());
-    res
+    /// Amount of bytes the opcode takes in the bytecode, labels take none
+    ///
+    /// Jumps to labels are counted with the size of the resolved offset
+    pub fn size_in_bytes(&self) -> usize {
+        match self {
+            Label(_) => 0,
+            Scope(ops) => 2 + ops.iter().map(|o| o.size_in_bytes()).sum::<usize>(),
+            Extension { operands, .. } => 2 + operands.iter().map(CodeRef::size).sum::<usize>(),
+            op => op.encoding(&mut |_| 0).map_or(0, |(code, operands)| {
+                code.size() + operands.iter().map(CodeRef::size).sum::<usize>()
+            }),
+        }
+    }
 }
 
-fn with_offset_and_ref(code: Nc, offset: usize, r: Ref) -> OpcodeBytes {
-    let mut res = OpcodeBytes::new();
-    res.extend(code.bytes());
-    res.extend_from_slice(&offset.to_le_bytes());
-    res.extend_from_slice(&r.to_le_bytes());
-    res
+/// Operands of an opcode, in the order [`Nc::operands`] lists them
+type Operands = SmallVec<[CodeRef; 6]>;
+
+/// Encode `code` followed by its operands
+///
+/// `None` if the operands are not those the opcode table gives `code`, or if an offset
+/// does not fit into the bytecode.
+fn encode(code: Nc, operands: &[CodeRef]) -> Option<OpcodeBytes> {
+    let kinds = code.operands();
+    let matching =
+        kinds.len() == operands.len() && kinds.iter().zip(operands).all(|(k, r)| k.kind.accepts(r));
+    if !matching {
+        return None;
+    }
+    let mut res = OpcodeBytes::from_iter(code.bytes());
+    for operand in operands {
+        res.extend_from_slice(&operand.to_bytes()?);
+    }
+    Some(res)
 }
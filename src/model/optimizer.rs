@@ -0,0 +1,699 @@
+//! Optional optimization passes over model opcodes
+//!
+//! The optimized code does the same as the original one: it traces the same values, stops
+//! with the same kind of error and, when it runs to its end, leaves the same values on the
+//! stack.
+//! Values in a scope are dropped at its end, so only loads in a scope are ever removed.
+//!
+//! The passes that track the stack assume it has the same height on every path to a label,
+//! code after a label reached with different heights is left as is.
+
+use std::collections::{HashMap, HashSet};
+
+use smallvec::{smallvec, SmallVec};
+
+use super::peephole::has_offset_jumps;
+use super::Opcode::{self, *};
+use crate::code::refs::{Immediate, StackRef};
+use crate::stack::data::{IntoStackData, StackData};
+use crate::types::PrimitiveType;
+use crate::{Code, ConstantPool, Vm};
+
+/// Rounds of the search for the stack height at the labels before it gives up
+const MAX_ROUNDS: usize = 16;
+
+/// Passes to run, all of them by default
+///
+/// The passes run in the order of the fields.
+#[derive(Debug, Copy, Clone)]
+pub struct Passes {
+    /// Retarget jumps that land on a `J` to its label, remove jumps to the next opcode
+    pub thread_jumps: bool,
+    /// Remove the opcodes after a `J` that no jump lands on
+    pub remove_dead_code: bool,
+    /// Run ALU opcodes on constants ahead of time, loading their result instead
+    pub fold_constants: bool,
+    /// Remove the loads of values nothing reads, renumbering the stack refs after them
+    pub compact_stack: bool,
+    /// Remove scopes without opcodes
+    pub remove_empty_scopes: bool,
+}
+
+impl Default for Passes {
+    fn default() -> Self {
+        Self {
+            thread_jumps: true,
+            remove_dead_code: true,
+            fold_constants: true,
+            compact_stack: true,
+            remove_empty_scopes: true,
+        }
+    }
+}
+
+impl Passes {
+    /// No pass, the code is kept as is
+    pub fn none() -> Self {
+        Self {
+            thread_jumps: false,
+            remove_dead_code: false,
+            fold_constants: false,
+            compact_stack: false,
+            remove_empty_scopes: false,
+        }
+    }
+}
+
+/// Run the `passes` on `ops`, whose constants are in `pool`
+///
+/// The passes change the byte offsets of the code, so code with jumps by a raw offset
+/// is returned as is.
+pub fn optimize(ops: &[Opcode], pool: &ConstantPool, passes: &Passes) -> Vec<Opcode> {
+    let mut ops = ops.to_vec();
+    if has_offset_jumps(&ops) {
+        return ops;
+    }
+    if passes.thread_jumps {
+        thread_jumps(&mut ops);
+    }
+    if passes.remove_dead_code {
+        remove_dead_code(&mut ops);
+    }
+    if passes.fold_constants {
+        fold_constants(&mut ops, pool);
+    }
+    if passes.compact_stack {
+        compact_stack(&mut ops, pool);
+    }
+    if passes.remove_empty_scopes {
+        remove_empty_scopes(&mut ops);
+    }
+    ops
+}
+
+fn jump_label(op: &Opcode) -> Option<usize> {
+    match op {
+        J { label } | JC { label, .. } | JCmp { label, .. } => Some(*label),
+        _ => None,
+    }
+}
+
+fn jump_label_mut(op: &mut Opcode) -> Option<&mut usize> {
+    match op {
+        J { label } | JC { label, .. } | JCmp { label, .. } => Some(label),
+        _ => None,
+    }
+}
+
+/// Result and operands of an ALU opcode
+fn alu(op: &Opcode) -> Option<(StackRef, SmallVec<[StackRef; 2]>)> {
+    match op {
+        INeg(v)
+        | FNeg(v)
+        | BNot(v)
+        | BBe(v)
+        | LNot(v)
+        | AddImm(v, _)
+        | SubImm(v, _)
+        | CmpImm { refs: v, .. } => Some((v.result, smallvec![v.op])),
+        op => op.bi_op().map(|(_, v)| (v.result, smallvec![v.op1, v.op2])),
+    }
+}
+
+/// Amount of values the opcode pushes, `None` if it is not known ahead of time
+///
+/// Scopes push none, `StartScope` and `EndScope` are followed by [`Height`].
+fn pushes(op: &Opcode) -> Option<usize> {
+    match op {
+        Ld0U64
+        | Ld0I64
+        | LdTyped0 { .. }
+        | LDType { .. }
+        | LdConst { .. }
+        | LdUnit
+        | LdTrue
+        | LdFalse
+        | LdSS(_)
+        | LdImm(_)
+        | LdOp { .. }
+        | TakeRef(_)
+        | TakeMut(_)
+        | Reborrow(_)
+        | ReborrowMut(_)
+        | Clone(_) => Some(1),
+        Label(_)
+        | J { .. }
+        | JC { .. }
+        | JCmp { .. }
+        | UAddCmp { .. }
+        | ScopedLdOp { .. }
+        | Scope(_)
+        | TraceStackValue(_) => Some(0),
+        op if alu(op).is_some() => Some(0),
+        _ => None,
+    }
+}
+
+/// Type, value and mutability of the value a load pushes, `None` if the load can fail
+fn constant(op: &Opcode, pool: &ConstantPool) -> Option<(PrimitiveType, StackData, bool)> {
+    let single = |t| pool.get_type(t).filter(|t: &PrimitiveType| t.is_single());
+    match op {
+        Ld0U64 => Some((PrimitiveType::U64, StackData::default(), true)),
+        Ld0I64 => Some((PrimitiveType::I64, StackData::default(), true)),
+        LdUnit => Some((PrimitiveType::Unit, StackData::default(), true)),
+        LdTrue => Some((PrimitiveType::Bool, true.into_stack_data(), true)),
+        LdFalse => Some((PrimitiveType::Bool, false.into_stack_data(), true)),
+        LdImm(imm) => Some((imm.value_type()?, imm.value(), true)),
+        LdTyped0 { type_location } => Some((single(*type_location)?, StackData::default(), true)),
+        LDType {
+            type_location,
+            value_location,
+        } => Some((
+            single(*type_location)?,
+            pool.get_single(*value_location)?,
+            true,
+        )),
+        LdConst {
+            type_location,
+            value_location,
+        } => Some((
+            single(*type_location)?,
+            pool.get_single(*value_location)?,
+            false,
+        )),
+        _ => None,
+    }
+}
+
+/// If the opcode is a load that can't fail
+fn is_removable_load(op: &Opcode, pool: &ConstantPool) -> bool {
+    match op {
+        LdSS(s) => pool.get_s_str(*s).is_some(),
+        op => constant(op, pool).is_some(),
+    }
+}
+
+/// Stack height before the opcodes of a body, relative to the start of the code
+struct Height<'a> {
+    labels: &'a HashMap<usize, usize>,
+    height: Option<usize>,
+    scopes: Vec<Option<usize>>,
+}
+
+impl<'a> Height<'a> {
+    fn new(labels: &'a HashMap<usize, usize>, height: Option<usize>) -> Self {
+        Self {
+            labels,
+            height,
+            scopes: Vec::new(),
+        }
+    }
+
+    fn step(&mut self, op: &Opcode) {
+        self.height = match op {
+            Label(label) => self.labels.get(label).copied(),
+            StartScope => {
+                self.scopes.push(self.height);
+                self.height
+            }
+            EndScope => self.scopes.pop().flatten(),
+            op => self.height.and_then(|h| Some(h + pushes(op)?)),
+        };
+    }
+}
+
+/// Stack height at the labels, those reached with different heights are left out
+fn label_heights(ops: &[Opcode]) -> HashMap<usize, usize> {
+    let mut walk = LabelWalk::default();
+    for _ in 0..MAX_ROUNDS {
+        let conflicts = walk.conflicts.len();
+        walk.incoming.clear();
+        walk.body(ops, Some(0));
+        let mut found = HashMap::new();
+        for (&label, &height) in &walk.incoming {
+            match height {
+                Some(height) => {
+                    found.insert(label, height);
+                }
+                None => {
+                    walk.conflicts.insert(label);
+                }
+            }
+        }
+        if found == walk.labels && conflicts == walk.conflicts.len() {
+            return found;
+        }
+        walk.labels = found;
+    }
+    HashMap::new()
+}
+
+#[derive(Default)]
+struct LabelWalk {
+    /// Heights found in the previous round
+    labels: HashMap<usize, usize>,
+    /// Labels reached with different heights in any round
+    conflicts: HashSet<usize>,
+    /// Height on every path to the labels in this round, `None` if they differ
+    incoming: HashMap<usize, Option<usize>>,
+}
+
+impl LabelWalk {
+    fn arrive(&mut self, label: usize, height: Option<usize>) {
+        let merged = match self.incoming.get(&label) {
+            Some(&known) if known != height => None,
+            _ => height,
+        };
+        self.incoming.insert(label, merged);
+    }
+
+    fn body(&mut self, ops: &[Opcode], mut height: Option<usize>) {
+        let mut reachable = true;
+        let mut scopes = Vec::new();
+        for op in ops {
+            match op {
+                Label(label) => {
+                    let fallthrough = height.filter(|_| reachable);
+                    if reachable {
+                        self.arrive(*label, height);
+                    }
+                    height = if self.conflicts.contains(label) {
+                        None
+                    } else {
+                        self.labels.get(label).copied().or(fallthrough)
+                    };
+                }
+                Scope(body) => self.body(body, height),
+                StartScope => scopes.push(height),
+                EndScope => height = scopes.pop().flatten(),
+                op => {
+                    if let Some(label) = jump_label(op) {
+                        self.arrive(label, height);
+                    }
+                    height = height.and_then(|h| Some(h + pushes(op)?));
+                }
+            }
+            reachable = match op {
+                J { .. } => false,
+                Label(_) => true,
+                _ => reachable,
+            };
+        }
+    }
+}
+
+/// Label the jumps to `label` can go to instead, skipping the labels followed by a `J`
+fn final_label(label: usize, targets: &HashMap<usize, usize>) -> usize {
+    let mut current = label;
+    let mut seen = HashSet::new();
+    while let Some(&next) = targets.get(&current) {
+        if !seen.insert(current) {
+            return label;
+        }
+        current = next;
+    }
+    current
+}
+
+fn thread_jumps(ops: &mut Vec<Opcode>) {
+    let mut targets = HashMap::new();
+    collect_jump_labels(ops, &mut targets);
+    retarget(ops, &targets);
+    remove_jumps_to_next(ops);
+}
+
+/// Labels directly followed by a `J`, with the label of the `J`
+fn collect_jump_labels(ops: &[Opcode], targets: &mut HashMap<usize, usize>) {
+    for (i, op) in ops.iter().enumerate() {
+        match op {
+            Label(label) => {
+                let next = ops[i + 1..].iter().find(|op| !matches!(op, Label(_)));
+                if let Some(J { label: to }) = next {
+                    targets.insert(*label, *to);
+                }
+            }
+            Scope(body) => collect_jump_labels(body, targets),
+            _ => {}
+        }
+    }
+}
+
+fn retarget(ops: &mut [Opcode], targets: &HashMap<usize, usize>) {
+    for op in ops {
+        if let Scope(body) = op {
+            retarget(body, targets);
+        } else if let Some(label) = jump_label_mut(op) {
+            *label = final_label(*label, targets);
+        }
+    }
+}
+
+fn remove_jumps_to_next(ops: &mut Vec<Opcode>) {
+    let mut i = 0;
+    while i < ops.len() {
+        if let Scope(body) = &mut ops[i] {
+            remove_jumps_to_next(body);
+        }
+        let to_next = match &ops[i] {
+            J { label } => ops[i + 1..]
+                .iter()
+                .take_while(|op| matches!(op, Label(_)))
+                .any(|op| matches!(op, Label(l) if l == label)),
+            _ => false,
+        };
+        if to_next {
+            ops.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+fn remove_dead_code(ops: &mut Vec<Opcode>) {
+    loop {
+        let mut targets = HashSet::new();
+        collect_targets(ops, &mut targets);
+        if !remove_unreachable(ops, &targets) {
+            break;
+        }
+    }
+}
+
+fn collect_targets(ops: &[Opcode], targets: &mut HashSet<usize>) {
+    for op in ops {
+        match op {
+            Scope(body) => collect_targets(body, targets),
+            op => targets.extend(jump_label(op)),
+        }
+    }
+}
+
+/// If the opcode is, or holds, a label some jump lands on
+fn is_target(op: &Opcode, targets: &HashSet<usize>) -> bool {
+    match op {
+        Label(label) => targets.contains(label),
+        Scope(body) => body.iter().any(|op| is_target(op, targets)),
+        _ => false,
+    }
+}
+
+/// Remove the opcodes between a `J` and the next label a jump lands on, if any was removed
+fn remove_unreachable(ops: &mut Vec<Opcode>, targets: &HashSet<usize>) -> bool {
+    let mut removed = false;
+    let mut reachable = true;
+    let old = std::mem::take(ops);
+    for mut op in old {
+        if !reachable && !is_target(&op, targets) {
+            removed = true;
+            continue;
+        }
+        if let Scope(body) = &mut op {
+            removed |= remove_unreachable(body, targets);
+        }
+        reachable = !matches!(op, J { .. });
+        ops.push(op);
+    }
+    removed
+}
+
+/// Constant in a stack slot, along with the path to its load
+struct Known {
+    t: PrimitiveType,
+    value: StackData,
+    mutable: bool,
+    /// If an opcode that is kept reads the slot after the load
+    read: bool,
+    load: Vec<usize>,
+}
+
+fn fold_constants(ops: &mut Vec<Opcode>, pool: &ConstantPool) {
+    let labels = label_heights(ops);
+    let mut folder = Folder {
+        pool,
+        known: HashMap::new(),
+        loads: HashMap::new(),
+        removed: HashSet::new(),
+    };
+    folder.body(ops, &mut Vec::new(), &mut Height::new(&labels, Some(0)));
+    apply(ops, &mut Vec::new(), &folder.loads, &folder.removed);
+}
+
+struct Folder<'a> {
+    pool: &'a ConstantPool,
+    /// Constants of the slots, by their height
+    known: HashMap<usize, Known>,
+    /// Loads to replace, by their path in the code
+    loads: HashMap<Vec<usize>, Immediate>,
+    /// Paths of the opcodes to remove
+    removed: HashSet<Vec<usize>>,
+}
+
+impl Folder<'_> {
+    fn body(&mut self, ops: &[Opcode], path: &mut Vec<usize>, height: &mut Height) {
+        for (i, op) in ops.iter().enumerate() {
+            path.push(i);
+            let before = height.height;
+            match op {
+                Scope(body) => {
+                    self.body(body, path, &mut Height::new(height.labels, before));
+                    self.forget_from(before);
+                }
+                Label(_) | J { .. } | JC { .. } | JCmp { .. } | StartScope | EndScope => {
+                    self.known.clear()
+                }
+                op => self.opcode(op, path, before),
+            }
+            height.step(op);
+            path.pop();
+        }
+    }
+
+    fn opcode(&mut self, op: &Opcode, path: &[usize], height: Option<usize>) {
+        let pushed = match (pushes(op), height) {
+            (Some(pushed), Some(height)) => height..height + pushed,
+            _ => {
+                self.known.clear();
+                return;
+            }
+        };
+        if let Some((t, value, mutable)) = constant(op, self.pool) {
+            let known = Known {
+                t,
+                value,
+                mutable,
+                read: false,
+                load: path.to_vec(),
+            };
+            self.known.insert(pushed.start, known);
+            return;
+        }
+        if self.fold(op, path) {
+            return;
+        }
+        let written = alu(op).map(|(result, _)| result);
+        for r in op.stack_refs() {
+            match self.known.get_mut(&r.0) {
+                Some(known) if written != Some(r) && alu(op).is_some() => known.read = true,
+                Some(_) => {
+                    self.known.remove(&r.0);
+                }
+                None => {}
+            }
+        }
+        for slot in pushed {
+            self.known.remove(&slot);
+        }
+    }
+
+    /// Fold `op` into the load of its result, if it is an ALU opcode on constants
+    fn fold(&mut self, op: &Opcode, path: &[usize]) -> bool {
+        let (result, operands) = match alu(op) {
+            Some(alu) => alu,
+            None => return false,
+        };
+        match self.known.get(&result.0) {
+            Some(known) if known.mutable && !known.read => {}
+            _ => return false,
+        }
+        if operands.iter().any(|r| !self.known.contains_key(&r.0)) {
+            return false;
+        }
+        let value = match evaluate(op, result, &self.known) {
+            Some(value) => value,
+            None => return false,
+        };
+        let known = self.known.get_mut(&result.0).unwrap();
+        let imm = match Immediate::from_raw(known.t, value) {
+            Some(imm) => imm,
+            None => return false,
+        };
+        known.value = value;
+        self.loads.insert(known.load.clone(), imm);
+        self.removed.insert(path.to_vec());
+        true
+    }
+
+    /// Forget the slots from `height` on, dropped at the end of a scope
+    fn forget_from(&mut self, height: Option<usize>) {
+        match height {
+            Some(height) => self.known.retain(|&slot, _| slot < height),
+            None => self.known.clear(),
+        }
+    }
+}
+
+/// Value `op` writes to `result`, run on a vm of its own with the constants of its operands
+fn evaluate(op: &Opcode, result: StackRef, known: &HashMap<usize, Known>) -> Option<StackData> {
+    let mut op = op.clone();
+    let mut slots: Vec<usize> = Vec::new();
+    for r in op.stack_refs_mut() {
+        let index = match slots.iter().position(|&s| s == r.0) {
+            Some(index) => index,
+            None => {
+                slots.push(r.0);
+                slots.len() - 1
+            }
+        };
+        *r = StackRef(index);
+    }
+    let mut ops = slots
+        .iter()
+        .map(|slot| {
+            let known = known.get(slot)?;
+            Some(LdImm(Immediate::from_raw(known.t, known.value)?))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let index = slots.iter().position(|&s| s == result.0)?;
+    ops.push(op);
+
+    let mut vm = Vm::headless(ConstantPool::new(vec![]));
+    Code::from_model(&ops)?.interpret(&mut vm).ok()?;
+    vm.stack_data(StackRef(index)).ok()?.first().copied()
+}
+
+/// Replace the loads and remove the opcodes the folder found, by their paths
+fn apply(
+    ops: &mut Vec<Opcode>,
+    path: &mut Vec<usize>,
+    loads: &HashMap<Vec<usize>, Immediate>,
+    removed: &HashSet<Vec<usize>>,
+) {
+    let old = std::mem::take(ops);
+    for (i, mut op) in old.into_iter().enumerate() {
+        path.push(i);
+        if let Some(&imm) = loads.get(path) {
+            op = LdImm(imm);
+        }
+        if let Scope(body) = &mut op {
+            apply(body, path, loads, removed);
+        }
+        if !removed.contains(path) {
+            ops.push(op);
+        }
+        path.pop();
+    }
+}
+
+fn compact_stack(ops: &mut Vec<Opcode>, pool: &ConstantPool) {
+    let labels = label_heights(ops);
+    compact_body(ops, pool, &mut Height::new(&labels, Some(0)), false);
+}
+
+/// If the effect of the opcode on the stack is known, without labels and jumps
+fn is_straight(op: &Opcode) -> bool {
+    match op {
+        Scope(body) => body.iter().all(is_straight),
+        Label(_) | J { .. } | JC { .. } | JCmp { .. } | StartScope | EndScope => false,
+        op => pushes(op).is_some(),
+    }
+}
+
+fn refers_to(op: &Opcode, slot: usize) -> bool {
+    match op {
+        Scope(body) => body.iter().any(|op| refers_to(op, slot)),
+        op => op.stack_refs().iter().any(|r| r.0 == slot),
+    }
+}
+
+/// If the opcodes only refer to slots below the stack height, starting at `height`
+fn in_bounds(ops: &[Opcode], height: usize) -> bool {
+    let mut height = height;
+    ops.iter().all(|op| {
+        let top = match op {
+            Scope(body) => return in_bounds(body, height),
+            LdOp { .. } | ScopedLdOp { .. } => height + 1,
+            _ => height,
+        };
+        height += pushes(op).unwrap_or(0);
+        op.stack_refs().iter().all(|r| r.0 < top)
+    })
+}
+
+/// If the opcode traces a slot above `slot`, traces show the slot they read
+fn traces_above(op: &Opcode, slot: usize) -> bool {
+    match op {
+        Scope(body) => body.iter().any(|op| traces_above(op, slot)),
+        TraceStackValue(r) => r.0 > slot,
+        _ => false,
+    }
+}
+
+fn renumber(op: &mut Opcode, removed: usize) {
+    if let Scope(body) = op {
+        body.iter_mut().for_each(|op| renumber(op, removed));
+    }
+    for r in op.stack_refs_mut() {
+        if r.0 > removed {
+            r.0 -= 1;
+        }
+    }
+}
+
+/// Remove the unused loads of `ops`, a scope body if `in_scope`
+fn compact_body(ops: &mut Vec<Opcode>, pool: &ConstantPool, height: &mut Height, in_scope: bool) {
+    let straight = in_scope
+        && ops.iter().all(is_straight)
+        && matches!(height.height, Some(h) if in_bounds(ops, h));
+    let mut i = 0;
+    while i < ops.len() {
+        if let (true, Some(slot)) = (straight, height.height) {
+            let rest = &ops[i + 1..];
+            if is_removable_load(&ops[i], pool)
+                && !rest
+                    .iter()
+                    .any(|op| refers_to(op, slot) || traces_above(op, slot))
+            {
+                ops.remove(i);
+                ops[i..].iter_mut().for_each(|op| renumber(op, slot));
+                continue;
+            }
+        }
+        if let Scope(body) = &mut ops[i] {
+            compact_body(
+                body,
+                pool,
+                &mut Height::new(height.labels, height.height),
+                true,
+            );
+        }
+        height.step(&ops[i]);
+        i += 1;
+    }
+}
+
+fn remove_empty_scopes(ops: &mut Vec<Opcode>) {
+    let old = std::mem::take(ops);
+    for mut op in old {
+        if let Scope(body) = &mut op {
+            remove_empty_scopes(body);
+            if body.is_empty() {
+                continue;
+            }
+        }
+        if let (EndScope, Some(StartScope)) = (&op, ops.last()) {
+            ops.pop();
+            continue;
+        }
+        ops.push(op);
+    }
+}
@@ -0,0 +1,108 @@
+//! Optional peephole pass that rewrites common opcode sequences into fused opcodes
+//!
+//! The rewritten code does the same as the original one, with fewer opcodes to dispatch.
+
+use super::Comparison;
+use super::Opcode::{self, *};
+use crate::code::refs::StackRef;
+
+/// Rewrite the eligible sequences of `ops` into fused opcodes
+///
+/// Fusing changes the byte offsets of the code, so code with jumps by a raw offset
+/// is returned as is.
+pub fn fuse(ops: &[Opcode]) -> Vec<Opcode> {
+    if has_offset_jumps(ops) {
+        ops.to_vec()
+    } else {
+        fuse_sequence(ops)
+    }
+}
+
+pub(super) fn has_offset_jumps(ops: &[Opcode]) -> bool {
+    ops.iter().any(|op| match op {
+        JOffset { .. } | JCOffset { .. } => true,
+        Scope(body) => has_offset_jumps(body),
+        _ => false,
+    })
+}
+
+fn fuse_sequence(ops: &[Opcode]) -> Vec<Opcode> {
+    let mut result = Vec::with_capacity(ops.len());
+    let mut i = 0;
+    while i < ops.len() {
+        let (fused, consumed) = fuse_at(&ops[i..]);
+        result.push(fused);
+        i += consumed;
+    }
+    result
+}
+
+/// Fused form of the opcodes at the start of `ops`, along with the number of opcodes it replaces
+fn fuse_at(ops: &[Opcode]) -> (Opcode, usize) {
+    match ops {
+        [Scope(body), ..] => match body.as_slice() {
+            [LDType {
+                type_location,
+                value_location,
+            }, op]
+                if op.bi_op().is_some() =>
+            {
+                (
+                    ScopedLdOp {
+                        type_location: *type_location,
+                        value_location: *value_location,
+                        op: Box::new(op.clone()),
+                    },
+                    1,
+                )
+            }
+            body => (Scope(fuse_sequence(body)), 1),
+        },
+        [cmp, JC { label, cond }, ..] if jumps_on(cmp, *cond) => {
+            let (cmp, refs) = Comparison::of(cmp).expect("checked by jumps_on");
+            let fused = JCmp {
+                cmp,
+                label: *label,
+                refs: refs.clone(),
+            };
+            (fused, 2)
+        }
+        [UAdd(add), cmp, rest @ ..] => match Comparison::of(cmp) {
+            Some((c, refs))
+                if add.result == add.op1
+                    && refs.op1 == add.result
+                    && !matches!(rest.first(), Some(JC { cond, .. }) if *cond == refs.result) =>
+            {
+                let fused = UAddCmp {
+                    cmp: c,
+                    counter: add.result,
+                    step: add.op2,
+                    result: refs.result,
+                    limit: refs.op2,
+                };
+                (fused, 2)
+            }
+            _ => (UAdd(add.clone()), 1),
+        },
+        [LDType {
+            type_location,
+            value_location,
+        }, op, ..]
+            if op.bi_op().is_some() =>
+        {
+            let fused = LdOp {
+                type_location: *type_location,
+                value_location: *value_location,
+                op: Box::new(op.clone()),
+            };
+            (fused, 2)
+        }
+        [op, ..] => (op.clone(), 1),
+        [] => unreachable!("fuse_at is called with opcodes left"),
+    }
+}
+
+/// `cmp` is a comparison whose result is `cond`
+fn jumps_on(cmp: &Opcode, cond: StackRef) -> bool {
+    Comparison::of(cmp).map_or(false, |(_, refs)| refs.result == cond)
+}
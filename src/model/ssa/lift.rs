@@ -0,0 +1,627 @@
+use std::collections::{BTreeSet, HashMap};
+
+use super::{Block, BlockId, Function, Inst, Operand, Phi, Value};
+use crate::code::refs::{PoolRef, StackRef};
+use crate::error::SsaError;
+use crate::model::Opcode::{self, *};
+use crate::types::{PointedType, PrimitiveType, RefKind, RefLocation, VmType};
+use crate::{Constant, ConstantPool};
+
+#[derive(Copy, Clone)]
+struct Access {
+    read: bool,
+    write: bool,
+}
+
+const R: Access = Access {
+    read: true,
+    write: false,
+};
+const W: Access = Access {
+    read: false,
+    write: true,
+};
+const RW: Access = Access {
+    read: true,
+    write: true,
+};
+
+/// How the opcode treats each of its stack refs, `None` if it has no SSA form
+fn accesses(op: &Opcode) -> Option<&'static [Access]> {
+    Some(match op {
+        Ld0U64
+        | Ld0I64
+        | LdTyped0 { .. }
+        | LDType { .. }
+        | LdConst { .. }
+        | LdUnit
+        | LdTrue
+        | LdFalse
+        | LdSS(_)
+        | LdStatic(_)
+        | LdImm(_)
+        | J { .. }
+        | Label(_)
+        | StartScope
+        | EndScope
+        | EndDeref
+        | SArrCreate0(..) => &[],
+        INeg(_)
+        | FNeg(_)
+        | BNot(_)
+        | BBe(_)
+        | LNot(_)
+        | AddImm(..)
+        | SubImm(..)
+        | CmpImm { .. }
+        | Mv(..) => &[W, R],
+        JCmp { .. } => &[W, R, R],
+        op if op.bi_op().is_some() => &[W, R, R],
+        JC { .. }
+        | TakeRef(_)
+        | TakeMut(_)
+        | StartDeref(_)
+        | StartDerefN { .. }
+        | Reborrow(_)
+        | ReborrowMut(_)
+        | Mp(_)
+        | Drop(_)
+        | Clone(_)
+        | Freeze(_)
+        | CellNew(_)
+        | CellBorrow(_)
+        | CellBorrowMut(_)
+        | RcNew(_)
+        | RcDeref(_)
+        | RcDowngrade(_)
+        | WeakUpgrade(_)
+        | WeakIsAlive(_)
+        | AnyWrap(_)
+        | AnyIs { .. }
+        | AnyDowncast { .. }
+        | TraceStackValue(_) => &[R],
+        Swap(..) => &[RW, RW],
+        Replace { .. } => &[R, RW],
+        SArrGet { .. } | SArrMut { .. } => &[R, R],
+        UAddCmp { .. } => &[RW, R, W, R],
+        _ => return None,
+    })
+}
+
+/// If the opcode writes through references, to slots that are not its operands
+fn writes_through_refs(op: &Opcode) -> bool {
+    matches!(op, EndDeref | Replace { .. })
+}
+
+fn jump_label(op: &mut Opcode) -> Option<&mut usize> {
+    match op {
+        J { label } | JC { label, .. } | JCmp { label, .. } => Some(label),
+        _ => None,
+    }
+}
+
+/// Spell out the scopes and the fused loads of `ops`
+fn flatten(ops: &[Opcode], flat: &mut Vec<Opcode>) {
+    for op in ops {
+        match op {
+            Scope(body) => {
+                flat.push(StartScope);
+                flatten(body, flat);
+                flat.push(EndScope);
+            }
+            LdOp {
+                type_location,
+                value_location,
+                op,
+            } => {
+                flat.push(LDType {
+                    type_location: *type_location,
+                    value_location: *value_location,
+                });
+                flat.push(op.as_ref().clone());
+            }
+            ScopedLdOp {
+                type_location,
+                value_location,
+                op,
+            } => {
+                flat.push(StartScope);
+                flat.push(LDType {
+                    type_location: *type_location,
+                    value_location: *value_location,
+                });
+                flat.push(op.as_ref().clone());
+                flat.push(EndScope);
+            }
+            op => flat.push(op.clone()),
+        }
+    }
+}
+
+/// A block of the flattened code, before its values are known
+struct Span {
+    start: usize,
+    end: usize,
+    successors: Vec<usize>,
+}
+
+/// Types of the values on the stack, along with the open scopes
+#[derive(Clone, PartialEq)]
+struct Layout {
+    types: Vec<VmType>,
+    scopes: Vec<usize>,
+}
+
+fn deref(mut t: &VmType, depth: usize) -> Option<VmType> {
+    if depth == 0 {
+        return None;
+    }
+    for _ in 0..depth {
+        t = &t.ref_type()?.pointer;
+    }
+    Some(t.clone())
+}
+
+fn rc_inner(t: &VmType) -> Option<&VmType> {
+    match t.pointed()? {
+        PointedType::Rc(inner) => Some(inner),
+        _ => None,
+    }
+}
+
+fn reference(pointer: VmType, kind: RefKind, location: RefLocation) -> VmType {
+    PointedType::reference(pointer, kind, location).into()
+}
+
+struct Lifter<'a> {
+    pool: &'a ConstantPool,
+    flat: Vec<Opcode>,
+    spans: Vec<Span>,
+    /// Slots a reference is taken to, opcodes that write through references may change them
+    escaped: BTreeSet<usize>,
+}
+
+impl Lifter<'_> {
+    fn split(&mut self) -> Result<(), SsaError> {
+        let mut labels = HashMap::new();
+        let mut start = 0;
+        let mut has_ops = false;
+        for (index, op) in self.flat.iter().enumerate() {
+            match op {
+                Label(label) => {
+                    if has_ops {
+                        self.spans.push(Span {
+                            start,
+                            end: index,
+                            successors: Vec::new(),
+                        });
+                        start = index;
+                        has_ops = false;
+                    }
+                    if labels.insert(*label, self.spans.len()).is_some() {
+                        return Err(SsaError::BadLabel(*label));
+                    }
+                }
+                J { .. } | JC { .. } | JCmp { .. } => {
+                    self.spans.push(Span {
+                        start,
+                        end: index + 1,
+                        successors: Vec::new(),
+                    });
+                    start = index + 1;
+                    has_ops = false;
+                }
+                _ => has_ops = true,
+            }
+        }
+        let count = self.spans.len();
+        if start < self.flat.len() || count == 0 || labels.values().any(|&s| s == count) {
+            self.spans.push(Span {
+                start,
+                end: self.flat.len(),
+                successors: Vec::new(),
+            });
+        }
+
+        for id in 0..self.spans.len() {
+            let span = &self.spans[id];
+            let last = self.flat[span.start..span.end]
+                .iter()
+                .rev()
+                .find(|op| !matches!(op, Label(_)));
+            let mut successors = Vec::new();
+            if !matches!(last, Some(J { .. })) && id + 1 < self.spans.len() {
+                successors.push(id + 1);
+            }
+            if let Some(J { label } | JC { label, .. } | JCmp { label, .. }) = last {
+                let target = *labels.get(label).ok_or(SsaError::BadLabel(*label))?;
+                if !successors.contains(&target) {
+                    successors.push(target);
+                }
+            }
+            self.spans[id].successors = successors;
+        }
+        // jumps are rewritten to spans, the labels go to the block of their span
+        for span in &self.spans {
+            for op in &mut self.flat[span.start..span.end] {
+                if let Some(label) = jump_label(op) {
+                    *label = labels[label];
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn pool_type(&self, index: usize, location: PoolRef) -> Result<VmType, SsaError> {
+        let t = self.pool.get_vm_type(location);
+        t.ok_or(SsaError::UnknownType(index))
+    }
+
+    /// Type of the value the opcode pushes, `slot` gives the types of the values on the stack
+    fn pushed_type<'t>(
+        &self,
+        index: usize,
+        op: &Opcode,
+        slot: impl Fn(StackRef) -> Option<&'t VmType>,
+    ) -> Result<Option<VmType>, SsaError> {
+        let operand = |r: &StackRef| slot(*r).ok_or(SsaError::InvalidStackRef(index));
+        let known = |t: Option<VmType>| t.ok_or(SsaError::UnknownType(index));
+        let t = match op {
+            Ld0U64 => PrimitiveType::U64.into(),
+            Ld0I64 => PrimitiveType::I64.into(),
+            LdTyped0 { type_location }
+            | LDType { type_location, .. }
+            | LdConst { type_location, .. }
+            | AnyDowncast { type_location, .. } => self.pool_type(index, *type_location)?,
+            LdUnit => PrimitiveType::Unit.into(),
+            LdTrue | LdFalse | WeakIsAlive(_) | AnyIs { .. } => PrimitiveType::Bool.into(),
+            LdSS(_) => PrimitiveType::SStr.into(),
+            LdStatic(p) => known(self.pool.get(*p).and_then(Constant::array_type))?,
+            LdImm(imm) => known(imm.value_type().map(VmType::from))?,
+            TakeRef(r) => reference(operand(r)?.clone(), RefKind::Ref, RefLocation::Stack),
+            TakeMut(r) => reference(operand(r)?.clone(), RefKind::Mut, RefLocation::Stack),
+            StartDeref(r) => known(deref(operand(r)?, 1))?,
+            StartDerefN { rf, depth } => known(deref(operand(rf)?, *depth))?,
+            Reborrow(r) | ReborrowMut(r) => {
+                let rf = operand(r)?.ref_type().cloned();
+                let rf = rf.ok_or(SsaError::UnknownType(index))?;
+                let kind = match op {
+                    Reborrow(_) => RefKind::Ref,
+                    _ => RefKind::Mut,
+                };
+                reference(rf.pointer, kind, rf.points_to)
+            }
+            Mp(r) | Clone(r) => operand(r)?.clone(),
+            SArrCreate0(len, t) => PointedType::s_arr(self.pool_type(index, *t)?, *len).into(),
+            SArrGet { arr_ref: r, .. } | SArrMut { arr_mut: r, .. } => {
+                let rf = operand(r)?.ref_type();
+                let element = known(
+                    rf.and_then(|rf| rf.pointer.s_arr())
+                        .map(|a| a.pointer.clone()),
+                )?;
+                let kind = match op {
+                    SArrGet { .. } => RefKind::Ref,
+                    _ => RefKind::Mut,
+                };
+                reference(element, kind, RefLocation::TransientOnStack)
+            }
+            CellNew(r) => PointedType::cell(operand(r)?.clone()).into(),
+            CellBorrow(r) | CellBorrowMut(r) => {
+                let rf = operand(r)?.ref_type();
+                let inner = known(rf.and_then(|rf| rf.pointer.cell()).cloned())?;
+                let kind = match op {
+                    CellBorrow(_) => RefKind::Ref,
+                    _ => RefKind::Mut,
+                };
+                reference(inner, kind, RefLocation::CellOnStack)
+            }
+            RcNew(r) => PointedType::Rc(operand(r)?.clone()).into(),
+            RcDeref(r) => {
+                let inner = known(rc_inner(operand(r)?).cloned())?;
+                reference(inner, RefKind::Ref, RefLocation::Rc)
+            }
+            RcDowngrade(r) => PointedType::Weak(known(rc_inner(operand(r)?).cloned())?).into(),
+            WeakUpgrade(r) => {
+                let inner = match operand(r)?.pointed() {
+                    Some(PointedType::Weak(inner)) => inner.clone(),
+                    _ => return Err(SsaError::UnknownType(index)),
+                };
+                PointedType::Rc(inner).into()
+            }
+            AnyWrap(_) => PointedType::Any.into(),
+            _ => return Ok(None),
+        };
+        Ok(Some(t))
+    }
+
+    /// Follow the types of the stack through the span
+    fn layout_span(&mut self, span: usize, layout: &mut Layout) -> Result<(), SsaError> {
+        for index in self.spans[span].start..self.spans[span].end {
+            let op = &self.flat[index];
+            match op {
+                StartScope => layout.scopes.push(layout.types.len()),
+                EndScope => {
+                    let height = layout.scopes.pop();
+                    layout
+                        .types
+                        .truncate(height.ok_or(SsaError::UnbalancedScope(index))?);
+                }
+                op => {
+                    accesses(op).ok_or(SsaError::Unsupported(index))?;
+                    for r in op.stack_refs() {
+                        if r.0 >= layout.types.len() {
+                            return Err(SsaError::InvalidStackRef(index));
+                        }
+                        if let TakeRef(_) | TakeMut(_) = op {
+                            self.escaped.insert(r.0);
+                        }
+                    }
+                    let types = &layout.types;
+                    if let Some(t) = self.pushed_type(index, op, |r| types.get(r.0))? {
+                        layout.types.push(t);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Layout of the stack at the start of the spans, `None` for the unreachable ones
+    fn layouts(&mut self) -> Result<Vec<Option<Layout>>, SsaError> {
+        let mut layouts = vec![None; self.spans.len()];
+        layouts[0] = Some(Layout {
+            types: Vec::new(),
+            scopes: Vec::new(),
+        });
+        let mut work = vec![0];
+        while let Some(span) = work.pop() {
+            let mut layout = layouts[span].clone().unwrap();
+            self.layout_span(span, &mut layout)?;
+            for &successor in &self.spans[span].successors {
+                match &layouts[successor] {
+                    None => {
+                        layouts[successor] = Some(layout.clone());
+                        work.push(successor);
+                    }
+                    Some(known) if *known != layout => {
+                        return Err(SsaError::StackMismatch(self.spans[successor].start))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        Ok(layouts)
+    }
+
+    /// Instructions of the span, `stack` holds the values on the stack
+    fn rename_span(
+        &self,
+        function: &mut Function,
+        span: usize,
+        stack: &mut Vec<Value>,
+        scopes: &mut Vec<usize>,
+        blocks: &[Option<BlockId>],
+    ) -> Result<Vec<Inst>, SsaError> {
+        let mut insts = Vec::new();
+        for index in self.spans[span].start..self.spans[span].end {
+            let mut op = self.flat[index].clone();
+            let access = match &op {
+                Label(_) => continue,
+                StartScope => {
+                    scopes.push(stack.len());
+                    &[]
+                }
+                EndScope => {
+                    stack.truncate(scopes.pop().ok_or(SsaError::UnbalancedScope(index))?);
+                    &[]
+                }
+                op => accesses(op).ok_or(SsaError::Unsupported(index))?,
+            };
+            let refs = op.stack_refs();
+            let mut operands = Vec::with_capacity(refs.len());
+            for (r, access) in refs.iter().zip(access) {
+                operands.push(Operand {
+                    value: *stack.get(r.0).ok_or(SsaError::InvalidStackRef(index))?,
+                    read: access.read,
+                    write: None,
+                });
+            }
+            let types = &function.types;
+            let pushed = self.pushed_type(index, &op, |r| stack.get(r.0).map(|v| &types[v.0]))?;
+
+            let mut written = Vec::new();
+            for ((operand, r), access) in operands.iter_mut().zip(&refs).zip(access) {
+                if access.write {
+                    let value = function.new_value(function.types[operand.value.0].clone());
+                    operand.write = Some(value);
+                    stack[r.0] = value;
+                    written.push(r.0);
+                }
+            }
+            let mut clobbers = Vec::new();
+            if writes_through_refs(&op) {
+                for &slot in self.escaped.range(..stack.len()) {
+                    if !written.contains(&slot) {
+                        let before = stack[slot];
+                        let after = function.new_value(function.types[before.0].clone());
+                        clobbers.push((before, after));
+                        stack[slot] = after;
+                    }
+                }
+            }
+            let pushed = pushed.map(|t| {
+                let value = function.new_value(t);
+                stack.push(value);
+                value
+            });
+            if let Some(label) = jump_label(&mut op) {
+                *label = blocks[*label].map_or(*label, |b| b.0);
+            }
+            insts.push(Inst {
+                op,
+                operands,
+                pushed,
+                clobbers,
+            });
+        }
+        Ok(insts)
+    }
+}
+
+impl Function {
+    /// Lift model code into SSA form, the types of the loaded constants are read from `pool`
+    ///
+    /// Scopes become `StartScope` and `EndScope` instructions and fused loads a load followed
+    /// by their operation, errors locate the opcodes by their index in the code spelled out
+    /// this way. Code with jumps by a raw offset or extension opcodes can't be lifted, and
+    /// every path to a label must reach it with the same types on the stack.
+    pub fn lift(ops: &[Opcode], pool: &ConstantPool) -> Result<Self, SsaError> {
+        let mut flat = Vec::new();
+        flatten(ops, &mut flat);
+        let mut lifter = Lifter {
+            pool,
+            flat,
+            spans: Vec::new(),
+            escaped: BTreeSet::new(),
+        };
+        lifter.split()?;
+        let layouts = lifter.layouts()?;
+
+        let mut blocks = vec![None; layouts.len()];
+        let mut spans = Vec::new();
+        for (span, layout) in layouts.iter().enumerate() {
+            if layout.is_some() {
+                blocks[span] = Some(BlockId(spans.len()));
+                spans.push(span);
+            }
+        }
+        let mut function = Function {
+            blocks: Vec::with_capacity(spans.len()),
+            types: Vec::new(),
+        };
+        for &span in &spans {
+            let successors: Vec<_> = lifter.spans[span]
+                .successors
+                .iter()
+                .filter_map(|&s| blocks[s])
+                .collect();
+            function.blocks.push(Block {
+                entry: Vec::new(),
+                scopes: layouts[span].as_ref().unwrap().scopes.clone(),
+                phis: Vec::new(),
+                insts: Vec::new(),
+                successors,
+                predecessors: Vec::new(),
+            });
+        }
+        for id in 0..function.blocks.len() {
+            for successor in function.blocks[id].successors.clone() {
+                function.blocks[successor.0].predecessors.push(BlockId(id));
+            }
+        }
+
+        let order = reverse_postorder(&function.blocks);
+        let mut position = vec![0; function.blocks.len()];
+        for (i, &block) in order.iter().enumerate() {
+            position[block] = i;
+        }
+        let mut exits: Vec<Vec<Value>> = vec![Vec::new(); function.blocks.len()];
+        for &block in &order {
+            let predecessors = &function.blocks[block].predecessors;
+            let mut stack = match predecessors.as_slice() {
+                [] => Vec::new(),
+                [p] if position[p.0] < position[block] => exits[p.0].clone(),
+                _ => {
+                    let types = &layouts[spans[block]].as_ref().unwrap().types;
+                    let mut entry = Vec::with_capacity(types.len());
+                    for t in types {
+                        let value = function.new_value(t.clone());
+                        function.blocks[block].phis.push(Phi {
+                            value,
+                            incoming: Vec::new(),
+                        });
+                        entry.push(value);
+                    }
+                    entry
+                }
+            };
+            function.blocks[block].entry = stack.clone();
+            let mut scopes = function.blocks[block].scopes.clone();
+            let insts = lifter.rename_span(
+                &mut function,
+                spans[block],
+                &mut stack,
+                &mut scopes,
+                &blocks,
+            )?;
+            function.blocks[block].insts = insts;
+            exits[block] = stack;
+        }
+
+        for block in &mut function.blocks {
+            let predecessors = &block.predecessors;
+            for (slot, phi) in block.phis.iter_mut().enumerate() {
+                phi.incoming = predecessors
+                    .iter()
+                    .map(|&p| (p, exits[p.0][slot]))
+                    .collect();
+            }
+        }
+        function.remove_trivial_phis();
+        Ok(function)
+    }
+
+    /// Replace the phis that merge a single value other than themselves by that value
+    fn remove_trivial_phis(&mut self) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in 0..self.blocks.len() {
+                let mut i = 0;
+                while i < self.blocks[block].phis.len() {
+                    let phi = &self.blocks[block].phis[i];
+                    let mut others = phi
+                        .incoming
+                        .iter()
+                        .map(|&(_, v)| v)
+                        .filter(|&v| v != phi.value);
+                    let first = others.next();
+                    let value = phi.value;
+                    match first {
+                        Some(single) if others.all(|v| v == single) => {
+                            self.blocks[block].phis.remove(i);
+                            self.replace_uses(value, single);
+                            changed = true;
+                        }
+                        _ => i += 1,
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Blocks reachable from the entry, in reverse postorder
+fn reverse_postorder(blocks: &[Block]) -> Vec<usize> {
+    let mut order = Vec::with_capacity(blocks.len());
+    if blocks.is_empty() {
+        return order;
+    }
+    let mut visited = vec![false; blocks.len()];
+    let mut stack = vec![(0, 0)];
+    visited[0] = true;
+    while let Some((block, next)) = stack.pop() {
+        match blocks[block].successors.get(next) {
+            Some(&BlockId(successor)) => {
+                stack.push((block, next + 1));
+                if !visited[successor] {
+                    visited[successor] = true;
+                    stack.push((successor, 0));
+                }
+            }
+            None => order.push(block),
+        }
+    }
+    order.reverse();
+    order
+}
@@ -0,0 +1,84 @@
+use std::collections::HashSet;
+
+use super::{Function, Value};
+use crate::code::refs::StackRef;
+use crate::error::SsaError;
+use crate::model::Opcode::{self, *};
+
+impl Function {
+    /// Turn the function back into model code
+    ///
+    /// The stack refs of every instruction are set to the slots its operands are in, so
+    /// instructions may be removed or added as long as the values they use stay on the stack.
+    /// Every edge must bring the values the phis of its target expect in their slots.
+    pub fn lower(&self) -> Result<Vec<Opcode>, SsaError> {
+        let mut targets = HashSet::new();
+        for block in &self.blocks {
+            for inst in &block.insts {
+                if let J { label } | JC { label, .. } | JCmp { label, .. } = inst.op {
+                    targets.insert(label);
+                }
+            }
+        }
+
+        let mut ops = Vec::new();
+        for (id, block) in self.blocks.iter().enumerate() {
+            if targets.contains(&id) {
+                ops.push(Label(id));
+            }
+            let mut stack = block.entry.clone();
+            let mut scopes = block.scopes.clone();
+            for inst in &block.insts {
+                let mut op = inst.op.clone();
+                let slot = |value: Value| {
+                    let slot = stack.iter().rposition(|&v| v == value);
+                    slot.ok_or(SsaError::NotOnStack(value.0, id))
+                };
+                let slots = inst
+                    .operands
+                    .iter()
+                    .map(|o| slot(o.value))
+                    .collect::<Result<Vec<_>, _>>()?;
+                for (r, &slot) in op.stack_refs_mut().into_iter().zip(&slots) {
+                    *r = StackRef(slot);
+                }
+                for (operand, &slot) in inst.operands.iter().zip(&slots) {
+                    if let Some(value) = operand.write {
+                        stack[slot] = value;
+                    }
+                }
+                for &(before, after) in &inst.clobbers {
+                    if let Some(slot) = stack.iter().position(|&v| v == before) {
+                        stack[slot] = after;
+                    }
+                }
+                match op {
+                    StartScope => scopes.push(stack.len()),
+                    EndScope => {
+                        let height = scopes.pop().ok_or(SsaError::EdgeMismatch(id, id))?;
+                        stack.truncate(height);
+                    }
+                    _ => {}
+                }
+                stack.extend(inst.pushed);
+                ops.push(op);
+            }
+
+            for &successor in &block.successors {
+                let target = &self.blocks[successor.0];
+                let expected = target.entry.iter().map(|&value| {
+                    let phi = target.phis.iter().find(|phi| phi.value == value);
+                    let incoming = phi.and_then(|phi| {
+                        let incoming = phi.incoming.iter().find(|&&(from, _)| from.0 == id);
+                        incoming.map(|&(_, v)| v)
+                    });
+                    incoming.unwrap_or(value)
+                });
+                if !stack.iter().copied().eq(expected) || scopes != target.scopes {
+                    return Err(SsaError::EdgeMismatch(id, successor.0));
+                }
+            }
+        }
+        Ok(ops)
+    }
+}
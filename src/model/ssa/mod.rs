@@ -0,0 +1,198 @@
+//! Static single assignment form of model code
+//!
+//! Opcodes overwrite stack slots in place, in SSA form every write defines a new [`Value`]
+//! instead, and the values that reach a block on different paths are merged by [`Phi`]s.
+//! Every value has the [`VmType`] of the slot that holds it.
+//!
+//! Instructions keep their model opcode along with the values of its stack refs, so
+//! [`Function::lower`] turns the code back into model opcodes whose stack refs are those of
+//! the slots the values end up in.
+
+use std::fmt::{self, Display, Formatter};
+
+use super::Opcode;
+pub use crate::code::cfg::BlockId;
+use crate::types::VmType;
+
+mod lift;
+mod lower;
+
+/// A value of the code, defined once
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Hash)]
+pub struct Value(pub usize);
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+/// A stack ref of an instruction
+#[derive(Debug, Clone)]
+pub struct Operand {
+    /// Value in the slot before the opcode runs
+    pub value: Value,
+    /// If the opcode reads the value, the result of an operation is only written
+    pub read: bool,
+    /// Value the opcode leaves in the slot, if it writes to it
+    pub write: Option<Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Inst {
+    /// The opcode, its stack refs are set by the lowering, jumps hold the block they go to
+    pub op: Opcode,
+    /// One for each stack ref of the opcode, in their order
+    pub operands: Vec<Operand>,
+    /// Value the opcode pushes
+    pub pushed: Option<Value>,
+    /// Values of the slots the opcode may change through references, before and after
+    pub clobbers: Vec<(Value, Value)>,
+}
+
+impl Inst {
+    /// Values the instruction reads
+    pub fn uses(&self) -> impl Iterator<Item = Value> + '_ {
+        let operands = self.operands.iter().filter(|o| o.read).map(|o| o.value);
+        operands.chain(self.clobbers.iter().map(|&(before, _)| before))
+    }
+
+    /// Values the instruction defines
+    pub fn defs(&self) -> impl Iterator<Item = Value> + '_ {
+        let writes = self.operands.iter().filter_map(|o| o.write);
+        writes
+            .chain(self.pushed)
+            .chain(self.clobbers.iter().map(|&(_, after)| after))
+    }
+}
+
+/// A value merged from the ones that reach the block from its predecessors
+#[derive(Debug, Clone)]
+pub struct Phi {
+    pub value: Value,
+    pub incoming: Vec<(BlockId, Value)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    /// Values on the stack at the start of the block, by slot
+    pub entry: Vec<Value>,
+    /// Number of values on the stack when each of the open scopes was started
+    pub scopes: Vec<usize>,
+    pub phis: Vec<Phi>,
+    pub insts: Vec<Inst>,
+    /// Blocks control goes to after this one, the fallthrough first
+    pub successors: Vec<BlockId>,
+    pub predecessors: Vec<BlockId>,
+}
+
+/// Code in SSA form, its blocks are in the order of the code
+///
+/// Only the blocks reachable from the start of the code are kept, the last instruction of
+/// a block that does not end with `J` falls through to the next block.
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub blocks: Vec<Block>,
+    /// Type of every value, by its index
+    types: Vec<VmType>,
+}
+
+impl Function {
+    pub fn value_type(&self, value: Value) -> Option<&VmType> {
+        self.types.get(value.0)
+    }
+
+    /// Number of values defined so far, removed values included
+    pub fn value_count(&self) -> usize {
+        self.types.len()
+    }
+
+    /// A new value of type `t`, for the instructions a pass adds
+    pub fn new_value(&mut self, t: VmType) -> Value {
+        self.types.push(t);
+        Value(self.types.len() - 1)
+    }
+
+    /// Replace every use of `old` by `new`, the definition of `old` is kept
+    pub fn replace_uses(&mut self, old: Value, new: Value) {
+        let replace = |v: &mut Value| {
+            if *v == old {
+                *v = new;
+            }
+        };
+        for block in &mut self.blocks {
+            block.entry.iter_mut().for_each(replace);
+            for phi in &mut block.phis {
+                phi.incoming.iter_mut().for_each(|(_, v)| replace(v));
+            }
+            for inst in &mut block.insts {
+                inst.operands.iter_mut().for_each(|o| replace(&mut o.value));
+                inst.clobbers.iter_mut().for_each(|(v, _)| replace(v));
+            }
+        }
+    }
+}
+
+/// Name of the opcode, without its operands
+fn name(op: &Opcode) -> String {
+    let debug = format!("{:?}", op);
+    let end = debug
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(debug.len());
+    debug[..end].to_owned()
+}
+
+fn list(f: &mut Formatter<'_>, values: impl IntoIterator<Item = Value>) -> fmt::Result {
+    for (i, value) in values.into_iter().enumerate() {
+        let separator = if i == 0 { "" } else { ", " };
+        write!(f, "{}{}", separator, value)?;
+    }
+    Ok(())
+}
+
+impl Display for Inst {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let writes = self.operands.iter().filter_map(|o| o.write);
+        let defs: Vec<_> = writes.chain(self.pushed).collect();
+        if !defs.is_empty() {
+            list(f, defs)?;
+            write!(f, " = ")?;
+        }
+        write!(f, "{}", name(&self.op))?;
+        let reads: Vec<_> = self.operands.iter().filter(|o| o.read).collect();
+        if !reads.is_empty() {
+            write!(f, " ")?;
+            list(f, reads.iter().map(|o| o.value))?;
+        }
+        if let Opcode::J { label } | Opcode::JC { label, .. } | Opcode::JCmp { label, .. } = self.op
+        {
+            write!(f, " -> b{}", label)?;
+        }
+        for (before, after) in &self.clobbers {
+            write!(f, " [{} -> {}]", before, after)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (id, block) in self.blocks.iter().enumerate() {
+            write!(f, "b{}: [", id)?;
+            list(f, block.entry.iter().copied())?;
+            writeln!(f, "]")?;
+            for phi in &block.phis {
+                write!(f, "    {} = phi", phi.value)?;
+                for (i, (from, value)) in phi.incoming.iter().enumerate() {
+                    let separator = if i == 0 { " " } else { ", " };
+                    write!(f, "{}b{}: {}", separator, from.0, value)?;
+                }
+                writeln!(f)?;
+            }
+            for inst in &block.insts {
+                writeln!(f, "    {}", inst)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,104 +1,300 @@
+use std::convert::TryFrom;
+
 use arrayvec::ArrayVec;
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
 
-#[repr(u16)]
-#[derive(Debug, Eq, PartialEq, Copy, Clone, ToPrimitive, FromPrimitive)]
-pub enum Opcode {
-    U64Ld0 = 0,
-    I64Ld0 = 1,
-    LdTyped0 = 2,
-    LdType = 3,
-    LdUnit = 4,
-    LdTrue = 5,
-    LdFalse = 6,
-    LdSS = 8,
-    // u ops
-    UAdd = 10,
-    USub = 11,
-    UMul = 12,
-    UDiv = 13,
-    URem = 14,
-    // i ops
-    IAdd = 15,
-    ISub = 16,
-    IMul = 17,
-    IDiv = 18,
-    IRem = 19,
-    INeg = 20,
-
-    // f ops
-    FAdd = 21,
-    FSub = 22,
-    FMul = 23,
-    FDiv = 24,
-    FRem = 25,
-    FNeg = 26,
-
-    // Todo: bool ops
-    // the behaviour of those ops are type independent,
-    // assuming the type is a primitive
-    BAnd = 27,
-    BOr = 28,
-    BNot = 29,
-    BBe = 30,
-    BXor = 31,
-
-    // logical  ops
-    LAnd = 32,
-    LOr = 33,
-    LNot = 34,
-    LXor = 35,
-
-    // shifts,
-    Shl = 36,
-    Shr = 37,
-    RotL = 38,
-    RotR = 39,
-    // comparisons
-    Ge = 40,
-    Gt = 41,
-    Le = 42,
-    Lt = 43,
-    Eq = 44,
-    Ne = 45,
-    // TODO: jump
-    /// Jump <offset>
-    J = 46,
-    /// Jump <offset> <condition>
-    JC = 47,
-    StartScope = 48,
-    EndScope = 49,
-    // TODO: call
-    /// Call <Module> <FnRef>
-    Call = 50, // Call <POOL REF>
-    Ret = 51, // Return <Value>
-    /// StartDeref <Ref>
-    StartDeref = 52,
-    /// EndDeref
-    EndDeref = 53,
-    /// TakeRef <Value>
-    TakeRef = 54,
-    /// TakeMut <Value>
-    TakeMut = 55,
-
-    Mv = 60,
-    Mp = 61,
-    // TODO: arrays if have time
-    /// SArrCreate0 <Size> <Type of array>
-    SArrCreate0 = 80,
-    SArrRef = 81,
-    SArrMut = 82,
-    SArrSet = 83,
-
-    /// SArrXCG <Mut Array Ref> <Index> <Value/OldValue>
-    SArrXCG = 84,
-    //
-    TraceStackValue = 254,
-    /// Handle wide, not an actually  a valid value for opcode
-    HWide = 255,
+use crate::code::refs::CodeRef;
+use crate::decoder::tags;
+
+/// The table of all the opcodes, the only place where an opcode is defined
+///
+/// Each entry gives the number of an opcode, its operands in the order they follow it
+/// in the bytecode, each with the tag the decoder gives it, and the function that
+/// interprets it. The table is passed to `$callback`, which derives something from it:
+/// the [`Opcode`] enum and its operands here, the handler tables of the interpreter and
+/// through them the decoder and the encoding of the model.
+/// Opcodes handled by `noop` are reserved, they are neither decoded nor interpreted.
+/// `HWide` starts the extension opcodes, registered at runtime in a
+/// [`crate::vm::ExtensionTable`].
+macro_rules! for_each_opcode {
+    ($callback: ident) => {
+        $callback! {
+            U64Ld0 = 0 [] => handle_u64_ld0,
+            I64Ld0 = 1 [] => handle_i64_ld0,
+            LdTyped0 = 2 [Pool(TYPE)] => handle_ld_typed0,
+            LdType = 3 [Pool(TYPE), Pool(VALUE)] => handle_ld_type,
+            LdUnit = 4 [] => handle_ld_unit,
+            LdTrue = 5 [] => handle_ld_true,
+            LdFalse = 6 [] => handle_ld_false,
+            /// LdConst <Type> <Value>, the loaded value is immutable
+            LdConst = 7 [Pool(TYPE), Pool(VALUE)] => handle_ld_const,
+            LdSS = 8 [Pool(VALUE)] => handle_ld_ss,
+            /// LdImm <Immediate>
+            LdImm = 9 [Immediate(IMMEDIATE)] => handle_ld_imm,
+            // u ops
+            UAdd = 10 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_u_add,
+            USub = 11 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_u_sub,
+            UMul = 12 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_u_mul,
+            UDiv = 13 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_u_div,
+            URem = 14 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_u_rem,
+            // i ops
+            IAdd = 15 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_i_add,
+            ISub = 16 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_i_sub,
+            IMul = 17 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_i_mul,
+            IDiv = 18 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_i_div,
+            IRem = 19 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_i_rem,
+            INeg = 20 [Stack(RESULT), Stack(OP)] => handle_i_neg,
+
+            // f ops
+            FAdd = 21 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_f_add,
+            FSub = 22 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_f_sub,
+            FMul = 23 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_f_mul,
+            FDiv = 24 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_f_div,
+            FRem = 25 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_f_rem,
+            FNeg = 26 [Stack(RESULT), Stack(OP)] => handle_f_neg,
+
+            // Todo: bool ops
+            // the behaviour of those ops are type independent,
+            // assuming the type is a primitive
+            BAnd = 27 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_b_and,
+            BOr = 28 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_b_or,
+            BNot = 29 [Stack(RESULT), Stack(OP)] => handle_b_not,
+            BBe = 30 [Stack(RESULT), Stack(OP)] => handle_b_be,
+            BXor = 31 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_b_xor,
+
+            // logical  ops
+            LAnd = 32 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_l_and,
+            LOr = 33 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_l_or,
+            LNot = 34 [Stack(RESULT), Stack(OP)] => handle_l_not,
+            LXor = 35 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_l_xor,
+
+            // shifts,
+            Shl = 36 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_shl,
+            Shr = 37 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_shr,
+            RotL = 38 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_rotl,
+            RotR = 39 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_rotr,
+            // comparisons
+            Ge = 40 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_ge,
+            Gt = 41 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_gt,
+            Le = 42 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_le,
+            Lt = 43 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_lt,
+            Eq = 44 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_eq,
+            Ne = 45 [Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_ne,
+            /// Jump <offset>
+            J = 46 [Jump(OFFSET)] => handle_j,
+            /// Jump <offset> <condition>
+            JC = 47 [Jump(OFFSET), Stack(CONDITION)] => handle_jc,
+            StartScope = 48 [] => handle_start_scope,
+            EndScope = 49 [] => handle_end_scope,
+            // TODO: call
+            /// Call <Module> <FnRef>
+            Call = 50 [] => noop,
+            /// Ret <Value>
+            Ret = 51 [] => noop,
+            /// StartDeref <Ref>
+            StartDeref = 52 [Stack(VALUE)] => handle_start_deref,
+            /// EndDeref
+            EndDeref = 53 [] => handle_end_deref,
+            /// TakeRef <Value>
+            TakeRef = 54 [Stack(VALUE)] => handle_take_ref,
+            /// TakeMut <Value>
+            TakeMut = 55 [Stack(VALUE)] => handle_take_mut,
+            /// Reborrow <Ref>
+            Reborrow = 56 [Stack(VALUE)] => handle_reborrow,
+            /// ReborrowMut <Mut Ref>
+            ReborrowMut = 57 [Stack(VALUE)] => handle_reborrow_mut,
+            /// StartDerefN <Ref> <Depth>
+            StartDerefN = 58 [Stack(VALUE), Value(DEPTH)] => handle_start_deref_n,
+
+            Mv = 60 [Stack(RESULT), Stack(OP)] => handle_mv,
+            Mp = 61 [Stack(OP)] => handle_mp,
+            /// Drop <Value>
+            Drop = 62 [Stack(OP)] => handle_drop,
+            /// Clone <Value>
+            Clone = 63 [Stack(OP)] => handle_clone,
+            /// Swap <Value> <Value>
+            Swap = 64 [Stack(OP1), Stack(OP2)] => handle_swap,
+            /// Replace <Mut Ref> <Value/OldValue>
+            Replace = 65 [Stack(MUT_REF), Stack(VALUE)] => handle_replace,
+            /// Freeze <Value>
+            Freeze = 66 [Stack(OP)] => handle_freeze,
+            // ops with an immediate second operand of the same type as the first one
+            /// AddImm <Result> <Op> <Immediate>
+            AddImm = 67 [Stack(RESULT), Stack(OP1), Immediate(IMMEDIATE)] => handle_add_imm,
+            /// SubImm <Result> <Op> <Immediate>
+            SubImm = 68 [Stack(RESULT), Stack(OP1), Immediate(IMMEDIATE)] => handle_sub_imm,
+            /// GeImm <Result> <Op> <Immediate>
+            GeImm = 69 [Stack(RESULT), Stack(OP1), Immediate(IMMEDIATE)] => handle_ge_imm,
+            GtImm = 70 [Stack(RESULT), Stack(OP1), Immediate(IMMEDIATE)] => handle_gt_imm,
+            LeImm = 71 [Stack(RESULT), Stack(OP1), Immediate(IMMEDIATE)] => handle_le_imm,
+            LtImm = 72 [Stack(RESULT), Stack(OP1), Immediate(IMMEDIATE)] => handle_lt_imm,
+            EqImm = 73 [Stack(RESULT), Stack(OP1), Immediate(IMMEDIATE)] => handle_eq_imm,
+            NeImm = 74 [Stack(RESULT), Stack(OP1), Immediate(IMMEDIATE)] => handle_ne_imm,
+            // TODO: arrays if have time
+            /// SArrCreate0 <Size> <Type of array>
+            SArrCreate0 = 80 [Size(SIZE), Pool(TYPE)] => handle_s_arr_create_0,
+            SArrRef = 81 [Stack(S_ARR_REF), Stack(IDX)] => handle_s_arr_get,
+            SArrMut = 82 [Stack(S_ARR_MUT), Stack(IDX)] => handle_s_arr_mut,
+            /// SArrSet <Mut Array Ref> <Index> <Value>
+            SArrSet = 83 [Stack(S_ARR_MUT), Stack(IDX), Stack(VALUE)] => noop,
+
+            /// SArrXCG <Mut Array Ref> <Index> <Value/OldValue>
+            SArrXCG = 84 [Stack(S_ARR_MUT), Stack(IDX), Stack(VALUE)] => noop,
+            /// LdStatic <Array>, the array is an immutable static
+            LdStatic = 85 [Pool(VALUE)] => handle_ld_static,
+
+            /// CellNew <Value>
+            CellNew = 90 [Stack(VALUE)] => handle_cell_new,
+            /// CellBorrow <Cell Ref>
+            CellBorrow = 91 [Stack(CELL_REF)] => handle_cell_borrow,
+            /// CellBorrowMut <Cell Ref>
+            CellBorrowMut = 92 [Stack(CELL_REF)] => handle_cell_borrow_mut,
+            /// RcNew <Value>
+            RcNew = 93 [Stack(VALUE)] => handle_rc_new,
+            /// RcDeref <Rc>
+            RcDeref = 94 [Stack(RC)] => handle_rc_deref,
+            /// RcDowngrade <Rc>
+            RcDowngrade = 95 [Stack(RC)] => handle_rc_downgrade,
+            /// WeakUpgrade <Weak>
+            WeakUpgrade = 96 [Stack(WEAK)] => handle_weak_upgrade,
+            /// WeakIsAlive <Weak>
+            WeakIsAlive = 97 [Stack(WEAK)] => handle_weak_is_alive,
+
+            /// AnyWrap <Value>
+            AnyWrap = 100 [Stack(VALUE)] => handle_any_wrap,
+            /// AnyIs <Any> <Type>
+            AnyIs = 101 [Stack(ANY), Pool(TYPE)] => handle_any_is,
+            /// AnyDowncast <Any> <Type>
+            AnyDowncast = 102 [Stack(ANY), Pool(TYPE)] => handle_any_downcast,
+
+            // fused opcodes, each does the same as the sequence it replaces
+            /// JGe <offset> <Result> <Op1> <Op2>, Ge followed by JC on its result
+            JGe = 110 [Jump(OFFSET), Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_j_ge,
+            JGt = 111 [Jump(OFFSET), Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_j_gt,
+            JLe = 112 [Jump(OFFSET), Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_j_le,
+            JLt = 113 [Jump(OFFSET), Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_j_lt,
+            JEq = 114 [Jump(OFFSET), Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_j_eq,
+            JNe = 115 [Jump(OFFSET), Stack(RESULT), Stack(OP1), Stack(OP2)] => handle_j_ne,
+            /// UAddGe <Counter> <Step> <Result> <Limit>,
+            /// UAdd of the step to the counter followed by Ge of the counter and the limit
+            UAddGe = 116 [Stack(COUNTER), Stack(STEP), Stack(RESULT), Stack(LIMIT)]
+                => handle_u_add_ge,
+            UAddGt = 117 [Stack(COUNTER), Stack(STEP), Stack(RESULT), Stack(LIMIT)]
+                => handle_u_add_gt,
+            UAddLe = 118 [Stack(COUNTER), Stack(STEP), Stack(RESULT), Stack(LIMIT)]
+                => handle_u_add_le,
+            UAddLt = 119 [Stack(COUNTER), Stack(STEP), Stack(RESULT), Stack(LIMIT)]
+                => handle_u_add_lt,
+            UAddEq = 120 [Stack(COUNTER), Stack(STEP), Stack(RESULT), Stack(LIMIT)]
+                => handle_u_add_eq,
+            UAddNe = 121 [Stack(COUNTER), Stack(STEP), Stack(RESULT), Stack(LIMIT)]
+                => handle_u_add_ne,
+            /// LdOp <Opcode> <Type> <Value> <Result> <Op1> <Op2>,
+            /// LdType followed by a binary operation
+            LdOp = 122 [
+                BiOp(OPCODE), Pool(TYPE), Pool(VALUE), Stack(RESULT), Stack(OP1), Stack(OP2)
+            ] => handle_ld_op,
+            /// ScopedLdOp <Opcode> <Type> <Value> <Result> <Op1> <Op2>, LdOp in a scope of its own
+            ScopedLdOp = 123 [
+                BiOp(OPCODE), Pool(TYPE), Pool(VALUE), Stack(RESULT), Stack(OP1), Stack(OP2)
+            ] => handle_scoped_ld_op,
+            //
+            TraceStackValue = 254 [Stack(VALUE)] => handle_trace_stack_value,
+            /// Prefix of the extension opcodes, followed by the number of the extension
+            HWide = 255 [] => handle_wide,
+        }
+    };
+}
+
+pub(crate) use for_each_opcode;
+
+/// What an operand of an opcode is, which decides how it is encoded
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum OperandKind {
+    /// Ref to a value on the stack
+    Stack,
+    /// Ref to a constant of the pool
+    Pool,
+    /// Plain number, encoded the same way as a ref
+    Value,
+    /// Number of a binary operation, encoded the same way as a ref
+    BiOp,
+    /// Size of an array, encoded as an offset
+    Size,
+    /// Jump offset, relative to the start of the opcode
+    Jump,
+    /// Value stored in the bytecode
+    Immediate,
+}
+
+impl OperandKind {
+    /// If `code_ref` is an operand of this kind
+    pub fn accepts(self, code_ref: &CodeRef) -> bool {
+        match (self, code_ref) {
+            (OperandKind::Stack, CodeRef::Stack(_))
+            | (OperandKind::Pool, CodeRef::Pool(_))
+            | (OperandKind::Value, CodeRef::Value(_))
+            | (OperandKind::Size, CodeRef::Offset(_))
+            | (OperandKind::Jump, CodeRef::Jump(_))
+            | (OperandKind::Immediate, CodeRef::Immediate(_)) => true,
+            (OperandKind::BiOp, CodeRef::Value(op)) => Opcode::bi_op_from(*op).is_some(),
+            _ => false,
+        }
+    }
+}
+
+/// An operand of an opcode, along with the tag the decoder gives it
+#[derive(Debug, Copy, Clone)]
+pub struct Operand {
+    pub kind: OperandKind,
+    pub tag: &'static str,
 }
 
+macro_rules! opcode_enum {
+    ($(
+        $(#[$meta: meta])*
+        $name: ident = $num: literal [$($kind: ident($tag: ident)),* $(,)?] => $handler: ident,
+    )*) => {
+        #[repr(u16)]
+        #[derive(Debug, Eq, PartialEq, Copy, Clone, ToPrimitive, FromPrimitive)]
+        pub enum Opcode {
+            $($(#[$meta])* $name = $num,)*
+        }
+
+        impl Opcode {
+            /// All the opcodes, in the order of their numbers
+            pub fn all() -> &'static [Opcode] {
+                &[$(Opcode::$name),*]
+            }
+
+            /// Operands of the opcode, in the order they follow it in the bytecode
+            pub fn operands(self) -> &'static [Operand] {
+                match self {
+                    $(Opcode::$name => &[$(Operand {
+                        kind: OperandKind::$kind,
+                        tag: tags::$tag,
+                    }),*],)*
+                }
+            }
+
+            /// If the opcode is decoded and interpreted on its own
+            ///
+            /// Reserved opcodes are not, nor is `HWide`, which is a prefix
+            pub fn is_supported(self) -> bool {
+                match self {
+                    $(Opcode::$name => opcode_enum!(@supported $handler),)*
+                }
+            }
+        }
+    };
+    (@supported noop) => { false };
+    (@supported handle_wide) => { false };
+    (@supported $handler: ident) => { true };
+}
+
+for_each_opcode!(opcode_enum);
+
 pub enum OpcodeKind {
     Single,
     Double,
@@ -135,6 +331,52 @@ impl Opcode {
         vec
     }
 
+    /// Operations that take a result and two operands from the stack
+    pub fn is_bi_op(self) -> bool {
+        use Opcode::*;
+        matches!(
+            self,
+            UAdd | USub
+                | UMul
+                | UDiv
+                | URem
+                | IAdd
+                | ISub
+                | IMul
+                | IDiv
+                | IRem
+                | FAdd
+                | FSub
+                | FMul
+                | FDiv
+                | FRem
+                | BAnd
+                | BOr
+                | BXor
+                | LAnd
+                | LOr
+                | LXor
+                | Shl
+                | Shr
+                | RotL
+                | RotR
+                | Ge
+                | Gt
+                | Le
+                | Lt
+                | Eq
+                | Ne
+        )
+    }
+
+    /// The binary operation whose number is `value`, as `LdOp` stores it
+    pub fn bi_op_from(value: usize) -> Option<Self> {
+        u8::try_from(value)
+            .ok()
+            .and_then(Self::single)
+            .filter(|op| op.is_bi_op())
+    }
+
     pub fn size(self) -> usize {
         match self.to_type() {
             OpcodeType::Single(_) => 1,
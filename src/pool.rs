@@ -1,17 +1,25 @@
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::mem::size_of;
 
-use crate::code::refs::PoolRef;
-use crate::types::PrimitiveType;
+use crate::code::refs::{CodeRef, PoolRef};
+use crate::code::Code;
+use crate::decoder::tags;
+use crate::error::{PoolError, PoolProblem};
+use crate::opcodes::Opcode;
+use crate::stack::data::{IntoStackData, StackData};
+use crate::types::{PointedType, PrimitiveType, VmType};
+use crate::vm::ExtensionTable;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Constant {
     Value([u8; 16]),
     String(Box<str>),
     Type(PrimitiveType),
-    // TODO
     /// Pointed type for things like Arr->i32, Ref->u64, etc.
-    PointedType,
+    PointedType(PointedType),
+    /// Typed element list, loaded as an array by `LdStatic`, see [`Constant::array`]
+    Array(VmType, Box<[Constant]>),
 }
 
 macro_rules! impl_from {
@@ -29,12 +37,122 @@ macro_rules! impl_from {
 
 impl_from!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
 
+impl From<bool> for Constant {
+    fn from(v: bool) -> Self {
+        Constant::from(v as u8)
+    }
+}
+
+impl From<char> for Constant {
+    fn from(v: char) -> Self {
+        Constant::from(v as u32)
+    }
+}
+
+/// Kind of a [`Constant`], without its payload
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ConstantKind {
+    Value,
+    String,
+    Type,
+    PointedType,
+    Array,
+}
+
+impl Constant {
+    pub fn kind(&self) -> ConstantKind {
+        match self {
+            Constant::Value(_) => ConstantKind::Value,
+            Constant::String(_) => ConstantKind::String,
+            Constant::Type(_) => ConstantKind::Type,
+            Constant::PointedType(_) => ConstantKind::PointedType,
+            Constant::Array(_, _) => ConstantKind::Array,
+        }
+    }
+
+    /// Array literal of elements of type `element`
+    ///
+    /// Elements are values of a primitive type, strings or arrays themselves, like
+    /// the rows of a table.
+    pub fn array<T: Into<Constant>>(
+        element: impl Into<VmType>,
+        values: impl IntoIterator<Item = T>,
+    ) -> Self {
+        let values = values.into_iter().map(Into::into).collect();
+        Constant::Array(element.into(), values)
+    }
+
+    /// Type of the array, if every element is of the element type of the array
+    pub fn array_type(&self) -> Option<VmType> {
+        match self {
+            Constant::Array(element, values)
+                if element.is_zeroable() && values.iter().all(|v| v.is_element_of(element)) =>
+            {
+                Some(PointedType::s_arr(element.clone(), values.len()).into())
+            }
+            _ => None,
+        }
+    }
+
+    fn is_element_of(&self, t: &VmType) -> bool {
+        match (t, self) {
+            (VmType::Primitive(PrimitiveType::SStr), Constant::String(_)) => true,
+            (VmType::Primitive(p), Constant::Value(v)) => p.is_single() && is_valid_value(*p, v),
+            (VmType::PointedType(_), Constant::Array(_, _)) => {
+                self.array_type().as_ref() == Some(t)
+            }
+            _ => false,
+        }
+    }
+
+    /// Append the stack data of the value, strings point into the constant
+    fn write_stack_data(&self, data: &mut Vec<StackData>) {
+        match self {
+            Constant::Value(v) => data.push(v[..8].try_into().unwrap()),
+            Constant::String(s) => {
+                data.push((s.as_ptr() as usize).into_stack_data());
+                data.push(s.len().into_stack_data());
+            }
+            Constant::Array(_, values) => values.iter().for_each(|v| v.write_stack_data(data)),
+            Constant::Type(_) | Constant::PointedType(_) => {}
+        }
+    }
+}
+
+/// If `value` is a valid payload of type `t`, only bools and chars have invalid ones
+fn is_valid_value(t: PrimitiveType, value: &[u8; 16]) -> bool {
+    let rest_is_zero = |from: usize| value[from..].iter().all(|&b| b == 0);
+    match t {
+        PrimitiveType::Bool => value[0] <= 1 && rest_is_zero(1),
+        PrimitiveType::Char => {
+            let code = u32::from_le_bytes(value[..4].try_into().unwrap());
+            std::char::from_u32(code).is_some() && rest_is_zero(4)
+        }
+        _ => true,
+    }
+}
+
 impl From<PrimitiveType> for Constant {
     fn from(t: PrimitiveType) -> Self {
         Constant::Type(t)
     }
 }
 
+impl From<PointedType> for Constant {
+    fn from(t: PointedType) -> Self {
+        Constant::PointedType(t)
+    }
+}
+
+impl From<VmType> for Constant {
+    fn from(t: VmType) -> Self {
+        match t {
+            VmType::Primitive(p) => Constant::Type(p),
+            VmType::PointedType(p) => Constant::PointedType(*p),
+        }
+    }
+}
+
 impl From<&'_ str> for Constant {
     fn from(obj: &str) -> Self {
         Constant::String(Box::from(obj))
@@ -64,6 +182,24 @@ impl ConstantPool {
         }
     }
 
+    /// The type at `index`, either primitive or pointed
+    pub fn get_vm_type(&self, index: PoolRef) -> Option<VmType> {
+        match self.get(index)? {
+            Constant::Type(t) => Some((*t).into()),
+            Constant::PointedType(t) => Some(t.clone().into()),
+            _ => None,
+        }
+    }
+
+    /// The type of the array at `index` along with its stack data
+    pub(crate) fn get_static(&self, index: PoolRef) -> Option<(VmType, Vec<StackData>)> {
+        let constant = self.get(index)?;
+        let t = constant.array_type()?;
+        let mut data = Vec::with_capacity(t.size());
+        constant.write_stack_data(&mut data);
+        Some((t, data))
+    }
+
     pub fn get_s_str(&self, index: PoolRef) -> Option<&str> {
         if let Some(Constant::String(s)) = self.get(index) {
             Some(s)
@@ -87,4 +223,136 @@ impl ConstantPool {
             None
         }
     }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Check that every pool ref of `code` points to a constant of the kind its opcode reads
+    pub fn validate(&self, code: &Code) -> Result<(), PoolError> {
+        self.validate_with(code, &ExtensionTable::default())
+    }
+
+    /// Same as [`validate`](Self::validate), `code` may contain the opcodes of `extensions`
+    ///
+    /// Types are read from type operands, values from value operands, except for `LdSS`
+    /// that reads a string and `LdStatic` that reads an array. Values loaded along with a
+    /// bool or char type must be one, and the type of a value must be primitive, other
+    /// types may be pointed.
+    pub fn validate_with(&self, code: &Code, extensions: &ExtensionTable) -> Result<(), PoolError> {
+        let decoded = code.decode_with(extensions);
+        let mut problems = Vec::new();
+        let mut offset = 0;
+        for op in &decoded.opcodes {
+            let mut value_type = None;
+            let loads_value = op.refs.iter().any(|r| r.tag == tags::VALUE);
+            for r in op.refs.iter() {
+                let location = match r.code_ref {
+                    CodeRef::Pool(location) => location,
+                    _ => continue,
+                };
+                let expected = match r.tag.as_ref() {
+                    tags::TYPE => ConstantKind::Type,
+                    tags::VALUE if op.op_code == Opcode::LdSS => ConstantKind::String,
+                    tags::VALUE if op.op_code == Opcode::LdStatic => ConstantKind::Array,
+                    _ => ConstantKind::Value,
+                };
+                let constant = match self.get(location) {
+                    Some(constant) => constant,
+                    None => {
+                        problems.push(PoolProblem::OutOfRange(offset, location));
+                        continue;
+                    }
+                };
+                match constant {
+                    Constant::PointedType(_) if expected == ConstantKind::Type && !loads_value => {}
+                    _ if constant.kind() != expected => {
+                        problems.push(PoolProblem::WrongKind(offset, location, expected))
+                    }
+                    Constant::Type(t) => value_type = Some(*t),
+                    Constant::Array(_, _) if constant.array_type().is_none() => {
+                        problems.push(PoolProblem::InvalidArray(offset, location))
+                    }
+                    Constant::Value(v) => match value_type {
+                        Some(t) if !is_valid_value(t, v) => {
+                            problems.push(PoolProblem::InvalidValue(offset, location, t))
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+            offset += op.consumed;
+        }
+        if !decoded.is_full {
+            problems.push(PoolProblem::InvalidBytecode(decoded.size));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(PoolError(problems))
+        }
+    }
+}
+
+/// Builds a [`ConstantPool`], every constant is added once
+///
+/// ```
+/// # use ngvm::ConstantPoolBuilder;
+/// # use ngvm::types::PrimitiveType;
+/// let mut builder = ConstantPoolBuilder::new();
+/// let t = builder.primitive_type(PrimitiveType::U64);
+/// let one = builder.value(1u64);
+/// assert_eq!(builder.value(1u64), one);
+/// assert_eq!(builder.primitive_type(PrimitiveType::U64), t);
+/// assert_eq!(builder.build().len(), 2);
+/// ```
+#[derive(Debug, Default)]
+pub struct ConstantPoolBuilder {
+    constants: Vec<Constant>,
+    indices: HashMap<Constant, PoolRef>,
+}
+
+impl ConstantPoolBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Location of `constant` in the pool, it's added if it was not yet
+    ///
+    /// Values are kept as bytes, so values of different types with the same bytes are one.
+    pub fn intern(&mut self, constant: Constant) -> PoolRef {
+        if let Some(&location) = self.indices.get(&constant) {
+            return location;
+        }
+        let location = PoolRef(self.constants.len());
+        self.constants.push(constant.clone());
+        self.indices.insert(constant, location);
+        location
+    }
+
+    pub fn value(&mut self, value: impl Into<Constant>) -> PoolRef {
+        self.intern(value.into())
+    }
+
+    pub fn string(&mut self, value: &str) -> PoolRef {
+        self.intern(value.into())
+    }
+
+    pub fn primitive_type(&mut self, t: PrimitiveType) -> PoolRef {
+        self.intern(t.into())
+    }
+
+    /// Location of `t`, as a primitive type or a pointed type descriptor
+    pub fn vm_type(&mut self, t: impl Into<VmType>) -> PoolRef {
+        self.intern(t.into().into())
+    }
+
+    pub fn build(self) -> ConstantPool {
+        ConstantPool::new(self.constants)
+    }
 }
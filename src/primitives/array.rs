@@ -3,7 +3,8 @@ use std::fmt::{self, Debug, Formatter};
 use std::marker::PhantomData;
 use std::ops::{Index, IndexMut};
 use std::ptr::{drop_in_place, NonNull};
-use std::slice::from_raw_parts;
+use std::mem::forget;
+use std::slice::{from_raw_parts, from_raw_parts_mut};
 
 /// Represents the array that is heap allocated (and thus can be dynamic).
 ///
@@ -72,6 +73,42 @@ impl<T> HeapArray<T> {
         self.navigate_to(i).and_then(|ptr| unsafe { ptr.as_mut() })
     }
 
+    pub fn as_slice(&self) -> &[T] {
+        // safety: safe, either the len is 0 or the data if valid
+        unsafe { from_raw_parts(self.data(), self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        // safety: safe, either the len is 0 or the data if valid
+        unsafe { from_raw_parts_mut(self.data(), self.len()) }
+    }
+
+    /// Consumes the array without freeing it, the memory is owned by the returned pointer
+    pub fn into_raw(self) -> NonNull<u8> {
+        let ptr = self.ptr;
+        forget(self);
+        ptr
+    }
+
+    /// Restores the array from a pointer returned by [`into_raw`](HeapArray::into_raw)
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw` of an array with the same `T`,
+    /// and must not be used after the restored array is dropped.
+    pub unsafe fn from_raw(ptr: NonNull<u8>) -> Self {
+        HeapArray {
+            ptr,
+            phantom: PhantomData,
+        }
+    }
+
+    #[inline]
+    fn data(&self) -> *mut T {
+        // safety: even if len == 0 we point 1 byte past allocation which is safe
+        unsafe { self.ptr.as_ptr().cast::<usize>().add(1).cast::<T>() }
+    }
+
     fn navigate_to(&self, offset: usize) -> Option<*mut T> {
         if offset >= self.len() {
             None
@@ -194,4 +231,13 @@ mod tests {
             assert_eq!(h_arr.get(i), Some(&10));
         }
     }
+
+    #[test]
+    fn test_raw_round_trip() {
+        let mut h_arr: HeapArray<usize> = HeapArray::with_default(3);
+        h_arr.as_mut_slice().copy_from_slice(&[1, 2, 3]);
+        let ptr = h_arr.into_raw();
+        let h_arr = unsafe { HeapArray::<usize>::from_raw(ptr) };
+        assert_eq!(h_arr.as_slice(), &[1, 2, 3]);
+    }
 }
@@ -48,11 +48,12 @@ impl FromSingle<StackData> for bool {
     }
 }
 
+/// Values that are not a char read as [`char::REPLACEMENT_CHARACTER`]
 impl FromSingle<StackData> for char {
     fn from_single(obj: [u8; 8]) -> Self {
         const S: usize = size_of::<u32>();
         std::char::from_u32(u32::from_le_bytes(obj[..S].try_into().unwrap()))
-            .expect("Invalid char {}")
+            .unwrap_or(char::REPLACEMENT_CHARACTER)
     }
 }
 
@@ -191,6 +191,8 @@ pub enum TypeError {
     AllNotEqual(Vec<TaggedType>),
     NotReference(TaggedType),
     NotMutReference(TaggedType),
+    /// A downcast of `Any` to the expected type (first) failed, the second is the actual type
+    BadDowncast(TaggedType, TaggedType),
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -248,6 +250,8 @@ impl<'a, C: HasTypeCheckerCtx> TypeChecker<'a, C> {
                     }
                     Some(r)
                 } else {
+                    let t = VmType::PointedType(bpt.clone());
+                    self.ctx.report(cond.get_error(t.tag(self.tag.clone())));
                     None
                 }
             }
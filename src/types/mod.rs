@@ -1,11 +1,13 @@
 pub use pointed::*;
 pub use primitive::*;
+pub use table::*;
 
 pub mod checker;
 mod pointed;
 mod primitive;
+mod table;
 
-#[derive(Debug, PartialEq, Clone, Hash)]
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
 pub enum VmType {
     Primitive(PrimitiveType),
     PointedType(Box<PointedType>),
@@ -40,6 +42,14 @@ impl VmType {
         }
     }
 
+    pub fn cell(&self) -> Option<&VmType> {
+        if let PointedType::Cell(t) = self.pointed()? {
+            Some(t)
+        } else {
+            None
+        }
+    }
+
     pub fn ref_type(&self) -> Option<&RefType> {
         if let PointedType::Ref(rf) = self.pointed()? {
             Some(rf)
@@ -62,6 +72,41 @@ impl VmType {
                 PointedType::SArr(a) => a.pointer.is_copy(),
                 PointedType::Ref(r) => r.is_copy(),
                 PointedType::Boxed(_) => false,
+                PointedType::Cell(_) => false,
+                PointedType::Rc(_) => false,
+                PointedType::Weak(_) => false,
+                PointedType::Any => false,
+            },
+        }
+    }
+
+    /// Whether zeroed stack data is a valid value of this type, as `LdTyped0` creates
+    ///
+    /// A zeroed `SStr` is the empty string, pointers are never zero.
+    pub fn is_zeroable(&self) -> bool {
+        match self {
+            VmType::Primitive(p) => p.is_single() || *p == PrimitiveType::SStr,
+            VmType::PointedType(p) => match p.as_ref() {
+                PointedType::SArr(a) => a.pointer.is_zeroable(),
+                _ => false,
+            },
+        }
+    }
+
+    /// Whether a value of this type can be duplicated by copying its stack data
+    ///
+    /// References are not cloneable this way, a new reference must be borrowed instead.
+    pub fn is_clone(&self) -> bool {
+        match self {
+            VmType::Primitive(_) => true,
+            VmType::PointedType(p) => match p.as_ref() {
+                PointedType::SArr(a) => a.pointer.is_clone(),
+                PointedType::Ref(_) => false,
+                PointedType::Boxed(_) => false,
+                PointedType::Cell(_) => false,
+                PointedType::Rc(_) => false,
+                PointedType::Weak(_) => false,
+                PointedType::Any => false,
             },
         }
     }
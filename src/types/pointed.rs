@@ -1,17 +1,26 @@
 use std::fmt::{self, Display, Formatter};
 
 use crate::code::refs::StackRef;
+use crate::error::VmError;
 use crate::stack::data::{IntoPrimitive, StackData};
 use crate::vm::refs::LocatedRef;
 use crate::vm::ValueLocation;
 
 use super::VmType;
 
-#[derive(Debug, PartialEq, Clone, Hash)]
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
 pub enum PointedType {
     SArr(SArrType),
     Ref(RefType),
     Boxed(VmType),
+    /// A value with a dynamically checked borrow state
+    Cell(VmType),
+    /// A shared owner of a reference counted value on the heap
+    Rc(VmType),
+    /// A non owning pointer to a reference counted value
+    Weak(VmType),
+    /// A heap value that carries its type at runtime
+    Any,
 }
 
 impl PointedType {
@@ -34,15 +43,23 @@ impl PointedType {
         Self::reference(pointer, RefKind::Ref, location)
     }
 
+    pub fn cell(inner: impl Into<VmType>) -> Self {
+        PointedType::Cell(inner.into())
+    }
+
     pub fn mut_reference(pointer: impl Into<VmType>, location: RefLocation) -> Self {
         Self::reference(pointer, RefKind::Mut, location)
     }
 
     pub fn size(&self) -> usize {
         match self {
-            PointedType::SArr(SArrType { len, pointer }) => len * pointer.size(),
+            PointedType::SArr(SArrType { len, pointer }) => len.saturating_mul(pointer.size()),
             PointedType::Ref(_) => 1,
             PointedType::Boxed(_) => 1,
+            PointedType::Cell(t) => 1 + t.size(),
+            PointedType::Rc(_) => 1,
+            PointedType::Weak(_) => 1,
+            PointedType::Any => 1,
         }
     }
 }
@@ -63,36 +80,53 @@ pub enum RefLocation {
     Heap,
     TransientOnStack,
     TransientOnHeap,
+    /// Inside of a cell on the stack
+    CellOnStack,
+    /// Inside of the value owned by an `Rc` on the stack
+    Rc,
 }
 
-#[derive(Debug, PartialEq, Clone, Hash)]
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
 pub struct RefType {
     pub kind: RefKind,
     pub points_to: RefLocation,
     pub pointer: VmType,
 }
 
-#[derive(Debug, PartialEq, Clone, Hash)]
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
 pub struct SArrType {
     pub len: usize,
     pub pointer: VmType,
 }
 
-impl RefType {
-    pub fn locate(&self, ref_value: &StackData) -> LocatedRef {
+impl RefLocation {
+    /// Where a reference to this location with the data `ref_value` points to
+    ///
+    /// References to the heap are never created, the vm is in a bad state if it has one.
+    pub fn locate(self, ref_value: &StackData) -> Result<LocatedRef, VmError> {
         let index: usize = ref_value.into_primitive();
-        match self.points_to {
+        Ok(match self {
             RefLocation::Stack => LocatedRef::Stack(StackRef(index)),
-            RefLocation::Heap => unimplemented!(),
+            RefLocation::Heap => return Err(VmError::BadVmState),
             RefLocation::TransientOnStack => LocatedRef::Transient(ValueLocation::Stack(index)),
-            RefLocation::TransientOnHeap => unreachable!(),
-        }
+            RefLocation::TransientOnHeap => {
+                LocatedRef::Transient(ValueLocation::Heap(index as *const ()))
+            }
+            RefLocation::CellOnStack => LocatedRef::Cell(index),
+            RefLocation::Rc => LocatedRef::Rc(StackRef(index)),
+        })
+    }
+}
+
+impl RefType {
+    pub fn locate(&self, ref_value: &StackData) -> Result<LocatedRef, VmError> {
+        self.points_to.locate(ref_value)
     }
 
     pub fn is_copy(&self) -> bool {
         match self.kind {
             RefKind::Mut => false,
-            RefKind::Ref => true,
+            RefKind::Ref => self.points_to != RefLocation::CellOnStack,
         }
     }
 }
@@ -114,7 +148,11 @@ impl Display for RefType {
                     write!(f, "[{:?};{}]", pointer, len)
                 }
                 PointedType::Ref(r) => write!(f, "({})", r),
-                PointedType::Boxed(t) => write!(f, "Box<{:?}>", t)
+                PointedType::Boxed(t) => write!(f, "Box<{:?}>", t),
+                PointedType::Cell(t) => write!(f, "Cell<{:?}>", t),
+                PointedType::Rc(t) => write!(f, "Rc<{:?}>", t),
+                PointedType::Weak(t) => write!(f, "Weak<{:?}>", t),
+                PointedType::Any => write!(f, "Any"),
             },
         }
     }
@@ -0,0 +1,161 @@
+//! Per-vm table of interned types
+//!
+//! Stack metadata refers to its type by a [`TypeId`], so types are compared by their ids
+//! and never cloned while the code runs. The table caches the size of each type and the
+//! ids of the types nested in it, e.g. the type a reference points to.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ops::Index;
+
+use super::{PointedType, PrimitiveType, RefKind, RefLocation, RefType, SArrType, VmType};
+
+/// Handle of a type interned in a [`TypeTable`]
+///
+/// Ids are only meaningful for the table that created them.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub struct TypeId(u32);
+
+#[derive(Debug)]
+struct TypeInfo {
+    vm_type: VmType,
+    size: usize,
+    is_copy: bool,
+    /// The type a reference points to, or the type owned by a cell, rc or array
+    inner: Option<TypeId>,
+}
+
+#[derive(Debug)]
+pub struct TypeTable {
+    types: Vec<TypeInfo>,
+    ids: HashMap<VmType, TypeId>,
+    /// Primitive types are interned without hashing, indexed by their discriminant
+    primitives: Vec<Option<TypeId>>,
+    references: HashMap<(TypeId, RefKind, RefLocation), TypeId>,
+}
+
+impl TypeTable {
+    pub fn new() -> Self {
+        Self {
+            types: Vec::new(),
+            ids: HashMap::new(),
+            primitives: vec![None; 128],
+            references: HashMap::new(),
+        }
+    }
+
+    /// Returns the id of `t`, adding it to the table if it is new
+    pub fn intern(&mut self, t: impl Into<VmType>) -> TypeId {
+        let t = t.into();
+        if let VmType::Primitive(p) = t {
+            return self.primitive(p);
+        }
+        if let Some(id) = self.ids.get(&t) {
+            return *id;
+        }
+        let inner = match t.pointed() {
+            Some(PointedType::SArr(SArrType { pointer: inner, .. }))
+            | Some(PointedType::Ref(RefType { pointer: inner, .. }))
+            | Some(PointedType::Boxed(inner))
+            | Some(PointedType::Cell(inner))
+            | Some(PointedType::Rc(inner))
+            | Some(PointedType::Weak(inner)) => Some(self.intern(inner.clone())),
+            Some(PointedType::Any) | None => None,
+        };
+        self.insert(t, inner)
+    }
+
+    pub fn primitive(&mut self, t: PrimitiveType) -> TypeId {
+        if let Some(id) = self.primitives[t as usize] {
+            return id;
+        }
+        let id = self.insert(t.into(), None);
+        self.primitives[t as usize] = Some(id);
+        id
+    }
+
+    /// Id of a reference of `kind` to a value of type `pointer`
+    pub fn reference(&mut self, pointer: TypeId, kind: RefKind, location: RefLocation) -> TypeId {
+        if let Some(id) = self.references.get(&(pointer, kind, location)) {
+            return *id;
+        }
+        let t = PointedType::reference(self[pointer].clone(), kind, location);
+        let id = self.intern(t);
+        self.references.insert((pointer, kind, location), id);
+        id
+    }
+
+    fn insert(&mut self, t: VmType, inner: Option<TypeId>) -> TypeId {
+        let id = TypeId(u32::try_from(self.types.len()).expect("too many types"));
+        self.types.push(TypeInfo {
+            size: t.size(),
+            is_copy: t.is_copy(),
+            vm_type: t.clone(),
+            inner,
+        });
+        self.ids.insert(t, id);
+        id
+    }
+
+    pub fn get(&self, id: TypeId) -> &VmType {
+        &self.types[id.0 as usize].vm_type
+    }
+
+    pub fn size(&self, id: TypeId) -> usize {
+        self.types[id.0 as usize].size
+    }
+
+    pub fn is_copy(&self, id: TypeId) -> bool {
+        self.types[id.0 as usize].is_copy
+    }
+
+    /// Id of the type a reference points to, or the type owned by a cell, rc or array
+    pub fn inner(&self, id: TypeId) -> Option<TypeId> {
+        self.types[id.0 as usize].inner
+    }
+}
+
+impl Default for TypeTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Index<TypeId> for TypeTable {
+    type Output = VmType;
+
+    fn index(&self, id: TypeId) -> &VmType {
+        self.get(id)
+    }
+}
+
+/// Types that can be turned into an id of a [`TypeTable`]
+pub trait IntoTypeId {
+    fn into_type_id(self, table: &mut TypeTable) -> TypeId;
+}
+
+impl IntoTypeId for TypeId {
+    #[inline]
+    fn into_type_id(self, _: &mut TypeTable) -> TypeId {
+        self
+    }
+}
+
+impl IntoTypeId for PrimitiveType {
+    #[inline]
+    fn into_type_id(self, table: &mut TypeTable) -> TypeId {
+        table.primitive(self)
+    }
+}
+
+macro_rules! impl_into_type_id {
+    ($($t: ty),*) => {
+        $(impl IntoTypeId for $t {
+            fn into_type_id(self, table: &mut TypeTable) -> TypeId {
+                table.intern(self)
+            }
+        })*
+    };
+}
+
+impl_into_type_id!(VmType, PointedType, RefType, SArrType);
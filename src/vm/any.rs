@@ -0,0 +1,36 @@
+//! Values with a type known only at runtime
+//!
+//! An `Any` value on the stack is a pointer to a heap allocated [`AnyValue`],
+//! which stores the type of the wrapped value next to its data.
+
+use crate::stack::data::StackData;
+use crate::types::TypeId;
+
+#[derive(Debug)]
+pub struct AnyValue {
+    pub type_id: TypeId,
+    pub data: Vec<StackData>,
+}
+
+/// Moves `value` to the heap, returns its pointer
+pub fn allocate(value: AnyValue) -> usize {
+    Box::into_raw(Box::new(value)) as usize
+}
+
+/// Returns the value at `ptr`
+///
+/// # Safety
+///
+/// `ptr` must be returned by [`allocate`] and not yet taken
+pub unsafe fn get<'a>(ptr: usize) -> &'a AnyValue {
+    &*(ptr as *const AnyValue)
+}
+
+/// Takes the value at `ptr` back from the heap
+///
+/// # Safety
+///
+/// Same as [`get`], `ptr` can't be used afterwards
+pub unsafe fn take(ptr: usize) -> AnyValue {
+    *Box::from_raw(ptr as *mut AnyValue)
+}
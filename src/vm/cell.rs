@@ -0,0 +1,31 @@
+//! Dynamic borrow state of cell values
+//!
+//! A cell stores its borrow state in the first stack cell, followed by the inner value.
+//! The state is the number of live shared borrows, or [`MUT_BORROWED`] while the
+//! cell is mutably borrowed.
+
+use crate::types::RefKind;
+use crate::vm::lock::LockError;
+
+pub const UNBORROWED: usize = 0;
+pub const MUT_BORROWED: usize = usize::MAX;
+
+/// Returns the borrow state after a new borrow of `kind`
+pub fn acquire(state: usize, kind: RefKind) -> Result<usize, LockError> {
+    match (state, kind) {
+        (MUT_BORROWED, RefKind::Ref) => Err(LockError::RefLockButMutLocked),
+        (MUT_BORROWED, RefKind::Mut) => Err(LockError::MutLockButMutLocked),
+        (UNBORROWED, RefKind::Mut) => Ok(MUT_BORROWED),
+        (_, RefKind::Mut) => Err(LockError::MutLockButRefLocked),
+        (n, RefKind::Ref) if n == MUT_BORROWED - 1 => Err(LockError::RefLockOverflow),
+        (n, RefKind::Ref) => Ok(n + 1),
+    }
+}
+
+/// Returns the borrow state after one of the borrows is released
+pub fn release(state: usize) -> usize {
+    match state {
+        MUT_BORROWED => UNBORROWED,
+        n => n.saturating_sub(1),
+    }
+}
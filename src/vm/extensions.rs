@@ -0,0 +1,75 @@
+//! Opcodes registered by the embedder
+//!
+//! Extension opcodes take the two byte space, [`Opcode::HWide`] followed by the number of
+//! the extension. Each one declares its operands, so the decoder, the disassembler and the
+//! model handle it like a built-in opcode, and its handler gets the operands decoded.
+//! Extension opcodes can't jump, the code always continues after them.
+
+use smallvec::SmallVec;
+
+use crate::code::refs::CodeRef;
+use crate::error::VmError;
+use crate::opcodes::{Opcode, Operand, OperandKind};
+use crate::Vm;
+
+/// Decoded operands of an extension opcode, in the order it declares them
+pub type ExtensionOperands = SmallVec<[CodeRef; 6]>;
+
+pub type ExtensionHandler = fn(&[CodeRef], &mut Vm) -> Result<(), VmError>;
+
+/// Refs an instruction of threaded code holds, extension opcodes can't take more
+const MAX_EXTENSION_REFS: usize = 6;
+
+#[derive(Debug, Copy, Clone)]
+pub struct ExtensionOpcode {
+    /// Name the disassembler shows
+    pub name: &'static str,
+    /// Operands, in the order they follow the opcode in the bytecode
+    pub operands: &'static [Operand],
+    pub handler: ExtensionHandler,
+}
+
+impl ExtensionOpcode {
+    /// If the operands can be held by an instruction of threaded code
+    fn has_valid_operands(&self) -> bool {
+        let count = |kind: OperandKind| self.operands.iter().filter(|o| o.kind == kind).count();
+        let refs = self.operands.len() - count(OperandKind::Size) - count(OperandKind::Immediate);
+        count(OperandKind::Jump) == 0
+            && count(OperandKind::Size) <= 1
+            && count(OperandKind::Immediate) <= 1
+            && refs <= MAX_EXTENSION_REFS
+    }
+}
+
+/// Extension opcodes, by the byte that follows [`Opcode::HWide`]
+#[derive(Debug, Clone, Default)]
+pub struct ExtensionTable {
+    opcodes: Vec<Option<ExtensionOpcode>>,
+}
+
+impl ExtensionTable {
+    /// Register `opcode` as the extension opcode `code`
+    pub fn register(&mut self, code: u8, opcode: ExtensionOpcode) -> Result<(), VmError> {
+        if self.get(code).is_some() {
+            return Err(VmError::ExtensionAlreadyRegistered(code));
+        }
+        if !opcode.has_valid_operands() {
+            return Err(VmError::InvalidExtension(code));
+        }
+        let index = code as usize;
+        if self.opcodes.len() <= index {
+            self.opcodes.resize(index + 1, None);
+        }
+        self.opcodes[index] = Some(opcode);
+        Ok(())
+    }
+
+    pub fn get(&self, code: u8) -> Option<ExtensionOpcode> {
+        self.opcodes.get(code as usize).copied().flatten()
+    }
+
+    /// Bytes an extension opcode starts with
+    pub fn opcode_bytes(code: u8) -> [u8; 2] {
+        [Opcode::HWide as u8, code]
+    }
+}
@@ -54,6 +54,10 @@ pub enum LockError {
     RefLockButMutLocked,
     #[error("Attempted to acquire a partial ref lock on a value, but value is already locked as fully as ref")]
     RefPartialLockButRefFullLock,
+    #[error("Attempted to move a value, but value is still borrowed")]
+    MoveButLocked,
+    #[error("Attempted to acquire a ref lock on a value, but value has too many ref locks")]
+    RefLockOverflow,
 }
 
 impl ValueLock {
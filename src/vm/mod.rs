@@ -1,33 +1,54 @@
 use std::collections::HashMap;
 
 use lock::ValueLock;
-pub use refs::code::VmRefSource;
+pub use extensions::{ExtensionOpcode, ExtensionTable};
+pub use modules::{FunctionId, ModuleId, ModuleTable};
+pub use refs::code::{OperandSource, VmRefSource};
 use refs::LocatedRef;
 
 use crate::{ConstantPool, Module};
 use crate::code::refs::StackRef;
 use crate::error::VmError;
 use crate::meta::{Meta, StackMeta, TransientMeta, VmMetaView};
-use crate::stack::data::IntoStackData;
+use crate::stack::data::{IntoPrimitive, IntoStackData};
 use crate::stack::data::StackData;
-use crate::types::{PointedType, PrimitiveType, RefKind, RefLocation, RefType, VmType};
-use crate::vm::lock::ValueLockData;
+use crate::types::checker::{tags, Taggable, TypeError};
+use crate::types::{
+    IntoTypeId, PointedType, PrimitiveType, RefKind, RefLocation, RefType, TypeId, TypeTable,
+    VmType,
+};
+use crate::vm::lock::{DerefLock, LockError, ValueLockData};
 
+pub mod any;
+pub mod cell;
+pub mod extensions;
 pub mod lock;
+pub mod modules;
+pub mod rc;
 pub mod refs;
 
+/// Cycle of the statics, no scope ends it
+pub const STATIC_CYCLE: usize = 0;
+
+/// Values the stack holds at most, allocations past it fail with [`VmError::StackOverflow`]
+pub const MAX_STACK_LEN: usize = 1 << 16;
+
 pub struct Vm {
     /// vm stack values
     pub(crate) stack: Vec<StackData>,
     /// vm stack metadata
     pub(crate) stack_metadata: Vec<StackMeta>,
+    /// Types of the values, referred to by the stack metadata
+    pub(crate) types: TypeTable,
 
     pub(crate) transient_refs: HashMap<ValueLocation, TransientMeta>,
 
     pub(crate) derefs: Vec<VmDeref>,
+
+    pub(crate) reborrows: Vec<VmReborrow>,
     /// The current cycle of the vm
     ///
-    /// Starts at 1. 0 is reserved for static data
+    /// Starts at 1. 0 is reserved for static data, see [`Vm::push_static`]
     pub(crate) cycle: usize,
     /// current instruction in the current stack frame
     pub(crate) ip: usize,
@@ -36,9 +57,13 @@ pub struct Vm {
     pub(crate) last_stack_frame: usize,
 
     /// Loaded modules
-    pub(crate) modules: HashMap<String, Module>,
-
-    pub(crate) current_module: String,
+    pub(crate) modules: ModuleTable,
+    /// Module whose constant pool the code uses
+    pub(crate) current_module: ModuleId,
+    /// Opcodes registered by the embedder
+    pub(crate) extensions: ExtensionTable,
+    /// Instructions left to run, `None` for no limit
+    pub(crate) steps: Option<usize>,
 }
 
 pub type Result<T> = std::result::Result<T, VmError>;
@@ -48,12 +73,9 @@ pub struct StackDataRef(pub usize);
 
 impl Vm {
     pub fn with_module(m: Module) -> Self {
-        let mut map = HashMap::new();
-        map.insert("".to_string(), m);
-        Self {
-            modules: map,
-            ..Default::default()
-        }
+        let mut vm = Self::default();
+        vm.current_module = vm.load_module("", m).expect("the module table is empty");
+        vm
     }
 
     pub fn headless(pool: ConstantPool) -> Self {
@@ -62,19 +84,24 @@ impl Vm {
     }
 
     pub fn light(pool: ConstantPool) -> Self {
-        let module = Module::new(pool);
-        let mut map = HashMap::new();
-        map.insert("".into(), module);
+        let mut modules = ModuleTable::default();
+        let current_module = modules
+            .load("", Module::new(pool))
+            .expect("the module table is empty");
         Self {
             stack: Vec::new(),
             stack_metadata: Vec::new(),
+            types: TypeTable::new(),
             transient_refs: HashMap::new(),
             derefs: Vec::new(),
+            reborrows: Vec::new(),
             cycle: 1,
             ip: 0,
             last_stack_frame: 0,
-            modules: map,
-            current_module: "".into(),
+            modules,
+            current_module,
+            extensions: Default::default(),
+            steps: None,
         }
     }
 
@@ -88,7 +115,7 @@ impl Vm {
 
     pub fn single_stack_data(&self, index: StackRef) -> Result<&StackData> {
         let meta = self.stack_metadata(index)?;
-        if meta.value_type.size() == 1 {
+        if self.types.size(meta.type_id) == 1 {
             self.stack
                 .get(self.last_stack_frame + meta.index.0)
                 .ok_or(VmError::BadVmState)
@@ -100,10 +127,21 @@ impl Vm {
     pub fn stack_data(&self, index: StackRef) -> Result<&[StackData]> {
         let meta = self.stack_metadata(index)?;
         let from = meta.index.0;
-        let until = from + meta.value_type.size();
+        let until = from + self.types.size(meta.type_id);
         self.stack.get(from..until).ok_or(VmError::BadVmState)
     }
 
+    /// Type of the value at `index`
+    pub fn value_type(&self, index: StackRef) -> Result<&VmType> {
+        let meta = self.stack_metadata(index)?;
+        Ok(&self.types[meta.type_id])
+    }
+
+    /// Types interned by the vm, the stack metadata refers to them by their ids
+    pub fn types(&self) -> &TypeTable {
+        &self.types
+    }
+
     pub fn stack_metadata(&self, index: StackRef) -> Result<&StackMeta> {
         self.stack_metadata
             .get(self.last_stack_frame + index.0)
@@ -112,12 +150,16 @@ impl Vm {
 
     pub fn meta_view(&self, l: LocatedRef) -> Result<VmMetaView> {
         match l {
-            LocatedRef::Stack(sr) => self.stack_metadata(sr).map(VmMetaView::Stack),
+            LocatedRef::Stack(sr) | LocatedRef::Rc(sr) => {
+                self.stack_metadata(sr).map(VmMetaView::Stack)
+            }
             LocatedRef::Transient(tr) => self
                 .transient_refs
                 .get(&tr)
                 .map(VmMetaView::Transient)
                 .ok_or(VmError::BadVmState),
+            // the inner value of a cell has no metadata of its own
+            LocatedRef::Cell(_) => Err(VmError::BadVmState),
         }
     }
 
@@ -132,7 +174,9 @@ impl Vm {
             .stack_metadata
             .get(self.last_stack_frame + index.0)
             .ok_or(VmError::BadVmState)?;
-        if meta.value_type.size() == 1 {
+        if !meta.mutable {
+            Err(VmError::WriteToImmutable(index))
+        } else if self.types.size(meta.type_id) == 1 {
             self.stack
                 .get_mut(self.last_stack_frame + meta.index.0)
                 .ok_or(VmError::BadVmState)
@@ -143,8 +187,11 @@ impl Vm {
 
     pub fn stack_data_mut(&mut self, index: StackRef) -> Result<&mut [StackData]> {
         let meta = self.stack_metadata(index)?;
+        if !meta.mutable {
+            return Err(VmError::WriteToImmutable(index));
+        }
         let from = meta.index.0;
-        let until = from + meta.value_type.size();
+        let until = from + self.types.size(meta.type_id);
         Ok(&mut self.stack[from..until])
     }
 
@@ -152,25 +199,21 @@ impl Vm {
     pub fn push_primitive(&mut self, value: StackData, t: PrimitiveType) {
         let len = self.stack.len();
         let cycle = self.current_cycle();
-        let meta = StackMeta::new(t, StackDataRef(len), cycle);
+        let meta = StackMeta::new(self.types.primitive(t), StackDataRef(len), cycle);
 
         self.stack_metadata.push(meta);
         self.stack.push(value);
     }
 
-    pub fn push_single_typed(&mut self, value: impl IntoStackData, t: impl Into<VmType>) {
-        let len = self.stack.len();
-        let cycle = self.current_cycle();
-        let meta = StackMeta::new(t, StackDataRef(len), cycle);
+    pub fn push_single_typed(&mut self, value: impl IntoStackData, t: impl IntoTypeId) {
+        let meta = self.new_stack_meta_of_type(t);
 
         self.stack_metadata.push(meta);
         self.stack.push(value.into_stack_data());
     }
 
-    pub fn push_typed(&mut self, value: impl IntoIterator<Item = StackData>, t: impl Into<VmType>) {
-        let len = self.stack.len();
-        let cycle = self.current_cycle();
-        let meta = StackMeta::new(t, StackDataRef(len), cycle);
+    pub fn push_typed(&mut self, value: impl IntoIterator<Item = StackData>, t: impl IntoTypeId) {
+        let meta = self.new_stack_meta_of_type(t);
 
         self.stack_metadata.push(meta);
         self.stack.extend(value);
@@ -183,12 +226,15 @@ impl Vm {
     pub fn push_stack_ref(&mut self, index: StackRef, kind: RefKind) -> Result<()> {
         let cycle = self.current_cycle();
         let meta = self.stack_metadata_mut(index)?;
+        if kind == RefKind::Mut && !meta.mutable {
+            return Err(VmError::WriteToImmutable(index));
+        }
         let lock = &mut meta.lock;
         match lock.add_lock(cycle, kind) {
             Ok(()) => {
-                let pointer = meta.value_type.clone();
+                let pointer = meta.type_id;
                 let len = self.stack.len();
-                let ref_type = PointedType::reference(pointer, kind, RefLocation::Stack);
+                let ref_type = self.types.reference(pointer, kind, RefLocation::Stack);
                 let ref_meta = StackMeta::new(ref_type, StackDataRef(len), cycle);
                 self.stack_metadata.push(ref_meta);
                 self.stack.push(index.0.into_stack_data());
@@ -201,44 +247,64 @@ impl Vm {
     /// Pop the last stack value in its entirety from the stack
     pub fn pop_stack(&mut self) -> Result<()> {
         if let Some(meta) = self.stack_metadata.pop() {
-            let size = meta.value_type.size();
-            match meta.value_type {
-                VmType::Primitive(_) => {}
-                VmType::PointedType(p) => match *p {
-                    PointedType::SArr(_) => {}
-                    PointedType::Ref(r) => {
+            let size = self.types.size(meta.type_id);
+            match self.types[meta.type_id].pointed() {
+                // The value of a deref is a copy, the borrowed value still owns what it holds
+                _ if meta.deref != DerefLock::None => {}
+                None | Some(PointedType::SArr(_)) => {}
+                Some(PointedType::Ref(_)) if meta.was_moved => {}
+                Some(PointedType::Ref(r)) => {
+                    let points_to = r.points_to;
+                    let index = StackRef(self.stack_metadata.len() - self.last_stack_frame);
+                    if !self.release_reborrow(index)? {
                         let ref_value = self
                             .stack
                             .get(self.last_stack_frame + meta.index.0)
                             .ok_or(VmError::BadVmState)?;
-                        let located_ref = r.locate(ref_value);
+                        let located_ref = points_to.locate(ref_value)?;
                         self.unlock_by_ref(located_ref)?;
                     }
-                    PointedType::Boxed(_) => unimplemented!()
-                },
+                }
+                Some(PointedType::Boxed(_)) => return Err(VmError::BadVmState),
+                _ if meta.was_moved => {}
+                Some(_) => {
+                    let value = self.stack[self.stack.len() - size..].to_vec();
+                    self.release_owned(meta.type_id, &value)?;
+                }
             }
             self.stack.truncate(self.stack.len() - size);
         }
         Ok(())
     }
 
+    /// Releases what the value at `index` holds before it is overwritten
+    ///
+    /// The value of a `&mut` deref stands for the borrowed one, it is written back by
+    /// `EndDeref`, so overwriting it releases the borrowed value once.
     pub fn free_by_index(&mut self, index: StackRef) -> Result<()> {
         let meta = self.stack_metadata(index)?;
-        let is_copy = meta.value_type.is_copy();
-        match &meta.value_type {
-            VmType::Primitive(_) => {}
-            VmType::PointedType(p) => match p.as_ref() {
-                PointedType::SArr(_) => {}
-                PointedType::Ref(r) => {
-                    let ref_value = self
-                        .stack
-                        .get(self.last_stack_frame + meta.index.0)
-                        .ok_or(VmError::BadVmState)?;
-                    let located_ref = r.locate(ref_value);
+        if meta.was_moved {
+            return Ok(());
+        }
+        let type_id = meta.type_id;
+        let is_copy = self.types.is_copy(type_id);
+        match self.types[type_id].pointed() {
+            None | Some(PointedType::SArr(_)) => {}
+            Some(PointedType::Ref(r)) => {
+                let ref_value = self
+                    .stack
+                    .get(self.last_stack_frame + meta.index.0)
+                    .ok_or(VmError::BadVmState)?;
+                let located_ref = r.locate(ref_value)?;
+                if !self.release_reborrow(index)? {
                     self.unlock_by_ref(located_ref)?;
                 }
-                PointedType::Boxed(_) => unimplemented!()
-            },
+            }
+            Some(PointedType::Boxed(_)) => return Err(VmError::BadVmState),
+            Some(_) => {
+                let value = self.stack_data(index)?.to_vec();
+                self.release_owned(type_id, &value)?;
+            }
         }
         if !is_copy {
             self.stack_metadata_mut(index)?.was_moved = true;
@@ -246,10 +312,131 @@ impl Vm {
         Ok(())
     }
 
+    /// Releases the heap values owned by `value` of type `t`
+    fn release_owned(&mut self, t: TypeId, value: &[StackData]) -> Result<()> {
+        let nested = self.types.inner(t);
+        match self.types[t].pointed() {
+            Some(PointedType::Rc(_)) => {
+                let ptr = value.first().ok_or(VmError::BadVmState)?.into_primitive();
+                // SAFETY: a live `Rc` always points to an allocated block
+                let strong = unsafe { rc::update_count(ptr, rc::STRONG, -1) };
+                if strong == 0 {
+                    let inner_value = unsafe { rc::with_block(ptr, |b| b[rc::VALUE..].to_vec()) };
+                    self.release_owned(nested.ok_or(VmError::BadVmState)?, &inner_value)?;
+                    if unsafe { rc::counts(ptr) }.1 == 0 {
+                        unsafe { rc::free(ptr) }
+                    }
+                }
+            }
+            Some(PointedType::Weak(_)) => {
+                let ptr = value.first().ok_or(VmError::BadVmState)?.into_primitive();
+                // SAFETY: a live `Weak` keeps its block allocated
+                let weak = unsafe { rc::update_count(ptr, rc::WEAK, -1) };
+                if weak == 0 && unsafe { rc::counts(ptr) }.0 == 0 {
+                    unsafe { rc::free(ptr) }
+                }
+            }
+            Some(PointedType::Cell(_)) => {
+                self.release_owned(nested.ok_or(VmError::BadVmState)?, &value[1..])?
+            }
+            Some(PointedType::Any) => {
+                let ptr = value.first().ok_or(VmError::BadVmState)?.into_primitive();
+                // SAFETY: a live `Any` owns its heap value
+                let inner = unsafe { any::take(ptr) };
+                self.release_owned(inner.type_id, &inner.data)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Ends the life of the value at `index` before its cycle ends
+    ///
+    /// The value releases its locks and is marked as moved, so it can't be used again.
+    pub fn drop_by_index(&mut self, index: StackRef) -> Result<()> {
+        self.check_movable(index)?;
+        self.free_by_index(index)?;
+        self.stack_metadata_mut(index)?.was_moved = true;
+        Ok(())
+    }
+
+    /// Checks that the value at `index` is neither moved nor borrowed
+    pub fn check_movable(&self, index: StackRef) -> Result<()> {
+        self.check_owned(index)?;
+        let meta = self.stack_metadata(index)?;
+        if meta.was_moved {
+            Err(VmError::UseOfMovedValue(index))
+        } else if meta.lock.is_locked() || self.cell_state(index)? != cell::UNBORROWED {
+            let location = ValueLocation::Stack(index.0);
+            Err(VmError::LockError(LockError::MoveButLocked, location))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks that the value at `index` owns its data
+    ///
+    /// The value pushed by `StartDeref` is a copy of the borrowed one, it is written back or
+    /// discarded by `EndDeref`, so it can't be moved or dropped.
+    pub fn check_owned(&self, index: StackRef) -> Result<()> {
+        if self.stack_metadata(index)?.deref != DerefLock::None {
+            return Err(VmError::MoveOfDeref(index));
+        }
+        Ok(())
+    }
+
+    /// Exchanges the values at `a` and `b`, neither of them is marked as moved
+    pub fn swap_by_index(&mut self, a: StackRef, b: StackRef) -> Result<()> {
+        self.check_movable(a)?;
+        self.check_movable(b)?;
+        let a_meta = self.stack_metadata(a)?;
+        let b_meta = self.stack_metadata(b)?;
+        if a_meta.type_id != b_meta.type_id {
+            let a_type = self.types[a_meta.type_id].tag(tags::OP1);
+            let b_type = self.types[b_meta.type_id].tag(tags::OP2);
+            let e = TypeError::TwoNotEqual(a_type, b_type);
+            return Err(VmError::TypeError(vec![e]));
+        }
+        let a_type = &self.types[a_meta.type_id];
+        if a_type.ref_type().is_some() && a_meta.cycle != b_meta.cycle {
+            let t = a_type.tag(tags::OP1);
+            let msg = "Cannot swap references created in different cycles";
+            let e = TypeError::Condition(t, msg.into());
+            return Err(VmError::TypeError(vec![e]));
+        }
+
+        let a_value = self.stack_data(a)?.to_vec();
+        let b_value = self.stack_data(b)?.to_vec();
+        self.stack_data_mut(a)?.copy_from_slice(&b_value);
+        self.stack_data_mut(b)?.copy_from_slice(&a_value);
+        for r in &mut self.reborrows {
+            if r.reborrow == a {
+                r.reborrow = b;
+            } else if r.reborrow == b {
+                r.reborrow = a;
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the value at `index` immutable for the rest of its life
+    pub fn freeze(&mut self, index: StackRef) -> Result<()> {
+        let meta = self.stack_metadata_mut(index)?;
+        if meta.was_moved {
+            return Err(VmError::UseOfMovedValue(index));
+        }
+        if let ValueLock::Mut(_) = meta.lock {
+            let location = ValueLocation::Stack(index.0);
+            return Err(VmError::LockError(LockError::RefLockButMutLocked, location));
+        }
+        meta.mutable = false;
+        Ok(())
+    }
+
     fn unlock_by_ref(&mut self, rf: LocatedRef) -> Result<()> {
         let vm_cycle = self.cycle;
         match rf {
-            LocatedRef::Stack(index) => {
+            LocatedRef::Stack(index) | LocatedRef::Rc(index) => {
                 let value_meta = self.stack_metadata_mut(index)?;
                 if let Some(c) = value_meta.lock.lock_cycle() {
                     if c == vm_cycle {
@@ -270,38 +457,143 @@ impl Vm {
                     }
                 }
             }
+            LocatedRef::Cell(index) => {
+                let state = self
+                    .stack
+                    .get_mut(index.checked_sub(1).ok_or(VmError::BadVmState)?)
+                    .ok_or(VmError::BadVmState)?;
+                let released = cell::release(state.into_primitive());
+                *state = released.into_stack_data();
+            }
         }
         Ok(())
     }
 
-    pub fn switch_lock_cycle(&mut self, rf: LocatedRef) -> Result<()> {
-        fn switch_cycle(m: &mut impl Meta, new_cycle: usize) -> Result<()> {
-            match m.lock() {
-                ValueLock::Mut(data) if new_cycle >= data.lock_cycle => {
-                    *m.lock_mut() = ValueLock::Mut(ValueLockData {
+    /// Moves the lock of the value `rf` points to into the current cycle as a lock of `kind`
+    ///
+    /// A `Mut` lock can be switched to either kind (a `Ref` switch is a downgrade),
+    /// a `Ref` lock can only stay `Ref`.
+    ///
+    /// Returns the lock the value had before the switch, so it can be restored with
+    /// [`restore_lock`](Vm::restore_lock) once the new cycle ends.
+    pub fn switch_lock_cycle(&mut self, rf: LocatedRef, kind: RefKind) -> Result<ValueLock> {
+        fn switch_cycle(
+            m: &mut impl Meta,
+            new_cycle: usize,
+            kind: RefKind,
+            location: ValueLocation,
+        ) -> Result<ValueLock> {
+            let previous = *m.lock();
+            match (previous, kind) {
+                (ValueLock::Mut(data), _) | (ValueLock::Ref(data), RefKind::Ref)
+                    if new_cycle >= data.lock_cycle =>
+                {
+                    let data = ValueLockData {
                         lock_cycle: new_cycle,
                         partial_lock: false,
-                    });
-                    Ok(())
+                    };
+                    *m.lock_mut() = match kind {
+                        RefKind::Ref => ValueLock::Ref(data),
+                        RefKind::Mut => ValueLock::Mut(data),
+                    };
+                    Ok(previous)
+                }
+                (ValueLock::Ref(_), RefKind::Mut) => {
+                    Err(VmError::LockError(LockError::MutLockButRefLocked, location))
                 }
                 // TODO: different error
-                ValueLock::Mut(_) => Err(VmError::BadVmState),
                 _ => Err(VmError::BadVmState),
             }
         }
         let vm_cycle = self.cycle;
         match rf {
-            LocatedRef::Stack(index) => {
+            LocatedRef::Stack(index) | LocatedRef::Rc(index) => {
                 let value_meta = self.stack_metadata_mut(index)?;
-                switch_cycle(value_meta, vm_cycle)
+                switch_cycle(value_meta, vm_cycle, kind, ValueLocation::Stack(index.0))
             }
             LocatedRef::Transient(index) => {
                 let value_meta = self
                     .transient_refs
                     .get_mut(&index)
                     .ok_or(VmError::BadVmState)?;
-                switch_cycle(value_meta, vm_cycle)
+                switch_cycle(value_meta, vm_cycle, kind, index)
             }
+            // the borrow state of a cell already guards its inner value
+            LocatedRef::Cell(_) => Ok(ValueLock::None),
+        }
+    }
+
+    /// Restores the lock previously returned by [`switch_lock_cycle`](Vm::switch_lock_cycle)
+    pub fn restore_lock(&mut self, rf: LocatedRef, lock: ValueLock) -> Result<()> {
+        match rf {
+            LocatedRef::Stack(index) | LocatedRef::Rc(index) => {
+                self.stack_metadata_mut(index)?.lock = lock
+            }
+            LocatedRef::Transient(index) => {
+                self.transient_refs
+                    .get_mut(&index)
+                    .ok_or(VmError::BadVmState)?
+                    .lock = lock
+            }
+            LocatedRef::Cell(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Pushes a new reference of `kind` to the value the reference at `index` points to
+    ///
+    /// The original reference is locked until the reborrow is popped,
+    /// the value behind it gets its lock switched to the current cycle.
+    pub fn push_reborrow(&mut self, index: StackRef, kind: RefKind) -> Result<()> {
+        let cycle = self.current_cycle();
+        let (located_ref, r) = self.locate_ref(index)?;
+        if r.kind == RefKind::Ref && kind == RefKind::Mut {
+            let t = VmType::from(r.clone()).tag(tags::OP);
+            return Err(VmError::TypeError(vec![TypeError::NotMutReference(t)]));
+        }
+        let points_to = r.points_to;
+        let ref_type = self.types.reference(self.pointee(index)?, kind, points_to);
+        let ref_value = *self.single_stack_data(index)?;
+
+        let meta = self.stack_metadata_mut(index)?;
+        let ref_lock = meta.lock;
+        meta.lock
+            .add_lock(cycle, kind)
+            .map_err(|e| VmError::LockError(e, ValueLocation::Stack(index.0)))?;
+        let value_lock = match self.switch_lock_cycle(located_ref, kind) {
+            Ok(lock) => lock,
+            Err(e) => {
+                self.stack_metadata_mut(index)?.lock = ref_lock;
+                return Err(e);
+            }
+        };
+
+        let reborrow = StackRef(self.stack_metadata.len() - self.last_stack_frame);
+        let ref_meta = self.new_stack_meta_of_type(ref_type);
+        self.stack_metadata.push(ref_meta);
+        self.stack.push(ref_value);
+        self.reborrows.push(VmReborrow {
+            rf: index,
+            reborrow,
+            ref_lock,
+            value_lock,
+        });
+        Ok(())
+    }
+
+    /// Releases the locks held by the reborrow at `index`
+    ///
+    /// Returns `false` if the value at `index` is not a reborrow
+    fn release_reborrow(&mut self, index: StackRef) -> Result<bool> {
+        let position = self.reborrows.iter().rposition(|r| r.reborrow == index);
+        if let Some(position) = position {
+            let reborrow = self.reborrows.remove(position);
+            let (located_ref, _) = self.locate_ref(reborrow.rf)?;
+            self.restore_lock(located_ref, reborrow.value_lock)?;
+            self.stack_metadata_mut(reborrow.rf)?.lock = reborrow.ref_lock;
+            Ok(true)
+        } else {
+            Ok(false)
         }
     }
 
@@ -319,52 +611,44 @@ impl Vm {
         }
     }
 
-    pub fn new_stack_meta_of_type(&self, t: VmType) -> StackMeta {
+    pub fn new_stack_meta_of_type(&mut self, t: impl IntoTypeId) -> StackMeta {
+        let type_id = t.into_type_id(&mut self.types);
         let cycle = self.current_cycle();
         let len = self.stack.len();
-        StackMeta::new(t, StackDataRef(len), cycle)
+        StackMeta::new(type_id, StackDataRef(len), cycle)
     }
 
     pub fn push_deref(
         &mut self,
         value: impl IntoIterator<Item = StackData>,
-        t: VmType,
+        t: TypeId,
         kind: RefKind,
         rf: StackRef,
+        depth: usize,
     ) {
         let len = self.stack_metadata.len();
         let mut meta = self.new_stack_meta_of_type(t);
         meta.deref = kind.into();
+        meta.mutable = kind == RefKind::Mut;
         self.stack_metadata.push(meta);
         self.stack.extend(value);
         self.derefs.push(VmDeref {
             rf,
             deref: StackRef(len),
+            depth,
         });
     }
 
     pub fn pop_deref(&mut self) -> Result<()> {
         if let Some(d) = self.derefs.pop() {
-            let (lr, rt) = self.locate_ref(d.rf)?;
-            let pointer_size = rt.pointer.size();
-            if rt.kind == RefKind::Mut {
+            let (lr, kind, pointer) = self.locate_ref_chain(d.rf, d.depth)?;
+            let pointer_size = self.types.size(pointer);
+            if kind == RefKind::Mut {
                 self.stack_metadata_mut(d.rf)?.lock = ValueLock::None;
                 let deref_data = self.stack_data(d.deref)?.to_vec();
-                match lr {
-                    LocatedRef::Stack(index) => {
-                        let from = index.0;
-                        let until = from + pointer_size;
-                        self.stack.splice(from..until, deref_data);
-                    }
-                    LocatedRef::Transient(index) => match index {
-                        ValueLocation::Stack(index) => {
-                            let from = index;
-                            let until = from + pointer_size;
-                            self.stack.splice(from..until, deref_data);
-                        }
-                        ValueLocation::Heap(_) => unimplemented!(),
-                    },
-                }
+                let from = self.data_index(lr)?;
+                let until = from + pointer_size;
+                self.stack.splice(from..until, deref_data);
                 self.stack_metadata_mut(d.deref)?.was_moved = true;
             }
 
@@ -376,36 +660,398 @@ impl Vm {
 
     pub fn locate_ref(&self, index: StackRef) -> Result<(LocatedRef, &RefType)> {
         let meta = self.stack_metadata(index)?;
-        if let Some(PointedType::Ref(r)) = meta.value_type.pointed() {
+        if let Some(PointedType::Ref(r)) = self.types[meta.type_id].pointed() {
             let data = self.single_stack_data(index)?;
-            Ok((r.locate(data), r))
+            Ok((r.locate(data)?, r))
         } else {
             Err(VmError::BadVmState)
         }
     }
 
-    pub fn push_array_0(&mut self, size: usize, t: PrimitiveType) {
-        let arr_type = PointedType::s_arr(t, size);
-        let stack_size = arr_type.size();
-        let meta = self.new_stack_meta_of_type(arr_type.into());
+    /// Follows `depth` levels of references, starting from the reference at `index`
+    ///
+    /// Returns the location of the final value, its type and the kind of access to it,
+    /// which is `Mut` only if every reference in the chain is `Mut`
+    pub fn locate_ref_chain(
+        &self,
+        index: StackRef,
+        depth: usize,
+    ) -> Result<(LocatedRef, RefKind, TypeId)> {
+        let (mut located_ref, r) = self.locate_ref(index)?;
+        let mut kind = r.kind;
+        let mut pointer = self.pointee(index)?;
+        for _ in 1..depth {
+            let r = self.types[pointer].ref_type().ok_or_else(|| {
+                let e = TypeError::NotReference(self.types[pointer].tag(tags::OP));
+                VmError::TypeError(vec![e])
+            })?;
+            let ref_value = self
+                .stack
+                .get(self.data_index(located_ref)?)
+                .ok_or(VmError::BadVmState)?;
+            located_ref = r.locate(ref_value)?;
+            if r.kind == RefKind::Ref {
+                kind = RefKind::Ref;
+            }
+            pointer = self.types.inner(pointer).ok_or(VmError::BadVmState)?;
+        }
+        Ok((located_ref, kind, pointer))
+    }
+
+    /// Type the reference at `index` points to
+    fn pointee(&self, index: StackRef) -> Result<TypeId> {
+        let meta = self.stack_metadata(index)?;
+        self.types.inner(meta.type_id).ok_or(VmError::BadVmState)
+    }
+
+    /// Index of the first stack cell of the value located by `rf`
+    pub(crate) fn data_index(&self, rf: LocatedRef) -> Result<usize> {
+        match rf {
+            LocatedRef::Stack(index) => Ok(self.stack_metadata(index)?.index.0),
+            LocatedRef::Transient(ValueLocation::Stack(index)) => Ok(index),
+            // elements of heap values are not supported yet
+            LocatedRef::Transient(ValueLocation::Heap(_)) => Err(VmError::BadVmState),
+            LocatedRef::Cell(index) => Ok(index),
+            // the value is on the heap
+            LocatedRef::Rc(_) => Err(VmError::BadVmState),
+        }
+    }
+
+    /// Copies the value of type `t` located by `rf`
+    pub(crate) fn located_data(&self, rf: LocatedRef, t: TypeId) -> Result<Vec<StackData>> {
+        let size = self.types.size(t);
+        match rf {
+            LocatedRef::Rc(index) => {
+                let ptr = self.single_stack_data(index)?.into_primitive();
+                let range = rc::VALUE..rc::VALUE + size;
+                // SAFETY: the `Rc` is locked while a reference to its value lives
+                unsafe { rc::with_block(ptr, |b| b.get(range).map(<[_]>::to_vec)) }
+                    .ok_or(VmError::BadVmState)
+            }
+            _ => {
+                let from = self.data_index(rf)?;
+                let range = from..from + size;
+                self.stack
+                    .get(range)
+                    .map(<[_]>::to_vec)
+                    .ok_or(VmError::BadVmState)
+            }
+        }
+    }
+
+    /// Moves the value at `index` into a new unborrowed cell
+    pub fn push_cell(&mut self, index: StackRef) -> Result<()> {
+        let t = self.value_type(index)?;
+        if t.ref_type().is_some() {
+            return Err(VmError::InvalidTypeForOperation(t.tag(tags::OP)));
+        }
+        self.check_movable(index)?;
+        let cell_type = PointedType::cell(t.clone());
+        let mut value = vec![cell::UNBORROWED.into_stack_data()];
+        value.extend_from_slice(self.stack_data(index)?);
+        self.stack_metadata_mut(index)?.was_moved = true;
+        self.push_typed(value, cell_type);
+        Ok(())
+    }
+
+    /// Borrows the inside of the cell the reference at `index` points to
+    ///
+    /// Pushes a reference of `kind` that releases the borrow once it is popped or dropped.
+    /// Only cells on the stack can be borrowed, the borrow state of a cell owned by an `Rc`
+    /// would be shared by all its owners, so `Rc<Cell<T>>` is rejected with a type error.
+    pub fn push_cell_borrow(&mut self, index: StackRef, kind: RefKind) -> Result<()> {
+        let (located_ref, r) = self.locate_ref(index)?;
+        if r.pointer.cell().is_none() {
+            let t = r.pointer.tag(tags::OP);
+            let e = TypeError::Condition(t, "Expected a reference to a cell".into());
+            return Err(VmError::TypeError(vec![e]));
+        }
+        if let LocatedRef::Rc(_) = located_ref {
+            let t = r.pointer.tag(tags::OP);
+            let e = TypeError::Condition(t, "Cells owned by an Rc can't be borrowed".into());
+            return Err(VmError::TypeError(vec![e]));
+        }
+        let state_index = self.data_index(located_ref)?;
+        let state = self.stack.get_mut(state_index).ok_or(VmError::BadVmState)?;
+        let acquired = cell::acquire(state.into_primitive(), kind)
+            .map_err(|e| VmError::LockError(e, ValueLocation::Stack(state_index)))?;
+        *state = acquired.into_stack_data();
+
+        let inner = self.types.inner(self.pointee(index)?);
+        let inner = inner.ok_or(VmError::BadVmState)?;
+        let ref_type = self.types.reference(inner, kind, RefLocation::CellOnStack);
+        self.push_single_typed(state_index + 1, ref_type);
+        Ok(())
+    }
+
+    /// Moves the value at `index` into a new reference counted heap block
+    pub fn push_rc(&mut self, index: StackRef) -> Result<()> {
+        let t = self.value_type(index)?;
+        if t.ref_type().is_some() {
+            return Err(VmError::InvalidTypeForOperation(t.tag(tags::OP)));
+        }
+        self.check_movable(index)?;
+        let rc_type = PointedType::Rc(t.clone());
+        let ptr = rc::allocate(self.stack_data(index)?);
+        self.stack_metadata_mut(index)?.was_moved = true;
+        self.push_single_typed(ptr, rc_type);
+        Ok(())
+    }
+
+    /// Block of the `Rc` or `Weak` at `index`, a moved one may have released it
+    fn rc_block(&self, index: StackRef) -> Result<usize> {
+        if self.stack_metadata(index)?.was_moved {
+            return Err(VmError::UseOfMovedValue(index));
+        }
+        Ok(self.single_stack_data(index)?.into_primitive())
+    }
+
+    /// Pushes a `&` reference to the value owned by the `Rc` at `index`
+    ///
+    /// The `Rc` is locked as long as the reference lives.
+    pub fn push_rc_deref(&mut self, index: StackRef) -> Result<()> {
+        let cycle = self.current_cycle();
+        let t = self.value_type(index)?;
+        if !matches!(t.pointed(), Some(PointedType::Rc(_))) {
+            return Err(VmError::InvalidTypeForOperation(t.tag(tags::OP)));
+        }
+        self.rc_block(index)?;
+        let inner = self.pointee(index)?;
+        self.stack_metadata_mut(index)?
+            .lock
+            .add_lock(cycle, RefKind::Ref)
+            .map_err(|e| VmError::LockError(e, ValueLocation::Stack(index.0)))?;
+        let ref_type = self.types.reference(inner, RefKind::Ref, RefLocation::Rc);
+        self.push_single_typed(index.0, ref_type);
+        Ok(())
+    }
+
+    /// Pushes a new `Rc` or `Weak` sharing the block with the one at `index`
+    pub fn push_rc_clone(&mut self, index: StackRef) -> Result<()> {
+        let t = self.stack_metadata(index)?.type_id;
+        let counter = match self.types[t].pointed() {
+            Some(PointedType::Rc(_)) => rc::STRONG,
+            Some(PointedType::Weak(_)) => rc::WEAK,
+            _ => {
+                let t = self.types[t].tag(tags::OP);
+                return Err(VmError::InvalidTypeForOperation(t));
+            }
+        };
+        let ptr = self.rc_block(index)?;
+        // SAFETY: a live `Rc` or `Weak` keeps its block allocated
+        unsafe { rc::update_count(ptr, counter, 1) };
+        self.push_single_typed(ptr, t);
+        Ok(())
+    }
+
+    /// Pushes a `Weak` pointing to the block of the `Rc` at `index`
+    pub fn push_weak(&mut self, index: StackRef) -> Result<()> {
+        let t = self.value_type(index)?;
+        let inner = match t.pointed() {
+            Some(PointedType::Rc(inner)) => inner.clone(),
+            _ => return Err(VmError::InvalidTypeForOperation(t.tag(tags::OP))),
+        };
+        let ptr = self.rc_block(index)?;
+        // SAFETY: a live `Rc` keeps its block allocated
+        unsafe { rc::update_count(ptr, rc::WEAK, 1) };
+        self.push_single_typed(ptr, PointedType::Weak(inner));
+        Ok(())
+    }
+
+    /// Strong count of the block the `Weak` at `index` points to
+    pub fn weak_strong_count(&self, index: StackRef) -> Result<usize> {
+        let t = self.value_type(index)?;
+        match t.pointed() {
+            Some(PointedType::Weak(_)) => {
+                let ptr = self.rc_block(index)?;
+                // SAFETY: a live `Weak` keeps its block allocated
+                Ok(unsafe { rc::counts(ptr) }.0)
+            }
+            _ => Err(VmError::InvalidTypeForOperation(t.tag(tags::OP))),
+        }
+    }
+
+    /// Pushes an `Rc` from the `Weak` at `index`, fails if the value is already released
+    pub fn push_upgraded(&mut self, index: StackRef) -> Result<()> {
+        if self.weak_strong_count(index)? == 0 {
+            return Err(VmError::UpgradeOfReleasedWeak(index));
+        }
+        let inner = match self.value_type(index)?.pointed() {
+            Some(PointedType::Weak(inner)) => inner.clone(),
+            _ => return Err(VmError::BadVmState),
+        };
+        let ptr = self.rc_block(index)?;
+        // SAFETY: the block is allocated as long as the `Weak` lives
+        unsafe { rc::update_count(ptr, rc::STRONG, 1) };
+        self.push_single_typed(ptr, PointedType::Rc(inner));
+        Ok(())
+    }
+
+    /// Moves the value at `index` into a new `Any`
+    pub fn push_any(&mut self, index: StackRef) -> Result<()> {
+        let meta = self.stack_metadata(index)?;
+        if self.types[meta.type_id].ref_type().is_some() {
+            let t = self.types[meta.type_id].tag(tags::OP);
+            return Err(VmError::InvalidTypeForOperation(t));
+        }
+        self.check_movable(index)?;
+        let value = any::AnyValue {
+            type_id: meta.type_id,
+            data: self.stack_data(index)?.to_vec(),
+        };
+        self.stack_metadata_mut(index)?.was_moved = true;
+        self.push_single_typed(any::allocate(value), PointedType::Any);
+        Ok(())
+    }
+
+    /// Type of the value wrapped by the `Any` at `index`
+    pub fn any_type(&self, index: StackRef) -> Result<&VmType> {
+        let meta = self.stack_metadata(index)?;
+        if meta.was_moved {
+            return Err(VmError::UseOfMovedValue(index));
+        }
+        if let Some(PointedType::Any) = self.types[meta.type_id].pointed() {
+            let ptr = self.single_stack_data(index)?.into_primitive();
+            // SAFETY: a live `Any` owns its heap value
+            Ok(&self.types[unsafe { any::get(ptr) }.type_id])
+        } else {
+            let t = self.types[meta.type_id].tag(tags::OP);
+            Err(VmError::InvalidTypeForOperation(t))
+        }
+    }
+
+    /// Moves the value out of the `Any` at `index` if it has the type `t`
+    pub fn push_downcast(&mut self, index: StackRef, t: &VmType) -> Result<()> {
+        let actual = self.any_type(index)?;
+        if actual != t {
+            let e = TypeError::BadDowncast(t.tag(tags::RESULT), actual.tag(tags::OP));
+            return Err(VmError::TypeError(vec![e]));
+        }
+        self.check_movable(index)?;
+        let ptr = self.single_stack_data(index)?.into_primitive();
+        self.stack_metadata_mut(index)?.was_moved = true;
+        // SAFETY: the `Any` was live until it was marked as moved above
+        let value = unsafe { any::take(ptr) };
+        self.push_typed(value.data, value.type_id);
+        Ok(())
+    }
+
+    /// Borrow state of the value at `index`, [`cell::UNBORROWED`] if it is not a cell
+    fn cell_state(&self, index: StackRef) -> Result<usize> {
+        if self.value_type(index)?.cell().is_some() {
+            Ok(self.stack_data(index)?[0].into_primitive())
+        } else {
+            Ok(cell::UNBORROWED)
+        }
+    }
+
+    pub fn push_array_0(&mut self, size: usize, t: impl Into<VmType>) -> Result<()> {
+        self.push_zeroed(PointedType::s_arr(t, size))
+    }
+
+    /// Push a value of type `t` whose data is all zeroes
+    pub fn push_zeroed(&mut self, t: impl Into<VmType>) -> Result<()> {
+        let t = t.into();
+        let stack_size = t.size();
+        if stack_size > MAX_STACK_LEN.saturating_sub(self.stack.len()) {
+            return Err(VmError::StackOverflow);
+        }
+        let meta = self.new_stack_meta_of_type(t);
         self.stack_metadata.push(meta);
         self.stack
-            .extend(std::iter::repeat(StackData::default()).take(stack_size))
+            .extend(std::iter::repeat(StackData::default()).take(stack_size));
+        Ok(())
+    }
+
+    /// Push an immutable value that lives as long as the stack, as `LdStatic` loads it
+    ///
+    /// Statics belong to [`STATIC_CYCLE`], so `&` can borrow them right away. They are
+    /// below any other value of the stack for scopes to never end them.
+    pub fn push_static(&mut self, t: impl IntoTypeId, data: Vec<StackData>) -> Result<()> {
+        if matches!(self.stack_metadata.last(), Some(m) if m.cycle != STATIC_CYCLE) {
+            return Err(VmError::MisplacedStatic);
+        }
+        if data.len() > MAX_STACK_LEN.saturating_sub(self.stack.len()) {
+            return Err(VmError::StackOverflow);
+        }
+        let type_id = t.into_type_id(&mut self.types);
+        let mut meta = StackMeta::new(type_id, StackDataRef(self.stack.len()), STATIC_CYCLE);
+        meta.mutable = false;
+        self.stack_metadata.push(meta);
+        self.stack.extend(data);
+        Ok(())
     }
 
     pub fn push_s_str(&mut self, ptr: usize, len: usize) {
-        let meta = self.new_stack_meta_of_type(PrimitiveType::SStr.into());
+        let meta = self.new_stack_meta_of_type(PrimitiveType::SStr);
         self.stack_metadata.push(meta);
         self.stack.push(ptr.into_stack_data());
         self.stack.push(len.into_stack_data());
     }
 
+    /// Stop the code with [`VmError::OutOfSteps`] after `steps` more instructions
+    ///
+    /// `None` lets the code run until it ends, which is the default.
+    pub fn set_step_limit(&mut self, steps: Option<usize>) {
+        self.steps = steps;
+    }
+
+    /// Count an instruction against the step limit
+    pub(crate) fn take_step(&mut self) -> Result<()> {
+        match &mut self.steps {
+            Some(0) => Err(VmError::OutOfSteps),
+            Some(steps) => {
+                *steps -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
     pub fn current_cycle(&self) -> usize {
         self.cycle
     }
 
-    pub fn current_const_pool(&self) -> &ConstantPool {
-        &self.modules[&self.current_module].const_pool
+    pub fn current_const_pool(&self) -> Result<&ConstantPool> {
+        self.modules
+            .const_pool(self.current_module)
+            .ok_or(VmError::ConstantPoolError)
+    }
+
+    /// Load `m` under `name`, the code of its functions is validated against its pool first
+    pub fn load_module(&mut self, name: impl Into<String>, m: Module) -> Result<ModuleId> {
+        for (fn_name, function) in &m.functions {
+            m.const_pool
+                .validate_with(&function.bytecode, &self.extensions)
+                .map_err(|e| VmError::InvalidPool(fn_name.clone(), e))?;
+        }
+        self.modules.load(name, m)
+    }
+
+    pub fn modules(&self) -> &ModuleTable {
+        &self.modules
+    }
+
+    /// Register an extension opcode, interpreted as `HWide` followed by `code`
+    pub fn register_extension(&mut self, code: u8, opcode: ExtensionOpcode) -> Result<()> {
+        self.extensions.register(code, opcode)
+    }
+
+    pub fn extensions(&self) -> &ExtensionTable {
+        &self.extensions
+    }
+
+    pub fn current_module(&self) -> ModuleId {
+        self.current_module
+    }
+
+    /// Switch the constant pool used by the code to the one of `module`
+    pub fn set_current_module(&mut self, module: ModuleId) -> Result<()> {
+        if self.modules.const_pool(module).is_none() {
+            return Err(VmError::BadVmState);
+        }
+        self.current_module = module;
+        Ok(())
     }
 }
 
@@ -414,13 +1060,17 @@ impl Default for Vm {
         Self {
             stack: Vec::with_capacity(128),
             stack_metadata: Vec::with_capacity(128),
+            types: TypeTable::new(),
             cycle: 1,
             ip: 0,
             last_stack_frame: 0,
             modules: Default::default(),
-            current_module: "".to_string(),
+            current_module: Default::default(),
+            extensions: Default::default(),
             transient_refs: HashMap::new(),
             derefs: Vec::new(),
+            reborrows: Vec::new(),
+            steps: None,
         }
     }
 }
@@ -441,4 +1091,18 @@ impl From<StackDataRef> for ValueLocation {
 pub struct VmDeref {
     pub rf: StackRef,
     pub deref: StackRef,
+    /// Number of references followed from `rf`
+    pub depth: usize,
+}
+
+#[derive(Debug)]
+pub struct VmReborrow {
+    /// The reference that was reborrowed
+    pub rf: StackRef,
+    /// The new reference
+    pub reborrow: StackRef,
+    /// Lock of `rf` before the reborrow
+    pub ref_lock: ValueLock,
+    /// Lock of the referenced value before the reborrow
+    pub value_lock: ValueLock,
 }
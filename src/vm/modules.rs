@@ -0,0 +1,103 @@
+//! Loaded modules and functions
+//!
+//! Modules are registered by name once, when they are loaded. After that the vm and the
+//! code refer to them, and to their functions, by dense [`ModuleId`] and [`FunctionId`]
+//! handles, so no names are hashed while the code runs.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::error::VmError;
+use crate::{ConstantPool, Function, Module};
+
+/// Handle of a module loaded into a [`ModuleTable`]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash, Default)]
+pub struct ModuleId(u32);
+
+/// Handle of a function of a module loaded into a [`ModuleTable`]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub struct FunctionId(u32);
+
+struct LoadedModule {
+    name: String,
+    const_pool: ConstantPool,
+    functions: HashMap<String, FunctionId>,
+}
+
+struct LoadedFunction {
+    module: ModuleId,
+    function: Function,
+}
+
+#[derive(Default)]
+pub struct ModuleTable {
+    modules: Vec<LoadedModule>,
+    functions: Vec<LoadedFunction>,
+    /// Used only to link modules by their names
+    names: HashMap<String, ModuleId>,
+}
+
+impl ModuleTable {
+    /// Register `module` and its functions under `name`
+    pub fn load(&mut self, name: impl Into<String>, module: Module) -> Result<ModuleId, VmError> {
+        let name = name.into();
+        if self.names.contains_key(&name) {
+            return Err(VmError::ModuleAlreadyLoaded(name));
+        }
+        let id = ModuleId(u32::try_from(self.modules.len()).expect("too many modules"));
+        let mut functions = HashMap::with_capacity(module.functions.len());
+        for (fn_name, function) in module.functions {
+            let fn_id =
+                FunctionId(u32::try_from(self.functions.len()).expect("too many functions"));
+            self.functions.push(LoadedFunction {
+                module: id,
+                function,
+            });
+            functions.insert(fn_name, fn_id);
+        }
+        self.names.insert(name.clone(), id);
+        self.modules.push(LoadedModule {
+            name,
+            const_pool: module.const_pool,
+            functions,
+        });
+        Ok(id)
+    }
+
+    pub fn module_id(&self, name: &str) -> Option<ModuleId> {
+        self.names.get(name).copied()
+    }
+
+    pub fn function_id(&self, module: ModuleId, name: &str) -> Option<FunctionId> {
+        self.modules
+            .get(module.0 as usize)?
+            .functions
+            .get(name)
+            .copied()
+    }
+
+    pub fn name(&self, module: ModuleId) -> Option<&str> {
+        self.modules.get(module.0 as usize).map(|m| m.name.as_str())
+    }
+
+    pub fn const_pool(&self, module: ModuleId) -> Option<&ConstantPool> {
+        self.modules.get(module.0 as usize).map(|m| &m.const_pool)
+    }
+
+    pub fn function(&self, id: FunctionId) -> Option<&Function> {
+        self.functions.get(id.0 as usize).map(|f| &f.function)
+    }
+
+    /// The module that defines the function
+    pub fn module_of(&self, id: FunctionId) -> Option<ModuleId> {
+        self.functions.get(id.0 as usize).map(|f| f.module)
+    }
+
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+}
@@ -0,0 +1,78 @@
+//! Reference counted values on the heap
+//!
+//! Both `Rc` and `Weak` values hold a pointer to a shared heap block.
+//!
+//! # Data layout
+//!
+//! | 0            | 1          | [2...2 + value size) |
+//! |--------------|------------|----------------------|
+//! | Strong count | Weak count | Value                |
+//!
+//! The value is released once the strong count drops to 0,
+//! the block itself is freed once both counts are 0.
+
+use std::mem::ManuallyDrop;
+use std::ptr::NonNull;
+
+use crate::primitives::HeapArray;
+use crate::stack::data::{IntoPrimitive, IntoStackData, StackData};
+
+pub const STRONG: usize = 0;
+pub const WEAK: usize = 1;
+pub const VALUE: usize = 2;
+
+/// Allocates a new block with a single strong owner, returns its pointer
+pub fn allocate(value: &[StackData]) -> usize {
+    let mut block: HeapArray<StackData> = HeapArray::with_default(VALUE + value.len());
+    let data = block.as_mut_slice();
+    data[STRONG] = 1usize.into_stack_data();
+    data[WEAK] = 0usize.into_stack_data();
+    data[VALUE..].copy_from_slice(value);
+    block.into_raw().as_ptr() as usize
+}
+
+/// Runs `f` on the data of the block at `ptr`
+///
+/// # Safety
+///
+/// `ptr` must be returned by [`allocate`] and not yet freed
+pub unsafe fn with_block<R>(ptr: usize, f: impl FnOnce(&mut [StackData]) -> R) -> R {
+    let mut block = ManuallyDrop::new(HeapArray::from_raw(NonNull::new_unchecked(ptr as *mut u8)));
+    f(block.as_mut_slice())
+}
+
+/// Returns the strong and the weak count of the block at `ptr`
+///
+/// # Safety
+///
+/// Same as [`with_block`]
+pub unsafe fn counts(ptr: usize) -> (usize, usize) {
+    with_block(ptr, |b| {
+        (b[STRONG].into_primitive(), b[WEAK].into_primitive())
+    })
+}
+
+/// Adds `delta` to the counter at `counter` of the block at `ptr`, returns the new count
+///
+/// # Safety
+///
+/// Same as [`with_block`]
+pub unsafe fn update_count(ptr: usize, counter: usize, delta: isize) -> usize {
+    with_block(ptr, |b| {
+        let count: usize = b[counter].into_primitive();
+        let count = (count as isize + delta) as usize;
+        b[counter] = count.into_stack_data();
+        count
+    })
+}
+
+/// Frees the block at `ptr`
+///
+/// # Safety
+///
+/// Same as [`with_block`], `ptr` can't be used afterwards
+pub unsafe fn free(ptr: usize) {
+    drop(HeapArray::<StackData>::from_raw(NonNull::new_unchecked(
+        ptr as *mut u8,
+    )));
+}
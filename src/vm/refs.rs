@@ -5,11 +5,18 @@ use crate::vm::ValueLocation;
 pub enum LocatedRef {
     Stack(StackRef),
     Transient(ValueLocation),
+    /// Inner value of a cell, at the given stack index
+    Cell(usize),
+    /// Value owned by the `Rc` at the given stack slot
+    Rc(StackRef),
 }
 
 pub(super) mod code {
     use crate::code::{refs::*, Chunk, RefSource};
+    use crate::decoder::read_operand;
     use crate::error::VmError;
+    use crate::opcodes::Operand;
+    use crate::vm::extensions::ExtensionOperands;
 
     pub trait VmRefSource {
         type VmError: std::error::Error;
@@ -22,6 +29,23 @@ pub(super) mod code {
 
         fn read_offset_vm(&self) -> Result<usize, Self::VmError>;
 
+        fn read_jump_vm(&self) -> Result<isize, Self::VmError>;
+
+        /// Reads the immediate that follows the first `n_refs` refs of the opcode
+        fn read_immediate_vm(&self, n_refs: usize) -> Result<Immediate, Self::VmError>;
+
+        fn refs_size_vm(&self, n_refs: usize) -> Result<usize, Self::VmError>;
+
+        fn refs_size_with_offset_vm(&self, n_refs: usize) -> Result<usize, Self::VmError>;
+
+        /// Reads the operands of an extension opcode, laid out as `operands` declares them
+        ///
+        /// Returns them along with the amount of bytes they take
+        fn read_extension_operands_vm(
+            &self,
+            operands: &[Operand],
+        ) -> Result<(ExtensionOperands, usize), Self::VmError>;
+
         fn read_two_vm(&self) -> Result<TwoStackRefs, Self::VmError> {
             let result = StackRef(self.read_ref_vm(0)?);
             let op = StackRef(self.read_ref_vm(1)?);
@@ -51,6 +75,25 @@ pub(super) mod code {
         }
     }
 
+    /// Operands of the opcode that is being interpreted, handlers are generic over it
+    pub trait OperandSource: VmRefSource<VmError = VmError> {
+        /// Offset of the opcode in the bytecode
+        fn offset(&self) -> usize;
+
+        /// Reads a byte of the opcode
+        fn read_byte(&self, index: usize) -> Option<u8>;
+    }
+
+    impl OperandSource for Chunk<'_> {
+        fn offset(&self) -> usize {
+            Chunk::offset(self)
+        }
+
+        fn read_byte(&self, index: usize) -> Option<u8> {
+            Chunk::read_byte(self, index)
+        }
+    }
+
     impl VmRefSource for Chunk<'_> {
         type VmError = VmError;
 
@@ -71,5 +114,38 @@ pub(super) mod code {
         fn read_offset_vm(&self) -> Result<usize, Self::VmError> {
             self.read_offset().ok_or(VmError::InvalidBytecode)
         }
+
+        fn read_jump_vm(&self) -> Result<isize, Self::VmError> {
+            self.read_jump().ok_or(VmError::InvalidBytecode)
+        }
+
+        fn read_immediate_vm(&self, n_refs: usize) -> Result<Immediate, Self::VmError> {
+            self.read_immediate(n_refs).ok_or(VmError::InvalidBytecode)
+        }
+
+        fn refs_size_vm(&self, n_refs: usize) -> Result<usize, Self::VmError> {
+            self.refs_size(n_refs).ok_or(VmError::InvalidBytecode)
+        }
+
+        fn refs_size_with_offset_vm(&self, n_refs: usize) -> Result<usize, Self::VmError> {
+            self.refs_size_with_offset(n_refs)
+                .ok_or(VmError::InvalidBytecode)
+        }
+
+        fn read_extension_operands_vm(
+            &self,
+            operands: &[Operand],
+        ) -> Result<(ExtensionOperands, usize), Self::VmError> {
+            // the operands follow the two bytes of the opcode
+            let mut position = 2;
+            let mut res = ExtensionOperands::new();
+            for operand in operands {
+                let (code_ref, size) =
+                    read_operand(self, operand.kind, position).ok_or(VmError::InvalidBytecode)?;
+                position += size;
+                res.push(code_ref);
+            }
+            Ok((res, position - 2))
+        }
     }
 }
@@ -0,0 +1,125 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::error::BuildError;
+use ngvm::model::builder::CodeBuilder;
+use ngvm::model::{Comparison, Opcode::*};
+use ngvm::types::PrimitiveType::*;
+use ngvm::Vm;
+
+use common::stack;
+
+mod common;
+
+fn counting_loop() -> CodeBuilder {
+    let mut b = CodeBuilder::new();
+    let counter = b.u64(0);
+    let step = b.u64(1);
+    let limit = b.u64(100);
+    let cond = b.bool(false);
+    let check = b.label();
+    b.jump(check);
+    let head = b.here();
+    {
+        let mut body = b.scope();
+        let twice = body.u64(2);
+        body.add(counter, counter, step).mul(twice, twice, step);
+    }
+    b.place(check)
+        .compare(Comparison::Lt, cond, counter, limit)
+        .jump_if(cond, head);
+    b
+}
+
+#[test]
+fn test_counting_loop() {
+    let (ops, pool) = counting_loop().into_model().unwrap();
+    let expected = vec![
+        LDType {
+            type_location: p(0),
+            value_location: p(1),
+        },
+        LDType {
+            type_location: p(0),
+            value_location: p(2),
+        },
+        LDType {
+            type_location: p(0),
+            value_location: p(3),
+        },
+        LdFalse,
+        J { label: 0 },
+        Label(1),
+        StartScope,
+        LDType {
+            type_location: p(0),
+            value_location: p(4),
+        },
+        UAdd(three(0, 0, 1)),
+        UMul(three(4, 4, 1)),
+        EndScope,
+        Label(0),
+        Lt(three(3, 0, 2)),
+        JC {
+            label: 1,
+            cond: s(3),
+        },
+    ];
+    assert_eq!(format!("{:?}", ops), format!("{:?}", expected));
+    assert_eq!(pool.get_single(p(3)), Some(100u64.to_le_bytes()));
+    assert!(pool.get(p(5)).is_none());
+
+    let (code, pool) = counting_loop().build().unwrap();
+    let mut vm = Vm::headless(pool);
+    code.interpret(&mut vm).unwrap();
+    assert_eq!(stack(&vm)[0], vec![100u64.to_le_bytes()]);
+}
+
+#[test]
+fn test_deref_guard_writes_back() {
+    let mut b = CodeBuilder::new();
+    let value = b.i64(-3);
+    {
+        let mut scope = b.scope();
+        let reference = scope.take_mut(value);
+        let two = scope.i64(2);
+        let mut view = scope.start_deref(reference);
+        let v = view.value();
+        view.add(v, v, two);
+    }
+    let (code, pool) = b.build().unwrap();
+    let mut vm = Vm::headless(pool);
+    code.interpret(&mut vm).unwrap();
+    assert_eq!(stack(&vm), vec![vec![(-1i64).to_le_bytes()]]);
+}
+
+#[test]
+fn test_misuses_are_collected() {
+    let mut b = CodeBuilder::new();
+    let unsigned = b.u64(1);
+    let signed = b.i64(1);
+    let inner = {
+        let mut scope = b.scope();
+        scope.bool(true)
+    };
+    b.add(unsigned, unsigned, signed).trace(inner);
+    let end = b.label();
+    b.jump_if(unsigned, end);
+    b.bool(true);
+    b.place(end);
+    let text = b.str("text");
+    b.add(text, text, text);
+
+    let errors = b.into_model().err().unwrap();
+    assert_eq!(
+        errors,
+        vec![
+            BuildError::TypeMismatch(5, U64.into(), I64.into()),
+            BuildError::StaleSlot(6),
+            BuildError::TypeMismatch(7, Bool.into(), U64.into()),
+            BuildError::StackMismatch(9, 0),
+            BuildError::InvalidType(11, SStr.into()),
+        ]
+    );
+}
@@ -0,0 +1,97 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::cfg::{BlockId, ControlFlowGraph};
+use ngvm::code::refs::*;
+use ngvm::model::{self, Opcode::*};
+use ngvm::Code;
+
+/// Counts up to a limit, with a scope in the body of the loop
+fn counting_loop() -> Vec<model::Opcode> {
+    vec![
+        Ld0U64,
+        Ld0U64,
+        LdFalse,
+        Label(0),
+        UAdd(three(0, 0, 1)),
+        Lt(three(2, 0, 1)),
+        JC {
+            label: 1,
+            cond: s(2),
+        },
+        Scope(vec![Ld0U64, UAdd(three(3, 3, 0))]),
+        J { label: 0 },
+        Label(1),
+        Ld0U64,
+    ]
+}
+
+#[test]
+fn test_blocks_are_split_at_jumps_and_scopes() {
+    let cfg = ControlFlowGraph::from_model(&counting_loop()).unwrap();
+    let blocks: Vec<_> = cfg.blocks().iter().map(|b| b.opcodes.len()).collect();
+    // entry, loop header, scope, jump back, exit
+    assert_eq!(blocks, vec![3, 3, 4, 1, 1]);
+
+    let successors: Vec<_> = cfg.blocks().iter().map(|b| b.successors.clone()).collect();
+    assert_eq!(
+        successors,
+        vec![
+            vec![BlockId(1)],
+            vec![BlockId(2), BlockId(4)],
+            vec![BlockId(3)],
+            vec![BlockId(1)],
+            vec![],
+        ]
+    );
+    assert_eq!(cfg.blocks()[1].predecessors, vec![BlockId(0), BlockId(3)]);
+    assert!(cfg.blocks()[4].exits);
+    assert_eq!(cfg.block_at(cfg.blocks()[2].start + 1), Some(BlockId(2)));
+}
+
+#[test]
+fn test_dominators_and_loops() {
+    let cfg = ControlFlowGraph::from_model(&counting_loop()).unwrap();
+    assert_eq!(cfg.immediate_dominator(BlockId(0)), None);
+    assert_eq!(cfg.immediate_dominator(BlockId(3)), Some(BlockId(2)));
+    assert_eq!(cfg.immediate_dominator(BlockId(4)), Some(BlockId(1)));
+    assert!(cfg.dominates(BlockId(1), BlockId(3)));
+    assert!(!cfg.dominates(BlockId(2), BlockId(4)));
+
+    assert_eq!(cfg.loops().len(), 1);
+    let natural_loop = &cfg.loops()[0];
+    assert_eq!(natural_loop.header, BlockId(1));
+    assert_eq!(
+        natural_loop.blocks,
+        vec![BlockId(1), BlockId(2), BlockId(3)]
+    );
+    assert!(cfg.is_back_edge(BlockId(3), BlockId(1)));
+}
+
+#[test]
+fn test_unreachable_blocks_have_no_dominator() {
+    let cfg = ControlFlowGraph::from_model(&[J { label: 0 }, Ld0U64, Label(0)]).unwrap();
+    assert_eq!(cfg.blocks().len(), 2);
+    assert!(cfg.blocks()[0].exits);
+    assert!(!cfg.is_reachable(BlockId(1)));
+    assert!(!cfg.dominates(BlockId(0), BlockId(1)));
+}
+
+#[test]
+fn test_dot_has_the_disassembly_of_every_block() {
+    let cfg = ControlFlowGraph::from_model(&counting_loop()).unwrap();
+    let dot = cfg.to_dot();
+    assert!(dot.starts_with("digraph cfg {"));
+    assert!(dot.contains("b3 -> b1 [style=dashed];"));
+    assert!(dot.contains("b1 -> b4;"));
+    assert!(dot.contains("b4 -> exit;"));
+    assert!(dot.contains("StartScope"));
+    assert_eq!(dot.matches("UAdd").count(), 2);
+}
+
+#[test]
+fn test_jump_into_an_opcode_has_no_graph() {
+    let code = Code::from_model(&[JOffset { offset: 1 }, Ld0U64]).unwrap();
+    assert!(code.decode().is_full);
+    assert!(code.control_flow().is_none());
+}
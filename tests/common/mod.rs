@@ -0,0 +1,120 @@
+//! Fixtures shared by the tests, the fuzz targets include this module by its path
+#![allow(dead_code)]
+
+use ngvm::code::refs::{p, s};
+use ngvm::error::{VmContextError, VmError};
+use ngvm::model::{self, Opcode::*};
+use ngvm::types::PrimitiveType::*;
+use ngvm::{Code, ConstantPool, Vm};
+
+/// The `U64` type followed by 1 and 100, the `I64` type followed by 1, then 0 as a `u64`
+/// and the string "a"
+pub fn pool() -> ConstantPool {
+    ConstantPool::new(vec![
+        U64.into(),
+        1u64.into(),
+        100u64.into(),
+        I64.into(),
+        1i64.into(),
+        0u64.into(),
+        "a".into(),
+    ])
+}
+
+/// Load the `u64` at `value` of [`pool`]
+pub fn ld(value: usize) -> model::Opcode {
+    LDType {
+        type_location: p(0),
+        value_location: p(value),
+    }
+}
+
+/// Run `ops` on a vm over `pool`
+pub fn run_with(pool: ConstantPool, ops: &[model::Opcode]) -> Result<Vm, VmContextError> {
+    let code = Code::from_model(ops).ok_or(VmError::InvalidBytecode)?;
+    let mut vm = Vm::headless(pool);
+    code.interpret(&mut vm)?;
+    Ok(vm)
+}
+
+/// Run `ops` on a vm over [`pool`]
+pub fn run(ops: &[model::Opcode]) -> Result<Vm, VmContextError> {
+    run_with(pool(), ops)
+}
+
+/// Data of the values on the stack, from the bottom up
+pub fn stack(vm: &Vm) -> Vec<Vec<[u8; 8]>> {
+    (0..)
+        .map(|i| vm.stack_data(s(i)).map(|d| d.to_vec()))
+        .take_while(Result::is_ok)
+        .map(Result::unwrap)
+        .collect()
+}
+
+/// The result of running code and the stack it leaves
+pub type Outcome = (Result<(), VmContextError>, Vec<Vec<[u8; 8]>>);
+
+/// Run `ops` as bytecode and pre-decoded on vms over [`pool`]
+pub fn run_both(ops: &[model::Opcode]) -> [Outcome; 2] {
+    let code = Code::from_model(ops).unwrap();
+    let threaded = code.compile().unwrap();
+
+    let mut vm = Vm::headless(pool());
+    let bytecode = code.interpret(&mut vm);
+    let bytecode = (bytecode, stack(&vm));
+
+    let mut vm = Vm::headless(pool());
+    let threaded = threaded.interpret(&mut vm);
+    [bytecode, (threaded, stack(&vm))]
+}
+
+/// Run `ops` on a vm over [`pool`], return the error it stops with or the stack it leaves
+pub fn outcome(ops: &[model::Opcode]) -> String {
+    match run(ops) {
+        Ok(vm) => format!("{:?}", stack(&vm)),
+        Err(e) => format!("{:?}", e.error),
+    }
+}
+
+/// Xorshift, the numbers are the same on every run
+pub struct XorShift(pub u64);
+
+impl XorShift {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    pub fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+}
+
+/// Constants of every kind, for the refs of malformed code to land on
+pub fn mixed_pool() -> ConstantPool {
+    ConstantPool::new(vec![
+        U64.into(),
+        3u64.into(),
+        Char.into(),
+        0xd800u32.into(),
+        Bool.into(),
+        "str".into(),
+        F32.into(),
+        I8.into(),
+        SStr.into(),
+        StackFrame.into(),
+    ])
+}
+
+/// A vm over [`mixed_pool`] that stops the code before long, loops are valid code
+pub fn limited_vm() -> Vm {
+    let mut vm = Vm::headless(mixed_pool());
+    vm.set_step_limit(Some(1000));
+    vm
+}
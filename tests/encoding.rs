@@ -0,0 +1,110 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::legacy;
+use ngvm::code::refs::*;
+use ngvm::model::Opcode::*;
+use ngvm::opcodes::Opcode as Nc;
+use ngvm::{Code, Vm};
+
+use common::{ld, pool};
+
+mod common;
+
+fn count_to_100() -> Vec<ngvm::model::Opcode> {
+    vec![
+        Ld0U64,
+        ld(1),
+        ld(2),
+        LdFalse,
+        Label(0),
+        UAdd(three(0, 0, 1)),
+        Lt(three(3, 0, 2)),
+        JC {
+            label: 0,
+            cond: s(3),
+        },
+    ]
+}
+
+fn legacy_op(code: Nc, operands: &[u64]) -> Vec<u8> {
+    let mut res = code.bytes().to_vec();
+    for operand in operands {
+        res.extend_from_slice(&operand.to_le_bytes());
+    }
+    res
+}
+
+#[test]
+fn test_ref_encoding_round_trip() {
+    for &r in &[0, 1, 127, 128, 300, u32::MAX as usize, usize::MAX] {
+        let bytes = encode_ref(r);
+        assert_eq!(decode_ref(&bytes), Some((r, bytes.len())));
+    }
+    assert_eq!(encode_ref(127).len(), 1);
+    assert_eq!(encode_ref(128).len(), 2);
+    assert_eq!(decode_ref(&[0x80, 0x80]), None);
+}
+
+#[test]
+fn test_alu_op_is_compact() {
+    let code = Code::from_model(&[UAdd(three(2, 0, 1))]).unwrap();
+    let decoded = code.decode();
+    assert!(decoded.is_full);
+    assert_eq!(decoded.size, 4);
+}
+
+#[test]
+fn test_jumps_use_encoded_offsets() {
+    let code = Code::from_model(&count_to_100()).unwrap();
+    assert!(code.decode().is_full);
+    let mut vm = Vm::headless(pool());
+    code.interpret(&mut vm).unwrap();
+    assert_eq!(vm.stack_data(s(0)).unwrap(), &[100u64.to_le_bytes()]);
+}
+
+#[test]
+fn test_linked_fragments_keep_their_jumps() {
+    let mut code = Code::from_model(&count_to_100()).unwrap();
+    let count_to_200 = Code::from_model(&[Scope(vec![
+        ld(2),
+        UAdd(three(4, 4, 4)),
+        Label(0),
+        UAdd(three(0, 0, 1)),
+        Lt(three(3, 0, 4)),
+        JC {
+            label: 0,
+            cond: s(3),
+        },
+    ])])
+    .unwrap();
+    code.append(&count_to_200);
+
+    let mut vm = Vm::headless(pool());
+    code.interpret(&mut vm).unwrap();
+    assert_eq!(vm.stack_data(s(0)).unwrap(), &[200u64.to_le_bytes()]);
+}
+
+#[test]
+fn test_legacy_code_is_migrated() {
+    let loop_start = 1 + (1 + 16) * 2 + 1;
+    let bytes = [
+        legacy_op(Nc::U64Ld0, &[]),
+        legacy_op(Nc::LdType, &[0, 1]),
+        legacy_op(Nc::LdType, &[0, 2]),
+        legacy_op(Nc::LdFalse, &[]),
+        legacy_op(Nc::UAdd, &[0, 0, 1]),
+        legacy_op(Nc::Lt, &[3, 0, 2]),
+        legacy_op(Nc::JC, &[loop_start, 3]),
+    ]
+    .concat();
+
+    let migrated = legacy::migrate(&bytes).unwrap();
+    let expected = Code::from_model(&count_to_100()).unwrap();
+    assert_eq!(migrated.decode().size, expected.decode().size);
+
+    let mut vm = Vm::headless(pool());
+    migrated.interpret(&mut vm).unwrap();
+    assert_eq!(vm.stack_data(s(0)).unwrap(), &[100u64.to_le_bytes()]);
+    assert!(legacy::migrate(&bytes[..bytes.len() - 1]).is_none());
+}
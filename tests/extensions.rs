@@ -0,0 +1,141 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::error::VmError;
+use ngvm::model::Opcode::*;
+use ngvm::opcodes::{Operand, OperandKind};
+use ngvm::vm::{ExtensionOpcode, ExtensionTable};
+use ngvm::{Code, Vm};
+
+use common::{ld, pool};
+
+mod common;
+
+const MUL_ADD: u8 = 1;
+
+fn read_u64(vm: &Vm, operand: &CodeRef) -> Result<u64, VmError> {
+    match operand {
+        CodeRef::Stack(r) => Ok(u64::from_le_bytes(*vm.single_stack_data(*r)?)),
+        _ => Err(VmError::InvalidBytecode),
+    }
+}
+
+/// `result = a * b + c`, on u64 values
+fn handle_mul_add(operands: &[CodeRef], vm: &mut Vm) -> Result<(), VmError> {
+    let result = match operands[0] {
+        CodeRef::Stack(r) => r,
+        _ => return Err(VmError::InvalidBytecode),
+    };
+    let a = read_u64(vm, &operands[1])?;
+    let b = read_u64(vm, &operands[2])?;
+    let c = match operands[3] {
+        CodeRef::Immediate(imm) => u64::from_le_bytes(imm.value()),
+        _ => return Err(VmError::InvalidBytecode),
+    };
+    *vm.single_stack_data_mut(result)? = (a * b + c).to_le_bytes();
+    Ok(())
+}
+
+fn mul_add() -> ExtensionOpcode {
+    ExtensionOpcode {
+        name: "MulAdd",
+        operands: &[
+            Operand {
+                kind: OperandKind::Stack,
+                tag: "r",
+            },
+            Operand {
+                kind: OperandKind::Stack,
+                tag: "a",
+            },
+            Operand {
+                kind: OperandKind::Stack,
+                tag: "b",
+            },
+            Operand {
+                kind: OperandKind::Immediate,
+                tag: "c",
+            },
+        ],
+        handler: handle_mul_add,
+    }
+}
+
+fn mul_add_code() -> Code<'static> {
+    Code::from_model(&[
+        Ld0U64,
+        ld(1),
+        ld(2),
+        Extension {
+            code: MUL_ADD,
+            operands: vec![
+                s(0).into(),
+                s(1).into(),
+                s(2).into(),
+                Immediate::new(5u64).into(),
+            ],
+        },
+        UAdd(three(0, 0, 1)),
+    ])
+    .unwrap()
+}
+
+#[test]
+fn test_extension_opcode_is_interpreted() {
+    let code = mul_add_code();
+    let mut vm = Vm::headless(pool());
+    vm.register_extension(MUL_ADD, mul_add()).unwrap();
+    let threaded = code.compile_with(vm.extensions()).unwrap();
+
+    code.interpret(&mut vm).unwrap();
+    assert_eq!(vm.stack_data(s(0)).unwrap(), &[106u64.to_le_bytes()]);
+
+    let mut threaded_vm = Vm::headless(pool());
+    threaded_vm.register_extension(MUL_ADD, mul_add()).unwrap();
+    threaded.interpret(&mut threaded_vm).unwrap();
+    assert_eq!(
+        threaded_vm.stack_data(s(0)).unwrap(),
+        &[106u64.to_le_bytes()]
+    );
+}
+
+#[test]
+fn test_extension_opcode_is_decoded_only_when_registered() {
+    let code = mul_add_code();
+    assert!(!code.decode().is_full);
+    assert!(code.compile().is_none());
+
+    let mut extensions = ExtensionTable::default();
+    extensions.register(MUL_ADD, mul_add()).unwrap();
+    let decoded = code.decode_with(&extensions);
+    assert!(decoded.is_full);
+    let extension = &decoded.opcodes[3];
+    assert_eq!(extension.extension(), Some(MUL_ADD));
+    assert_eq!(extension.name(), "MulAdd");
+    assert_eq!(extension.refs().count(), 4);
+
+    let error = code.interpret(&mut Vm::headless(pool())).unwrap_err();
+    assert!(matches!(error.error, VmError::UnknownExtension(MUL_ADD)));
+}
+
+#[test]
+fn test_invalid_extensions_are_rejected() {
+    let mut extensions = ExtensionTable::default();
+    extensions.register(MUL_ADD, mul_add()).unwrap();
+    let error = extensions.register(MUL_ADD, mul_add()).unwrap_err();
+    assert!(matches!(
+        error,
+        VmError::ExtensionAlreadyRegistered(MUL_ADD)
+    ));
+
+    let jump = ExtensionOpcode {
+        operands: &[Operand {
+            kind: OperandKind::Jump,
+            tag: "*",
+        }],
+        ..mul_add()
+    };
+    let error = extensions.register(2, jump).unwrap_err();
+    assert!(matches!(error, VmError::InvalidExtension(2)));
+}
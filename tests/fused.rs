@@ -0,0 +1,95 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::model::{self, peephole::fuse, Opcode::*};
+
+use common::{ld, run_both};
+
+mod common;
+
+/// The outcomes of [`run_both`] as text, without the locations of the errors, they differ
+/// between fused and unfused code
+fn outcomes(ops: &[model::Opcode]) -> Vec<String> {
+    run_both(ops)
+        .iter()
+        .map(|(result, stack)| format!("{:?} {:?}", result.as_ref().map_err(|e| &e.error), stack))
+        .collect()
+}
+
+fn counting_loop() -> Vec<model::Opcode> {
+    vec![
+        Ld0U64,
+        ld(1),
+        ld(2),
+        LdFalse,
+        Ld0U64,
+        Label(0),
+        UAdd(three(0, 0, 1)),
+        Scope(vec![ld(1), UAdd(three(4, 4, 5))]),
+        Lt(three(3, 0, 2)),
+        JC {
+            label: 0,
+            cond: s(3),
+        },
+        UAdd(three(0, 0, 1)),
+        Eq(three(3, 0, 2)),
+        ld(2),
+        UMul(three(4, 4, 5)),
+    ]
+}
+
+#[test]
+fn test_fused_loop_matches_unfused() {
+    let ops = counting_loop();
+    let fused = fuse(&ops);
+    let fused_names: Vec<_> = fused
+        .iter()
+        .map(|op| format!("{:?}", op))
+        .filter_map(|op| op.split(' ').next().map(str::to_owned))
+        .collect();
+    for name in &["ScopedLdOp", "JCmp", "UAddCmp", "LdOp"] {
+        assert!(
+            fused_names.iter().any(|n| n == name),
+            "{} is not fused",
+            name
+        );
+    }
+
+    let unfused = outcomes(&ops);
+    assert!(unfused[0].starts_with("Ok"));
+    assert_eq!(unfused[0], unfused[1]);
+    assert_eq!(unfused, outcomes(&fused));
+}
+
+#[test]
+fn test_fused_error_matches_unfused() {
+    let ops = vec![
+        Ld0U64,
+        LDType {
+            type_location: p(3),
+            value_location: p(4),
+        },
+        IAdd(three(0, 0, 1)),
+    ];
+    let fused = fuse(&ops);
+    assert_eq!(fused.len(), 2);
+
+    let unfused = outcomes(&ops);
+    assert!(unfused[0].starts_with("Err"));
+    assert_eq!(unfused, outcomes(&fused));
+}
+
+#[test]
+fn test_offset_jumps_are_not_fused() {
+    let ops = vec![
+        Ld0U64,
+        LdFalse,
+        Ge(three(1, 0, 0)),
+        JCOffset {
+            offset: 0,
+            cond: s(1),
+        },
+    ];
+    assert_eq!(fuse(&ops).len(), ops.len());
+}
@@ -0,0 +1,74 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::error::VmError;
+use ngvm::model::{Comparison, Opcode::*};
+use ngvm::opcodes::Opcode;
+use ngvm::types::PrimitiveType::*;
+use ngvm::{Code, Vm};
+
+use common::{ld, pool, run_both};
+
+mod common;
+
+#[test]
+fn test_immediate_loop_matches_pool_loop() {
+    let pool_loop = run_both(&[
+        Ld0U64,
+        ld(1),
+        ld(2),
+        LdFalse,
+        Label(0),
+        UAdd(three(0, 0, 1)),
+        Lt(three(3, 0, 2)),
+        JC {
+            label: 0,
+            cond: s(3),
+        },
+    ]);
+    let imm_loop = run_both(&[
+        LdImm(Immediate::new(0u64)),
+        LdFalse,
+        Label(0),
+        AddImm(two(0, 0), Immediate::new(1u64)),
+        CmpImm {
+            cmp: Comparison::Lt,
+            refs: two(1, 0),
+            imm: Immediate::new(100u64),
+        },
+        JC {
+            label: 0,
+            cond: s(1),
+        },
+    ]);
+    assert_eq!(format!("{:?}", imm_loop[0]), format!("{:?}", imm_loop[1]));
+    assert!(imm_loop[0].0.is_ok());
+    let counter = &pool_loop[0].1[0];
+    assert_eq!(imm_loop[0].1, vec![counter.clone(), vec![[0; 8]]]);
+}
+
+#[test]
+fn test_immediate_is_checked_against_its_type() {
+    let mismatch = run_both(&[Ld0U64, AddImm(two(0, 0), Immediate::new(1i64))]);
+    assert_eq!(format!("{:?}", mismatch[0]), format!("{:?}", mismatch[1]));
+    assert!(matches!(&mismatch[0].0, Err(e) if matches!(e.error, VmError::TypeError(_))));
+
+    let overflow = run_both(&[
+        LdImm(Immediate::new(u8::MAX)),
+        AddImm(two(0, 0), Immediate::new(1u8)),
+    ]);
+    assert_eq!(format!("{:?}", overflow[0]), format!("{:?}", overflow[1]));
+    assert!(matches!(&overflow[0].0, Err(e) if matches!(e.error, VmError::BiOpError)));
+}
+
+#[test]
+fn test_immediate_of_invalid_type_is_rejected() {
+    let mut bytes = Opcode::LdImm.bytes().to_vec();
+    bytes.push(StackFrame as u8);
+    bytes.extend_from_slice(&1u64.to_le_bytes());
+    let code = Code::from_vec(bytes);
+    assert!(!code.decode().is_full);
+    let error = code.interpret(&mut Vm::headless(pool())).unwrap_err();
+    assert!(matches!(error.error, VmError::InvalidBytecode));
+}
@@ -0,0 +1,130 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::error::VmError;
+use ngvm::model::Opcode::*;
+use ngvm::opcodes::Opcode as Nc;
+use ngvm::types::PrimitiveType::*;
+use ngvm::types::{PointedType, RefKind, RefLocation, VmType};
+use ngvm::{Code, Vm};
+
+use common::{limited_vm, mixed_pool, XorShift};
+
+mod common;
+
+/// Opcodes, mostly followed by operands small enough to point into the stack and the pool
+fn random_code(rng: &mut XorShift) -> Vec<u8> {
+    let len = rng.next() % 64;
+    let mut bytes = Vec::new();
+    while (bytes.len() as u64) < len {
+        let byte = match rng.next() % 8 {
+            0 => rng.next() as u8,
+            1..=3 => Nc::all()[rng.below(Nc::all().len())] as u16 as u8,
+            _ => (rng.next() % 8) as u8,
+        };
+        bytes.push(byte);
+    }
+    bytes
+}
+
+#[test]
+fn test_malformed_code_does_not_panic() {
+    let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+    for _ in 0..20_000 {
+        let code = Code::from_vec(random_code(&mut rng));
+        let _ = code.decode();
+        let _ = code.interpret(&mut limited_vm());
+        if let Some(threaded) = code.compile() {
+            let _ = threaded.interpret(&mut limited_vm());
+        }
+    }
+}
+
+#[test]
+fn test_array_indices_are_checked() {
+    for index in [2u64, u64::MAX].iter() {
+        let get = vec![
+            TakeRef(s(0)),
+            LdImm(Immediate::new(*index)),
+            Scope(vec![SArrGet {
+                arr_ref: s(1),
+                index: s(2),
+            }]),
+        ];
+        let set = vec![
+            TakeMut(s(0)),
+            LdImm(Immediate::new(*index)),
+            Scope(vec![SArrMut {
+                arr_mut: s(1),
+                index: s(2),
+            }]),
+        ];
+        for access in &[get, set] {
+            let code = Code::from_model(&[SArrCreate0(2, p(0)), Scope(access.clone())]).unwrap();
+            let e = code.interpret(&mut Vm::headless(mixed_pool())).unwrap_err();
+            assert!(matches!(e.error, VmError::IndexOutOfBounds(_, 2)));
+        }
+    }
+}
+
+#[test]
+fn test_refs_into_the_heap_are_rejected() {
+    let heap_ref =
+        |t: VmType| PointedType::reference(t, RefKind::Ref, RefLocation::TransientOnHeap);
+    let deref = vec![Scope(vec![StartDeref(s(0))])];
+    let get = vec![
+        Ld0U64,
+        Scope(vec![SArrGet {
+            arr_ref: s(0),
+            index: s(1),
+        }]),
+    ];
+    let cases = vec![
+        (heap_ref(U64.into()), deref),
+        (heap_ref(PointedType::s_arr(U64, 2).into()), get),
+    ];
+    for (t, code) in cases {
+        let mut vm = Vm::headless(mixed_pool());
+        vm.push_single_typed(8usize, t);
+        let e = Code::from_model(&code)
+            .unwrap()
+            .interpret(&mut vm)
+            .unwrap_err();
+        assert!(matches!(e.error, VmError::BadVmState));
+    }
+}
+
+#[test]
+fn test_loops_stop_at_the_step_limit() {
+    let code = Code::from_model(&[Label(0), Ld0U64, J { label: 0 }]).unwrap();
+    let e = code.interpret(&mut limited_vm()).unwrap_err();
+    assert!(matches!(e.error, VmError::OutOfSteps));
+    let e = code
+        .compile()
+        .unwrap()
+        .interpret(&mut limited_vm())
+        .unwrap_err();
+    assert!(matches!(e.error, VmError::OutOfSteps));
+}
+
+#[test]
+fn test_pool_loads_need_a_loaded_module() {
+    let loads = [
+        LdTyped0 {
+            type_location: p(0),
+        },
+        LDType {
+            type_location: p(0),
+            value_location: p(1),
+        },
+        LdSS(p(5)),
+    ];
+    for load in loads.iter() {
+        let code = Code::from_model(std::slice::from_ref(load)).unwrap();
+        for vm in &mut [Vm::default(), Vm::default_growing_stack()] {
+            let e = code.interpret(vm).unwrap_err();
+            assert!(matches!(e.error, VmError::ConstantPoolError));
+        }
+    }
+}
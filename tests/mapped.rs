@@ -0,0 +1,48 @@
+use std::fs;
+use std::path::PathBuf;
+
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::mapped::MappedCode;
+use ngvm::code::refs::*;
+use ngvm::error::LoadError;
+use ngvm::model::Opcode::*;
+use ngvm::{Code, Vm};
+
+use common::{ld, pool};
+
+mod common;
+
+/// Write `bytes` to a file of the temporary directory that is unique to the test
+fn temp_file(name: &str, bytes: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ngvm-{}-{}", std::process::id(), name));
+    fs::write(&path, bytes).unwrap();
+    path
+}
+
+#[test]
+fn test_mapped_code_runs_without_copying() {
+    let code = Code::from_model(&[Ld0U64, ld(2), UAdd(three(0, 0, 1))]).unwrap();
+    let path = temp_file("valid", code.as_bytes());
+
+    // SAFETY: the file is only removed once the code is no longer used
+    let mapped = unsafe { MappedCode::open(&path) }.unwrap();
+    let mut vm = Vm::headless(pool());
+    mapped.interpret(&mut vm).unwrap();
+    assert_eq!(vm.stack_data(s(0)).unwrap(), &[100u64.to_le_bytes()]);
+    assert_eq!(mapped.code().as_bytes(), code.as_bytes());
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_truncated_file_is_rejected() {
+    let code = Code::from_model(&[Ld0U64, UAdd(three(0, 0, 0))]).unwrap();
+    let bytes = code.as_bytes();
+    let path = temp_file("truncated", &bytes[..bytes.len() - 1]);
+
+    // SAFETY: the file is not changed by the test
+    let error = unsafe { MappedCode::open(&path) }.err().unwrap();
+    assert!(matches!(error, LoadError::InvalidBytecode(1)));
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,37 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::error::VmError;
+use ngvm::model::Opcode::*;
+use ngvm::types::PrimitiveType::*;
+use ngvm::types::VmType;
+use ngvm::{Code, ConstantPool, Module, Vm};
+
+use common::pool;
+
+mod common;
+
+#[test]
+fn test_current_module_selects_the_pool() {
+    let code = Code::from_model(&[LdTyped0 {
+        type_location: p(0),
+    }])
+    .unwrap();
+
+    let mut vm = Vm::default();
+    let first = vm.load_module("first", Module::new(pool())).unwrap();
+    let second = vm
+        .load_module("second", Module::new(ConstantPool::new(vec![U8.into()])))
+        .unwrap();
+    assert_eq!(vm.modules().module_id("second"), Some(second));
+    assert_eq!(vm.modules().name(first), Some("first"));
+    assert_eq!(vm.current_module(), first);
+
+    vm.set_current_module(second).unwrap();
+    code.interpret(&mut vm).unwrap();
+    assert_eq!(vm.value_type(s(0)).unwrap(), &VmType::from(U8));
+
+    let again = vm.load_module("first", Module::new(pool()));
+    assert!(matches!(again, Err(VmError::ModuleAlreadyLoaded(_))));
+}
@@ -0,0 +1,360 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::model::{self, Comparison, Opcode::*};
+use ngvm::error::VmError;
+use ngvm::opcodes::{Opcode as Nc, Operand, OperandKind};
+use ngvm::vm::{ExtensionOpcode, ExtensionTable};
+use ngvm::{Code, Vm};
+
+/// An operand of `kind` whose encoding takes more than one byte where it can
+fn sample_operand(kind: OperandKind) -> CodeRef {
+    match kind {
+        OperandKind::Stack => CodeRef::Stack(s(300)),
+        OperandKind::Pool => CodeRef::Pool(p(2)),
+        OperandKind::Value => CodeRef::Value(128),
+        OperandKind::BiOp => CodeRef::Value(Nc::URem as usize),
+        OperandKind::Size => CodeRef::Offset(70_000),
+        OperandKind::Jump => CodeRef::Jump(-5),
+        OperandKind::Immediate => CodeRef::Immediate(Immediate::new(-3i32)),
+    }
+}
+
+#[test]
+fn test_every_opcode_round_trips_through_the_decoder() {
+    for &op in Nc::all() {
+        let operands: Vec<_> = op
+            .operands()
+            .iter()
+            .map(|o| sample_operand(o.kind))
+            .collect();
+        let mut bytes = op.bytes().to_vec();
+        for operand in &operands {
+            bytes.extend_from_slice(&operand.to_bytes().unwrap());
+        }
+
+        let decoded = Code::from_vec(bytes.clone()).decode();
+        if !op.is_supported() {
+            assert!(decoded.opcodes.is_empty(), "{:?} is decoded", op);
+            continue;
+        }
+        assert!(decoded.is_full, "{:?} is not decoded", op);
+        assert_eq!(decoded.opcodes.len(), 1);
+        let decoded = &decoded.opcodes[0];
+        assert_eq!(decoded.op_code(), op);
+        assert_eq!(decoded.refs().count(), operands.len());
+        assert_eq!(
+            decoded.refs().bytes().unwrap().to_vec(),
+            bytes[op.size()..].to_vec()
+        );
+
+        for len in op.size()..bytes.len() {
+            let truncated = Code::from_vec(bytes[..len].to_vec());
+            assert!(!truncated.decode().is_full, "truncated {:?} is decoded", op);
+        }
+    }
+}
+
+#[test]
+fn test_opcode_numbers_are_unique() {
+    let numbers: Vec<_> = Nc::all().iter().map(|&op| op as u16).collect();
+    assert!(numbers.windows(2).all(|w| w[0] < w[1]));
+    for &op in Nc::all() {
+        assert_eq!(Nc::single(op as u16 as u8), Some(op));
+    }
+}
+
+/// Every model opcode, the match has no wildcard so new variants have to be added here
+fn model_opcodes() -> Vec<model::Opcode> {
+    let ops = vec![
+        Ld0U64,
+        Ld0I64,
+        LdTyped0 {
+            type_location: p(0),
+        },
+        LDType {
+            type_location: p(0),
+            value_location: p(1),
+        },
+        LdConst {
+            type_location: p(0),
+            value_location: p(1),
+        },
+        LdUnit,
+        LdTrue,
+        LdFalse,
+        LdSS(p(3)),
+        LdStatic(p(4)),
+        LdImm(Immediate::new(7u16)),
+        UAdd(three(0, 1, 2)),
+        USub(three(0, 1, 2)),
+        UMul(three(0, 1, 2)),
+        UDiv(three(0, 1, 2)),
+        URem(three(0, 1, 2)),
+        IAdd(three(0, 1, 2)),
+        ISub(three(0, 1, 2)),
+        IMul(three(0, 1, 2)),
+        IDiv(three(0, 1, 2)),
+        IRem(three(0, 1, 2)),
+        INeg(two(0, 1)),
+        FAdd(three(0, 1, 2)),
+        FSub(three(0, 1, 2)),
+        FMul(three(0, 1, 2)),
+        FDiv(three(0, 1, 2)),
+        FRem(three(0, 1, 2)),
+        FNeg(two(0, 1)),
+        BAnd(three(0, 1, 2)),
+        BOr(three(0, 1, 2)),
+        BNot(two(0, 1)),
+        BBe(two(0, 1)),
+        BXor(three(0, 1, 2)),
+        LAnd(three(0, 1, 2)),
+        LOr(three(0, 1, 2)),
+        LNot(two(0, 1)),
+        LXor(three(0, 1, 2)),
+        Shl(three(0, 1, 2)),
+        Shr(three(0, 1, 2)),
+        RotL(three(0, 1, 2)),
+        RotR(three(0, 1, 2)),
+        Ge(three(0, 1, 2)),
+        Gt(three(0, 1, 2)),
+        Le(three(0, 1, 2)),
+        Lt(three(0, 1, 2)),
+        Eq(three(0, 1, 2)),
+        Ne(three(0, 1, 2)),
+        AddImm(two(0, 1), Immediate::new(1u64)),
+        SubImm(two(0, 1), Immediate::new(1.5f64)),
+        CmpImm {
+            cmp: Comparison::Le,
+            refs: two(0, 1),
+            imm: Immediate::new(1i8),
+        },
+        Label(0),
+        J { label: 0 },
+        JC {
+            label: 0,
+            cond: s(200),
+        },
+        JOffset { offset: -3 },
+        JCOffset {
+            offset: 3,
+            cond: s(0),
+        },
+        StartScope,
+        EndScope,
+        Scope(vec![Ld0U64, J { label: 0 }]),
+        TakeRef(s(0)),
+        TakeMut(s(0)),
+        StartDeref(s(0)),
+        StartDerefN {
+            rf: s(0),
+            depth: 130,
+        },
+        EndDeref,
+        Reborrow(s(0)),
+        ReborrowMut(s(0)),
+        Mv(s(0), s(1)),
+        Mp(s(0)),
+        Drop(s(0)),
+        Clone(s(0)),
+        Swap(s(0), s(1)),
+        Replace {
+            dest: s(0),
+            value: s(1),
+        },
+        Freeze(s(0)),
+        SArrCreate0(4, p(0)),
+        SArrGet {
+            arr_ref: s(0),
+            index: s(1),
+        },
+        SArrMut {
+            arr_mut: s(0),
+            index: s(1),
+        },
+        CellNew(s(0)),
+        CellBorrow(s(0)),
+        CellBorrowMut(s(0)),
+        RcNew(s(0)),
+        RcDeref(s(0)),
+        RcDowngrade(s(0)),
+        WeakUpgrade(s(0)),
+        WeakIsAlive(s(0)),
+        AnyWrap(s(0)),
+        AnyIs {
+            any: s(0),
+            type_location: p(0),
+        },
+        AnyDowncast {
+            any: s(0),
+            type_location: p(0),
+        },
+        TraceStackValue(s(0)),
+        JCmp {
+            cmp: Comparison::Ne,
+            label: 0,
+            refs: three(0, 1, 2),
+        },
+        UAddCmp {
+            cmp: Comparison::Gt,
+            counter: s(0),
+            step: s(1),
+            result: s(2),
+            limit: s(3),
+        },
+        LdOp {
+            type_location: p(0),
+            value_location: p(1),
+            op: Box::new(IMul(three(0, 1, 2))),
+        },
+        ScopedLdOp {
+            type_location: p(0),
+            value_location: p(1),
+            op: Box::new(Shr(three(0, 1, 2))),
+        },
+        Extension {
+            code: 3,
+            operands: vec![CodeRef::Stack(s(1)), Immediate::new(2u8).into()],
+        },
+    ];
+    for op in &ops {
+        match op {
+            Ld0U64
+            | Ld0I64
+            | LdTyped0 { .. }
+            | LDType { .. }
+            | LdConst { .. }
+            | LdUnit
+            | LdTrue
+            | LdFalse
+            | LdSS(_)
+            | LdStatic(_)
+            | LdImm(_)
+            | UAdd(_)
+            | USub(_)
+            | UMul(_)
+            | UDiv(_)
+            | URem(_)
+            | IAdd(_)
+            | ISub(_)
+            | IMul(_)
+            | IDiv(_)
+            | IRem(_)
+            | INeg(_)
+            | FAdd(_)
+            | FSub(_)
+            | FMul(_)
+            | FDiv(_)
+            | FRem(_)
+            | FNeg(_)
+            | BAnd(_)
+            | BOr(_)
+            | BNot(_)
+            | BBe(_)
+            | BXor(_)
+            | LAnd(_)
+            | LOr(_)
+            | LNot(_)
+            | LXor(_)
+            | Shl(_)
+            | Shr(_)
+            | RotL(_)
+            | RotR(_)
+            | Ge(_)
+            | Gt(_)
+            | Le(_)
+            | Lt(_)
+            | Eq(_)
+            | Ne(_)
+            | AddImm(_, _)
+            | SubImm(_, _)
+            | CmpImm { .. }
+            | J { .. }
+            | JC { .. }
+            | JOffset { .. }
+            | JCOffset { .. }
+            | Label(_)
+            | StartScope
+            | EndScope
+            | Scope(_)
+            | TakeRef(_)
+            | TakeMut(_)
+            | StartDeref(_)
+            | StartDerefN { .. }
+            | EndDeref
+            | Reborrow(_)
+            | ReborrowMut(_)
+            | Mv(_, _)
+            | Mp(_)
+            | Drop(_)
+            | Clone(_)
+            | Swap(_, _)
+            | Replace { .. }
+            | Freeze(_)
+            | SArrCreate0(_, _)
+            | SArrGet { .. }
+            | SArrMut { .. }
+            | CellNew(_)
+            | CellBorrow(_)
+            | CellBorrowMut(_)
+            | RcNew(_)
+            | RcDeref(_)
+            | RcDowngrade(_)
+            | WeakUpgrade(_)
+            | WeakIsAlive(_)
+            | AnyWrap(_)
+            | AnyIs { .. }
+            | AnyDowncast { .. }
+            | TraceStackValue(_)
+            | JCmp { .. }
+            | UAddCmp { .. }
+            | LdOp { .. }
+            | ScopedLdOp { .. }
+            | Extension { .. } => {}
+        }
+    }
+    ops
+}
+
+fn ignore_operands(_: &[CodeRef], _: &mut Vm) -> Result<(), VmError> {
+    Ok(())
+}
+
+#[test]
+fn test_every_model_opcode_round_trips_through_the_decoder() {
+    let mut extensions = ExtensionTable::default();
+    let extension = ExtensionOpcode {
+        name: "Ext",
+        operands: &[
+            Operand {
+                kind: OperandKind::Stack,
+                tag: "a",
+            },
+            Operand {
+                kind: OperandKind::Immediate,
+                tag: "b",
+            },
+        ],
+        handler: ignore_operands,
+    };
+    extensions.register(3, extension).unwrap();
+
+    let ops = model_opcodes();
+    for op in &ops {
+        let code = Code::from_model(&[Label(0), op.clone()]).unwrap();
+        let decoded = code.decode_with(&extensions);
+        assert!(decoded.is_full, "{:?} is not decoded", op);
+        assert_eq!(decoded.size, op.size_in_bytes(), "size of {:?}", op);
+        let expected = match op {
+            Label(_) => 0,
+            Scope(ops) => ops.len() + 2,
+            _ => 1,
+        };
+        assert_eq!(decoded.opcodes.len(), expected, "{:?}", op);
+    }
+
+    let code = Code::from_model(&ops).unwrap();
+    let size: usize = ops.iter().map(model::Opcode::size_in_bytes).sum();
+    assert_eq!(code.decode_with(&extensions).size, size);
+}
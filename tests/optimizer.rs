@@ -0,0 +1,180 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::model::optimizer::{optimize, Passes};
+use ngvm::model::{self, Opcode::*};
+
+use common::{ld, outcome, pool};
+
+mod common;
+
+/// The [`outcome`] of `ops` with only the kind of the error, its operands change along with
+/// the stack refs
+fn kind_or_stack(ops: &[model::Opcode]) -> String {
+    outcome(ops).split('(').next().unwrap().to_owned()
+}
+
+fn only(set: fn(&mut Passes)) -> Passes {
+    let mut passes = Passes::none();
+    set(&mut passes);
+    passes
+}
+
+fn single_passes() -> Vec<Passes> {
+    vec![
+        only(|p| p.thread_jumps = true),
+        only(|p| p.remove_dead_code = true),
+        only(|p| p.fold_constants = true),
+        only(|p| p.compact_stack = true),
+        only(|p| p.remove_empty_scopes = true),
+        Passes::default(),
+    ]
+}
+
+fn programs() -> Vec<Vec<model::Opcode>> {
+    vec![
+        // counting loop, with constants folded in its scope
+        vec![
+            Ld0U64,
+            ld(1),
+            ld(2),
+            LdFalse,
+            J { label: 1 },
+            Label(0),
+            UAdd(three(0, 0, 1)),
+            Scope(vec![
+                Ld0U64,
+                ld(2),
+                LdSS(p(6)),
+                UAdd(three(4, 5, 5)),
+                UAdd(three(4, 4, 1)),
+                UAdd(three(0, 0, 4)),
+            ]),
+            Label(1),
+            Lt(three(3, 0, 2)),
+            JC {
+                label: 0,
+                cond: s(3),
+            },
+            Scope(vec![]),
+            StartScope,
+            EndScope,
+        ],
+        // jumps over jumps and dead code
+        vec![
+            Ld0U64,
+            ld(1),
+            J { label: 0 },
+            UAdd(three(0, 0, 1)),
+            Label(0),
+            J { label: 1 },
+            Scope(vec![Ld0U64, UAdd(three(0, 0, 1))]),
+            Label(1),
+            J { label: 2 },
+            Label(2),
+            UAdd(three(0, 0, 1)),
+        ],
+        // division by zero, kept for the error it stops with
+        vec![
+            Ld0U64,
+            Scope(vec![
+                ld(2),
+                ld(5),
+                Ld0U64,
+                UDiv(three(3, 1, 2)),
+                UAdd(three(0, 0, 3)),
+            ]),
+        ],
+        // constants written through a reference are not folded
+        vec![
+            Ld0U64,
+            Scope(vec![ld(1), TakeMut(s(0)), UAdd(three(0, 0, 1))]),
+            Scope(vec![
+                ld(1),
+                ld(2),
+                UAdd(three(1, 1, 2)),
+                UAdd(three(0, 0, 1)),
+            ]),
+        ],
+    ]
+}
+
+#[test]
+fn test_optimized_code_matches_unoptimized() {
+    for ops in programs() {
+        let expected = kind_or_stack(&ops);
+        for passes in single_passes() {
+            let optimized = optimize(&ops, &pool(), &passes);
+            assert_eq!(
+                expected,
+                kind_or_stack(&optimized),
+                "{:?} changed {:#?}",
+                passes,
+                optimized
+            );
+        }
+    }
+}
+
+#[test]
+fn test_constants_fold_into_their_result() {
+    let ops = vec![Ld0U64, Scope(vec![ld(1), ld(2), UAdd(three(0, 1, 2))])];
+    let optimized = optimize(&ops, &pool(), &Passes::default());
+    assert_eq!(
+        format!("{:?}", optimized),
+        format!("{:?}", vec![LdImm(Immediate::new(101u64))])
+    );
+
+    let folded = optimize(&ops, &pool(), &only(|p| p.fold_constants = true));
+    assert_eq!(
+        format!("{:?}", folded),
+        format!(
+            "{:?}",
+            vec![LdImm(Immediate::new(101u64)), Scope(vec![ld(1), ld(2)])]
+        )
+    );
+}
+
+#[test]
+fn test_jumps_are_threaded_and_dead_code_removed() {
+    let ops = programs().swap_remove(1);
+    let threaded = optimize(&ops, &pool(), &only(|p| p.thread_jumps = true));
+    assert_eq!(
+        threaded
+            .iter()
+            .filter(|op| matches!(op, J { label: 2 }))
+            .count(),
+        2
+    );
+
+    let optimized = optimize(&ops, &pool(), &Passes::default());
+    let expected = vec![
+        Ld0U64,
+        ld(1),
+        J { label: 2 },
+        Label(2),
+        UAdd(three(0, 0, 1)),
+    ];
+    assert_eq!(format!("{:?}", optimized), format!("{:?}", expected));
+}
+
+#[test]
+fn test_stack_refs_are_renumbered() {
+    let ops = vec![
+        Ld0U64,
+        Scope(vec![ld(2), LdTrue, ld(1), UAdd(three(0, 0, 3))]),
+    ];
+    let compacted = optimize(&ops, &pool(), &only(|p| p.compact_stack = true));
+    let expected = vec![Ld0U64, Scope(vec![ld(1), UAdd(three(0, 0, 1))])];
+    assert_eq!(format!("{:?}", compacted), format!("{:?}", expected));
+    assert_eq!(kind_or_stack(&ops), kind_or_stack(&compacted));
+}
+
+#[test]
+fn test_no_passes_keep_the_code() {
+    for ops in programs() {
+        let kept = optimize(&ops, &pool(), &Passes::none());
+        assert_eq!(format!("{:?}", ops), format!("{:?}", kept));
+    }
+}
@@ -0,0 +1,78 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::error::{BuildError, PoolError, PoolProblem, VmError};
+use ngvm::model::builder::CodeBuilder;
+use ngvm::model::Opcode::*;
+use ngvm::types::PrimitiveType::*;
+use ngvm::types::{PointedType, RefLocation, VmType};
+use ngvm::{Code, ConstantKind, ConstantPool, Vm};
+
+#[test]
+fn test_arrays_of_pointed_and_string_elements() {
+    let code = Code::from_model(&[
+        SArrCreate0(3, p(0)),
+        SArrCreate0(2, p(1)),
+        LdTyped0 {
+            type_location: p(0),
+        },
+    ])
+    .unwrap();
+    let pool = ConstantPool::new(vec![PointedType::s_arr(U64, 2).into(), SStr.into()]);
+    let mut vm = Vm::headless(pool);
+    code.interpret(&mut vm).unwrap();
+    assert_eq!(vm.stack_data(s(0)).unwrap(), &[[0u8; 8]; 6]);
+    assert_eq!(vm.stack_data(s(1)).unwrap(), &[[0u8; 8]; 4]);
+    assert_eq!(vm.stack_data(s(2)).unwrap(), &[[0u8; 8]; 2]);
+}
+
+#[test]
+fn test_types_without_empty_value_are_rejected() {
+    let pool = ConstantPool::new(vec![
+        PointedType::s_arr(U64, 2).into(),
+        PointedType::ref_reference(U64, RefLocation::Stack).into(),
+        0u64.into(),
+    ]);
+    let loaded = Code::from_model(&[
+        LDType {
+            type_location: p(0),
+            value_location: p(2),
+        },
+        AnyIs {
+            any: s(0),
+            type_location: p(0),
+        },
+    ])
+    .unwrap();
+    assert_eq!(
+        pool.validate(&loaded),
+        Err(PoolError(vec![PoolProblem::WrongKind(
+            0,
+            p(0),
+            ConstantKind::Type
+        )]))
+    );
+
+    let reference = Code::from_model(&[LdTyped0 {
+        type_location: p(1),
+    }])
+    .unwrap();
+    let mut vm = Vm::headless(pool);
+    let e = reference.interpret(&mut vm).err().unwrap();
+    assert!(matches!(e.error, VmError::ConstantPoolError));
+}
+
+#[test]
+fn test_builder_interns_pointed_types() {
+    let mut b = CodeBuilder::new();
+    let matrix = PointedType::s_arr(PointedType::s_arr(U64, 2), 3);
+    b.s_arr(PointedType::s_arr(U64, 2), 3);
+    let zeroed = b.zero(matrix.clone());
+    assert_eq!(b.type_of(zeroed), Some(&VmType::from(matrix)));
+    b.zero(PointedType::ref_reference(U64, RefLocation::Stack));
+
+    let errors = b.into_model().err().unwrap();
+    let reference = PointedType::ref_reference(U64, RefLocation::Stack).into();
+    assert_eq!(errors, vec![BuildError::InvalidType(2, reference)]);
+}
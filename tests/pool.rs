@@ -0,0 +1,85 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::error::{PoolError, PoolProblem, VmError};
+use ngvm::model::Opcode::*;
+use ngvm::types::PrimitiveType::*;
+use ngvm::{
+    Code, ConstantKind, ConstantPool, ConstantPoolBuilder, Function, Module, Signature, Vm,
+};
+
+#[test]
+fn test_builder_interns_constants() {
+    let mut builder = ConstantPoolBuilder::new();
+    let t = builder.primitive_type(U64);
+    let hello = builder.string("hello");
+    let one = builder.value(1u64);
+    assert_eq!(builder.value(1u64), one);
+    assert_eq!(builder.string("hello"), hello);
+    assert_eq!(builder.primitive_type(Char), p(3));
+    assert_eq!(builder.primitive_type(U64), t);
+
+    let pool = builder.build();
+    assert_eq!(pool.len(), 4);
+    assert_eq!(pool.get_s_str(hello), Some("hello"));
+    assert_eq!(pool.get_single(one), Some(1u64.to_le_bytes()));
+}
+
+#[test]
+fn test_every_problem_is_listed() {
+    let pool = ConstantPool::new(vec![
+        Char.into(),
+        0xD800u32.into(),
+        "text".into(),
+        Bool.into(),
+        1u8.into(),
+    ]);
+    let code = Code::from_model(&[
+        LDType {
+            type_location: p(0),
+            value_location: p(1),
+        },
+        LdSS(p(4)),
+        LdConst {
+            type_location: p(3),
+            value_location: p(4),
+        },
+        LdTyped0 {
+            type_location: p(2),
+        },
+        LdSS(p(9)),
+    ])
+    .unwrap();
+    let expected = PoolError(vec![
+        PoolProblem::InvalidValue(0, p(1), Char),
+        PoolProblem::WrongKind(3, p(4), ConstantKind::String),
+        PoolProblem::WrongKind(8, p(2), ConstantKind::Type),
+        PoolProblem::OutOfRange(10, p(9)),
+    ]);
+    assert_eq!(pool.validate(&code), Err(expected));
+}
+
+#[test]
+fn test_modules_are_validated_when_loaded() {
+    let code = Code::from_model(&[LdTyped0 {
+        type_location: p(1),
+    }])
+    .unwrap();
+    let function = Function {
+        signature: Signature::new(vec![], Unit.into()),
+        bytecode: code,
+    };
+    let mut module = Module::new(ConstantPool::new(vec![U64.into()]));
+    module.add_fn("main".to_owned(), function);
+
+    let mut vm = Vm::default();
+    match vm.load_module("main", module) {
+        Err(VmError::InvalidPool(name, e)) => {
+            assert_eq!(name, "main");
+            assert_eq!(e, PoolError(vec![PoolProblem::OutOfRange(0, p(1))]));
+        }
+        _ => panic!("the module was loaded"),
+    }
+    assert!(vm.modules().module_id("main").is_none());
+}
@@ -0,0 +1,326 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::error::VmError;
+use ngvm::model::Opcode::*;
+use ngvm::types::checker::TypeError;
+use ngvm::types::PrimitiveType::*;
+use ngvm::types::{PointedType, RefKind, RefLocation, TypeTable, VmType};
+
+use common::{ld, run};
+
+mod common;
+
+#[test]
+fn test_deref_n_writes_through_mut_chain() {
+    let vm = run(&[
+        Ld0U64,
+        Scope(vec![
+            TakeMut(s(0)),
+            Scope(vec![
+                TakeMut(s(1)),
+                ld(2),
+                StartDerefN { rf: s(2), depth: 2 },
+                UAdd(three(4, 4, 3)),
+                EndDeref,
+            ]),
+        ]),
+    ])
+    .unwrap();
+    assert_eq!(vm.stack_data(s(0)).unwrap(), &[100u64.to_le_bytes()]);
+}
+
+#[test]
+fn test_shared_reborrow_restores_mut_lock() {
+    let vm = run(&[
+        Ld0U64,
+        Scope(vec![
+            TakeMut(s(0)),
+            Scope(vec![Reborrow(s(1)), TakeRef(s(0))]),
+            ld(2),
+            StartDeref(s(1)),
+            UAdd(three(3, 3, 2)),
+            EndDeref,
+        ]),
+    ])
+    .unwrap();
+    assert_eq!(vm.stack_data(s(0)).unwrap(), &[100u64.to_le_bytes()]);
+}
+
+#[test]
+fn test_reborrowed_ref_is_locked() {
+    let result = run(&[
+        Ld0U64,
+        Scope(vec![
+            TakeMut(s(0)),
+            Scope(vec![ReborrowMut(s(1)), StartDeref(s(1))]),
+        ]),
+    ]);
+    let e = result.err().unwrap();
+    assert!(matches!(e.error, VmError::LockError(_, _)));
+}
+
+#[test]
+fn test_drop_releases_lock() {
+    let result = run(&[
+        Ld0U64,
+        Scope(vec![TakeMut(s(0)), Drop(s(1)), TakeMut(s(0)), Drop(s(1))]),
+    ]);
+    let e = result.err().unwrap();
+    assert!(matches!(e.error, VmError::UseOfMovedValue(_)));
+}
+
+#[test]
+fn test_deref_values_are_not_moved() {
+    for op in &[Drop(s(2)), Mp(s(2))] {
+        let deref = vec![TakeMut(s(0)), StartDeref(s(1)), op.clone(), EndDeref];
+        let e = run(&[Ld0U64, Scope(deref)]).err().unwrap();
+        assert!(matches!(e.error, VmError::MoveOfDeref(_)));
+    }
+}
+
+#[test]
+fn test_replace_and_swap() {
+    let vm = run(&[
+        Ld0U64,
+        ld(2),
+        Scope(vec![
+            TakeMut(s(0)),
+            Replace {
+                dest: s(2),
+                value: s(1),
+            },
+        ]),
+        Clone(s(0)),
+        Swap(s(1), s(2)),
+    ])
+    .unwrap();
+    assert_eq!(vm.stack_data(s(0)).unwrap(), &[100u64.to_le_bytes()]);
+    assert_eq!(vm.stack_data(s(1)).unwrap(), &[100u64.to_le_bytes()]);
+    assert_eq!(vm.stack_data(s(2)).unwrap(), &[0u64.to_le_bytes()]);
+}
+
+#[test]
+fn test_immutable_values_reject_writes() {
+    let result = run(&[
+        LdConst {
+            type_location: p(0),
+            value_location: p(2),
+        },
+        UAdd(three(0, 0, 0)),
+    ]);
+    let e = result.err().unwrap();
+    assert!(matches!(e.error, VmError::WriteToImmutable(_)));
+
+    let result = run(&[Ld0U64, Freeze(s(0)), Scope(vec![TakeMut(s(0))])]);
+    let e = result.err().unwrap();
+    assert!(matches!(e.error, VmError::WriteToImmutable(_)));
+}
+
+#[test]
+fn test_cell_borrows_are_checked_dynamically() {
+    let borrows = vec![
+        TakeRef(s(1)),
+        CellBorrowMut(s(2)),
+        ld(2),
+        StartDeref(s(3)),
+        UAdd(three(5, 5, 4)),
+        EndDeref,
+        Drop(s(3)),
+        CellBorrow(s(2)),
+    ];
+    let vm = run(&[Ld0U64, CellNew(s(0)), Scope(borrows.clone())]).unwrap();
+    let expected = [0u64.to_le_bytes(), 100u64.to_le_bytes()];
+    assert_eq!(vm.stack_data(s(1)).unwrap(), &expected);
+
+    let mut conflicting = borrows;
+    conflicting.push(CellBorrowMut(s(2)));
+    let result = run(&[Ld0U64, CellNew(s(0)), Scope(conflicting)]);
+    let e = result.err().unwrap();
+    assert!(matches!(e.error, VmError::LockError(_, _)));
+}
+
+#[test]
+fn test_cells_in_rcs_are_not_borrowed() {
+    let borrow = vec![RcDeref(s(2)), CellBorrow(s(3))];
+    let result = run(&[Ld0U64, CellNew(s(0)), RcNew(s(1)), Scope(borrow)]);
+    let e = result.err().unwrap();
+    assert!(format!("{:?}", e.error).contains("Cells owned by an Rc can't be borrowed"));
+}
+
+#[test]
+fn test_rc_is_released_with_last_owner() {
+    let code = vec![
+        Ld0U64,
+        ld(2),
+        RcNew(s(1)),
+        Clone(s(2)),
+        RcDowngrade(s(2)),
+        Scope(vec![
+            RcDeref(s(3)),
+            StartDeref(s(5)),
+            UAdd(three(0, 0, 6)),
+            EndDeref,
+        ]),
+        Drop(s(2)),
+        WeakIsAlive(s(4)),
+        Drop(s(3)),
+        WeakIsAlive(s(4)),
+    ];
+    let vm = run(&code).unwrap();
+    assert_eq!(vm.stack_data(s(0)).unwrap(), &[100u64.to_le_bytes()]);
+    assert_eq!(vm.stack_data(s(5)).unwrap(), &[1u64.to_le_bytes()]);
+    assert_eq!(vm.stack_data(s(6)).unwrap(), &[0u64.to_le_bytes()]);
+
+    let mut upgrade = code;
+    upgrade.push(WeakUpgrade(s(4)));
+    let e = run(&upgrade).err().unwrap();
+    assert!(matches!(e.error, VmError::UpgradeOfReleasedWeak(_)));
+}
+
+#[test]
+fn test_dropped_rc_and_weak_are_not_used() {
+    let code = vec![
+        ld(2),
+        RcNew(s(0)),
+        RcDowngrade(s(1)),
+        Drop(s(1)),
+        Drop(s(2)),
+    ];
+    let uses = vec![
+        Scope(vec![RcDeref(s(1))]),
+        RcDowngrade(s(1)),
+        WeakIsAlive(s(2)),
+        WeakUpgrade(s(2)),
+    ];
+    for op in uses {
+        let mut dropped = code.clone();
+        dropped.push(op);
+        let e = run(&dropped).err().unwrap();
+        assert!(matches!(e.error, VmError::UseOfMovedValue(_)));
+    }
+}
+
+#[test]
+fn test_deref_of_rc_does_not_own_it() {
+    let vm = run(&[
+        ld(2),
+        RcNew(s(0)),
+        Clone(s(1)),
+        RcDowngrade(s(1)),
+        Scope(vec![TakeRef(s(1)), StartDeref(s(4)), EndDeref]),
+        Drop(s(2)),
+        WeakIsAlive(s(3)),
+        Ld0U64,
+        Scope(vec![
+            RcDeref(s(1)),
+            StartDeref(s(6)),
+            UAdd(three(5, 5, 7)),
+            EndDeref,
+        ]),
+        Drop(s(1)),
+        WeakIsAlive(s(3)),
+    ])
+    .unwrap();
+    assert_eq!(vm.stack_data(s(4)).unwrap(), &[1u64.to_le_bytes()]);
+    assert_eq!(vm.stack_data(s(5)).unwrap(), &[100u64.to_le_bytes()]);
+    assert_eq!(vm.stack_data(s(6)).unwrap(), &[0u64.to_le_bytes()]);
+}
+
+#[test]
+fn test_arrays_in_cells_and_rcs_are_not_indexed() {
+    let cell = (CellNew(s(0)), vec![TakeRef(s(1)), CellBorrow(s(2))]);
+    let rc = (RcNew(s(0)), vec![Clone(s(1)), RcDeref(s(1))]);
+    for (wrap, borrow) in &[cell, rc] {
+        let mut borrow = borrow.clone();
+        borrow.push(Ld0U64);
+        borrow.push(Scope(vec![SArrGet {
+            arr_ref: s(3),
+            index: s(4),
+        }]));
+        let e = run(&[SArrCreate0(2, p(0)), wrap.clone(), Scope(borrow)]);
+        assert!(matches!(
+            e.err().unwrap().error,
+            VmError::UnsupportedArrayLocation(_)
+        ));
+    }
+}
+
+#[test]
+fn test_any_downcast_checks_type() {
+    let code = vec![
+        ld(2),
+        AnyWrap(s(0)),
+        AnyIs {
+            any: s(1),
+            type_location: p(0),
+        },
+        AnyDowncast {
+            any: s(1),
+            type_location: p(0),
+        },
+    ];
+    let vm = run(&code).unwrap();
+    assert_eq!(vm.stack_data(s(2)).unwrap(), &[1u64.to_le_bytes()]);
+    assert_eq!(vm.stack_data(s(3)).unwrap(), &[100u64.to_le_bytes()]);
+
+    let e = run(&[
+        ld(2),
+        AnyWrap(s(0)),
+        AnyDowncast {
+            any: s(1),
+            type_location: p(3),
+        },
+    ])
+    .err()
+    .unwrap();
+    match e.error {
+        VmError::TypeError(errors) => {
+            assert!(matches!(errors.as_slice(), [TypeError::BadDowncast(_, _)]))
+        }
+        e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn test_deref_of_any_does_not_own_it() {
+    let code = vec![
+        ld(2),
+        AnyWrap(s(0)),
+        Scope(vec![TakeRef(s(1)), StartDeref(s(2)), EndDeref]),
+    ];
+    let mut downcast = code.clone();
+    downcast.push(AnyDowncast {
+        any: s(1),
+        type_location: p(0),
+    });
+    let vm = run(&downcast).unwrap();
+    assert_eq!(vm.stack_data(s(2)).unwrap(), &[100u64.to_le_bytes()]);
+
+    let mut dropped = code;
+    dropped.push(Drop(s(1)));
+    assert!(run(&dropped).is_ok());
+}
+
+#[test]
+fn test_refs_to_same_type_share_type_id() {
+    let mut types = TypeTable::new();
+    let u64_id = types.primitive(U64);
+    let by_id = types.reference(u64_id, RefKind::Ref, RefLocation::Stack);
+    let ref_type = PointedType::reference(VmType::from(U64), RefKind::Ref, RefLocation::Stack);
+    let by_type = types.intern(ref_type);
+    assert_eq!(by_id, by_type);
+    assert_ne!(
+        by_id,
+        types.reference(u64_id, RefKind::Mut, RefLocation::Stack)
+    );
+    assert_eq!(types.size(by_id), 1);
+    assert!(types.is_copy(by_id));
+    assert_eq!(types.inner(by_id), Some(u64_id));
+
+    let vm = run(&[Ld0U64, ld(2)]).unwrap();
+    let id = |i| vm.stack_metadata(s(i)).unwrap().type_id;
+    assert_eq!(id(0), id(1));
+    assert_eq!(vm.value_type(s(1)).unwrap(), &VmType::from(U64));
+}
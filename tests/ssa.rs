@@ -0,0 +1,142 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::error::SsaError;
+use ngvm::model::ssa::{Function, Value};
+use ngvm::model::{self, Opcode::*};
+use ngvm::types::PrimitiveType::*;
+
+use common::{ld, outcome, pool};
+
+mod common;
+
+fn counting_loop() -> Vec<model::Opcode> {
+    vec![
+        Ld0U64,
+        ld(1),
+        ld(2),
+        LdFalse,
+        J { label: 1 },
+        Label(0),
+        UAdd(three(0, 0, 1)),
+        Label(1),
+        Lt(three(3, 0, 2)),
+        JC {
+            label: 0,
+            cond: s(3),
+        },
+    ]
+}
+
+#[test]
+fn test_loop_counter_is_merged_by_a_phi() {
+    let function = Function::lift(&counting_loop(), &pool()).unwrap();
+    let expected = "\
+b0: []
+    v0 = Ld0U64
+    v1 = LDType
+    v2 = LDType
+    v3 = LdFalse
+    J -> b2
+b1: [v4, v1, v2, v8]
+    v9 = UAdd v4, v1
+b2: [v4, v1, v2, v7]
+    v4 = phi b0: v0, b1: v9
+    v7 = phi b0: v3, b1: v8
+    v8 = Lt v4, v2
+    JC v8 -> b1
+";
+    assert_eq!(function.to_string(), expected);
+    assert_eq!(function.value_type(Value(4)), Some(&U64.into()));
+    assert_eq!(function.value_type(Value(8)), Some(&Bool.into()));
+}
+
+#[test]
+fn test_lowered_code_matches_the_original() {
+    let programs = vec![
+        counting_loop(),
+        vec![
+            Ld0U64,
+            Scope(vec![ld(1), ld(2), Swap(s(1), s(2)), UAdd(three(0, 1, 2))]),
+            LdOp {
+                type_location: p(0),
+                value_location: p(2),
+                op: Box::new(UAdd(three(0, 0, 1))),
+            },
+            ld(1),
+            Mv(s(0), s(1)),
+        ],
+        vec![
+            Ld0U64,
+            Scope(vec![
+                TakeMut(s(0)),
+                ld(2),
+                StartDeref(s(1)),
+                UAdd(three(3, 3, 2)),
+                EndDeref,
+            ]),
+            ld(1),
+            UAdd(three(0, 0, 1)),
+        ],
+    ];
+    for ops in programs {
+        let function = Function::lift(&ops, &pool()).unwrap();
+        let lowered = function.lower().unwrap();
+        assert_eq!(outcome(&ops), outcome(&lowered), "{:#?}", lowered);
+    }
+}
+
+#[test]
+fn test_removed_values_shift_the_stack_refs() {
+    let ops = vec![Ld0U64, ld(2), ld(1), UAdd(three(0, 0, 2))];
+    let mut function = Function::lift(&ops, &pool()).unwrap();
+    function.blocks[0].insts.remove(1);
+    let expected = vec![Ld0U64, ld(1), UAdd(three(0, 0, 1))];
+    let lowered = function.lower().unwrap();
+    assert_eq!(format!("{:?}", lowered), format!("{:?}", expected));
+}
+
+#[test]
+fn test_writes_through_references_define_new_values() {
+    let ops = vec![
+        Ld0U64,
+        Scope(vec![
+            TakeMut(s(0)),
+            StartDeref(s(1)),
+            UAdd(three(2, 2, 2)),
+            EndDeref,
+        ]),
+        UAdd(three(0, 0, 0)),
+    ];
+    let function = Function::lift(&ops, &pool()).unwrap();
+    let insts = &function.blocks[0].insts;
+    let end_deref = insts.iter().find(|i| matches!(i.op, EndDeref)).unwrap();
+    assert_eq!(end_deref.clobbers, vec![(Value(0), Value(4))]);
+    let last = insts.last().unwrap();
+    assert_eq!(last.operands[1].value, Value(4));
+}
+
+#[test]
+fn test_code_that_has_no_ssa_form_is_rejected() {
+    let offset = vec![Ld0U64, JOffset { offset: 1 }, Ld0U64];
+    assert_eq!(
+        Function::lift(&offset, &pool()).err(),
+        Some(SsaError::Unsupported(1))
+    );
+
+    let mismatch = vec![
+        LdTrue,
+        JC {
+            label: 0,
+            cond: s(0),
+        },
+        Ld0U64,
+        Label(0),
+        Ld0U64,
+    ];
+    assert_eq!(
+        Function::lift(&mismatch, &pool()).err(),
+        Some(SsaError::StackMismatch(3))
+    );
+}
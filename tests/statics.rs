@@ -0,0 +1,112 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::error::{PoolError, PoolProblem, VmError};
+use ngvm::model::builder::CodeBuilder;
+use ngvm::model::Opcode::*;
+use ngvm::types::PointedType;
+use ngvm::types::PrimitiveType::*;
+use ngvm::vm::STATIC_CYCLE;
+use ngvm::{Code, Constant, ConstantKind, ConstantPool, Vm};
+
+use common::run_with;
+
+mod common;
+
+/// Arrays of `u64`, of arrays and of strings, then a string
+fn arrays() -> ConstantPool {
+    let squares = Constant::array(U64, vec![0u64, 1, 4, 9]);
+    let rows = Constant::array(
+        PointedType::s_arr(Bool, 2),
+        vec![
+            Constant::array(Bool, vec![true, false]),
+            Constant::array(Bool, vec![false, true]),
+        ],
+    );
+    let names = Constant::array(SStr, vec!["a", "bc"]);
+    ConstantPool::new(vec![squares, rows, names, "text".into()])
+}
+
+#[test]
+fn test_statics_are_loaded_in_one_step() {
+    let vm = run_with(
+        arrays(),
+        &[
+            LdStatic(p(0)),
+            LdStatic(p(1)),
+            LdStatic(p(2)),
+            TakeRef(s(0)),
+        ],
+    )
+    .unwrap();
+    let squares: Vec<_> = [0u64, 1, 4, 9].iter().map(|v| v.to_le_bytes()).collect();
+    assert_eq!(vm.stack_data(s(0)).unwrap(), &squares[..]);
+    let rows: Vec<_> = [1u64, 0, 0, 1].iter().map(|v| v.to_le_bytes()).collect();
+    assert_eq!(vm.stack_data(s(1)).unwrap(), &rows[..]);
+    let names = vm.stack_data(s(2)).unwrap();
+    assert_eq!(names.len(), 4);
+    assert_eq!(names[3], 2usize.to_le_bytes());
+
+    let meta = vm.stack_metadata(s(0)).unwrap();
+    assert_eq!(meta.cycle, STATIC_CYCLE);
+    assert!(!meta.mutable);
+}
+
+#[test]
+fn test_statics_stay_below_other_values() {
+    let e = run_with(arrays(), &[Ld0U64, LdStatic(p(0))]).err().unwrap();
+    assert!(matches!(e.error, VmError::MisplacedStatic));
+
+    let e = run_with(arrays(), &[LdStatic(p(0)), TakeMut(s(0))])
+        .err()
+        .unwrap();
+    assert!(matches!(e.error, VmError::WriteToImmutable(_)));
+
+    let e = run_with(arrays(), &[SArrCreate0(2, p(3)), LdStatic(p(0))])
+        .err()
+        .unwrap();
+    assert!(matches!(e.error, VmError::ConstantPoolError));
+
+    let vm = run_with(
+        arrays(),
+        &[LdStatic(p(0)), Scope(vec![LdStatic(p(2)), Ld0U64])],
+    )
+    .unwrap();
+    assert!(vm.stack_data(s(1)).is_ok());
+    assert!(vm.stack_data(s(2)).is_err());
+}
+
+#[test]
+fn test_arrays_are_validated() {
+    let pool = ConstantPool::new(vec![
+        Constant::array(Bool, vec![1u8, 2]),
+        Constant::array(
+            PointedType::s_arr(U64, 2),
+            vec![Constant::array(U64, vec![1u64])],
+        ),
+        "text".into(),
+        Constant::array(U64, Vec::<u64>::new()),
+    ]);
+    let code = Code::from_model(&[
+        LdStatic(p(0)),
+        LdStatic(p(1)),
+        LdStatic(p(2)),
+        LdStatic(p(3)),
+    ]);
+    let expected = PoolError(vec![
+        PoolProblem::InvalidArray(0, p(0)),
+        PoolProblem::InvalidArray(2, p(1)),
+        PoolProblem::WrongKind(4, p(2), ConstantKind::Array),
+    ]);
+    assert_eq!(pool.validate(&code.unwrap()), Err(expected));
+
+    let mut b = CodeBuilder::new();
+    let table = b.static_array(U64, vec![1u64, 2, 3]);
+    let reference = b.take_ref(table);
+    b.trace(reference);
+    let (code, pool) = b.build().unwrap();
+    let mut vm = Vm::headless(pool);
+    code.interpret(&mut vm).unwrap();
+    assert_eq!(vm.stack_data(s(0)).unwrap()[2], 3u64.to_le_bytes());
+}
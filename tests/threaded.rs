@@ -0,0 +1,56 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::model::{self, Opcode::*};
+
+use common::{ld, run_both};
+
+mod common;
+
+fn sum_loop() -> Vec<model::Opcode> {
+    vec![
+        Ld0U64,
+        Ld0U64,
+        ld(1),
+        ld(2),
+        LdFalse,
+        Label(0),
+        UAdd(three(0, 0, 2)),
+        UAdd(three(1, 1, 0)),
+        Lt(three(4, 0, 3)),
+        Scope(vec![
+            TakeMut(s(1)),
+            StartDeref(s(5)),
+            UAdd(three(6, 6, 2)),
+            EndDeref,
+        ]),
+        JC {
+            label: 0,
+            cond: s(4),
+        },
+        J { label: 1 },
+        UAdd(three(1, 1, 1)),
+        Label(1),
+    ]
+}
+
+#[test]
+fn test_threaded_loop_matches_bytecode() {
+    let [bytecode, threaded] = run_both(&sum_loop());
+    assert_eq!(format!("{:?}", bytecode), format!("{:?}", threaded));
+    assert!(bytecode.0.is_ok());
+}
+
+#[test]
+fn test_threaded_error_matches_bytecode() {
+    let [bytecode, threaded] = run_both(&[
+        Ld0U64,
+        LdFalse,
+        Label(0),
+        UAdd(three(0, 0, 1)),
+        J { label: 0 },
+    ]);
+    assert_eq!(format!("{:?}", bytecode), format!("{:?}", threaded));
+    assert!(bytecode.0.is_err());
+}
@@ -0,0 +1,266 @@
+#[allow(unused_imports)]
+use pretty_assertions::{assert_eq, assert_ne};
+
+use ngvm::code::refs::*;
+use ngvm::error::VmError;
+use ngvm::model::{self, Opcode::*};
+use ngvm::types::PrimitiveType::{self, *};
+use ngvm::{Code, ConstantPool, Vm};
+
+use common::{stack, XorShift};
+
+mod common;
+
+/// Types in the pool and the pool indices of their values
+const TYPES: [(PrimitiveType, [usize; 2]); 4] =
+    [(U64, [4, 5]), (I32, [6, 7]), (F64, [8, 9]), (U8, [10, 11])];
+
+/// The types of [`TYPES`] at their index, their values, then a string and an empty type
+fn typed_pool() -> ConstantPool {
+    ConstantPool::new(vec![
+        U64.into(),
+        I32.into(),
+        F64.into(),
+        U8.into(),
+        3u64.into(),
+        (u64::MAX - 1).into(),
+        (-7i32).into(),
+        i32::MAX.into(),
+        2.5f64.into(),
+        0f64.into(),
+        200u8.into(),
+        0u8.into(),
+        SStr.into(),
+        Never.into(),
+    ])
+}
+
+/// Run `code` checked and verified, `None` if the verifier rejects it
+fn run_both(code: &Code) -> Option<(String, String)> {
+    let verified = code.compile().unwrap().verify(&typed_pool()).ok()?;
+
+    let mut vm = Vm::headless(typed_pool());
+    let checked_result = code.interpret(&mut vm);
+    let checked = format!("{:?} {:?}", checked_result, stack(&vm));
+
+    let mut vm = Vm::headless(typed_pool());
+    let verified_result = verified.interpret(&mut vm);
+    Some((checked, format!("{:?} {:?}", verified_result, stack(&vm))))
+}
+
+fn verify_error(ops: &[model::Opcode]) -> VmError {
+    let code = Code::from_model(ops).unwrap();
+    match code.compile().unwrap().verify(&typed_pool()) {
+        Ok(_) => panic!("the code was verified"),
+        Err(e) => e.error,
+    }
+}
+
+/// Generates programs that are mostly, but not always, well typed
+struct Generator {
+    rng: XorShift,
+    /// Type and mutability of the values on the stack
+    slots: Vec<(PrimitiveType, bool)>,
+}
+
+impl Generator {
+    fn load(&mut self) -> model::Opcode {
+        if self.rng.chance(10) {
+            // values of other sizes than one cell shift the data of the values above
+            let (t, location) = if self.rng.chance(80) {
+                (SStr, 12)
+            } else {
+                (Never, 13)
+            };
+            self.slots.push((t, true));
+            return LdTyped0 {
+                type_location: p(location),
+            };
+        }
+        if self.rng.chance(20) {
+            self.slots.push((Bool, true));
+            return if self.rng.chance(50) { LdTrue } else { LdFalse };
+        }
+        let t = self.rng.below(TYPES.len());
+        let (primitive, values) = TYPES[t];
+        let value = p(values[self.rng.below(2)]);
+        let mutable = !self.rng.chance(15);
+        self.slots.push((primitive, mutable));
+        if !mutable {
+            LdConst {
+                type_location: p(t),
+                value_location: value,
+            }
+        } else if self.rng.chance(30) {
+            LdTyped0 {
+                type_location: p(t),
+            }
+        } else {
+            LDType {
+                type_location: p(t),
+                value_location: value,
+            }
+        }
+    }
+
+    /// A random slot of type `t`, or any slot now and then
+    fn slot(&mut self, t: PrimitiveType, mutable: bool) -> usize {
+        let matching: Vec<_> = (0..self.slots.len())
+            .filter(|&i| self.slots[i].0 == t && (self.slots[i].1 || !mutable))
+            .collect();
+        if matching.is_empty() || self.rng.chance(1) {
+            self.rng.below(self.slots.len())
+        } else {
+            matching[self.rng.below(matching.len())]
+        }
+    }
+
+    fn bi_op(&mut self) -> model::Opcode {
+        let numbers: Vec<_> = self.slots.iter().filter(|(t, _)| t.is_number()).collect();
+        let t = match numbers.len() {
+            0 => U64,
+            n => numbers[self.rng.below(n)].0,
+        };
+        let op1 = self.slot(t, false);
+        let op2 = self.slot(t, false);
+        if self.rng.chance(30) {
+            let refs = three(self.slot(Bool, true), op1, op2);
+            let ops = [Ge, Gt, Le, Lt, Eq, Ne];
+            return ops[self.rng.below(ops.len())](refs);
+        }
+        let refs = three(self.slot(t, true), op1, op2);
+        let ops = match t {
+            U64 | U8 => [UAdd, USub, UMul, UDiv, URem],
+            I32 => [IAdd, ISub, IMul, IDiv, IRem],
+            _ => [FAdd, FSub, FMul, FDiv, FRem],
+        };
+        ops[self.rng.below(ops.len())](refs)
+    }
+
+    fn program(&mut self) -> Vec<model::Opcode> {
+        self.slots.clear();
+        self.slots.push((Bool, true));
+        let mut steps = vec![vec![LdFalse]];
+        for _ in 0..4 + self.rng.below(4) {
+            steps.push(vec![self.load()]);
+        }
+        let body = 5 + self.rng.below(20);
+        let mut labels = vec![vec![]; body + 1];
+        for step in 0..body {
+            if self.rng.chance(15) {
+                let label = step;
+                let target = step + 1 + self.rng.below(body - step);
+                labels[target].push(Label(label));
+                let cond = s(self.slot(Bool, false));
+                steps.push(vec![JC { label, cond }]);
+            } else if self.rng.chance(15) {
+                let outer = self.slots.len();
+                let mut scope = vec![self.load()];
+                scope.push(self.bi_op());
+                self.slots.truncate(outer);
+                steps.push(vec![Scope(scope)]);
+            } else {
+                steps.push(vec![self.bi_op()]);
+            }
+        }
+        let prelude = steps.len() - body;
+        for (i, mut labels) in labels.into_iter().enumerate() {
+            if let Some(step) = steps.get_mut(prelude + i) {
+                labels.append(step);
+                *step = labels;
+            } else {
+                steps.push(labels);
+            }
+        }
+        steps.concat()
+    }
+}
+
+#[test]
+fn test_verified_loop_matches_checked() {
+    let code = Code::from_model(&[
+        Ld0U64,
+        LdConst {
+            type_location: p(0),
+            value_location: p(4),
+        },
+        LdFalse,
+        Label(0),
+        Scope(vec![
+            LDType {
+                type_location: p(2),
+                value_location: p(8),
+            },
+            FMul(three(3, 3, 3)),
+        ]),
+        UAdd(three(0, 0, 1)),
+        Lt(three(2, 0, 1)),
+        JC {
+            label: 0,
+            cond: s(2),
+        },
+    ])
+    .unwrap();
+    let (checked, verified) = run_both(&code).unwrap();
+    assert_eq!(checked, verified);
+    assert!(checked.starts_with("Ok"));
+}
+
+#[test]
+fn test_verifier_rejects_what_checks_would_fail() {
+    let mismatch = verify_error(&[Ld0U64, Ld0I64, UAdd(three(0, 0, 1))]);
+    assert!(matches!(mismatch, VmError::TypeError(_)));
+
+    let immutable = verify_error(&[
+        LdConst {
+            type_location: p(0),
+            value_location: p(4),
+        },
+        UAdd(three(0, 0, 0)),
+    ]);
+    assert!(matches!(immutable, VmError::WriteToImmutable(StackRef(0))));
+
+    let unbalanced = verify_error(&[
+        LdFalse,
+        JC {
+            label: 0,
+            cond: s(0),
+        },
+        Ld0U64,
+        Label(0),
+    ]);
+    assert!(matches!(unbalanced, VmError::StackMismatch));
+
+    let unsupported = verify_error(&[Ld0U64, TakeRef(s(0))]);
+    assert!(matches!(unsupported, VmError::Unverifiable(_)));
+}
+
+#[test]
+fn test_random_programs_match_checked() {
+    let mut generator = Generator {
+        rng: XorShift(0x2545_f491_4f6c_dd1d),
+        slots: vec![],
+    };
+    let mut verified = 0;
+    for _ in 0..2000 {
+        let program = generator.program();
+        let code = Code::from_model(&program).unwrap();
+        if let Some((checked, fast)) = run_both(&code) {
+            assert_eq!(checked, fast, "{:?}", program);
+            verified += 1;
+        }
+    }
+    assert!(verified > 500, "only {} programs were verified", verified);
+}
+
+#[test]
+fn test_verified_code_needs_an_empty_stack() {
+    let code = Code::from_model(&[Ld0U64, UAdd(three(0, 0, 0))]).unwrap();
+    let verified = code.compile().unwrap().verify(&typed_pool()).unwrap();
+    let mut vm = Vm::headless(typed_pool());
+    vm.push_primitive_zeroed(U64);
+    assert!(matches!(
+        verified.interpret(&mut vm).unwrap_err().error,
+        VmError::BadVmState
+    ));
+}